@@ -0,0 +1,121 @@
+//! ACPIテーブルを読み込むモジュール
+//!
+//! ブートローダから渡されたRSDPのアドレスを起点にテーブルを辿り、
+//! MADT・FADT・HPET・MCFGを解析した結果をカーネル全体から参照できるようにする
+
+use core::ptr::NonNull;
+
+use ::acpi::{
+    fadt::Fadt, platform::interrupt::Apic, AcpiError, AcpiHandler, AcpiResult, AcpiTables,
+    HpetInfo, InterruptModel, PciConfigRegions, PhysicalMapping, PlatformInfo,
+};
+use alloc::alloc::Global;
+use spin::Once;
+use x86_64::VirtAddr;
+
+static ACPI_INFO: Once<AcpiInfo> = Once::new();
+
+/// 物理メモリ全体が一定のオフセットでマップされていることを利用して、ACPIテーブルにアクセスするハンドラ
+///
+/// `memory::paging::init()`に渡したものと同じオフセットを使う
+#[derive(Debug, Clone, Copy)]
+pub struct PhysicalOffsetHandler {
+    physical_memory_offset: VirtAddr,
+}
+
+impl PhysicalOffsetHandler {
+    /// 物理メモリのオフセットからハンドラを作る
+    ///
+    /// ## Safety
+    /// 呼び出し元は全物理メモリが与えられた `physical_memory_offset`（だけずらした上）でマップされていることを保証しなければならない
+    pub const unsafe fn new(physical_memory_offset: VirtAddr) -> Self {
+        PhysicalOffsetHandler {
+            physical_memory_offset,
+        }
+    }
+}
+
+impl AcpiHandler for PhysicalOffsetHandler {
+    unsafe fn map_physical_region<T>(
+        &self,
+        physical_address: usize,
+        size: usize,
+    ) -> PhysicalMapping<Self, T> {
+        let virtual_address = self.physical_memory_offset + physical_address;
+        let virtual_start = NonNull::new(virtual_address.as_mut_ptr())
+            .expect("ACPI table is mapped at a null address");
+
+        PhysicalMapping::new(physical_address, virtual_start, size, size, *self)
+    }
+
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {
+        // 物理メモリは常にマップされているため、何もしない
+    }
+}
+
+/// 解析済みのACPIテーブルの情報
+pub struct AcpiInfo {
+    /// MADTとFADTから得た割り込みコントローラ・プロセッサ・電源管理の情報
+    pub platform_info: PlatformInfo<'static, Global>,
+    /// FADTの写し
+    pub fadt: Fadt,
+    /// HPETテーブルの情報。HPETが無い環境では`None`になる
+    pub hpet: Option<HpetInfo>,
+    /// MCFGテーブルから得たPCIe拡張コンフィギュレーション空間の情報。MCFGが無い環境では`None`になる
+    pub pci_config_regions: Option<PciConfigRegions<'static, Global>>,
+}
+
+impl AcpiInfo {
+    /// MADTに記載されたAPICの構成を返す。APICを使わない環境では`None`になる
+    pub fn apic(&self) -> Option<&Apic<'static, Global>> {
+        match &self.platform_info.interrupt_model {
+            InterruptModel::Apic(apic) => Some(apic),
+            _ => None,
+        }
+    }
+}
+
+/// RSDPからACPIテーブルを辿って解析し、結果を保存する
+///
+/// HPETとMCFGは必須ではないため、見つからなくてもエラーにはしない
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. 全物理メモリが与えられた `physical_memory_offset`（だけずらした上）でマップされていること
+/// 1. `rsdp_addr`が有効なRSDPの物理アドレスであること
+/// 1. ヒープが初期化済みであること
+pub unsafe fn init(rsdp_addr: u64, physical_memory_offset: u64) -> AcpiResult<&'static AcpiInfo> {
+    if let Some(info) = ACPI_INFO.get() {
+        return Ok(info);
+    }
+
+    let handler = PhysicalOffsetHandler::new(VirtAddr::new(physical_memory_offset));
+    let tables = AcpiTables::from_rsdp(handler, rsdp_addr as usize)?;
+
+    let platform_info = PlatformInfo::new(&tables)?;
+    let fadt = *tables.find_table::<Fadt>()?;
+    let hpet = optional_table(HpetInfo::new(&tables))?;
+    let pci_config_regions = optional_table(PciConfigRegions::new(&tables))?;
+
+    Ok(ACPI_INFO.call_once(|| AcpiInfo {
+        platform_info,
+        fadt,
+        hpet,
+        pci_config_regions,
+    }))
+}
+
+/// 解析済みのACPIテーブルの情報を返す。`init()`の前は`None`になる
+#[inline(always)]
+pub fn info() -> Option<&'static AcpiInfo> {
+    ACPI_INFO.get()
+}
+
+/// テーブルが存在しないことによるエラーだけを`None`に変換する
+fn optional_table<T>(result: AcpiResult<T>) -> AcpiResult<Option<T>> {
+    match result {
+        Ok(table) => Ok(Some(table)),
+        Err(AcpiError::TableMissing(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -11,3 +11,30 @@ pub fn halt() {
         asm!("hlt", options(nomem, nostack, preserves_flags));
     }
 }
+
+/// マスク可能な割り込みを有効化する
+#[inline(always)]
+pub fn enable() {
+    x86_64::instructions::interrupts::enable();
+}
+
+/// マスク可能な割り込みを無効化する
+#[inline(always)]
+pub fn disable() {
+    x86_64::instructions::interrupts::disable();
+}
+
+/// 割り込みを無効にした状態で`is_idle`を呼び、`true`であれば割り込みがあるまでCPUの動きを止める
+///
+/// 確認してから止まるまでの間に入った割り込みを取りこぼさないよう、割り込みの有効化と`hlt`を続けて実行する。
+/// 戻るときには割り込みは有効になっている
+pub fn halt_if(is_idle: &dyn Fn() -> bool) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::disable();
+    if is_idle() {
+        interrupts::enable_and_hlt();
+    } else {
+        interrupts::enable();
+    }
+}
@@ -1 +1,232 @@
+//! APIC (Advanced Programmable Interrupt Controller)を扱うモジュール
+//!
+//! MADTから得たLocal APICとI/O APICの情報をもとに割り込みコントローラを初期化する。
+//! レガシーな8259 PICは初期化時にマスクし、以降は使わない
 
+pub mod io_apic;
+pub mod local_apic;
+
+use acpi::platform::interrupt::{
+    InterruptSourceOverride, IoApic as IoApicInfo, Polarity as AcpiPolarity,
+    TriggerMode as AcpiTriggerMode,
+};
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use self::{
+    io_apic::{IoApic, Polarity, RedirectionEntry, TriggerMode},
+    local_apic::LocalApic,
+};
+use crate::memory::mmio::{self, CacheType};
+
+/// レガシーなISA IRQを割り当てる割り込みベクタの先頭。IRQ nはベクタ`ISA_IRQ_BASE + n`になる
+pub const ISA_IRQ_BASE: u8 = 0x20;
+/// ISA IRQの数
+pub const ISA_IRQ_COUNT: u8 = 16;
+/// Local APICタイマの割り込みベクタ
+pub const TIMER_VECTOR: u8 = 0x30;
+/// TLBシュートダウンを依頼するIPIのベクタ
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xfd;
+/// Local APICのエラー割り込みのベクタ
+pub const ERROR_VECTOR: u8 = 0xfe;
+/// Local APICのスプリアス割り込みのベクタ
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// 使わない8259 PICに割り当てておくベクタの先頭。マスタが`LEGACY_PIC_BASE`から、スレーブがその8つ後ろから使う
+///
+/// `ISA_IRQ_BASE`と重なると、PICのスプリアス割り込みがI/O APICから届いたISA IRQと見分けられなくなる
+pub const LEGACY_PIC_BASE: u8 = 0xf0;
+/// マスクしたPICでも発生しうるスプリアス割り込み（マスタのIRQ7とスレーブのIRQ15）のベクタ
+pub const LEGACY_PIC_SPURIOUS_VECTORS: [u8; 2] = [LEGACY_PIC_BASE + 7, LEGACY_PIC_BASE + 8 + 7];
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+static ISA_ROUTES: Once<[IsaRoute; ISA_IRQ_COUNT as usize]> = Once::new();
+
+/// ISA IRQがどのGSIにどのような信号で接続されているか
+#[derive(Debug, Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+}
+
+impl IsaRoute {
+    /// 割り込みソースオーバーライドが無い場合、ISA IRQは同じ番号のGSIにエッジトリガ・アクティブハイで接続される
+    const fn identity(irq: u8) -> Self {
+        IsaRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }
+    }
+}
+
+/// MADTから得た情報をもとにLocal APICとI/O APICを初期化し、レガシーPICを無効化する
+///
+/// 各APICのレジスタ領域は、キャッシュしない設定でカーネルの仮想アドレス空間にマップする
+///
+/// ## Panic
+/// メモリ管理が初期化されていないなど、レジスタ領域をマップできなかった場合はパニックを起こす
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. 与えた各アドレスが、MADTに記載された各APICのレジスタ領域の物理アドレスであること
+/// 1. `SPURIOUS_VECTOR`と`ERROR_VECTOR`のハンドラがIDTに登録済みであること
+/// 1. この関数が全処理の中で一度だけ呼び出されていること
+pub unsafe fn init(
+    local_apic_address: u64,
+    io_apics: &[IoApicInfo],
+    interrupt_source_overrides: &[InterruptSourceOverride],
+) {
+    disable_legacy_pic();
+
+    let local_apic = LOCAL_APIC.call_once(|| {
+        let registers = mmio::ioremap(
+            "local APIC",
+            PhysAddr::new(local_apic_address),
+            local_apic::REGISTERS_SIZE,
+            CacheType::Uncached,
+        )
+        .expect("Failed to map the local APIC registers");
+        LocalApic::new(registers)
+    });
+    local_apic.enable(SPURIOUS_VECTOR, ERROR_VECTOR);
+
+    IO_APICS.call_once(|| {
+        io_apics
+            .iter()
+            .map(|info| {
+                let registers = mmio::ioremap(
+                    "I/O APIC",
+                    PhysAddr::new(info.address as u64),
+                    io_apic::REGISTERS_SIZE,
+                    CacheType::Uncached,
+                )
+                .expect("Failed to map the I/O APIC registers");
+                let io_apic = IoApic::new(registers, info.id, info.global_system_interrupt_base);
+                io_apic.mask_all();
+                io_apic
+            })
+            .collect()
+    });
+
+    ISA_ROUTES.call_once(|| {
+        let mut routes: [IsaRoute; ISA_IRQ_COUNT as usize] =
+            core::array::from_fn(|irq| IsaRoute::identity(irq as u8));
+
+        for source_override in interrupt_source_overrides {
+            let Some(route) = routes.get_mut(source_override.isa_source as usize) else {
+                continue;
+            };
+
+            route.gsi = source_override.global_system_interrupt;
+            // `SameAsBus`はISAバスの既定値（アクティブハイ・エッジトリガ）として扱う
+            route.polarity = match source_override.polarity {
+                AcpiPolarity::ActiveLow => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            };
+            route.trigger_mode = match source_override.trigger_mode {
+                AcpiTriggerMode::Level => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            };
+        }
+
+        routes
+    });
+}
+
+/// このプロセッサのLocal APICを返す。`init()`の前は`None`になる
+#[inline(always)]
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// Local APICに割り込み処理の終了を通知する。割り込みハンドラの最後に呼ぶこと
+#[inline(always)]
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = LOCAL_APIC.get() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+/// ISA IRQを、このプロセッサのベクタ`ISA_IRQ_BASE + irq`へ配送するよう設定し、マスクを解除する
+///
+/// ## Panic
+/// `init()`の前に呼び出した場合や、IRQ番号が範囲外の場合、IRQに対応するI/O APICが無い場合はパニックを起こす
+pub fn route_isa_irq(irq: u8) {
+    let route = isa_route(irq);
+    let destination = LOCAL_APIC
+        .get()
+        .expect("Local APIC is not initialized")
+        .id();
+
+    io_apic_for(route.gsi).set_entry(
+        route.gsi,
+        RedirectionEntry {
+            vector: ISA_IRQ_BASE + irq,
+            destination,
+            polarity: route.polarity,
+            trigger_mode: route.trigger_mode,
+            masked: false,
+        },
+    );
+}
+
+/// ISA IRQのマスク状態を変更する
+///
+/// ## Panic
+/// `route_isa_irq()`と同じ条件でパニックを起こす
+pub fn set_isa_irq_masked(irq: u8, masked: bool) {
+    let route = isa_route(irq);
+    io_apic_for(route.gsi).set_masked(route.gsi, masked);
+}
+
+fn isa_route(irq: u8) -> IsaRoute {
+    assert!(irq < ISA_IRQ_COUNT, "ISA IRQ {} is out of range", irq);
+    ISA_ROUTES.get().expect("I/O APIC is not initialized")[irq as usize]
+}
+
+fn io_apic_for(gsi: u32) -> &'static IoApic {
+    IO_APICS
+        .get()
+        .expect("I/O APIC is not initialized")
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .unwrap_or_else(|| panic!("No I/O APIC handles GSI {}", gsi))
+}
+
+/// レガシーな8259 PICをリマップした上ですべてのIRQをマスクする
+///
+/// マスクしていても発生しうるスプリアス割り込みがCPU例外やISA IRQのベクタと重ならないよう、
+/// 先にベクタを`LEGACY_PIC_BASE`へずらしておく
+fn disable_legacy_pic() {
+    const PIC1_COMMAND: u16 = 0x20;
+    const PIC1_DATA: u16 = 0x21;
+    const PIC2_COMMAND: u16 = 0xa0;
+    const PIC2_DATA: u16 = 0xa1;
+
+    let mut pic1_command: Port<u8> = Port::new(PIC1_COMMAND);
+    let mut pic1_data: Port<u8> = Port::new(PIC1_DATA);
+    let mut pic2_command: Port<u8> = Port::new(PIC2_COMMAND);
+    let mut pic2_data: Port<u8> = Port::new(PIC2_DATA);
+
+    unsafe {
+        // ICW1: 初期化開始、ICW4あり
+        pic1_command.write(0x11);
+        pic2_command.write(0x11);
+        // ICW2: ベクタオフセット
+        pic1_data.write(LEGACY_PIC_BASE);
+        pic2_data.write(LEGACY_PIC_BASE + 8);
+        // ICW3: マスタのIRQ2にスレーブを接続
+        pic1_data.write(1 << 2);
+        pic2_data.write(2);
+        // ICW4: 8086モード
+        pic1_data.write(0x01);
+        pic2_data.write(0x01);
+
+        // すべてのIRQをマスクする
+        pic1_data.write(0xff);
+        pic2_data.write(0xff);
+    }
+}
@@ -0,0 +1,173 @@
+//! I/O APICのドライバ
+
+use spin::Mutex;
+
+use crate::memory::mmio::IoMem;
+
+// レジスタ選択用・データ用のMMIOレジスタのオフセット
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+/// レジスタ領域のうち、実際に使う大きさ
+pub const REGISTERS_SIZE: usize = 0x20;
+
+// IOREGSELで選択する間接レジスタの番号
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+/// リダイレクションエントリのマスクビット
+const ENTRY_MASKED: u64 = 1 << 16;
+
+/// 割り込み信号の極性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// 割り込み信号のトリガモード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// I/O APICのリダイレクションテーブルの一項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    /// 配送する割り込みベクタ
+    pub vector: u8,
+    /// 配送先のLocal APIC ID
+    pub destination: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    fn to_bits(self) -> u64 {
+        // 配送モードはFixed、宛先モードは物理モードで固定する
+        let mut bits = self.vector as u64 | (self.destination as u64) << 56;
+        if self.polarity == Polarity::ActiveLow {
+            bits |= 1 << 13;
+        }
+        if self.trigger_mode == TriggerMode::Level {
+            bits |= 1 << 15;
+        }
+        if self.masked {
+            bits |= ENTRY_MASKED;
+        }
+        bits
+    }
+}
+
+/// I/O APICを操作する構造体
+///
+/// レジスタへのアクセスは選択と読み書きの二段階で行うため、内部でロックを取る
+#[derive(Debug)]
+pub struct IoApic {
+    registers: Mutex<IoMem>,
+    id: u8,
+    global_system_interrupt_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    /// I/O APICのレジスタ領域をマップしたハンドルから構造体を作る
+    ///
+    /// ## Safety
+    /// 呼び出し元は`registers`がI/O APICのレジスタ領域をキャッシュせずにマップしたものであることを保証しなければならない
+    pub unsafe fn new(registers: IoMem, id: u8, global_system_interrupt_base: u32) -> Self {
+        let mut io_apic = IoApic {
+            registers: Mutex::new(registers),
+            id,
+            global_system_interrupt_base,
+            redirection_entries: 0,
+        };
+        io_apic.redirection_entries = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        let registers = self.registers.lock();
+        registers.write(IOREGSEL, register);
+        registers.read(IOWIN)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        let registers = self.registers.lock();
+        registers.write(IOREGSEL, register);
+        registers.write(IOWIN, value);
+    }
+
+    /// MADTに記載されたI/O APIC ID
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// レジスタから読み出したI/O APIC ID
+    pub fn hardware_id(&self) -> u8 {
+        ((self.read(REG_ID) >> 24) & 0x0f) as u8
+    }
+
+    /// このI/O APICが受け持つグローバルシステム割り込み (GSI)の範囲に`gsi`が含まれるかを返す
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.global_system_interrupt_base
+            ..self.global_system_interrupt_base + self.redirection_entries)
+            .contains(&gsi)
+    }
+
+    /// 入力ピンの数
+    pub fn redirection_entries(&self) -> u32 {
+        self.redirection_entries
+    }
+
+    /// GSIに対応するリダイレクションエントリを書き込む
+    ///
+    /// ## Panic
+    /// このI/O APICが`gsi`を受け持っていない場合はパニックを起こす
+    pub fn set_entry(&self, gsi: u32, entry: RedirectionEntry) {
+        assert!(
+            self.handles(gsi),
+            "GSI {} is not handled by this I/O APIC",
+            gsi
+        );
+
+        let register = REG_REDIRECTION_TABLE + (gsi - self.global_system_interrupt_base) * 2;
+        let bits = entry.to_bits();
+
+        // 書き換え中に中途半端なエントリで割り込みが配送されないよう、先にマスクしてから上位、下位の順に書き込む
+        self.write(register, ENTRY_MASKED as u32);
+        self.write(register + 1, (bits >> 32) as u32);
+        self.write(register, bits as u32);
+    }
+
+    /// GSIのマスク状態を変更する
+    ///
+    /// ## Panic
+    /// このI/O APICが`gsi`を受け持っていない場合はパニックを起こす
+    pub fn set_masked(&self, gsi: u32, masked: bool) {
+        assert!(
+            self.handles(gsi),
+            "GSI {} is not handled by this I/O APIC",
+            gsi
+        );
+
+        let register = REG_REDIRECTION_TABLE + (gsi - self.global_system_interrupt_base) * 2;
+        let low = self.read(register);
+        let low = if masked {
+            low | ENTRY_MASKED as u32
+        } else {
+            low & !(ENTRY_MASKED as u32)
+        };
+        self.write(register, low);
+    }
+
+    /// すべての入力ピンをマスクする
+    pub fn mask_all(&self) {
+        for index in 0..self.redirection_entries {
+            self.write(REG_REDIRECTION_TABLE + index * 2, ENTRY_MASKED as u32);
+        }
+    }
+}
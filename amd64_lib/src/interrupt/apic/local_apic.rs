@@ -0,0 +1,290 @@
+//! Local APICのドライバ
+//!
+//! xAPICモードでのMMIOレジスタ操作のみに対応している
+
+use x86_64::registers::model_specific::Msr;
+
+use crate::memory::mmio::IoMem;
+
+/// レジスタ領域の大きさ
+pub const REGISTERS_SIZE: usize = 0x1000;
+
+/// Local APICのベースアドレスを保持するMSR
+const IA32_APIC_BASE: u32 = 0x1b;
+/// TSCデッドラインモードで使う期限を書き込むMSR
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+/// `IA32_APIC_BASE`のうち、APICを有効化するビット
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// レジスタのオフセット
+const REG_ID: usize = 0x20;
+const REG_VERSION: usize = 0x30;
+const REG_TASK_PRIORITY: usize = 0x80;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ERROR_STATUS: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_LINT0: usize = 0x350;
+const REG_LVT_LINT1: usize = 0x360;
+const REG_LVT_ERROR: usize = 0x370;
+const REG_TIMER_INITIAL_COUNT: usize = 0x380;
+const REG_TIMER_CURRENT_COUNT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+/// スプリアス割り込みベクタレジスタのうち、APICをソフトウェア的に有効化するビット
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+/// LVTエントリのマスクビット
+const LVT_MASKED: u32 = 1 << 16;
+/// ICRの送信中ビット
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// ICRのレベルアサートビット
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Local APICタイマの動作モード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// 初期カウントが0になった時に一度だけ割り込む
+    OneShot,
+    /// 初期カウントが0になるたびに割り込み、カウントを再設定する
+    Periodic,
+    /// TSCが`IA32_TSC_DEADLINE`に書き込んだ値に達した時に割り込む
+    TscDeadline,
+}
+
+impl TimerMode {
+    const fn lvt_bits(self) -> u32 {
+        match self {
+            TimerMode::OneShot => 0b00 << 17,
+            TimerMode::Periodic => 0b01 << 17,
+            TimerMode::TscDeadline => 0b10 << 17,
+        }
+    }
+}
+
+/// Local APICタイマのカウンタをバスクロックの何分の一で減らすか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerDivide {
+    By1,
+    By2,
+    By4,
+    By8,
+    By16,
+    By32,
+    By64,
+    By128,
+}
+
+impl TimerDivide {
+    /// 分周比を設定するレジスタに書き込む値（ビット0, 1, 3を使う変則的な配置になっている）
+    const fn register_bits(self) -> u32 {
+        match self {
+            TimerDivide::By1 => 0b1011,
+            TimerDivide::By2 => 0b0000,
+            TimerDivide::By4 => 0b0001,
+            TimerDivide::By8 => 0b0010,
+            TimerDivide::By16 => 0b0011,
+            TimerDivide::By32 => 0b1000,
+            TimerDivide::By64 => 0b1001,
+            TimerDivide::By128 => 0b1010,
+        }
+    }
+
+    /// 分周比を数値で返す
+    pub const fn divisor(self) -> u32 {
+        match self {
+            TimerDivide::By1 => 1,
+            TimerDivide::By2 => 2,
+            TimerDivide::By4 => 4,
+            TimerDivide::By8 => 8,
+            TimerDivide::By16 => 16,
+            TimerDivide::By32 => 32,
+            TimerDivide::By64 => 64,
+            TimerDivide::By128 => 128,
+        }
+    }
+}
+
+/// プロセッサ間割り込み (Inter-Processor Interrupt, IPI)の配送モード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    StartUp = 0b110,
+}
+
+/// IPIの送り先
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDestination {
+    /// 指定したAPIC IDを持つプロセッサ
+    Single(u8),
+    /// 自分自身
+    SelfOnly,
+    /// 自分自身を含むすべてのプロセッサ
+    AllIncludingSelf,
+    /// 自分自身を除くすべてのプロセッサ
+    AllExcludingSelf,
+}
+
+impl IpiDestination {
+    const fn shorthand_bits(self) -> u32 {
+        match self {
+            IpiDestination::Single(_) => 0b00 << 18,
+            IpiDestination::SelfOnly => 0b01 << 18,
+            IpiDestination::AllIncludingSelf => 0b10 << 18,
+            IpiDestination::AllExcludingSelf => 0b11 << 18,
+        }
+    }
+}
+
+/// Local APICを操作する構造体
+///
+/// レジスタはプロセッサごとに存在するため、同じアドレスでも実行中のプロセッサのLocal APICを操作することになる
+#[derive(Debug)]
+pub struct LocalApic {
+    registers: IoMem,
+}
+
+impl LocalApic {
+    /// Local APICのレジスタ領域をマップしたハンドルから構造体を作る
+    ///
+    /// ## Safety
+    /// 呼び出し元は`registers`がLocal APICのレジスタ領域（4KiB）をキャッシュせずにマップしたものであることを保証しなければならない
+    pub const unsafe fn new(registers: IoMem) -> Self {
+        LocalApic { registers }
+    }
+
+    #[inline(always)]
+    fn read(&self, offset: usize) -> u32 {
+        self.registers.read(offset)
+    }
+
+    #[inline(always)]
+    fn write(&self, offset: usize, value: u32) {
+        self.registers.write(offset, value)
+    }
+
+    /// Local APICを有効化し、スプリアス割り込みとエラー割り込みのベクタを設定する
+    ///
+    /// LINT0とLINT1はI/O APICを使うため、マスクしておく
+    ///
+    /// ## Safety
+    /// 呼び出し元は、与えたベクタにハンドラがIDTに登録済みであることを保証しなければならない
+    pub unsafe fn enable(&self, spurious_vector: u8, error_vector: u8) {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        apic_base.write(apic_base.read() | APIC_BASE_ENABLE);
+
+        self.write(REG_TASK_PRIORITY, 0);
+        self.write(REG_LVT_LINT0, LVT_MASKED);
+        self.write(REG_LVT_LINT1, LVT_MASKED);
+        self.write(REG_LVT_ERROR, error_vector as u32);
+        self.write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | spurious_vector as u32);
+
+        // 有効化以前に溜まっていたエラーを消しておく
+        self.error_status();
+        self.end_of_interrupt();
+    }
+
+    /// このプロセッサのLocal APIC IDを返す
+    pub fn id(&self) -> u8 {
+        (self.read(REG_ID) >> 24) as u8
+    }
+
+    /// Local APICのバージョンを返す
+    pub fn version(&self) -> u8 {
+        self.read(REG_VERSION) as u8
+    }
+
+    /// 割り込み処理の終了 (End of Interrupt, EOI)を通知する
+    #[inline(always)]
+    pub fn end_of_interrupt(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// エラーステータスレジスタを読み出す
+    pub fn error_status(&self) -> u32 {
+        // 読み出す前に書き込むことで、最新のエラー状態がレジスタに反映される
+        self.write(REG_ERROR_STATUS, 0);
+        self.read(REG_ERROR_STATUS)
+    }
+
+    /// タイマの動作モード、割り込みベクタ、分周比を設定する。タイマはこの時点では動き出さない
+    ///
+    /// ## Panic
+    /// `TimerMode::TscDeadline`を指定したにもかかわらず、CPUがTSCデッドラインモードに対応していない場合はパニックを起こす
+    pub fn configure_timer(&self, mode: TimerMode, vector: u8, divide: TimerDivide) {
+        if mode == TimerMode::TscDeadline {
+            assert!(
+                supports_tsc_deadline(),
+                "TSC deadline mode is not supported by this CPU"
+            );
+        }
+
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+        self.write(REG_TIMER_DIVIDE, divide.register_bits());
+        self.write(REG_LVT_TIMER, mode.lvt_bits() | vector as u32);
+    }
+
+    /// ワンショットまたは周期モードのタイマに初期カウントを書き込み、カウントダウンを開始させる
+    #[inline(always)]
+    pub fn start_timer(&self, initial_count: u32) {
+        self.write(REG_TIMER_INITIAL_COUNT, initial_count);
+    }
+
+    /// TSCデッドラインモードのタイマに期限を設定する。0を書き込むとタイマは止まる
+    ///
+    /// ## Safety
+    /// 呼び出し元は、事前に`TimerMode::TscDeadline`でタイマを設定していることを保証しなければならない
+    #[inline(always)]
+    pub unsafe fn set_tsc_deadline(&self, deadline: u64) {
+        Msr::new(IA32_TSC_DEADLINE).write(deadline);
+    }
+
+    /// タイマの現在のカウントを返す
+    #[inline(always)]
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(REG_TIMER_CURRENT_COUNT)
+    }
+
+    /// タイマを止める
+    pub fn stop_timer(&self) {
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+        self.write(REG_LVT_TIMER, self.read(REG_LVT_TIMER) | LVT_MASKED);
+    }
+
+    /// プロセッサ間割り込みを送り、配送が完了するまで待つ
+    ///
+    /// ## Safety
+    /// INITやSIPIは送り先のプロセッサをリセットするため、呼び出し元はその影響に責任を持たなければならない
+    pub unsafe fn send_ipi(&self, destination: IpiDestination, mode: DeliveryMode, vector: u8) {
+        let destination_id = match destination {
+            IpiDestination::Single(id) => id,
+            _ => 0,
+        };
+
+        // INITのデアサートは古いプロセッサ向けのものなので、常にアサートで送る
+        let command =
+            vector as u32 | (mode as u32) << 8 | ICR_LEVEL_ASSERT | destination.shorthand_bits();
+
+        // 上位側を先に書き込む。下位側への書き込みで送信が始まる
+        self.write(REG_ICR_HIGH, (destination_id as u32) << 24);
+        self.write(REG_ICR_LOW, command);
+
+        while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// CPUがLocal APICタイマのTSCデッドラインモードに対応しているかを返す
+pub fn supports_tsc_deadline() -> bool {
+    // 対象ツールチェインによっては`__cpuid`がunsafeでないため、警告を抑制する
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(0x1) };
+    cpuid.ecx & (1 << 24) != 0
+}
@@ -1,35 +1,299 @@
+use core::fmt;
+
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use spin::Once;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{
+        InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, SelectorErrorCode,
+    },
+};
 
-use crate::interrupt::gdt;
+use crate::{
+    interrupt::{apic, gdt},
+    keyboard,
+    memory::{
+        demand::{self, FaultError},
+        tlb,
+    },
+    serial, serial_println, thread, timer,
+};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); // new
         }
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.cp_protection_exception.set_handler_fn(cp_protection_handler);
+        idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+        idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+        idt.security_exception.set_handler_fn(security_exception_handler);
+        idt[(apic::ISA_IRQ_BASE + timer::pit::IRQ) as usize]
+            .set_handler_fn(timer::pit_interrupt_handler);
+        idt[(apic::ISA_IRQ_BASE + keyboard::IRQ) as usize]
+            .set_handler_fn(keyboard::keyboard_interrupt_handler);
+        idt[(apic::ISA_IRQ_BASE + serial::IRQ) as usize]
+            .set_handler_fn(serial::serial_interrupt_handler);
+        idt[apic::TIMER_VECTOR as usize].set_handler_fn(timer::local_apic_timer_interrupt_handler);
+        idt[apic::TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(tlb::tlb_shootdown_interrupt_handler);
+        idt[apic::ERROR_VECTOR as usize].set_handler_fn(apic_error_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        for vector in apic::LEGACY_PIC_SPURIOUS_VECTORS {
+            idt[vector as usize].set_handler_fn(spurious_interrupt_handler);
+        }
 
         idt
     };
 }
 
+/// 回復可能な例外の内容を、シリアル以外にも出力するための関数
+static EXCEPTION_REPORTER: Once<fn(fmt::Arguments)> = Once::new();
+
 /// 割り込み記述子表 (interrupt descriptor table, 以下IDT)の初期化
 pub fn init() {
     IDT.load();
 }
 
+/// ブレークポイントなどの回復可能な例外の内容を、シリアルに加えて出力する関数を登録する
+///
+/// 回復不能な例外はパニックとして報告されるため、この関数を通らない
+pub fn set_exception_reporter(reporter: fn(fmt::Arguments)) {
+    EXCEPTION_REPORTER.call_once(|| reporter);
+}
+
+/// 回復可能な例外の内容をシリアルと登録済みの出力先に書き出す
+fn report(args: fmt::Arguments) {
+    serial_println!("{}", args);
+    if let Some(reporter) = EXCEPTION_REPORTER.get() {
+        reporter(format_args!("{}\n", args));
+    }
+}
+
+/// セグメントセレクタを含むエラーコードを各ビットフィールドに分解して表示するための型
+struct SelectorError(u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SelectorErrorCode::new(self.0) {
+            Some(code) => write!(
+                f,
+                "{:#x} (external: {}, table: {:?}, index: {})",
+                self.0,
+                code.external(),
+                code.descriptor_table(),
+                code.index()
+            ),
+            None => write!(f, "{:#x} (reserved bits are set)", self.0),
+        }
+    }
+}
+
+/// エラーコードを持たない、回復不能な例外のハンドラを定義する
+macro_rules! fatal_handler {
+    ($name:ident, $vector:literal, $description:literal) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+            panic!(
+                concat!(
+                    "EXCEPTION: ",
+                    $description,
+                    " (vector ",
+                    $vector,
+                    ")\n{:#?}"
+                ),
+                stack_frame
+            );
+        }
+    };
+}
+
+/// セグメントセレクタをエラーコードとして持つ、回復不能な例外のハンドラを定義する
+macro_rules! fatal_selector_handler {
+    ($name:ident, $vector:literal, $description:literal) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) {
+            panic!(
+                concat!(
+                    "EXCEPTION: ",
+                    $description,
+                    " (vector ",
+                    $vector,
+                    ")\nError code: {}\n{:#?}"
+                ),
+                SelectorError(error_code),
+                stack_frame
+            );
+        }
+    };
+}
+
+fatal_handler!(divide_error_handler, 0, "DIVIDE ERROR");
+fatal_handler!(overflow_handler, 4, "OVERFLOW");
+fatal_handler!(bound_range_exceeded_handler, 5, "BOUND RANGE EXCEEDED");
+fatal_handler!(invalid_opcode_handler, 6, "INVALID OPCODE");
+fatal_handler!(device_not_available_handler, 7, "DEVICE NOT AVAILABLE");
+fatal_selector_handler!(invalid_tss_handler, 10, "INVALID TSS");
+fatal_selector_handler!(segment_not_present_handler, 11, "SEGMENT NOT PRESENT");
+fatal_selector_handler!(stack_segment_fault_handler, 12, "STACK SEGMENT FAULT");
+fatal_selector_handler!(
+    general_protection_fault_handler,
+    13,
+    "GENERAL PROTECTION FAULT"
+);
+fatal_handler!(x87_floating_point_handler, 16, "x87 FLOATING POINT");
+fatal_handler!(simd_floating_point_handler, 19, "SIMD FLOATING POINT");
+fatal_handler!(virtualization_handler, 20, "VIRTUALIZATION");
+fatal_handler!(hv_injection_handler, 28, "HYPERVISOR INJECTION");
+
+/// デバッグ例外
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    report(format_args!(
+        "EXCEPTION: DEBUG (vector 1)\n{:#?}",
+        stack_frame
+    ));
+}
+
+/// マスク不能割り込み
+///
+/// 割り込みを無効にしていても入ってくるため、ロックを持っている処理に割り込んでいることがある。
+/// ロックを取らずにシリアルへ直接書き出し、画面には出力しない
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    serial::write_unlocked(format_args!(
+        "EXCEPTION: NON MASKABLE INTERRUPT (vector 2)\n{:#?}\n",
+        stack_frame
+    ));
+}
+
 /// ブレークポイント割り込み
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    // println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    report(format_args!(
+        "EXCEPTION: BREAKPOINT (vector 3)\n{:#?}",
+        stack_frame
+    ));
 }
 
 /// ダブルフォルト割り込み
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    // スタックがガードページに達すると、ページフォルトの例外フレームを積めずにダブルフォルトになる
+    if let Some(name) = thread::guard_page_hit(Cr2::read().as_u64()) {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT (vector 8)\nKernel stack overflow in thread `{}`\n{:#?}",
+            name, stack_frame
+        );
+    }
+
+    panic!(
+        "EXCEPTION: DOUBLE FAULT (vector 8)\nError code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+/// ページフォルト
+///
+/// デマンドページングの領域内で、まだマップされていないページへのアクセスであれば、ページを割り当てて再実行する
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        match demand::handle_page_fault(Cr2::read()) {
+            Ok(()) => return,
+            Err(FaultError::NotDemandPaged) => {}
+            Err(e) => panic!(
+                "EXCEPTION: PAGE FAULT (vector 14)\n{}\n{:#?}",
+                e, stack_frame
+            ),
+        }
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT (vector 14)\nAccessed address: {:?}\nError code: {:#x} {:?}\n{:#?}",
+        Cr2::read(),
+        error_code.bits(),
+        error_code,
+        stack_frame
+    );
+}
+
+/// アラインメントチェック例外
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    panic!(
+        "EXCEPTION: ALIGNMENT CHECK (vector 17)\nError code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+/// マシンチェック例外
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: MACHINE CHECK (vector 18)\n{:#?}", stack_frame);
 }
+
+/// 制御フロー保護例外
+extern "x86-interrupt" fn cp_protection_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    // ビット0-14が原因の種類、ビット15がSGXに関係するかどうかを表す
+    panic!(
+        "EXCEPTION: CONTROL PROTECTION (vector 21)\nError code: {:#x} (cause: {}, enclave: {})\n{:#?}",
+        error_code,
+        error_code & 0x7fff,
+        error_code & (1 << 15) != 0,
+        stack_frame
+    );
+}
+
+/// VMM通信例外
+extern "x86-interrupt" fn vmm_communication_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    panic!(
+        "EXCEPTION: VMM COMMUNICATION (vector 29)\nError code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+/// セキュリティ例外
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    panic!(
+        "EXCEPTION: SECURITY (vector 30)\nError code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+/// Local APICのエラー割り込み
+extern "x86-interrupt" fn apic_error_handler(_stack_frame: InterruptStackFrame) {
+    if let Some(local_apic) = apic::local_apic() {
+        let error_status = local_apic.error_status();
+        log::error!("APIC error: {:#x}", error_status);
+    }
+    apic::end_of_interrupt();
+}
+
+/// Local APICと、使っていない8259 PICのスプリアス割り込み。EOIを送ってはならない
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
@@ -0,0 +1,142 @@
+//! PS/2キーボードのドライバ
+//!
+//! IRQ1で受け取ったスキャンコードを解釈し、`common_lib::input`の入力キューに積む
+
+use common_lib::{
+    input::{
+        self,
+        keyboard::{
+            layout::KeyboardLayout,
+            scancode::{ScancodeDecoder, ScancodeSet},
+            Keyboard,
+        },
+    },
+    sync::{IrqMutex, LockLevel},
+};
+use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+
+use crate::interrupt::apic;
+
+/// PS/2キーボードが接続されているISA IRQ
+pub const IRQ: u8 = 1;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+/// ステータスレジスタの出力バッファフルビット
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// ステータスレジスタの入力バッファフルビット
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// コントローラへのコマンド
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND_PORT: u8 = 0xa7;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xad;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xae;
+
+/// 設定バイトのうち、第一ポートの割り込みを有効にするビット
+const CONFIG_FIRST_PORT_INTERRUPT: u8 = 1 << 0;
+/// 設定バイトのうち、スキャンコードセット1への変換を有効にするビット
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// コントローラの応答を待つ最大の試行回数
+const TIMEOUT: usize = 100_000;
+
+/// IRQ1のハンドラからも使うため、ロック中は割り込みを無効にする
+static KEYBOARD: IrqMutex<Option<Keyboard>> =
+    IrqMutex::with_level(None, "keyboard", LockLevel::KEYBOARD);
+
+/// PS/2コントローラとキーボードを初期化し、IRQ1の割り込みを受け付け始める
+///
+/// スキャンコードセットはコントローラの変換設定から判断する
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. APICと入力キューが初期化済みであること
+/// 1. この関数が全処理の中で一度だけ呼び出されていること
+pub unsafe fn init(layout: &'static dyn KeyboardLayout) {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+
+    // 設定中にキーボードからの入力が混ざらないよう、両方のポートを止めておく
+    write_command(COMMAND_DISABLE_FIRST_PORT);
+    write_command(COMMAND_DISABLE_SECOND_PORT);
+    while read_status() & STATUS_OUTPUT_FULL != 0 {
+        data.read();
+    }
+
+    write_command(COMMAND_READ_CONFIG);
+    let config = read_data().unwrap_or(CONFIG_TRANSLATION);
+    let set = if config & CONFIG_TRANSLATION != 0 {
+        ScancodeSet::Set1
+    } else {
+        ScancodeSet::Set2
+    };
+
+    write_command(COMMAND_WRITE_CONFIG);
+    write_data(config | CONFIG_FIRST_PORT_INTERRUPT);
+
+    *KEYBOARD.lock() = Some(Keyboard::new(ScancodeDecoder::new(set), layout));
+
+    write_command(COMMAND_ENABLE_FIRST_PORT);
+    apic::route_isa_irq(IRQ);
+}
+
+/// キー配列を変更する
+pub fn set_layout(layout: &'static dyn KeyboardLayout) {
+    if let Some(keyboard) = KEYBOARD.lock().as_mut() {
+        keyboard.set_layout(layout);
+    }
+}
+
+fn read_status() -> u8 {
+    let mut status: Port<u8> = Port::new(STATUS_COMMAND_PORT);
+    unsafe { status.read() }
+}
+
+fn wait_for_input_empty() {
+    for _ in 0..TIMEOUT {
+        if read_status() & STATUS_INPUT_FULL == 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn write_command(command: u8) {
+    wait_for_input_empty();
+    Port::new(STATUS_COMMAND_PORT).write(command);
+}
+
+unsafe fn write_data(value: u8) {
+    wait_for_input_empty();
+    Port::new(DATA_PORT).write(value);
+}
+
+unsafe fn read_data() -> Option<u8> {
+    for _ in 0..TIMEOUT {
+        if read_status() & STATUS_OUTPUT_FULL != 0 {
+            return Some(Port::new(DATA_PORT).read());
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// キーボード割り込み
+pub(crate) extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    let scancode = unsafe { data.read() };
+
+    if let Some(keyboard) = KEYBOARD.lock().as_mut() {
+        if let Some(key) = keyboard
+            .add_byte(scancode)
+            .and_then(|event| keyboard.process_event(event))
+        {
+            // キューが溢れた場合は、その入力を捨てる
+            input::push_key(key);
+        }
+    }
+
+    apic::end_of_interrupt();
+}
@@ -1,7 +1,17 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
+#![feature(allocator_api)]
 #![feature(associated_type_bounds)]
 
+extern crate alloc;
+
+pub mod acpi;
 pub mod interrupt;
+pub mod keyboard;
 pub mod memory;
+pub mod pci;
+pub mod random;
 pub mod serial;
+pub mod thread;
+pub mod timer;
+pub mod virtio;
@@ -1,2 +1,67 @@
+//! メモリ管理を行うモジュール
+//!
+//! ページテーブルと物理フレームアロケータは`init()`で一度だけ作り、以降は`manager()`を通して共有する。
+//! ページフォルトハンドラからも使うため、ロック中は割り込みを無効にする
+
+pub mod demand;
+pub mod dma;
+pub mod frame;
 pub mod heap;
+pub mod mmio;
 pub mod paging;
+pub mod tlb;
+pub mod vma;
+
+use bootloader_api::info::MemoryRegions;
+use common_lib::sync::{IrqMutex, LockLevel};
+use spin::Once;
+use x86_64::structures::paging::OffsetPageTable;
+
+use self::{frame::BitmapFrameAllocator, vma::AddressSpace};
+
+/// カーネル全体で共有する、アクティブなページテーブルと物理フレームアロケータの組
+pub struct MemoryManager {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator,
+    pub physical_memory_offset: u64,
+    /// カーネルの仮想アドレス空間の使用状況
+    pub address_space: AddressSpace,
+}
+
+static MEMORY_MANAGER: Once<IrqMutex<MemoryManager>> = Once::new();
+
+/// ページテーブルと物理フレームアロケータを初期化する
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. 参照先のメモリマップが有効であり、特に`Usable`なフレームは実際に未使用であること
+/// 1. 全物理メモリが与えられた `physical_memory_offset`（だけずらした上）でマップされていること
+/// 1. 割り込みが無効になっていること
+/// 1. この関数が全処理の中で一度だけ呼び出されていること
+pub unsafe fn init(
+    memory_regions: &'static MemoryRegions,
+    physical_memory_offset: u64,
+) -> &'static IrqMutex<MemoryManager> {
+    MEMORY_MANAGER.call_once(|| {
+        let mut manager = MemoryManager {
+            mapper: paging::init(physical_memory_offset),
+            frame_allocator: BitmapFrameAllocator::init(memory_regions, physical_memory_offset),
+            physical_memory_offset,
+            address_space: AddressSpace::new(),
+        };
+
+        // ブートローダーは、メモリマップ中の最も大きなアドレスまでの物理メモリをマップしている
+        let physical_memory_size = memory_regions.iter().map(|r| r.end).max().unwrap_or(0);
+        vma::init(&mut manager, physical_memory_size);
+
+        // MMIO領域をマップする前に、ライトコンバイニングを使えるようにしておく
+        mmio::init_pat();
+
+        IrqMutex::with_level(manager, "memory manager", LockLevel::MEMORY_MANAGER)
+    })
+}
+
+/// 初期化済みであれば、共有のページテーブルと物理フレームアロケータを返す
+pub fn manager() -> Option<&'static IrqMutex<MemoryManager>> {
+    MEMORY_MANAGER.get()
+}
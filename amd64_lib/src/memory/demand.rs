@@ -0,0 +1,135 @@
+//! ページフォルトを契機に物理フレームを割り当てる、デマンドページング
+//!
+//! `Backing::Demand`を持つVMAへ初めてアクセスされたときに起こるページフォルトで、
+//! 物理フレームを割り当ててマップする。領域ごとにマップできるページ数の上限を持ち、
+//! 上限に達した場合と物理フレームが尽きた場合は、メモリ不足として報告する
+//!
+//! ページフォルトはアロケータのロックを取ったまま起こりうるため、この処理ではヒープを使わない
+
+use core::fmt;
+
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{
+    vma::{Backing, Vma},
+    MemoryManager,
+};
+
+/// ページフォルトを処理できなかった理由
+#[derive(Debug, Clone, Copy)]
+pub enum FaultError {
+    /// メモリ管理が初期化されていないか、予約済みの領域の外へのアクセス
+    NotDemandPaged,
+    /// 領域にマップできるページ数の上限に達した
+    LimitExceeded { region: Vma, address: VirtAddr },
+    /// 物理フレームが尽きた
+    OutOfFrames {
+        region: Vma,
+        address: VirtAddr,
+        total_frames: usize,
+    },
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultError::NotDemandPaged => write!(f, "Address is not in a demand-paged region"),
+            FaultError::LimitExceeded { region, address } => write!(
+                f,
+                "OUT OF MEMORY: region `{}` reached its limit while accessing {:#x}\n{}",
+                region.name,
+                address.as_u64(),
+                region
+            ),
+            FaultError::OutOfFrames {
+                region,
+                address,
+                total_frames,
+            } => write!(
+                f,
+                "OUT OF MEMORY: no free physical frames left ({} frames in total) \
+                 while accessing {:#x} in region `{}`\n{}",
+                total_frames,
+                address.as_u64(),
+                region.name,
+                region
+            ),
+        }
+    }
+}
+
+/// 存在しないページへのアクセスで起きたページフォルトを処理する
+///
+/// `address`がデマンドページングの領域に含まれていれば、物理フレームを割り当ててゼロで埋め、マップする。
+/// 成功した場合は、フォルトを起こした命令を再実行すればよい
+pub fn handle_page_fault(address: VirtAddr) -> Result<(), FaultError> {
+    let mut manager = super::manager().ok_or(FaultError::NotDemandPaged)?.lock();
+    let MemoryManager {
+        mapper,
+        frame_allocator,
+        physical_memory_offset,
+        address_space,
+    } = &mut *manager;
+
+    let region = address_space
+        .find_mut(address)
+        .ok_or(FaultError::NotDemandPaged)?;
+    let Backing::Demand {
+        limit_pages,
+        mapped_pages,
+    } = region.backing
+    else {
+        return Err(FaultError::NotDemandPaged);
+    };
+
+    if mapped_pages >= limit_pages {
+        return Err(FaultError::LimitExceeded {
+            region: *region,
+            address,
+        });
+    }
+
+    let page = Page::<Size4KiB>::containing_address(address);
+    let Some(frame) = frame_allocator.allocate_frame() else {
+        return Err(FaultError::OutOfFrames {
+            region: *region,
+            address,
+            total_frames: frame_allocator.total_frames(),
+        });
+    };
+
+    // 前の持ち主のデータが見えないよう、マップする前にゼロで埋める
+    unsafe {
+        let frame_address = (*physical_memory_offset + frame.start_address().as_u64()) as *mut u8;
+        frame_address.write_bytes(0, Size4KiB::SIZE as usize);
+    }
+
+    match unsafe { mapper.map_to(page, frame, region.flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        // 別の経路ですでにマップされていた場合は、そのまま再実行すればよい
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return Ok(());
+        }
+        Err(_) => {
+            // ページテーブル用のフレームが取れなかった
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return Err(FaultError::OutOfFrames {
+                region: *region,
+                address,
+                total_frames: frame_allocator.total_frames(),
+            });
+        }
+    }
+
+    region.backing = Backing::Demand {
+        limit_pages,
+        mapped_pages: mapped_pages + 1,
+    };
+    Ok(())
+}
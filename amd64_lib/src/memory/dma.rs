@@ -0,0 +1,101 @@
+//! DMAに使う、物理的に連続したバッファ
+//!
+//! デバイスは物理アドレスでメモリにアクセスするため、ヒープのように物理フレームが散らばった領域は渡せない。
+//! 連続した物理フレームを割り当て、物理メモリ全体のマッピングを通してカーネルからアクセスする。
+//! x86_64ではDMAとCPUのキャッシュの一貫性がハードウェアで保たれるため、キャッシュ属性は変えない
+
+use core::{ptr, slice};
+
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// DMAバッファを割り当てられなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// メモリ管理が初期化されていない
+    NotInitialized,
+    /// 連続した物理フレームが見つからなかった
+    OutOfFrames { pages: usize },
+}
+
+/// 物理的に連続したバッファ。破棄すると物理フレームを解放する
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    pages: usize,
+    virtual_address: VirtAddr,
+}
+
+impl DmaBuffer {
+    /// `size`バイト以上の、ゼロで埋めたバッファを割り当てる。先頭はページ境界に揃う
+    ///
+    /// ## Panic
+    /// `size`が0の場合はパニックを起こす
+    pub fn allocate(size: usize) -> Result<Self, DmaError> {
+        assert!(size > 0, "DMA buffer must not be empty");
+        let pages = size.div_ceil(Size4KiB::SIZE as usize);
+
+        let mut manager = super::manager().ok_or(DmaError::NotInitialized)?.lock();
+        let start = manager
+            .frame_allocator
+            .allocate_contiguous(pages)
+            .ok_or(DmaError::OutOfFrames { pages })?;
+        let virtual_address =
+            VirtAddr::new(manager.physical_memory_offset + start.start_address().as_u64());
+        drop(manager);
+
+        let buffer = DmaBuffer {
+            start,
+            pages,
+            virtual_address,
+        };
+        unsafe { ptr::write_bytes(buffer.as_mut_ptr(), 0, buffer.len()) };
+        Ok(buffer)
+    }
+
+    /// バッファの先頭の物理アドレス。デバイスに渡す
+    pub fn physical_address(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// バッファのバイト数（ページ単位に切り上げたもの）
+    pub fn len(&self) -> usize {
+        self.pages * Size4KiB::SIZE as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages == 0
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.virtual_address.as_ptr()
+    }
+
+    /// バッファの先頭を指すポインタ。デバイスと共有する領域は揮発性の読み書きでアクセスすること
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.virtual_address.as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Some(manager) = super::manager() {
+            unsafe {
+                manager
+                    .lock()
+                    .frame_allocator
+                    .deallocate_contiguous(self.start, self.pages)
+            };
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! 物理フレームを管理するビットマップアロケータ
+//!
+//! 物理メモリの4KiBフレーム一つにつき1ビットを割り当て、1なら使用中、0なら空きを表す。
+//! ビットマップ自体はメモリマップ中の`Usable`な領域に置き、物理メモリのマッピングを通してアクセスする
+
+use core::slice;
+
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// ビットマップの1ワードあたりのビット数
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// 4KiBフレームを単位とした、`S`のフレーム一つ分のフレーム数
+const fn frames_per<S: PageSize>() -> usize {
+    (S::SIZE / Size4KiB::SIZE) as usize
+}
+
+/// ビットマップで物理フレームを管理するアロケータ
+///
+/// 4KiBフレームに加えて、2MiB・1GiBの連続したフレームの割り当てと解放に対応する
+pub struct BitmapFrameAllocator {
+    /// フレームの使用状況。ビットが1なら使用中
+    bitmap: &'static mut [u64],
+    /// ビットマップが管理するフレームの数
+    frame_count: usize,
+    /// `Usable`な領域に含まれていたフレームの数
+    total_frames: usize,
+    /// 空いているフレームの数
+    free_frames: usize,
+    /// 次に4KiBフレームを探し始めるワードの位置
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// 渡されたメモリマップからアロケータを作る
+    ///
+    /// ## Safety
+    /// 呼び出し元は以下の点を保証しなければならない:
+    /// 1. 参照先のメモリマップが有効であり、特に`Usable`なフレームは実際に未使用であること
+    /// 1. 全物理メモリが与えられた `physical_memory_offset`（だけずらした上）でマップされていること
+    /// 1. この関数が全処理の中で一度だけ呼び出されていること
+    ///
+    /// ## Panic
+    /// ビットマップを置けるだけの`Usable`な領域が無い場合はパニックを起こす
+    pub unsafe fn init(
+        memory_regions: &'static MemoryRegions,
+        physical_memory_offset: u64,
+    ) -> Self {
+        let usable_regions = || {
+            memory_regions
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+                .map(|r| {
+                    let start = r.start.next_multiple_of(Size4KiB::SIZE);
+                    let end = r.end & !(Size4KiB::SIZE - 1);
+                    start..end
+                })
+                .filter(|r| r.start < r.end)
+        };
+
+        let max_address = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        let frame_count = (max_address / Size4KiB::SIZE) as usize;
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (word_count * core::mem::size_of::<u64>()) as u64;
+
+        // ビットマップを置く場所を探す
+        let bitmap_start = usable_regions()
+            .find(|r| r.end - r.start >= bitmap_bytes)
+            .expect("No usable memory region can hold the frame bitmap")
+            .start;
+        let bitmap_ptr = VirtAddr::new(physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        // 一旦すべてを使用中にしてから、Usableなフレームを空きにする
+        bitmap.fill(u64::MAX);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            let start = (region.start / Size4KiB::SIZE) as usize;
+            let end = (region.end / Size4KiB::SIZE) as usize;
+            for index in start..end {
+                allocator.set_free(index);
+            }
+            allocator.total_frames += end - start;
+        }
+
+        // ビットマップ自身が置かれたフレームと、ヌルポインタと紛らわしい0番フレームは使わない
+        let bitmap_frames = bitmap_bytes.div_ceil(Size4KiB::SIZE) as usize;
+        let bitmap_index = (bitmap_start / Size4KiB::SIZE) as usize;
+        for index in bitmap_index..bitmap_index + bitmap_frames {
+            allocator.set_used(index);
+        }
+        if frame_count > 0 && !allocator.is_used(0) {
+            allocator.set_used(0);
+        }
+
+        allocator
+    }
+
+    /// `Usable`な領域に含まれていたフレームの数を返す
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// 空いているフレームの数を返す
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// 使用中のフレームの数を返す
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    #[inline(always)]
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    #[inline(always)]
+    fn set_used(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    #[inline(always)]
+    fn set_free(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+        self.free_frames += 1;
+    }
+
+    /// `count`個の連続した空きフレームを探し、先頭のフレーム番号を返す
+    ///
+    /// 先頭のフレーム番号は`align`の倍数になる
+    fn find_contiguous(&self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            // 範囲内で最後に見つかった使用中のフレームの次から探し直す
+            match (start..start + count)
+                .rev()
+                .find(|&index| self.is_used(index))
+            {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// ワード単位で確認できる、整列された大きなブロックを探す
+    ///
+    /// `frames`は`BITS_PER_WORD`の倍数でなければならない
+    fn find_aligned_block(&self, frames: usize) -> Option<usize> {
+        let words = frames / BITS_PER_WORD;
+        if frames > self.free_frames {
+            return None;
+        }
+
+        self.bitmap
+            .chunks_exact(words)
+            .position(|chunk| chunk.iter().all(|&word| word == 0))
+            .map(|block| block * frames)
+    }
+
+    /// 物理的に連続した`count`個の4KiBフレームを割り当て、先頭のフレームを返す
+    ///
+    /// DMAのバッファなど、物理アドレスが連続している必要がある場合に使う
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let start = self.find_contiguous(count, 1)?;
+        for index in start..start + count {
+            self.set_used(index);
+        }
+        Some(frame_at(start))
+    }
+
+    /// `allocate_contiguous()`で割り当てたフレームをまとめて解放する
+    ///
+    /// ## Safety
+    /// 呼び出し元は、解放するフレームがすべて未使用であることを保証しなければならない
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let start = frame_index(start.start_address());
+        for index in start..start + count {
+            self.release(index);
+        }
+    }
+
+    fn allocate_block<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let frames = frames_per::<S>();
+        let start = if frames >= BITS_PER_WORD {
+            self.find_aligned_block(frames)?
+        } else {
+            self.find_contiguous(frames, frames)?
+        };
+
+        for index in start..start + frames {
+            self.set_used(index);
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            start as u64 * Size4KiB::SIZE,
+        )))
+    }
+
+    unsafe fn deallocate_block<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let start = frame_index(frame.start_address());
+        for index in start..start + frames_per::<S>() {
+            self.release(index);
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        debug_assert!(self.is_used(index), "frame {:#x} is already free", index);
+        if self.is_used(index) {
+            self.set_free(index);
+        }
+    }
+}
+
+#[inline(always)]
+fn frame_index(address: PhysAddr) -> usize {
+    (address.as_u64() / Size4KiB::SIZE) as usize
+}
+
+#[inline(always)]
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * Size4KiB::SIZE))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        // 前回見つけた位置から、空きビットを含むワードを探す
+        let word_count = self.bitmap.len();
+        let word = (0..word_count)
+            .map(|i| (self.next_word + i) % word_count)
+            .find(|&w| self.bitmap[w] != u64::MAX)?;
+        let index = word * BITS_PER_WORD + self.bitmap[word].trailing_ones() as usize;
+        if index >= self.frame_count {
+            return None;
+        }
+
+        self.set_used(index);
+        self.next_word = word;
+        Some(frame_at(index))
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_block()
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_block()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.release(frame_index(frame.start_address()));
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_block(frame);
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate_block(frame);
+    }
+}
@@ -1,43 +1,44 @@
-use common_lib::memory::{allocator::Allocator, heap::Heap};
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
-    VirtAddr,
+use common_lib::{
+    locked::Locked,
+    memory::{allocator::Allocator, heap::Heap},
 };
+use x86_64::structures::paging::PageTableFlags;
+
+use super::vma::{self, Backing, VmaError, VmaKind};
+
+/// ヒープとして割り当てる領域の名前
+pub const HEAP_REGION_NAME: &str = "kernel heap";
+/// ヒープの先頭を揃える境界
+const HEAP_ALIGN: u64 = 2 * 1024 * 1024;
 
 /// ヒープ領域の初期化を行う。このとき、アロケータの初期化も同時に行う
 ///
+/// `size`バイトの仮想アドレスを割り当てるだけで、物理フレームはページフォルトのたびに割り当てる。
+/// 割り当てる大きさの合計は`limit`バイトまでとし、超えた場合はメモリ不足として報告する
+///
 /// ## Safety
 /// 呼び出し元は以下の点を保証しなければならない:
-/// - この関数が全処理の中で一度だけ呼び出されていること
+/// 1. この関数が全処理の中で一度だけ呼び出されていること
+/// 1. メモリ管理が初期化済みであり、そのロックを保持していないこと
 pub unsafe fn init<A: Allocator>(
-    heap: Heap<A>,
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    // ヒープに対応付けたいページ範囲を作成する
-    let page_range = {
-        let heap_start = VirtAddr::new(heap.start as u64);
-        let heap_end = heap_start + heap.size - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    // ページをヒープにマッピングする
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+    allocator: &'static Locked<A>,
+    size: usize,
+    limit: usize,
+) -> Result<Heap<A>, VmaError> {
+    let start = vma::allocate(
+        size as u64,
+        HEAP_ALIGN,
+        HEAP_REGION_NAME,
+        VmaKind::Heap,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        Backing::demand(limit as u64),
+    )?;
+    let heap = unsafe { Heap::new(start.as_u64() as usize, size, allocator) };
 
-    // アロケータの初期化
+    // アロケータの初期化。ヒープの先頭に書き込んだ時点で最初のページが割り当てられる
     unsafe {
         heap.allocator.lock().init(heap.start, heap.size);
     }
 
-    Ok(())
+    Ok(heap)
 }
@@ -0,0 +1,323 @@
+//! デバイスのレジスタやメモリ（MMIO領域）をカーネルの仮想アドレス空間にマップするモジュール
+//!
+//! `ioremap()`は物理アドレスの範囲を、指定したキャッシュ属性で新しい仮想アドレスにマップし、
+//! 揮発性の読み書きを行うハンドル`IoMem`を返す。ハンドルを破棄するとアンマップする
+//!
+//! キャッシュ属性はPAT (Page Attribute Table)で選ぶ。起動時の既定値のうち、
+//! PWTのみを立てたエントリ（PAT1）をライトスルーからライトコンバイニングに変更して使う
+
+use core::{mem, ptr};
+
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    vma::{self, Backing, VmaError, VmaKind},
+    MemoryManager,
+};
+
+/// PATを設定するMSR
+const IA32_PAT: u32 = 0x277;
+/// PATの設定値。PAT0から順に WB, WC, UC-, UC, WB, WT, UC-, UC
+const PAT_VALUE: u64 = 0x0007_0406_0007_0106;
+
+/// ページのキャッシュ属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    /// 通常のメモリと同じくキャッシュする
+    WriteBack,
+    /// 書き込みをまとめてから送る。フレームバッファ向け
+    WriteCombining,
+    /// キャッシュしない。デバイスのレジスタ向け
+    Uncached,
+}
+
+impl CacheType {
+    /// PATのエントリを選ぶページテーブルのフラグ
+    fn page_flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteBack => PageTableFlags::empty(),
+            // PATに対応していない場合、PAT1は起動時の既定値（ライトスルー）のままなので、キャッシュしない設定で代用する
+            CacheType::WriteCombining if !supports_pat() => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+            CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheType::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// MMIO領域のマップに失敗した理由
+#[derive(Debug)]
+pub enum MmioError {
+    /// 仮想アドレスを割り当てられなかった
+    Vma(VmaError),
+    /// ページをマップできなかった
+    Map(MapToError<Size4KiB>),
+    /// 付け替えようとした仮想アドレスがマップされていない
+    NotMapped(VirtAddr),
+    /// 付け替えようとした範囲が物理的に連続していない
+    NotContiguous(VirtAddr),
+}
+
+/// CPUがPATに対応していれば`true`を返す
+pub fn supports_pat() -> bool {
+    // 対象ツールチェインによっては`__cpuid`がunsafeでないため、警告を抑制する
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(0x1) };
+    cpuid.edx & (1 << 16) != 0
+}
+
+/// PATを設定し、`CacheType::WriteCombining`を使えるようにする
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. PWTのみを立てた（ライトスルーの）マッピングが存在しないこと
+/// 1. 割り込みが無効になっていること
+pub(super) unsafe fn init_pat() {
+    if !supports_pat() {
+        return;
+    }
+
+    // 属性を変える前に、キャッシュとTLBに残った古い属性の内容を捨てる
+    core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    Msr::new(IA32_PAT).write(PAT_VALUE);
+    core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    tlb::flush_all();
+}
+
+/// MMIOの読み書きに使える型
+pub trait MmioValue: Copy + private::Sealed {}
+
+impl MmioValue for u8 {}
+impl MmioValue for u16 {}
+impl MmioValue for u32 {}
+impl MmioValue for u64 {}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// マップしたMMIO領域のハンドル。破棄するとアンマップする
+#[derive(Debug)]
+pub struct IoMem {
+    /// 割り当てたVMAの先頭（ページ境界）
+    area_start: VirtAddr,
+    /// 要求された物理アドレスに対応する仮想アドレス
+    base: VirtAddr,
+    physical_address: PhysAddr,
+    len: usize,
+    cache_type: CacheType,
+}
+
+impl IoMem {
+    /// `offset`バイト目から`T`を読み出す
+    ///
+    /// ## Panic
+    /// 範囲外か、`offset`が`T`の境界に揃っていない場合はパニックを起こす
+    #[inline(always)]
+    pub fn read<T: MmioValue>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.pointer::<T>(offset)) }
+    }
+
+    /// `offset`バイト目に`T`を書き込む
+    ///
+    /// ## Panic
+    /// 範囲外か、`offset`が`T`の境界に揃っていない場合はパニックを起こす
+    #[inline(always)]
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.pointer::<T>(offset), value) }
+    }
+
+    #[inline(always)]
+    fn pointer<T: MmioValue>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + mem::size_of::<T>() <= self.len && offset.is_multiple_of(mem::align_of::<T>()),
+            "Invalid MMIO access: offset {:#x}, size {} (region size {:#x})",
+            offset,
+            mem::size_of::<T>(),
+            self.len
+        );
+        (self.base + offset as u64).as_mut_ptr()
+    }
+
+    /// 先頭の仮想アドレス
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// 先頭の物理アドレス
+    pub fn physical_address(&self) -> PhysAddr {
+        self.physical_address
+    }
+
+    /// 大きさ（バイト数）
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// 大きさが0であれば`true`を返す
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn cache_type(&self) -> CacheType {
+        self.cache_type
+    }
+
+    /// マップを解除せずにハンドルを手放し、領域全体をバイト列として返す
+    ///
+    /// フレームバッファのように、カーネルが動いている間ずっと使う領域に使う
+    pub fn leak(self) -> &'static mut [u8] {
+        let slice = unsafe { core::slice::from_raw_parts_mut(self.base.as_mut_ptr(), self.len) };
+        mem::forget(self);
+        slice
+    }
+}
+
+impl Drop for IoMem {
+    fn drop(&mut self) {
+        if let Err(e) = vma::unmap(self.area_start) {
+            log::error!(
+                "Failed to unmap MMIO region at {:#x}: {:?}",
+                self.area_start.as_u64(),
+                e
+            );
+        }
+    }
+}
+
+/// 物理アドレス`physical_address`から`len`バイトのMMIO領域を、キャッシュ属性`cache_type`でマップする
+///
+/// `name`は仮想アドレス空間の記録に使う
+///
+/// ## Safety
+/// 呼び出し元は、範囲がデバイスのレジスタやメモリであり、通常のメモリとして使われていないことを保証しなければならない
+pub unsafe fn ioremap(
+    name: &'static str,
+    physical_address: PhysAddr,
+    len: usize,
+    cache_type: CacheType,
+) -> Result<IoMem, MmioError> {
+    let mut manager = super::manager()
+        .ok_or(MmioError::Vma(VmaError::NotInitialized))?
+        .lock();
+    map(
+        &mut manager,
+        name,
+        VmaKind::Mmio,
+        physical_address,
+        len,
+        cache_type,
+    )
+}
+
+/// すでにマップされている`address`から`len`バイトの範囲を、キャッシュ属性`cache_type`でマップし直す
+///
+/// 新しい仮想アドレスにマップしてから、元のマッピングを解除する。
+/// ブートローダーがマップしたフレームバッファなど、キャッシュ属性を変えたい領域に使う
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. 元の範囲がデバイスのメモリであり、`vma::claim()`などで`address`を含むページから始まる領域として記録済みであること
+/// 1. 元の範囲を以降使わないこと
+pub unsafe fn remap(
+    name: &'static str,
+    kind: VmaKind,
+    address: VirtAddr,
+    len: usize,
+    cache_type: CacheType,
+) -> Result<IoMem, MmioError> {
+    let mut manager = super::manager()
+        .ok_or(MmioError::Vma(VmaError::NotInitialized))?
+        .lock();
+
+    // 範囲が物理的に連続していることを確かめる
+    let physical_address = manager
+        .mapper
+        .translate_addr(address)
+        .ok_or(MmioError::NotMapped(address))?;
+    let first = Page::<Size4KiB>::containing_address(address);
+    let last = Page::<Size4KiB>::containing_address(address + (len.max(1) - 1) as u64);
+    let first_frame = physical_address.align_down(Size4KiB::SIZE);
+    for page in Page::range_inclusive(first, last) {
+        let expected = first_frame + (page.start_address() - first.start_address());
+        match manager.mapper.translate_addr(page.start_address()) {
+            Some(actual) if actual == expected => {}
+            Some(_) => return Err(MmioError::NotContiguous(page.start_address())),
+            None => return Err(MmioError::NotMapped(page.start_address())),
+        }
+    }
+
+    let io_mem = map(&mut manager, name, kind, physical_address, len, cache_type)?;
+    vma::unmap_locked(&mut manager, first.start_address()).map_err(MmioError::Vma)?;
+    Ok(io_mem)
+}
+
+/// 仮想アドレスを割り当て、物理アドレスの範囲をマップする
+fn map(
+    manager: &mut MemoryManager,
+    name: &'static str,
+    kind: VmaKind,
+    physical_address: PhysAddr,
+    len: usize,
+    cache_type: CacheType,
+) -> Result<IoMem, MmioError> {
+    let offset = physical_address.as_u64() % Size4KiB::SIZE;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(physical_address);
+    let size = (offset + len as u64).next_multiple_of(Size4KiB::SIZE);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache_type.page_flags();
+
+    let area_start = manager
+        .address_space
+        .allocate(size, Size4KiB::SIZE, name, kind, flags, Backing::Borrowed)
+        .map_err(MmioError::Vma)?;
+
+    let first_page = Page::<Size4KiB>::containing_address(area_start);
+    for index in 0..size / Size4KiB::SIZE {
+        let MemoryManager {
+            mapper,
+            frame_allocator,
+            ..
+        } = &mut *manager;
+
+        let result = unsafe {
+            mapper.map_to(
+                first_page + index,
+                first_frame + index,
+                flags,
+                frame_allocator,
+            )
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                // マップ済みのページを戻す。フレームはデバイスのものなので解放しない
+                let _ = vma::unmap_locked(manager, area_start);
+                return Err(MmioError::Map(e));
+            }
+        }
+    }
+
+    Ok(IoMem {
+        area_start,
+        base: area_start + offset,
+        physical_address,
+        len,
+        cache_type,
+    })
+}
@@ -1,12 +1,9 @@
-use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use x86_64::registers::control::Cr3;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
+    structures::paging::{OffsetPageTable, PageTable},
+    VirtAddr,
 };
 
-const SIZE_4KIB: usize = 4096;
-
 /// 新しいOffsetPageTableを初期化する
 ///
 /// ## Safety
@@ -20,52 +17,6 @@ pub unsafe fn init(physical_memory_offset: u64) -> OffsetPageTable<'static> {
     OffsetPageTable::new(level_4_table, phys_offset)
 }
 
-/// ブートローダのメモリマップから、使用可能なフレームを返す構造体
-pub struct BootInfoFrameAllocator {
-    memory_regions: &'static MemoryRegions,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// 渡されたメモリマップからFrameAllocatorを作る関数
-    ///
-    /// ## Safety
-    /// 呼び出し元は参照先のメモリマップが有効であることを保証しなければならない。
-    /// 特に、`Usable` なフレームは実際に未使用でなくてはならない
-    #[inline(always)]
-    pub const unsafe fn init(memory_regions: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_regions,
-            next: 0,
-        }
-    }
-
-    /// メモリマップによって指定された `Usable` なフレームのイテレータを返す
-    /// 手順は以下の通り:
-    /// 1. メモリマップから `Usable` な領域を得る
-    /// 1. それぞれの `Usable` な領域をアドレス範囲にmapで変換する
-    /// 1. フレームの開始アドレスのイテレータへと変換する
-    /// 1. 開始アドレスから`Usable` なフレームたる `PhysFrame` 型を作る
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_regions.iter();
-        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
-
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(SIZE_4KIB));
-
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
 unsafe fn active_level_4_table(phys_offset: VirtAddr) -> &'static mut PageTable {
     let (level_4_table_frame, _) = Cr3::read();
 
@@ -0,0 +1,92 @@
+//! TLBの無効化（シュートダウン）
+//!
+//! ページをアンマップしたときは、実行中のCPUだけでなく、他のCPUのTLBに残った古い変換も消す必要がある。
+//! 他のCPUが動いている場合はIPIで無効化を依頼し、すべてのCPUが応答するまで待つ
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::{
+    instructions::tlb,
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{Page, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use crate::interrupt::apic::{
+    self,
+    local_apic::{DeliveryMode, IpiDestination},
+};
+
+/// これを超えるページ数を無効化する場合は、1ページずつではなくTLB全体を無効化する
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+/// 実行中のCPU以外に動いているCPUの数
+static REMOTE_CPUS: AtomicUsize = AtomicUsize::new(0);
+/// 他のCPUに依頼中の範囲
+static PENDING_START: AtomicU64 = AtomicU64::new(0);
+static PENDING_PAGES: AtomicU64 = AtomicU64::new(0);
+/// 依頼に応答したCPUの数
+static ACKNOWLEDGED: AtomicUsize = AtomicUsize::new(0);
+
+/// 実行中のCPU以外に動いているCPUの数を設定する。APを起動・停止したときに呼ぶ
+pub fn set_remote_cpus(count: usize) {
+    REMOTE_CPUS.store(count, Ordering::Release);
+}
+
+/// 実行中のCPUのTLBから、`start`から`pages`ページ分の変換を消す
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+        return;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    for page in Page::range(first, first + pages) {
+        tlb::flush(page.start_address());
+    }
+}
+
+/// すべてのCPUのTLBから、`start`から`pages`ページ分の変換を消す
+///
+/// 他のCPUが動いている場合は、すべてのCPUが無効化を終えるまで待つ。
+/// 依頼は一度に一つしか出せないため、メモリ管理のロックを取ったまま呼ぶこと
+pub fn shootdown(start: VirtAddr, pages: u64) {
+    flush_local(start, pages);
+
+    let remote_cpus = REMOTE_CPUS.load(Ordering::Acquire);
+    let Some(local_apic) = apic::local_apic() else {
+        return;
+    };
+    if remote_cpus == 0 {
+        return;
+    }
+
+    PENDING_START.store(start.as_u64(), Ordering::Relaxed);
+    PENDING_PAGES.store(pages, Ordering::Relaxed);
+    ACKNOWLEDGED.store(0, Ordering::Release);
+
+    unsafe {
+        local_apic.send_ipi(
+            IpiDestination::AllExcludingSelf,
+            DeliveryMode::Fixed,
+            apic::TLB_SHOOTDOWN_VECTOR,
+        );
+    }
+
+    while ACKNOWLEDGED.load(Ordering::Acquire) < remote_cpus {
+        core::hint::spin_loop();
+    }
+}
+
+/// TLBシュートダウンの依頼を受けたCPUで実行される割り込みハンドラ
+pub(crate) extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(
+    _stack_frame: InterruptStackFrame,
+) {
+    let start = VirtAddr::new(PENDING_START.load(Ordering::Relaxed));
+    flush_local(start, PENDING_PAGES.load(Ordering::Relaxed));
+
+    ACKNOWLEDGED.fetch_add(1, Ordering::AcqRel);
+    apic::end_of_interrupt();
+}
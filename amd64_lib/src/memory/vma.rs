@@ -0,0 +1,510 @@
+//! カーネルの仮想アドレス空間の管理
+//!
+//! 仮想アドレスの領域（Virtual Memory Area, 以下VMA）ごとに、用途と権限、物理フレームの持ち方を記録する。
+//! 新しい領域は`DYNAMIC_START`から`DYNAMIC_END`までの間で、既存の領域と重ならないように割り当てる。
+//! ブートローダーが作ったマッピングは、初期化時にレベル4ページテーブルから拾って予約しておく
+//!
+//! ページフォルトハンドラからも参照するため、記録にはヒープを使わない
+
+use core::fmt;
+
+use x86_64::{
+    structures::paging::{
+        FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{tlb, MemoryManager};
+
+/// 記録できるVMAの数
+pub const MAX_AREAS: usize = 128;
+/// 動的に割り当てる領域の先頭
+pub const DYNAMIC_START: u64 = 0x_1000_0000_0000;
+/// 動的に割り当てる領域の終端（このアドレス自体は含まない）
+pub const DYNAMIC_END: u64 = 0x_7fff_ffff_0000;
+/// 動的に割り当てた領域の間に空けておく、マップしないページ数
+const GUARD_PAGES: u64 = 1;
+/// レベル4ページテーブルの1エントリが表す大きさ
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+/// VMAの用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// ブートローダーが作ったマッピング（カーネルイメージやブート時のスタックなど）
+    Boot,
+    /// 物理メモリ全体のマッピング
+    PhysicalMap,
+    Heap,
+    Stack,
+    Mmio,
+    FrameBuffer,
+    Other,
+}
+
+impl VmaKind {
+    /// 解放できない用途であれば`true`を返す
+    pub const fn is_permanent(self) -> bool {
+        matches!(self, VmaKind::Boot | VmaKind::PhysicalMap)
+    }
+}
+
+impl fmt::Display for VmaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            VmaKind::Boot => "boot",
+            VmaKind::PhysicalMap => "physmap",
+            VmaKind::Heap => "heap",
+            VmaKind::Stack => "stack",
+            VmaKind::Mmio => "mmio",
+            VmaKind::FrameBuffer => "framebuffer",
+            VmaKind::Other => "other",
+        };
+        f.pad(name)
+    }
+}
+
+/// VMAにマップされる物理フレームの持ち方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// ページフォルトのたびにフレームを割り当てる。`limit_pages`ページまでマップできる
+    Demand { limit_pages: u64, mapped_pages: u64 },
+    /// 領域の持ち主がフレームを割り当ててマップする。解放時にフレームも解放する
+    Owned,
+    /// 他所が持つフレーム（デバイスのメモリなど）をマップする。解放時にフレームは解放しない
+    Borrowed,
+}
+
+impl Backing {
+    /// 最大で`limit`バイト分のフレームを割り当てるデマンドページング
+    pub const fn demand(limit: u64) -> Self {
+        Backing::Demand {
+            limit_pages: limit.div_ceil(Size4KiB::SIZE),
+            mapped_pages: 0,
+        }
+    }
+
+    /// 解放時にフレームも解放するなら`true`を返す
+    const fn owns_frames(&self) -> bool {
+        !matches!(self, Backing::Borrowed)
+    }
+}
+
+/// 仮想アドレスの領域
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    /// デバッグ用の名前
+    pub name: &'static str,
+    pub kind: VmaKind,
+    pub start: VirtAddr,
+    /// 大きさ（バイト数）。ページ境界に揃っている
+    pub size: u64,
+    /// マップするページに設定するフラグ。`PRESENT`を含む
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    /// 領域の終端（このアドレス自体は含まない）
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// `address`が領域に含まれていれば`true`を返す
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        start < self.end() && self.start < end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.start);
+        Page::range(start, start + self.size / Size4KiB::SIZE)
+    }
+}
+
+impl fmt::Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag: PageTableFlags, c: char| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:>10} KiB r{}{}{}{} {:<11} {}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.size / 1024,
+            flag(PageTableFlags::WRITABLE, 'w'),
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) {
+                '-'
+            } else {
+                'x'
+            },
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            flag(PageTableFlags::NO_CACHE, 'c'),
+            self.kind,
+            self.name
+        )?;
+
+        if let Backing::Demand {
+            limit_pages,
+            mapped_pages,
+        } = self.backing
+        {
+            write!(f, " (demand: {}/{} pages)", mapped_pages, limit_pages)?;
+        }
+        Ok(())
+    }
+}
+
+/// VMAの操作に失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// メモリ管理が初期化されていない
+    NotInitialized,
+    /// 先頭または大きさがページ境界に揃っていないか、大きさが0
+    Unaligned,
+    /// 既存の領域と重なっている
+    Overlap { name: &'static str },
+    /// 記録できるVMAの数が上限に達している
+    TooManyAreas,
+    /// 割り当てられるだけの空きが無い
+    NoSpace,
+    /// 指定したアドレスから始まる領域が無い
+    NotFound,
+    /// 解放できない用途の領域
+    Permanent { name: &'static str },
+}
+
+/// カーネルの仮想アドレス空間
+pub struct AddressSpace {
+    areas: [Option<Vma>; MAX_AREAS],
+}
+
+impl AddressSpace {
+    pub const fn new() -> Self {
+        AddressSpace {
+            areas: [None; MAX_AREAS],
+        }
+    }
+
+    /// 指定したアドレスに領域を登録する
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if !vma.start.is_aligned(Size4KiB::SIZE)
+            || !vma.size.is_multiple_of(Size4KiB::SIZE)
+            || vma.size == 0
+        {
+            return Err(VmaError::Unaligned);
+        }
+
+        if let Some(area) = self.iter().find(|area| area.overlaps(vma.start, vma.end())) {
+            return Err(VmaError::Overlap { name: area.name });
+        }
+
+        let slot = self
+            .areas
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmaError::TooManyAreas)?;
+        *slot = Some(Vma {
+            flags: vma.flags | PageTableFlags::PRESENT,
+            ..vma
+        });
+        Ok(())
+    }
+
+    /// 既存の領域と重ならない`size`バイトの範囲を`align`バイト境界から割り当て、領域として登録する
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        align: u64,
+        name: &'static str,
+        kind: VmaKind,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<VirtAddr, VmaError> {
+        let align = align.max(Size4KiB::SIZE);
+        if !align.is_power_of_two() {
+            return Err(VmaError::Unaligned);
+        }
+        let size = size.next_multiple_of(Size4KiB::SIZE);
+        let guard = GUARD_PAGES * Size4KiB::SIZE;
+
+        // 候補の先頭から見て、重なる領域があればその後ろへずらしていく（先に見つかった空きを使う）
+        let mut candidate = DYNAMIC_START.next_multiple_of(align);
+        loop {
+            let end = candidate.checked_add(size).ok_or(VmaError::NoSpace)?;
+            if end > DYNAMIC_END {
+                return Err(VmaError::NoSpace);
+            }
+
+            let blocker = self
+                .iter()
+                .filter(|area| {
+                    area.overlaps(
+                        VirtAddr::new(candidate.saturating_sub(guard)),
+                        VirtAddr::new(end + guard),
+                    )
+                })
+                .map(|area| area.end().as_u64())
+                .max();
+
+            match blocker {
+                Some(blocker_end) => candidate = (blocker_end + guard).next_multiple_of(align),
+                None => break,
+            }
+        }
+
+        let start = VirtAddr::new(candidate);
+        self.insert(Vma {
+            name,
+            kind,
+            start,
+            size,
+            flags,
+            backing,
+        })?;
+        Ok(start)
+    }
+
+    /// ブートローダーが作ったマッピングの一部を、用途の分かった領域として切り出す
+    ///
+    /// `vma`の範囲を含む`Boot`の領域を分割し、その部分を`vma`に置き換える。
+    /// 含む領域が無ければ、そのまま登録する
+    pub fn claim(&mut self, vma: Vma) -> Result<(), VmaError> {
+        let Some(index) = self.areas.iter().position(|slot| {
+            matches!(slot, Some(area) if area.kind == VmaKind::Boot
+                && area.start <= vma.start && vma.end() <= area.end())
+        }) else {
+            return self.insert(vma);
+        };
+
+        let boot = self.areas[index].take().unwrap();
+        let left = Vma {
+            size: vma.start - boot.start,
+            ..boot
+        };
+        let right = Vma {
+            start: vma.end(),
+            size: boot.end() - vma.end(),
+            ..boot
+        };
+
+        let result = self.insert(vma).and_then(|()| {
+            [left, right]
+                .into_iter()
+                .filter(|area| area.size > 0)
+                .try_for_each(|area| self.insert(area))
+        });
+        if result.is_err() {
+            // 登録できなかった場合は元に戻す
+            self.areas
+                .iter_mut()
+                .filter(|slot| matches!(slot, Some(area) if boot.overlaps(area.start, area.end())))
+                .for_each(|slot| *slot = None);
+            self.areas[index] = Some(boot);
+        }
+        result
+    }
+
+    /// `start`から始まる領域の登録を取り消し、その領域を返す
+    fn remove(&mut self, start: VirtAddr) -> Result<Vma, VmaError> {
+        let slot = self
+            .areas
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(area) if area.start == start))
+            .ok_or(VmaError::NotFound)?;
+
+        let area = slot.as_ref().unwrap();
+        if area.kind.is_permanent() {
+            return Err(VmaError::Permanent { name: area.name });
+        }
+        Ok(slot.take().unwrap())
+    }
+
+    /// 登録済みの領域を順不同で返す
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter().flatten()
+    }
+
+    /// `address`を含む領域を返す
+    pub fn find(&self, address: VirtAddr) -> Option<&Vma> {
+        self.iter().find(|area| area.contains(address))
+    }
+
+    pub(super) fn find_mut(&mut self, address: VirtAddr) -> Option<&mut Vma> {
+        self.areas
+            .iter_mut()
+            .flatten()
+            .find(|area| area.contains(address))
+    }
+
+    /// 使用中のレベル4ページテーブルのエントリのうち、登録済みの領域と重ならないものを`Boot`として登録する
+    fn reserve_boot_mappings(&mut self, mapper: &mut OffsetPageTable<'static>) {
+        for (index, entry) in mapper.level_4_table().iter().enumerate() {
+            if entry.is_unused() {
+                continue;
+            }
+
+            let start = VirtAddr::new_truncate(index as u64 * LEVEL_4_ENTRY_SIZE);
+            let end = start + (LEVEL_4_ENTRY_SIZE - 1);
+            if self.iter().any(|area| area.overlaps(start, end)) {
+                continue;
+            }
+
+            // 正規形の境界やアドレス空間の末尾にかかる場合、終端を表せないため最後のページを除く
+            let size = match start.as_u64().checked_add(LEVEL_4_ENTRY_SIZE) {
+                Some(end) if VirtAddr::try_new(end).is_ok() => LEVEL_4_ENTRY_SIZE,
+                _ => LEVEL_4_ENTRY_SIZE - Size4KiB::SIZE,
+            };
+
+            let _ = self.insert(Vma {
+                name: "bootloader",
+                kind: VmaKind::Boot,
+                start,
+                size,
+                flags: PageTableFlags::PRESENT,
+                backing: Backing::Borrowed,
+            });
+        }
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for AddressSpace {
+    /// 登録済みの領域を先頭アドレス順に一行ずつ書き出す
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut areas = self.areas;
+        areas.sort_unstable_by_key(|area| area.map_or(u64::MAX, |area| area.start.as_u64()));
+        for area in areas.iter().flatten() {
+            writeln!(f, "{}", area)?;
+        }
+        Ok(())
+    }
+}
+
+/// 物理メモリのマッピングとブートローダーのマッピングを登録する
+///
+/// `physical_memory_size`は物理メモリのマッピングの大きさ（バイト数）
+pub(super) fn init(manager: &mut MemoryManager, physical_memory_size: u64) {
+    let MemoryManager {
+        mapper,
+        physical_memory_offset,
+        address_space,
+        ..
+    } = manager;
+
+    let _ = address_space.insert(Vma {
+        name: "physical memory",
+        kind: VmaKind::PhysicalMap,
+        start: VirtAddr::new(*physical_memory_offset),
+        size: physical_memory_size.next_multiple_of(Size4KiB::SIZE),
+        flags: PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        backing: Backing::Borrowed,
+    });
+    address_space.reserve_boot_mappings(mapper);
+}
+
+/// 空いている範囲を割り当て、領域として登録する。ページのマップは`backing`に従って別途行う
+pub fn allocate(
+    size: u64,
+    align: u64,
+    name: &'static str,
+    kind: VmaKind,
+    flags: PageTableFlags,
+    backing: Backing,
+) -> Result<VirtAddr, VmaError> {
+    super::manager()
+        .ok_or(VmaError::NotInitialized)?
+        .lock()
+        .address_space
+        .allocate(size, align, name, kind, flags, backing)
+}
+
+/// ブートローダーが作ったマッピングの一部を、用途の分かった領域として登録する
+pub fn claim(vma: Vma) -> Result<(), VmaError> {
+    super::manager()
+        .ok_or(VmaError::NotInitialized)?
+        .lock()
+        .address_space
+        .claim(vma)
+}
+
+/// ブートローダーがマップした`start`から`len`バイトの範囲を、書き込み可能なデバイスのメモリとして記録する
+///
+/// 範囲がページ境界に揃っていない場合は、範囲を含むページ全体を記録する
+pub fn claim_range(
+    name: &'static str,
+    kind: VmaKind,
+    start: u64,
+    len: u64,
+) -> Result<(), VmaError> {
+    let start = VirtAddr::try_new(start).map_err(|_| VmaError::Unaligned)?;
+    let aligned_start = start.align_down(Size4KiB::SIZE);
+    let size = (start + len).align_up(Size4KiB::SIZE) - aligned_start;
+
+    claim(Vma {
+        name,
+        kind,
+        start: aligned_start,
+        size,
+        flags: PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        backing: Backing::Borrowed,
+    })
+}
+
+/// `start`から始まる領域のページをすべてアンマップし、登録を取り消す
+///
+/// フレームを持つ領域であれば、フレームも解放する。アンマップしたページは、すべてのCPUのTLBから消す
+pub fn unmap(start: VirtAddr) -> Result<Vma, VmaError> {
+    let mut manager = super::manager().ok_or(VmaError::NotInitialized)?.lock();
+    unmap_locked(&mut manager, start)
+}
+
+/// メモリ管理のロックを取った状態で`unmap()`を行う
+pub(super) fn unmap_locked(manager: &mut MemoryManager, start: VirtAddr) -> Result<Vma, VmaError> {
+    let MemoryManager {
+        mapper,
+        frame_allocator,
+        address_space,
+        ..
+    } = manager;
+
+    let area = address_space.remove(start)?;
+    for page in area.pages() {
+        // マップされていないページは飛ばす
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.ignore();
+            if area.backing.owns_frames() {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    }
+    tlb::shootdown(area.start, area.size / Size4KiB::SIZE);
+
+    Ok(area)
+}
+
+/// `address`を含む領域の写しを返す
+pub fn find(address: VirtAddr) -> Option<Vma> {
+    super::manager()?
+        .lock()
+        .address_space
+        .find(address)
+        .copied()
+}
+
+/// 登録済みの領域を先頭アドレス順に`f`へ渡す。ロックを外してから呼ぶため、`f`の中でVMAを操作してもよい
+pub fn for_each(mut f: impl FnMut(&Vma)) {
+    let Some(manager) = super::manager() else {
+        return;
+    };
+    let mut areas = manager.lock().address_space.areas;
+    areas.sort_unstable_by_key(|area| area.map_or(u64::MAX, |area| area.start.as_u64()));
+    areas.iter().flatten().for_each(&mut f);
+}
@@ -0,0 +1,235 @@
+//! PCIのコンフィギュレーション空間へのアクセス
+//!
+//! ACPIのMCFGテーブルがあればPCI Expressのメモリマップ方式（ECAM）を使い、
+//! 無ければI/Oポート`0xCF8`/`0xCFC`を使う従来の方式（Configuration Mechanism #1）を使う
+
+use alloc::vec::Vec;
+
+use common_lib::{
+    pci::{self, BusRange, ConfigAccess, PciAddress, PciDevice},
+    sync::{IrqMutex, LockLevel},
+};
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+    acpi,
+    memory::mmio::{self, CacheType, IoMem, MmioError},
+};
+
+/// アドレスを書き込むI/Oポート
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+/// データを読み書きするI/Oポート
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+/// ECAMで1ファンクションあたりに割り当てられるバイト数
+const ECAM_FUNCTION_SIZE: usize = 4096;
+
+/// I/Oポートを使うアクセス方法。セグメント0の、各ファンクションの先頭256バイトにしかアクセスできない
+pub struct PortIoAccess {
+    ports: IrqMutex<(Port<u32>, Port<u32>)>,
+}
+
+impl PortIoAccess {
+    const fn new() -> Self {
+        PortIoAccess {
+            ports: IrqMutex::with_level(
+                (Port::new(CONFIG_ADDRESS_PORT), Port::new(CONFIG_DATA_PORT)),
+                "pci config",
+                LockLevel::PCI_CONFIG,
+            ),
+        }
+    }
+
+    fn config_address(address: PciAddress, offset: u16) -> u32 {
+        1 << 31
+            | (address.bus as u32) << 16
+            | (address.device as u32) << 11
+            | (address.function as u32) << 8
+            | (offset as u32 & 0xfc)
+    }
+
+    /// 範囲外のアクセスであれば`true`を返す
+    fn out_of_range(address: PciAddress, offset: u16) -> bool {
+        address.segment != 0 || offset >= 0x100
+    }
+}
+
+impl ConfigAccess for PortIoAccess {
+    fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        if Self::out_of_range(address, offset) {
+            return u32::MAX;
+        }
+
+        let mut ports = self.ports.lock();
+        let (address_port, data_port) = &mut *ports;
+        unsafe {
+            address_port.write(Self::config_address(address, offset));
+            data_port.read()
+        }
+    }
+
+    fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        if Self::out_of_range(address, offset) {
+            return;
+        }
+
+        let mut ports = self.ports.lock();
+        let (address_port, data_port) = &mut *ports;
+        unsafe {
+            address_port.write(Self::config_address(address, offset));
+            data_port.write(value);
+        }
+    }
+
+    fn write_u16(&self, address: PciAddress, offset: u16, value: u16) {
+        if Self::out_of_range(address, offset) {
+            return;
+        }
+
+        let mut ports = self.ports.lock();
+        let (address_port, _) = &mut *ports;
+        // データポートの上位2バイトに書き込むと、4バイトのうち上位2バイトだけが書き換わる
+        let mut data_port: Port<u16> = Port::new(CONFIG_DATA_PORT + (offset & 0x2));
+        unsafe {
+            address_port.write(Self::config_address(address, offset));
+            data_port.write(value);
+        }
+    }
+
+    fn has_extended_space(&self) -> bool {
+        false
+    }
+}
+
+/// MCFGテーブルの1項目に対応する、マップ済みのECAM領域
+struct EcamRegion {
+    segment: u16,
+    bus_start: u8,
+    bus_end: u8,
+    memory: IoMem,
+}
+
+/// PCI Expressのメモリマップ方式によるアクセス方法
+pub struct EcamAccess {
+    regions: Vec<EcamRegion>,
+}
+
+impl EcamAccess {
+    /// `address`のコンフィギュレーション空間のうち、`offset`バイト目を含む領域とその中のオフセットを返す
+    fn locate(&self, address: PciAddress, offset: u16) -> Option<(&IoMem, usize)> {
+        if offset as usize >= ECAM_FUNCTION_SIZE {
+            return None;
+        }
+
+        let region = self.regions.iter().find(|region| {
+            region.segment == address.segment
+                && (region.bus_start..=region.bus_end).contains(&address.bus)
+        })?;
+        let function_index = ((address.bus - region.bus_start) as usize) << 8
+            | (address.device as usize) << 3
+            | address.function as usize;
+
+        Some((
+            &region.memory,
+            function_index * ECAM_FUNCTION_SIZE + (offset as usize & !0x3),
+        ))
+    }
+}
+
+impl ConfigAccess for EcamAccess {
+    fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        match self.locate(address, offset) {
+            Some((memory, offset)) => memory.read::<u32>(offset),
+            None => u32::MAX,
+        }
+    }
+
+    fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        if let Some((memory, offset)) = self.locate(address, offset) {
+            memory.write::<u32>(offset, value);
+        }
+    }
+
+    fn write_u16(&self, address: PciAddress, offset: u16, value: u16) {
+        if let Some((memory, aligned)) = self.locate(address, offset) {
+            memory.write::<u16>(aligned + (offset as usize & 0x2), value);
+        }
+    }
+
+    fn has_extended_space(&self) -> bool {
+        true
+    }
+}
+
+static PORT_IO_ACCESS: PortIoAccess = PortIoAccess::new();
+static ECAM_ACCESS: Once<EcamAccess> = Once::new();
+
+/// MCFGテーブルに記載されたECAM領域をすべてマップする。MCFGが無ければ`None`を返す
+///
+/// ## Safety
+/// 呼び出し元はMCFGテーブルの内容が正しいことを保証しなければならない
+unsafe fn map_ecam() -> Result<Option<(EcamAccess, Vec<BusRange>)>, MmioError> {
+    let Some(config_regions) = acpi::info().and_then(|info| info.pci_config_regions.as_ref())
+    else {
+        return Ok(None);
+    };
+
+    let mut regions = Vec::new();
+    let mut ranges = Vec::new();
+    for entry in config_regions.iter() {
+        let bus_start = *entry.bus_range.start();
+        let bus_end = *entry.bus_range.end();
+        let bus_count = (bus_end - bus_start) as usize + 1;
+
+        let memory = mmio::ioremap(
+            "pci ecam",
+            PhysAddr::new(entry.physical_address as u64),
+            bus_count << 20,
+            CacheType::Uncached,
+        )?;
+
+        regions.push(EcamRegion {
+            segment: entry.segment_group,
+            bus_start,
+            bus_end,
+            memory,
+        });
+        ranges.push(BusRange {
+            segment: entry.segment_group,
+            buses: entry.bus_range,
+        });
+    }
+
+    Ok(Some((EcamAccess { regions }, ranges)))
+}
+
+/// PCIバスを列挙し、見つかったデバイスを返す
+///
+/// MCFGテーブルがあればECAMを使い、無いかマップに失敗した場合はI/Oポートを使ってセグメント0を列挙する
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. メモリ管理とACPIテーブルの解析が済んでいること
+/// 1. MCFGテーブルの内容が正しいこと
+pub unsafe fn init() -> &'static [PciDevice] {
+    if pci::is_initialized() {
+        return pci::devices();
+    }
+
+    match map_ecam() {
+        Ok(Some((access, ranges))) => {
+            let access = ECAM_ACCESS.call_once(|| access);
+            return pci::init(access, &ranges);
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to map PCI ECAM, falling back to port I/O: {:?}", e),
+    }
+
+    pci::init(
+        &PORT_IO_ACCESS,
+        &[BusRange {
+            segment: 0,
+            buses: 0..=255,
+        }],
+    )
+}
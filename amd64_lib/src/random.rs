@@ -0,0 +1,70 @@
+//! 乱数を生成するモジュール
+//!
+//! CPUがRDRAND命令に対応していればそれを使い、対応していなければタイムスタンプカウンタを種にした疑似乱数を使う
+
+use common_lib::fs::{devfs::CharDevice, FsError};
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+
+/// 乱数を読み出すためのデバイス
+///
+/// RDRANDが使えない場合の疑似乱数は、暗号用途に使えるほど予測しにくいものではない
+pub struct RandomDevice {
+    rdrand: Option<RdRand>,
+    /// RDRANDが使えない場合に使うxorshift64*の状態
+    state: Mutex<u64>,
+}
+
+impl RandomDevice {
+    pub fn new() -> Self {
+        let rdrand = RdRand::new();
+        if rdrand.is_none() {
+            log::warn!("RDRAND is not supported; falling back to a pseudo-random generator");
+        }
+
+        // 状態が0になるとxorshiftは0しか返さなくなる
+        let seed = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+        RandomDevice {
+            rdrand,
+            state: Mutex::new(seed),
+        }
+    }
+
+    /// 64ビットの乱数を返す
+    pub fn next_u64(&self) -> u64 {
+        // RDRANDは一時的に失敗することがあるため、何度か試す
+        if let Some(value) = self
+            .rdrand
+            .and_then(|rdrand| (0..10).find_map(|_| rdrand.get_u64()))
+        {
+            return value;
+        }
+
+        let mut state = self.state.lock();
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Default for RandomDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CharDevice for RandomDevice {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        for chunk in buffer.chunks_mut(8) {
+            let bytes = self.next_u64().to_ne_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(buffer.len())
+    }
+
+    /// 書き込まれたデータは捨てる
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        Ok(buffer.len())
+    }
+}
@@ -1,13 +1,34 @@
+use common_lib::{
+    fs::{devfs::CharDevice, FsError},
+    logger::LogSink,
+    sync::{IrqMutex, LockLevel},
+    task::queue::{QueueStream, WakeQueue},
+};
 use core::fmt::Write;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::Once;
 use uart_16550::SerialPort;
+use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame};
+
+use crate::interrupt::apic;
+
+/// COM1が接続されているISA IRQ
+pub const IRQ: u8 = 4;
+
+const COM1_BASE: u16 = 0x3F8;
+/// ラインステータスレジスタ
+const LINE_STATUS_PORT: u16 = COM1_BASE + 5;
+/// ラインステータスレジスタの受信データありビット
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+/// ラインステータスレジスタの送信バッファ空きビット
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    /// COM1。割り込みハンドラからも出力するため、ロック中は割り込みを無効にする
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1_BASE) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::with_level(serial_port, "serial", LockLevel::SERIAL)
     };
 }
 
@@ -19,6 +40,33 @@ pub fn _print(args: ::core::fmt::Arguments) {
         .expect("Printing to serial failed");
 }
 
+/// `SERIAL1`のロックを取らずに、COM1へ直接出力する
+///
+/// マスク不能割り込みのように、ロックを持っている処理に割り込んだ可能性がある場所で使う。
+/// 他の出力と文字が混ざることがある
+pub fn write_unlocked(args: ::core::fmt::Arguments) {
+    // 直接書き込むため、失敗することはない
+    let _ = UnlockedWriter.write_fmt(args);
+}
+
+struct UnlockedWriter;
+
+impl Write for UnlockedWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut line_status: Port<u8> = Port::new(LINE_STATUS_PORT);
+        let mut data: Port<u8> = Port::new(COM1_BASE);
+        for byte in s.bytes() {
+            unsafe {
+                while line_status.read() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                    core::hint::spin_loop();
+                }
+                data.write(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
 /// シリアルインターフェースを通じてホストに出力する
 #[macro_export]
 macro_rules! serial_print {
@@ -35,3 +83,95 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// ログの出力先としてシリアルインターフェースを使うための型
+pub struct SerialSink;
+
+impl LogSink for SerialSink {
+    fn write_line(&self, line: ::core::fmt::Arguments) {
+        _print(format_args!("{}\n", line));
+    }
+}
+
+/// 受信したバイトを溜めておくキュー
+static SERIAL_INPUT: Once<WakeQueue<u8>> = Once::new();
+
+/// シリアルからの受信を割り込みで受け付け始める。受信したバイトは`input_stream()`で取り出せる
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. APICとヒープが初期化済みであること
+/// 1. この関数が全処理の中で一度だけ呼び出されていること
+pub unsafe fn init_input(capacity: usize) {
+    SERIAL_INPUT.call_once(|| WakeQueue::new(capacity));
+
+    // 受信割り込みの有効化はSERIAL1の初期化で済んでいる
+    lazy_static::initialize(&SERIAL1);
+    apic::route_isa_irq(IRQ);
+}
+
+/// 受信したバイトを順に取り出す`Stream`を返す。受信を始めていなければ`None`を返す
+///
+/// 受信を待てるタスクは一つだけなので、複数のタスクから同時に待たないこと
+pub fn input_stream() -> Option<QueueStream<'static, u8>> {
+    SERIAL_INPUT.get().map(WakeQueue::stream)
+}
+
+/// 受信したバイトを1つ取り出す。受信したバイトが無いか、受信を始めていなければ`None`を返す
+pub fn read_input() -> Option<u8> {
+    SERIAL_INPUT.get()?.pop()
+}
+
+/// COM1をファイルとして読み書きするためのデバイス
+///
+/// 読み込みは受信済みのバイトを取り出すだけで、受信を待たない。書き込んだバイトは変換せずに送信する
+pub struct SerialDevice;
+
+impl CharDevice for SerialDevice {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let mut len = 0;
+        while len < buffer.len() {
+            let Some(byte) = read_input() else {
+                break;
+            };
+            buffer[len] = byte;
+            len += 1;
+        }
+        if len == 0 {
+            return Err(FsError::WouldBlock);
+        }
+        Ok(len)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut serial = SERIAL1.lock();
+        for &byte in buffer {
+            serial.send_raw(byte);
+        }
+        Ok(buffer.len())
+    }
+}
+
+/// シリアル受信割り込み
+///
+/// 受信レジスタの読み出しは送信と干渉しないため、`SERIAL1`のロックを取らずに直接読む
+pub(crate) extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let mut line_status: Port<u8> = Port::new(LINE_STATUS_PORT);
+    let mut data: Port<u8> = Port::new(COM1_BASE);
+
+    unsafe {
+        while line_status.read() & LINE_STATUS_DATA_READY != 0 {
+            let byte = data.read();
+            // キューが溢れた場合や受信を始めていない場合は、そのバイトを捨てる
+            if let Some(queue) = SERIAL_INPUT.get() {
+                queue.push(byte);
+            }
+        }
+    }
+
+    apic::end_of_interrupt();
+}
@@ -0,0 +1,498 @@
+//! カーネルスレッドとスケジューラ
+//!
+//! タイマ割り込みごとに実行中のスレッドのタイムスライスを減らし、使い切ったら次のスレッドへ切り替える
+//! ラウンドロビン方式のスケジューラ。実行できるスレッドが無いときはアイドルスレッドが`hlt`で待つ
+//!
+//! スケジューラの状態を触る処理はすべて割り込みを無効にした状態で行う
+
+pub mod context;
+pub mod stack;
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use common_lib::time::{self, Duration, Instant};
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{Page, Size4KiB},
+};
+
+use self::stack::{KernelStack, StackError, DEFAULT_STACK_PAGES};
+
+/// 一つのスレッドが続けて実行できるタイマ割り込みの回数
+pub const TIME_SLICE_TICKS: u32 = 10;
+
+/// スレッドの識別子
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// スレッドの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// 実行可能で、順番を待っている
+    Ready,
+    /// 実行中
+    Running,
+    /// 指定した時刻まで眠っている
+    Sleeping(Instant),
+    /// 他のスレッドの終了を待っている
+    Blocked,
+    /// 終了した
+    Exited,
+}
+
+/// スレッドの生成に失敗した理由
+#[derive(Debug)]
+pub enum SpawnError {
+    /// スケジューラが初期化されていない
+    NotInitialized,
+    /// スタックを確保できなかった
+    Stack(StackError),
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    /// 切り替えられたときに保存したスタックポインタ
+    stack_pointer: u64,
+    /// 起動時のスタックを使うスレッドは`None`
+    stack: Option<KernelStack>,
+    /// 最初に実行する処理。実行を始めると`None`になる
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// このスレッドの終了を待っているスレッド
+    joiners: Vec<ThreadId>,
+}
+
+struct Scheduler {
+    /// スレッド本体。スタックポインタの保存先のアドレスが変わらないよう`Box`に入れておく
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    sleeping: BTreeSet<(Instant, ThreadId)>,
+    current: ThreadId,
+    idle: ThreadId,
+    /// 実行中のスレッドに残っているタイマ割り込みの回数
+    slice_remaining: u32,
+    /// 終了したが、まだ取り除いていないスレッド
+    exited: Vec<ThreadId>,
+    /// 取り除いたが、まだスタックを解放していないスレッド
+    dead: Vec<Thread>,
+}
+
+impl Scheduler {
+    /// 時刻を過ぎた眠っているスレッドを起こす
+    fn wake_sleepers(&mut self, now: Instant) {
+        while let Some(&(deadline, id)) = self.sleeping.first() {
+            if deadline > now {
+                break;
+            }
+            self.sleeping.pop_first();
+            self.make_ready(id);
+        }
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            if matches!(
+                thread.state,
+                ThreadState::Sleeping(_) | ThreadState::Blocked
+            ) {
+                thread.state = ThreadState::Ready;
+                self.ready.push_back(id);
+            }
+        }
+    }
+
+    /// 次に実行するスレッドを選び、切り替えに必要なスタックポインタの組を返す
+    ///
+    /// 切り替える必要が無ければ`None`を返す
+    fn switch_next(&mut self) -> Option<(*mut u64, u64)> {
+        let current = self.current;
+        let current_runnable = self.threads[&current].state == ThreadState::Running;
+
+        let next = match self.ready.pop_front() {
+            Some(next) => next,
+            // 実行中のスレッドが続けられるなら、そのまま続ける
+            None if current_runnable => return None,
+            None => self.idle,
+        };
+        if next == current {
+            // 起こされたスレッドがまだ切り替わる前だった場合は、そのまま実行を続ける
+            self.threads.get_mut(&current).unwrap().state = ThreadState::Running;
+            return None;
+        }
+
+        if current_runnable {
+            self.threads.get_mut(&current).unwrap().state = ThreadState::Ready;
+            if current != self.idle {
+                self.ready.push_back(current);
+            }
+        }
+
+        let next_thread = self.threads.get_mut(&next).unwrap();
+        next_thread.state = ThreadState::Running;
+        let new_stack_pointer = next_thread.stack_pointer;
+
+        let old_stack_pointer =
+            &mut self.threads.get_mut(&current).unwrap().stack_pointer as *mut u64;
+        self.current = next;
+        self.slice_remaining = TIME_SLICE_TICKS;
+
+        Some((old_stack_pointer, new_stack_pointer))
+    }
+
+    /// 終了したスレッドを取り除く。実行中のスレッドは取り除かない
+    fn reap(&mut self) {
+        let current = self.current;
+        let threads = &mut self.threads;
+        let dead = &mut self.dead;
+        self.exited.retain(|&id| {
+            if id == current {
+                return true;
+            }
+            if let Some(thread) = threads.remove(&id) {
+                dead.push(*thread);
+            }
+            false
+        });
+    }
+}
+
+static SCHEDULER: Once<Mutex<Scheduler>> = Once::new();
+
+/// スレッドを生成した際に返すハンドル。`join()`でスレッドの終了を待ち、戻り値を受け取る
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// 対象のスレッドの識別子を返す
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// 対象のスレッドが終了するまで待ち、戻り値を返す
+    pub fn join(self) -> T {
+        loop {
+            let finished = interrupts::without_interrupts(|| {
+                {
+                    let mut scheduler = scheduler().lock();
+                    let current = scheduler.current;
+
+                    match scheduler.threads.get_mut(&self.id) {
+                        Some(thread) if thread.state != ThreadState::Exited => {
+                            thread.joiners.push(current);
+                            scheduler.threads.get_mut(&current).unwrap().state =
+                                ThreadState::Blocked;
+                        }
+                        _ => return true,
+                    }
+                }
+                schedule();
+                false
+            });
+
+            if finished {
+                break;
+            }
+        }
+
+        self.result
+            .lock()
+            .take()
+            .expect("Joined thread did not produce a result")
+    }
+}
+
+fn scheduler() -> &'static Mutex<Scheduler> {
+    SCHEDULER.get().expect("Scheduler is not initialized")
+}
+
+/// スケジューラを初期化し、現在の処理の流れを`main`スレッドとして登録する
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. メモリ管理とヒープが初期化済みであること
+/// 1. この関数が全処理の中で一度だけ呼び出されていること
+pub unsafe fn init() -> Result<(), SpawnError> {
+    context::enable_fpu();
+
+    let main = Box::new(Thread {
+        id: ThreadId::new(),
+        name: "main",
+        state: ThreadState::Running,
+        stack_pointer: 0,
+        stack: None,
+        entry: None,
+        joiners: Vec::new(),
+    });
+    let idle = new_thread("idle", Box::new(idle_loop))?;
+
+    let main_id = main.id;
+    let idle_id = idle.id;
+    let mut threads = BTreeMap::new();
+    threads.insert(main_id, main);
+    threads.insert(idle_id, idle);
+
+    SCHEDULER.call_once(|| {
+        Mutex::new(Scheduler {
+            threads,
+            ready: VecDeque::new(),
+            sleeping: BTreeSet::new(),
+            current: main_id,
+            idle: idle_id,
+            slice_remaining: TIME_SLICE_TICKS,
+            exited: Vec::new(),
+            dead: Vec::new(),
+        })
+    });
+
+    Ok(())
+}
+
+/// スケジューラが初期化済みであれば`true`を返す
+pub fn is_initialized() -> bool {
+    SCHEDULER.get().is_some()
+}
+
+fn idle_loop() {
+    loop {
+        free_dead_threads();
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// 終了したスレッドのスタックを解放する
+///
+/// スタックの解放はページテーブルのロックを取るため、割り込みが有効な状態で呼ぶこと。
+/// 割り込みを無効にしたまま、ロックを持ったスレッドの再開を待つと行き詰まるためである
+fn free_dead_threads() {
+    let dead = interrupts::without_interrupts(|| core::mem::take(&mut scheduler().lock().dead));
+    drop(dead);
+}
+
+/// スタックを確保し、最初に切り替えられたときに`entry`を実行するスレッドを作る
+fn new_thread(
+    name: &'static str,
+    entry: Box<dyn FnOnce() + Send>,
+) -> Result<Box<Thread>, SpawnError> {
+    let stack = KernelStack::allocate(DEFAULT_STACK_PAGES).map_err(SpawnError::Stack)?;
+    let stack_pointer = unsafe { context::prepare_stack(stack.top().as_u64(), thread_entry, 0) };
+
+    Ok(Box::new(Thread {
+        id: ThreadId::new(),
+        name,
+        state: ThreadState::Ready,
+        stack_pointer,
+        stack: Some(stack),
+        entry: Some(entry),
+        joiners: Vec::new(),
+    }))
+}
+
+/// すべてのスレッドが最初に実行する関数
+extern "C" fn thread_entry(_argument: usize) -> ! {
+    finish_switch();
+
+    let entry = interrupts::without_interrupts(|| {
+        let mut scheduler = scheduler().lock();
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().entry.take()
+    });
+
+    // 切り替えは割り込みを無効にして行われるため、ここで有効に戻す
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit();
+}
+
+/// 名前を付けて新しいスレッドを生成する
+pub fn spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if !is_initialized() {
+        return Err(SpawnError::NotInitialized);
+    }
+    free_dead_threads();
+
+    let result = Arc::new(Mutex::new(None));
+    let thread_result = result.clone();
+    let thread = new_thread(
+        name,
+        Box::new(move || {
+            let value = f();
+            *thread_result.lock() = Some(value);
+        }),
+    )?;
+    let id = thread.id;
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = scheduler().lock();
+        scheduler.threads.insert(id, thread);
+        scheduler.ready.push_back(id);
+    });
+
+    Ok(JoinHandle { id, result })
+}
+
+/// 実行中のスレッドの識別子を返す
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| scheduler().lock().current)
+}
+
+/// 実行中のスレッドの名前を返す
+pub fn current_name() -> &'static str {
+    interrupts::without_interrupts(|| {
+        let scheduler = scheduler().lock();
+        scheduler.threads[&scheduler.current].name
+    })
+}
+
+/// 他のスレッドに実行を譲る
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// 指定した時間だけ実行を止める。スケジューラの初期化前に呼んだ場合は`hlt`で待つ
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// 指定した時刻まで実行を止める
+pub fn sleep_until(deadline: Instant) {
+    if !is_initialized() {
+        time::sleep_until(deadline);
+        return;
+    }
+
+    while Instant::now() < deadline {
+        interrupts::without_interrupts(|| {
+            {
+                let mut scheduler = scheduler().lock();
+                let current = scheduler.current;
+                scheduler.threads.get_mut(&current).unwrap().state =
+                    ThreadState::Sleeping(deadline);
+                scheduler.sleeping.insert((deadline, current));
+            }
+            schedule();
+        });
+    }
+}
+
+/// 実行中のスレッドを終了する
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = scheduler().lock();
+        let current = scheduler.current;
+        let thread = scheduler.threads.get_mut(&current).unwrap();
+        thread.state = ThreadState::Exited;
+        let joiners = core::mem::take(&mut thread.joiners);
+
+        for joiner in joiners {
+            scheduler.make_ready(joiner);
+        }
+        scheduler.exited.push(current);
+    }
+    schedule();
+
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// 次のスレッドへ切り替える。割り込みを無効にした状態で呼ぶこと
+fn schedule() {
+    let switch = {
+        let mut scheduler = scheduler().lock();
+        scheduler.wake_sleepers(Instant::now());
+        scheduler.switch_next()
+    };
+
+    if let Some((old_stack_pointer, new_stack_pointer)) = switch {
+        unsafe { context::switch_context(old_stack_pointer, new_stack_pointer) };
+        finish_switch();
+    }
+}
+
+/// 切り替え後に、前のスレッドが終了していれば一覧から取り除く
+fn finish_switch() {
+    scheduler().lock().reap();
+}
+
+/// タイマ割り込みから呼ばれ、タイムスライスを使い切っていれば次のスレッドへ切り替える
+///
+/// EOIを送った後に呼ぶこと
+pub(crate) fn preempt() {
+    let Some(scheduler) = SCHEDULER.get() else {
+        return;
+    };
+
+    let should_switch = {
+        let mut scheduler = scheduler.lock();
+        scheduler.wake_sleepers(Instant::now());
+        scheduler.slice_remaining = scheduler.slice_remaining.saturating_sub(1);
+
+        let idle = scheduler.current == scheduler.idle;
+        (scheduler.slice_remaining == 0 || idle) && !scheduler.ready.is_empty()
+    };
+
+    if should_switch {
+        schedule();
+    }
+}
+
+/// `address`が実行中のスレッドのガードページ内であれば、そのスレッドの名前を返す
+///
+/// 例外ハンドラから呼ぶため、スケジューラのロックが取れない場合は`None`を返す
+pub fn guard_page_hit(address: u64) -> Option<&'static str> {
+    let scheduler = SCHEDULER.get()?.try_lock()?;
+    let thread = scheduler.threads.get(&scheduler.current)?;
+    let guard_page = thread.stack.as_ref()?.guard_page().as_u64();
+
+    (guard_page..guard_page + Page::<Size4KiB>::SIZE)
+        .contains(&address)
+        .then_some(thread.name)
+}
+
+/// スレッドの一覧を`(識別子, 名前, 状態)`の組で返す
+pub fn list() -> Vec<(ThreadId, &'static str, ThreadState)> {
+    interrupts::without_interrupts(|| {
+        scheduler()
+            .lock()
+            .threads
+            .values()
+            .map(|t| (t.id, t.name, t.state))
+            .collect()
+    })
+}
@@ -0,0 +1,122 @@
+//! スレッドのコンテキストスイッチ
+//!
+//! System V ABIで呼び出し先が保存すべきレジスタ（rbx, rbp, r12〜r15）とFPU/SSEの状態を
+//! スタックに積み、スタックポインタを差し替えることでスレッドを切り替える
+
+use core::arch::global_asm;
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// `fxsave`で保存する領域の大きさ。16バイト境界に揃えるための8バイトを含む
+const FX_AREA_SIZE: usize = 520;
+
+/// FPU制御ワードの初期値（すべての例外をマスク）
+const DEFAULT_FCW: u16 = 0x037f;
+/// MXCSRの初期値（すべての例外をマスク）
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+global_asm!(
+    ".global emer_switch_context",
+    "emer_switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "sub rsp, 520",
+    "fxsave64 [rsp]",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "fxrstor64 [rsp]",
+    "add rsp, 520",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global emer_thread_trampoline",
+    "emer_thread_trampoline:",
+    "mov rdi, r12",
+    "and rsp, -16",
+    "call r13",
+    "ud2",
+);
+
+extern "C" {
+    fn emer_switch_context(old_stack_pointer: *mut u64, new_stack_pointer: u64);
+    fn emer_thread_trampoline();
+}
+
+/// 現在のスレッドの状態を保存し、`new_stack_pointer`が指すスレッドへ切り替える
+///
+/// 切り替え先のスレッドが再びこのスレッドへ切り替えると、この関数から戻る
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. 割り込みが無効になっていること
+/// 1. `old_stack_pointer`が、このスレッドが再開されるまで有効な領域を指していること
+/// 1. `new_stack_pointer`が、`switch_context()`で保存されたか`prepare_stack()`で作られたスタックポインタであること
+#[inline(always)]
+pub unsafe fn switch_context(old_stack_pointer: *mut u64, new_stack_pointer: u64) {
+    emer_switch_context(old_stack_pointer, new_stack_pointer);
+}
+
+/// 新しいスレッドのスタックに、初めて切り替えられたときに`entry(argument)`を呼ぶような初期状態を積む
+///
+/// 戻り値を`switch_context()`の`new_stack_pointer`に渡すと、そのスレッドが動き始める
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. `stack_top`が、書き込み可能で十分な大きさを持つスタックの最上部を指していること
+/// 1. `entry`が戻らない関数であること
+pub unsafe fn prepare_stack(
+    stack_top: u64,
+    entry: extern "C" fn(usize) -> !,
+    argument: usize,
+) -> u64 {
+    // `fxrstor`の対象が16バイト境界に揃うよう、最上部を16バイト境界に合わせておく
+    let mut stack_pointer = stack_top & !0xf;
+
+    let mut push = |value: u64| {
+        stack_pointer -= 8;
+        (stack_pointer as *mut u64).write(value);
+    };
+
+    // `ret`で飛ぶ先
+    push(emer_thread_trampoline as *const () as u64);
+    // rbp, rbx, r12, r13, r14, r15の順に積まれる
+    push(0);
+    push(0);
+    push(argument as u64);
+    push(entry as *const () as u64);
+    push(0);
+    push(0);
+
+    // FPU/SSEの初期状態
+    stack_pointer -= FX_AREA_SIZE as u64;
+    let fx_area = stack_pointer as *mut u8;
+    fx_area.write_bytes(0, FX_AREA_SIZE);
+    (fx_area as *mut u16).write(DEFAULT_FCW);
+    (fx_area.add(24) as *mut u32).write(DEFAULT_MXCSR);
+
+    stack_pointer
+}
+
+/// `fxsave`/`fxrstor`でSSEの状態を保存できるよう、FPUとSSEを有効化する
+///
+/// ## Safety
+/// 呼び出し元はCPUがSSEに対応していることを保証しなければならない
+pub unsafe fn enable_fpu() {
+    let mut cr0 = Cr0::read();
+    cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+    cr0.insert(Cr0Flags::MONITOR_COPROCESSOR);
+    Cr0::write(cr0);
+
+    let mut cr4 = Cr4::read();
+    cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+    Cr4::write(cr4);
+}
@@ -0,0 +1,179 @@
+//! カーネルスレッド用のスタック
+//!
+//! スタックは専用の仮想アドレス領域に、一定の大きさの枠（スロット）単位で置く。
+//! 領域は最初のスタックを確保するときに、カーネルの仮想アドレス空間から割り当てる。
+//! 各スロットの最下部の1ページはマップせずにガードページとし、スタックが溢れた場合はページフォルトを起こす
+
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+        PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory::{
+    self,
+    vma::{self, Backing, VmaError, VmaKind},
+};
+
+/// スタック用の仮想アドレス領域の先頭
+static STACK_REGION: Once<VirtAddr> = Once::new();
+/// スタック用の仮想アドレス領域に置けるスロットの数
+const MAX_STACKS: u64 = 4096;
+/// 一つのスタックに割り当てられる最大のページ数（ガードページを除く）
+pub const MAX_STACK_PAGES: usize = 255;
+/// スロット一つあたりのページ数（ガードページを含む）
+const SLOT_PAGES: u64 = MAX_STACK_PAGES as u64 + 1;
+/// 特に指定しない場合のスタックのページ数
+pub const DEFAULT_STACK_PAGES: usize = 16; // 64 KiB
+
+/// スロットの割り当て状況
+struct SlotAllocator {
+    next: u64,
+    free: Vec<u64>,
+}
+
+static SLOTS: Mutex<SlotAllocator> = Mutex::new(SlotAllocator {
+    next: 0,
+    free: Vec::new(),
+});
+
+/// スタックの確保に失敗した理由
+#[derive(Debug)]
+pub enum StackError {
+    /// スタック用の仮想アドレス領域を割り当てられなかった
+    Region(VmaError),
+    /// スタック用の仮想アドレス領域のスロットが尽きた
+    TooManyStacks,
+    /// ページをマップできなかった
+    Map(MapToError<Size4KiB>),
+}
+
+/// スタック用の仮想アドレス領域の先頭を返す。まだ割り当てていなければ割り当てる
+fn region_start() -> Result<VirtAddr, StackError> {
+    STACK_REGION
+        .try_call_once(|| {
+            vma::allocate(
+                MAX_STACKS * SLOT_PAGES * Size4KiB::SIZE,
+                Size4KiB::SIZE,
+                "kernel stacks",
+                VmaKind::Stack,
+                PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                Backing::Owned,
+            )
+        })
+        .copied()
+        .map_err(StackError::Region)
+}
+
+/// ガードページ付きのカーネルスタック。破棄されるとマッピングと物理フレームを解放する
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: u64,
+    pages: usize,
+}
+
+impl KernelStack {
+    /// `pages`ページ分のスタックを確保する
+    ///
+    /// ## Panic
+    /// `pages`が0または`MAX_STACK_PAGES`を超える場合と、メモリ管理が初期化されていない場合はパニックを起こす
+    pub fn allocate(pages: usize) -> Result<Self, StackError> {
+        assert!(
+            (1..=MAX_STACK_PAGES).contains(&pages),
+            "Invalid kernel stack size: {} pages",
+            pages
+        );
+        region_start()?;
+
+        let slot = {
+            let mut slots = SLOTS.lock();
+            match slots.free.pop() {
+                Some(slot) => slot,
+                None if slots.next < MAX_STACKS => {
+                    slots.next += 1;
+                    slots.next - 1
+                }
+                None => return Err(StackError::TooManyStacks),
+            }
+        };
+        let stack = KernelStack { slot, pages };
+
+        let mut manager = memory::manager()
+            .expect("Memory manager is not initialized")
+            .lock();
+        let manager = &mut *manager;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        for page in stack.page_range() {
+            let frame = manager
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(StackError::Map(MapToError::FrameAllocationFailed))?;
+            let result = unsafe {
+                manager
+                    .mapper
+                    .map_to(page, frame, flags, &mut manager.frame_allocator)
+            };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    unsafe { manager.frame_allocator.deallocate_frame(frame) };
+                    return Err(StackError::Map(e));
+                }
+            }
+        }
+
+        Ok(stack)
+    }
+
+    /// スロットの先頭（ガードページ）のアドレス
+    fn slot_start(&self) -> VirtAddr {
+        // スタックが存在する時点で、領域は割り当て済みである
+        let region_start = *STACK_REGION.get().unwrap();
+        region_start + self.slot * SLOT_PAGES * Page::<Size4KiB>::SIZE
+    }
+
+    /// ガードページのアドレス
+    pub fn guard_page(&self) -> VirtAddr {
+        self.slot_start()
+    }
+
+    /// マップされたページの範囲。ガードページの直後から始まる
+    fn page_range(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.slot_start()) + 1;
+        Page::range(start, start + self.pages as u64)
+    }
+
+    /// スタックの最上部（初期のスタックポインタ）
+    pub fn top(&self) -> VirtAddr {
+        self.slot_start() + (self.pages as u64 + 1) * Page::<Size4KiB>::SIZE
+    }
+
+    /// スタックの最下部（これより下はガードページ）
+    pub fn bottom(&self) -> VirtAddr {
+        self.slot_start() + Page::<Size4KiB>::SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        if let Some(manager) = memory::manager() {
+            let mut manager = manager.lock();
+            let manager = &mut *manager;
+
+            for page in self.page_range() {
+                // 確保の途中で失敗した場合は、マップされていないページが残っている
+                if let Ok((frame, flush)) = manager.mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { manager.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        }
+
+        SLOTS.lock().free.push(self.slot);
+    }
+}
@@ -0,0 +1,130 @@
+//! タイマ割り込みを扱うモジュール
+//!
+//! 最初にPITで周期割り込みを起こし、それ（HPETがあればHPET）を基準にLocal APICタイマを較正した上で、
+//! 以降の周期割り込みをLocal APICタイマに切り替える。
+//! いずれのタイマの割り込みも`common_lib::time::tick()`を呼び、カーネル全体の時刻を進める
+
+pub mod hpet;
+pub mod pit;
+
+use common_lib::time::{self, Duration};
+use x86_64::{structures::idt::InterruptStackFrame, PhysAddr};
+
+use self::hpet::Hpet;
+use crate::{
+    interrupt::{
+        self,
+        apic::{
+            self,
+            local_apic::{TimerDivide, TimerMode},
+        },
+    },
+    memory::mmio::{self, CacheType},
+    thread,
+};
+
+/// タイマ割り込みの周波数（Hz）
+pub const TICK_HZ: u32 = 1000;
+
+/// Local APICタイマの較正に使う時間
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+/// Local APICタイマの分周比
+const LAPIC_TIMER_DIVIDE: TimerDivide = TimerDivide::By16;
+
+/// タイマ割り込みを初期化する
+///
+/// `hpet_address`にはACPIのHPETテーブルから得たHPETの物理アドレスを渡す。
+/// `None`の場合はPITを基準にLocal APICタイマを較正する
+///
+/// ## Safety
+/// 呼び出し元は以下の点を保証しなければならない:
+/// 1. `hpet_address`が、HPETテーブルに記載されたHPETのレジスタ領域の物理アドレスであること
+/// 1. APICが初期化済みで、なおかつ割り込みが有効になっていること
+/// 1. この関数が全処理の中で一度だけ呼び出されていること
+pub unsafe fn init(hpet_address: Option<u64>) {
+    // まずはPITで周期割り込みを起こす
+    let pit_period = pit::start_periodic(TICK_HZ);
+    time::init(pit_period, interrupt::halt);
+    apic::route_isa_irq(pit::IRQ);
+
+    let Some(local_apic) = apic::local_apic() else {
+        return;
+    };
+
+    // Local APICタイマを最大値からカウントダウンさせ、一定時間でいくつ減ったかを測る
+    // 較正が済めばHPETは使わないため、この関数を抜けるときにアンマップされる
+    let hpet = hpet_address.and_then(|address| {
+        let registers = mmio::ioremap(
+            "HPET",
+            PhysAddr::new(address),
+            hpet::REGISTERS_SIZE,
+            CacheType::Uncached,
+        );
+        match registers {
+            Ok(registers) => {
+                let hpet = Hpet::new(registers);
+                hpet.enable();
+                Some(hpet)
+            }
+            Err(e) => {
+                log::warn!("Failed to map the HPET registers: {:?}", e);
+                None
+            }
+        }
+    });
+
+    local_apic.configure_timer(TimerMode::OneShot, apic::TIMER_VECTOR, LAPIC_TIMER_DIVIDE);
+    let elapsed = match &hpet {
+        Some(hpet) => {
+            local_apic.start_timer(u32::MAX);
+            hpet.busy_wait(CALIBRATION_PERIOD);
+            CALIBRATION_PERIOD
+        }
+        None => {
+            // PITの割り込みの境目から数え始める
+            let start = time::ticks();
+            while time::ticks() == start {
+                core::hint::spin_loop();
+            }
+
+            let start = time::ticks();
+            let calibration_ticks = (CALIBRATION_PERIOD.as_nanos() / pit_period.as_nanos()) as u64;
+            local_apic.start_timer(u32::MAX);
+            while time::ticks() - start < calibration_ticks {
+                core::hint::spin_loop();
+            }
+            pit_period * calibration_ticks as u32
+        }
+    };
+    let counted = u32::MAX - local_apic.timer_current_count();
+    local_apic.stop_timer();
+
+    let counts_per_tick =
+        counted as u64 * 1_000_000_000 / TICK_HZ as u64 / elapsed.as_nanos() as u64;
+    if counts_per_tick == 0 {
+        // 較正に失敗した場合はPITを使い続ける
+        return;
+    }
+
+    // PITを止めて、Local APICタイマに切り替える
+    apic::set_isa_irq_masked(pit::IRQ, true);
+    time::set_tick_period(Duration::from_nanos(1_000_000_000 / TICK_HZ as u64));
+    local_apic.configure_timer(TimerMode::Periodic, apic::TIMER_VECTOR, LAPIC_TIMER_DIVIDE);
+    local_apic.start_timer(counts_per_tick as u32);
+}
+
+/// PITの割り込み
+pub(crate) extern "x86-interrupt" fn pit_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    time::tick();
+    apic::end_of_interrupt();
+    thread::preempt();
+}
+
+/// Local APICタイマの割り込み
+pub(crate) extern "x86-interrupt" fn local_apic_timer_interrupt_handler(
+    _stack_frame: InterruptStackFrame,
+) {
+    time::tick();
+    apic::end_of_interrupt();
+    thread::preempt();
+}
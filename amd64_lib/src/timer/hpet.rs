@@ -0,0 +1,84 @@
+//! HPET (High Precision Event Timer)のドライバ
+//!
+//! 現状はメインカウンタを時間計測に使うのみで、コンパレータによる割り込みは使わない
+
+use common_lib::time::Duration;
+
+use crate::memory::mmio::IoMem;
+
+/// レジスタ領域の大きさ
+pub const REGISTERS_SIZE: usize = 0x400;
+
+// レジスタのオフセット
+const REG_CAPABILITIES: usize = 0x00;
+const REG_CONFIGURATION: usize = 0x10;
+const REG_MAIN_COUNTER: usize = 0xf0;
+
+/// 設定レジスタのうち、メインカウンタを動かすビット
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+/// 1ナノ秒あたりのフェムト秒
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+/// HPETを操作する構造体
+#[derive(Debug)]
+pub struct Hpet {
+    registers: IoMem,
+    /// メインカウンタが1増えるのにかかる時間（フェムト秒）
+    period_femtos: u64,
+}
+
+impl Hpet {
+    /// HPETのレジスタ領域をマップしたハンドルから構造体を作る
+    ///
+    /// ## Safety
+    /// 呼び出し元は`registers`がHPETのレジスタ領域（1KiB）をキャッシュせずにマップしたものであることを保証しなければならない
+    pub unsafe fn new(registers: IoMem) -> Self {
+        let mut hpet = Hpet {
+            registers,
+            period_femtos: 0,
+        };
+        hpet.period_femtos = hpet.read(REG_CAPABILITIES) >> 32;
+        hpet
+    }
+
+    #[inline(always)]
+    fn read(&self, offset: usize) -> u64 {
+        self.registers.read(offset)
+    }
+
+    #[inline(always)]
+    fn write(&self, offset: usize, value: u64) {
+        self.registers.write(offset, value)
+    }
+
+    /// メインカウンタを動かす
+    pub fn enable(&self) {
+        let configuration = self.read(REG_CONFIGURATION);
+        self.write(REG_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+    }
+
+    /// メインカウンタの現在値を返す
+    #[inline(always)]
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER)
+    }
+
+    /// メインカウンタが1増えるのにかかる時間（フェムト秒）を返す
+    pub fn period_femtos(&self) -> u64 {
+        self.period_femtos
+    }
+
+    /// 指定した時間が経過するまで、メインカウンタを見ながら待つ
+    ///
+    /// 割り込みを使わないため、タイマ割り込みが動いていない時点でも使える
+    pub fn busy_wait(&self, duration: Duration) {
+        let femtos = duration.as_nanos() as u64 * FEMTOS_PER_NANO;
+        let target = femtos / self.period_femtos;
+        let start = self.counter();
+
+        while self.counter().wrapping_sub(start) < target {
+            core::hint::spin_loop();
+        }
+    }
+}
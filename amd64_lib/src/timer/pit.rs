@@ -0,0 +1,43 @@
+//! 8253/8254 PIT (Programmable Interval Timer)のドライバ
+
+use x86_64::instructions::port::Port;
+
+use common_lib::time::Duration;
+
+/// PITの入力クロック周波数（Hz）
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+/// PITのチャンネル0が接続されているISA IRQ
+pub const IRQ: u8 = 0;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+/// チャンネル0、下位・上位バイトの順にアクセス、モード2（レートジェネレータ）、バイナリカウント
+const COMMAND_CHANNEL0_RATE_GENERATOR: u8 = 0x34;
+
+/// チャンネル0を指定した周波数の周期モードで動かし、実際の割り込み周期を返す
+///
+/// 分周比は整数でしか指定できないため、実際の周期は要求した周波数から僅かにずれる
+///
+/// ## Panic
+/// `frequency`が0の場合や、PITで実現できない周波数の場合はパニックを起こす
+pub fn start_periodic(frequency: u32) -> Duration {
+    assert!(frequency != 0, "PIT frequency must not be zero");
+
+    let divisor = BASE_FREQUENCY / frequency;
+    assert!(
+        (1..=u16::MAX as u32).contains(&divisor),
+        "PIT cannot generate {} Hz",
+        frequency
+    );
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel0: Port<u8> = Port::new(CHANNEL0_DATA);
+    unsafe {
+        command.write(COMMAND_CHANNEL0_RATE_GENERATOR);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+
+    Duration::from_nanos(divisor as u64 * 1_000_000_000 / BASE_FREQUENCY as u64)
+}
@@ -0,0 +1,93 @@
+//! virtioデバイスのドライバ
+//!
+//! PCIに接続されたvirtioデバイス（virtio-pci）を扱う。
+//! virtio 1.0以降のモダンなインタフェースと、それ以前のレガシーなインタフェースの両方に対応し、
+//! 仮想キューには分割仮想キュー（split virtqueue）を使う
+
+pub mod block;
+pub mod queue;
+pub mod transport;
+
+use core::fmt;
+
+use crate::memory::{dma::DmaError, mmio::MmioError};
+
+use self::queue::QueueError;
+
+/// virtioデバイスのPCIベンダーID
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// デバイスステータスのビット
+pub mod status {
+    /// ゲストがデバイスを認識した
+    pub const ACKNOWLEDGE: u8 = 1;
+    /// ゲストがデバイスを扱うドライバを持っている
+    pub const DRIVER: u8 = 2;
+    /// ドライバの準備ができた
+    pub const DRIVER_OK: u8 = 4;
+    /// 機能のネゴシエーションが済んだ
+    pub const FEATURES_OK: u8 = 8;
+    /// デバイスが回復不能なエラーを起こし、リセットを必要としている
+    pub const DEVICE_NEEDS_RESET: u8 = 64;
+    /// ゲストがデバイスを諦めた
+    pub const FAILED: u8 = 128;
+}
+
+/// デバイスの種類によらない機能ビット
+pub mod feature {
+    /// virtio 1.0以降の仕様に従う
+    pub const VERSION_1: u64 = 1 << 32;
+}
+
+/// virtioデバイスの初期化や操作に失敗した理由
+#[derive(Debug)]
+pub enum VirtioError {
+    /// 必要なPCIのBARやケーパビリティが無い
+    MissingCapability(&'static str),
+    /// 必要な機能にデバイスが対応していない
+    FeatureNotSupported(u64),
+    /// デバイスが機能のネゴシエーションを受け入れなかった
+    FeaturesRejected,
+    /// 使おうとした仮想キューがデバイスに無い
+    QueueUnavailable(u16),
+    /// 仮想キューの操作に失敗した
+    Queue(QueueError),
+    /// レジスタをマップできなかった
+    Mmio(MmioError),
+    /// DMAバッファを割り当てられなかった
+    Dma(DmaError),
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtioError::MissingCapability(name) => write!(f, "missing {}", name),
+            VirtioError::FeatureNotSupported(bits) => {
+                write!(f, "required features {:#x} are not supported", bits)
+            }
+            VirtioError::FeaturesRejected => write!(f, "device rejected the negotiated features"),
+            VirtioError::QueueUnavailable(index) => write!(f, "virtqueue {} is unavailable", index),
+            VirtioError::Queue(e) => write!(f, "virtqueue error: {:?}", e),
+            VirtioError::Mmio(e) => write!(f, "failed to map registers: {:?}", e),
+            VirtioError::Dma(e) => write!(f, "failed to allocate DMA buffer: {:?}", e),
+        }
+    }
+}
+
+impl From<QueueError> for VirtioError {
+    fn from(e: QueueError) -> Self {
+        VirtioError::Queue(e)
+    }
+}
+
+impl From<MmioError> for VirtioError {
+    fn from(e: MmioError) -> Self {
+        VirtioError::Mmio(e)
+    }
+}
+
+impl From<DmaError> for VirtioError {
+    fn from(e: DmaError) -> Self {
+        VirtioError::Dma(e)
+    }
+}
@@ -0,0 +1,346 @@
+//! virtio-blkのドライバ
+//!
+//! 要求ごとにヘッダ・データ・ステータスの3つのバッファを仮想キューに渡し、完了をポーリングで待つ。
+//! 呼び出し元のバッファは物理的に連続しているとは限らないため、データはDMAバッファを経由して受け渡す
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use common_lib::{
+    block::{self, BlockDevice, BlockError},
+    pci::{
+        driver::{DeviceMatch, PciDriver, ProbeError},
+        PciDevice,
+    },
+    time::{Duration, Instant},
+};
+use spin::Mutex;
+
+use super::{
+    queue::{Buffer, VirtQueue},
+    status,
+    transport::Transport,
+    VirtioError, VENDOR_ID,
+};
+use crate::memory::dma::DmaBuffer;
+
+/// レガシー（トランジショナル）なvirtio-blkのデバイスID
+const LEGACY_DEVICE_ID: u16 = 0x1001;
+/// モダンなvirtio-blkのデバイスID（0x1040 + デバイスタイプ2）
+const MODERN_DEVICE_ID: u16 = 0x1042;
+
+/// virtio-blkのセクタの大きさ。デバイスのブロックサイズによらず、要求はこの単位で行う
+pub const SECTOR_SIZE: usize = 512;
+
+/// 使う仮想キューの大きさの上限。要求は1つずつ処理するため、多くは要らない
+const QUEUE_SIZE_LIMIT: u16 = 64;
+/// 1回の要求で受け渡す最大のバイト数
+const BOUNCE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 要求の完了を待つ最大の時間
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// 要求の完了を確かめる最大の回数。割り込みが無効で時刻が進まない場合でも、待ち続けないようにする
+const MAX_POLLS: usize = 100_000_000;
+
+/// 機能ビット
+mod feature {
+    /// デバイスは読み込み専用である
+    pub const RO: u64 = 1 << 5;
+    /// キャッシュのフラッシュに対応している
+    pub const FLUSH: u64 = 1 << 9;
+}
+
+/// 要求の種類
+mod request {
+    pub const IN: u32 = 0;
+    pub const OUT: u32 = 1;
+    pub const FLUSH: u32 = 4;
+}
+
+/// 要求の結果
+mod request_status {
+    pub const OK: u8 = 0;
+    pub const IO_ERROR: u8 = 1;
+    pub const UNSUPPORTED: u8 = 2;
+}
+
+/// 要求ヘッダ
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// 要求ヘッダの、DMAバッファ内の位置
+const HEADER_OFFSET: usize = 0;
+/// ステータスの、DMAバッファ内の位置
+const STATUS_OFFSET: usize = 16;
+
+/// デバイスの名前に付ける番号
+static NEXT_DEVICE_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// ロックの中で操作する、デバイスの状態
+struct Inner {
+    transport: Transport,
+    queue: VirtQueue,
+    /// 要求ヘッダとステータスを置くバッファ
+    request: DmaBuffer,
+    /// データを受け渡すバッファ
+    bounce: DmaBuffer,
+    /// 要求が完了しなかったか、デバイスがリセットを求めた。以降の要求はすべて失敗させる
+    failed: bool,
+}
+
+/// virtio-blkデバイス
+pub struct VirtioBlock {
+    name: String,
+    sector_count: u64,
+    read_only: bool,
+    supports_flush: bool,
+    inner: Mutex<Inner>,
+}
+
+impl VirtioBlock {
+    /// デバイスを初期化する
+    pub fn new(device: &PciDevice, name: String) -> Result<Self, VirtioError> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(0, feature::RO | feature::FLUSH)?;
+
+        let buffers = transport
+            .setup_queue(0, QUEUE_SIZE_LIMIT)
+            .and_then(|queue| {
+                let request = DmaBuffer::allocate(STATUS_OFFSET + 1)?;
+                let bounce = DmaBuffer::allocate(BOUNCE_BUFFER_SIZE)?;
+                Ok((queue, request, bounce))
+            });
+        let (queue, request, bounce) = match buffers {
+            Ok(buffers) => buffers,
+            Err(e) => {
+                transport.add_status(status::FAILED);
+                return Err(e);
+            }
+        };
+
+        // 完了はポーリングで確かめるため、レガシーな割り込みは止めておく
+        device.disable_legacy_interrupt();
+        device.enable_bus_master();
+        transport.add_status(status::DRIVER_OK);
+
+        // 容量は常に512バイトのセクタ数で表される
+        let sector_count = transport.read_config_u64(0);
+
+        log::info!(
+            "virtio-blk {}: {} transport, {} MiB",
+            device.address,
+            if transport.is_legacy() {
+                "legacy"
+            } else {
+                "modern"
+            },
+            sector_count * SECTOR_SIZE as u64 / (1024 * 1024)
+        );
+
+        Ok(VirtioBlock {
+            name,
+            sector_count,
+            read_only: features & feature::RO != 0,
+            supports_flush: features & feature::FLUSH != 0,
+            inner: Mutex::new(Inner {
+                transport,
+                queue,
+                request,
+                bounce,
+                failed: false,
+            }),
+        })
+    }
+}
+
+impl Inner {
+    /// 要求を1つ送り、完了するまで待つ
+    ///
+    /// `data_len`が0でなければ、バウンスバッファの先頭`data_len`バイトをデータとして渡す。
+    /// 一定時間内に完了しない場合や、デバイスがリセットを求めた場合は、デバイスを諦めて`Io`を返す
+    fn submit(&mut self, kind: u32, sector: u64, data_len: usize) -> Result<(), BlockError> {
+        if self.failed {
+            return Err(BlockError::Io);
+        }
+
+        unsafe {
+            let base = self.request.as_mut_ptr();
+            write_volatile(
+                base.add(HEADER_OFFSET) as *mut RequestHeader,
+                RequestHeader {
+                    kind,
+                    reserved: 0,
+                    sector,
+                },
+            );
+            // デバイスが書き込まなかった場合に成功と取り違えないようにする
+            write_volatile(base.add(STATUS_OFFSET), 0xff);
+        }
+
+        let request_address = self.request.physical_address();
+        let header = Buffer::readable(
+            request_address + HEADER_OFFSET as u64,
+            core::mem::size_of::<RequestHeader>() as u32,
+        );
+        let status = Buffer::writable(request_address + STATUS_OFFSET as u64, 1);
+        let data = Buffer {
+            address: self.bounce.physical_address(),
+            len: data_len as u32,
+            device_writable: kind == request::IN,
+        };
+
+        let head = unsafe {
+            if data_len > 0 {
+                self.queue.add(&[header, data, status])
+            } else {
+                self.queue.add(&[header, status])
+            }
+        }
+        .map_err(|_| BlockError::Io)?;
+        self.transport.notify(&self.queue);
+
+        // 要求は1つずつ処理するため、返ってくるのは今送ったものだけである
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut polls = 0;
+        loop {
+            match self.queue.pop_used() {
+                Ok(Some(completion)) if completion.head == head => break,
+                Ok(Some(_)) => continue,
+                Ok(None) => {}
+                Err(_) => return Err(self.give_up("the device returned an invalid descriptor")),
+            }
+
+            polls += 1;
+            if self.transport.status() & status::DEVICE_NEEDS_RESET != 0 {
+                return Err(self.give_up("the device needs a reset"));
+            }
+            if polls >= MAX_POLLS || Instant::now() >= deadline {
+                return Err(self.give_up("the request timed out"));
+            }
+            core::hint::spin_loop();
+        }
+
+        match unsafe { read_volatile(self.request.as_ptr().add(STATUS_OFFSET)) } {
+            request_status::OK => Ok(()),
+            request_status::UNSUPPORTED => Err(BlockError::Unsupported),
+            request_status::IO_ERROR => Err(BlockError::Io),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    /// デバイスにFAILEDを伝え、以降の要求を受け付けないようにする
+    ///
+    /// 送った要求はデバイスが持ったままなので、バッファや仮想キューは再利用しない
+    fn give_up(&mut self, reason: &str) -> BlockError {
+        log::error!("virtio-blk: {}; giving up the device", reason);
+        self.transport.add_status(status::FAILED);
+        self.failed = true;
+        BlockError::Io
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buffer.len())?;
+
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buffer.chunks_mut(BOUNCE_BUFFER_SIZE).enumerate() {
+            let chunk_sector = sector + (i * BOUNCE_BUFFER_SIZE / SECTOR_SIZE) as u64;
+            inner.submit(request::IN, chunk_sector, chunk.len())?;
+            chunk.copy_from_slice(&inner.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_request(self, sector, buffer.len())?;
+
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buffer.chunks(BOUNCE_BUFFER_SIZE).enumerate() {
+            let chunk_sector = sector + (i * BOUNCE_BUFFER_SIZE / SECTOR_SIZE) as u64;
+            inner.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            inner.submit(request::OUT, chunk_sector, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // フラッシュに対応していないデバイスは、書き込みを常に記録媒体へ直接反映する
+        if !self.supports_flush {
+            return Ok(());
+        }
+        self.inner.lock().submit(request::FLUSH, 0, 0)
+    }
+}
+
+/// virtio-blkのPCIドライバ
+pub struct VirtioBlockDriver;
+
+/// `common_lib::pci::driver::register()`に渡すドライバ
+pub static DRIVER: VirtioBlockDriver = VirtioBlockDriver;
+
+const ID_TABLE: [DeviceMatch; 2] = [
+    DeviceMatch::id(VENDOR_ID, LEGACY_DEVICE_ID),
+    DeviceMatch::id(VENDOR_ID, MODERN_DEVICE_ID),
+];
+
+impl PciDriver for VirtioBlockDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn id_table(&self) -> &[DeviceMatch] {
+        &ID_TABLE
+    }
+
+    fn probe(&self, device: &'static PciDevice) -> Result<(), ProbeError> {
+        let index = NEXT_DEVICE_INDEX.fetch_add(1, Ordering::Relaxed);
+        let name = format!("vd{}", disk_suffix(index));
+
+        let device = VirtioBlock::new(device, name).map_err(|e| {
+            log::warn!("virtio-blk {}: {}", device.address, e);
+            ProbeError::InitializationFailed("virtio-blk initialization failed")
+        })?;
+        block::register(Arc::new(device));
+        Ok(())
+    }
+}
+
+/// `index`番目のディスクの名前の末尾。a, b, ..., z, aa, ab, ...と、Linuxと同じ規則で名付ける
+fn disk_suffix(index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut rest = index + 1;
+    while rest > 0 {
+        rest -= 1;
+        letters.push(b'a' + (rest % 26) as u8);
+        rest /= 26;
+    }
+    letters.iter().rev().map(|&letter| letter as char).collect()
+}
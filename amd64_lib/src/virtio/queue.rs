@@ -0,0 +1,322 @@
+//! 分割仮想キュー（split virtqueue）
+//!
+//! ディスクリプタテーブル、ドライバが書き込むAvailableリング、デバイスが書き込むUsedリングの3つからなる。
+//! レガシーなインタフェースでも使えるよう、3つを1つのDMAバッファにレガシーの配置規則で並べる
+
+use core::{
+    mem::size_of,
+    ptr::{addr_of_mut, read_volatile, write_volatile},
+    sync::atomic::{fence, Ordering},
+};
+
+use x86_64::PhysAddr;
+
+use crate::memory::dma::{DmaBuffer, DmaError};
+
+/// レガシーなインタフェースでUsedリングを揃える境界
+const LEGACY_ALIGN: usize = 4096;
+
+/// 続くディスクリプタがある
+const DESC_F_NEXT: u16 = 1;
+/// デバイスが書き込むバッファである
+const DESC_F_WRITE: u16 = 2;
+/// Usedリングを更新しても割り込みを起こさないよう、デバイスに頼む
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// 仮想キューの操作に失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// キューの大きさが0か、2の冪でない
+    InvalidSize(u16),
+    /// 空いているディスクリプタが足りない
+    Full,
+    /// 空のバッファの並びを渡した
+    EmptyChain,
+    /// DMAバッファを割り当てられなかった
+    Dma(DmaError),
+    /// デバイスが範囲外のディスクリプタや、終わらない並びを返した
+    InvalidUsed,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// デバイスに渡すバッファ1つ分
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub len: u32,
+    /// デバイスが書き込むバッファであれば`true`
+    pub device_writable: bool,
+}
+
+impl Buffer {
+    /// デバイスが読むだけのバッファ
+    pub const fn readable(address: PhysAddr, len: u32) -> Self {
+        Buffer {
+            address,
+            len,
+            device_writable: false,
+        }
+    }
+
+    /// デバイスが書き込むバッファ
+    pub const fn writable(address: PhysAddr, len: u32) -> Self {
+        Buffer {
+            address,
+            len,
+            device_writable: true,
+        }
+    }
+}
+
+/// デバイスが処理を終えたバッファの並び
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion {
+    /// `add()`が返した、並びの先頭のディスクリプタの番号
+    pub head: u16,
+    /// デバイスが書き込んだバイト数
+    pub len: u32,
+}
+
+/// 分割仮想キュー
+#[derive(Debug)]
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    /// 空いているディスクリプタの連結リストの先頭
+    free_head: u16,
+    free_count: u16,
+    /// 次に書き込むAvailableリングの位置
+    avail_index: u16,
+    /// 次に読み出すUsedリングの位置
+    last_used_index: u16,
+}
+
+impl VirtQueue {
+    /// `size`個のディスクリプタを持つ仮想キューを作る。`index`はデバイスの何番目のキューかを表す
+    pub fn new(index: u16, size: u16) -> Result<Self, QueueError> {
+        if size == 0 || !size.is_power_of_two() {
+            return Err(QueueError::InvalidSize(size));
+        }
+
+        let (avail_offset, used_offset, total) = Self::layout(size);
+        let memory = DmaBuffer::allocate(total).map_err(QueueError::Dma)?;
+
+        let queue = VirtQueue {
+            index,
+            size,
+            memory,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            last_used_index: 0,
+        };
+
+        // すべてのディスクリプタを空きの連結リストに繋ぐ
+        for i in 0..size {
+            unsafe {
+                write_volatile(
+                    queue.descriptor(i),
+                    Descriptor {
+                        address: 0,
+                        len: 0,
+                        flags: 0,
+                        next: (i + 1) % size,
+                    },
+                )
+            };
+        }
+
+        // 完了はポーリングで確かめるため、割り込みは要らない
+        unsafe { write_volatile(queue.avail_ring_header(), AVAIL_F_NO_INTERRUPT) };
+        Ok(queue)
+    }
+
+    /// ディスクリプタテーブルから見た、AvailableリングとUsedリングの位置と、全体の大きさ
+    fn layout(size: u16) -> (usize, usize, usize) {
+        let size = size as usize;
+        let avail_offset = size_of::<Descriptor>() * size;
+        let avail_len = size_of::<u16>() * (3 + size);
+        let used_offset = (avail_offset + avail_len).next_multiple_of(LEGACY_ALIGN);
+        let used_len = size_of::<u16>() * 3 + size_of::<UsedElement>() * size;
+        (avail_offset, used_offset, used_offset + used_len)
+    }
+
+    /// デバイスの何番目のキューか
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// 空いているディスクリプタの数
+    pub fn available_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    /// ディスクリプタテーブルの物理アドレス。レガシーなインタフェースでは仮想キュー全体の先頭になる
+    pub fn descriptor_table_address(&self) -> PhysAddr {
+        self.memory.physical_address()
+    }
+
+    /// Availableリングの物理アドレス
+    pub fn avail_ring_address(&self) -> PhysAddr {
+        self.memory.physical_address() + self.avail_offset as u64
+    }
+
+    /// Usedリングの物理アドレス
+    pub fn used_ring_address(&self) -> PhysAddr {
+        self.memory.physical_address() + self.used_offset as u64
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { (self.memory.as_mut_ptr() as *mut Descriptor).add(index as usize) }
+    }
+
+    /// Availableリングの先頭（flags, idxの順に並ぶ）
+    fn avail_ring_header(&self) -> *mut u16 {
+        unsafe { self.memory.as_mut_ptr().add(self.avail_offset) as *mut u16 }
+    }
+
+    /// Usedリングの先頭（flags, idxの順に並ぶ）
+    fn used_ring_header(&self) -> *mut u16 {
+        unsafe { self.memory.as_mut_ptr().add(self.used_offset) as *mut u16 }
+    }
+
+    /// バッファの並びをデバイスに渡し、先頭のディスクリプタの番号を返す
+    ///
+    /// デバイスに知らせるには、この後トランスポートの`notify()`を呼ぶ
+    ///
+    /// ## Safety
+    /// 呼び出し元は、各バッファが`Completion`として返されるまで有効であり、
+    /// デバイスが書き込むバッファを他から読み書きしないことを保証しなければならない
+    pub unsafe fn add(&mut self, buffers: &[Buffer]) -> Result<u16, QueueError> {
+        if buffers.is_empty() {
+            return Err(QueueError::EmptyChain);
+        }
+        if buffers.len() > self.free_count as usize {
+            return Err(QueueError::Full);
+        }
+
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(current);
+            let next = read_volatile(addr_of_mut!((*descriptor).next));
+
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            write_volatile(
+                descriptor,
+                Descriptor {
+                    address: buffer.address.as_u64(),
+                    len: buffer.len,
+                    flags,
+                    next,
+                },
+            );
+
+            if i + 1 < buffers.len() {
+                current = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        // Availableリングに先頭を書き込んでから、インデックスを進める
+        let ring = self.avail_ring_header().add(2);
+        write_volatile(ring.add((self.avail_index % self.size) as usize), head);
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        write_volatile(self.avail_ring_header().add(1), self.avail_index);
+        fence(Ordering::SeqCst);
+
+        Ok(head)
+    }
+
+    /// デバイスが処理を終えたバッファの並びがあれば取り出し、そのディスクリプタを空きに戻す
+    ///
+    /// デバイスが返した並びが壊れていれば、ディスクリプタには触れずに`QueueError::InvalidUsed`を返す
+    pub fn pop_used(&mut self) -> Result<Option<Completion>, QueueError> {
+        fence(Ordering::SeqCst);
+        let used_index = unsafe { read_volatile(self.used_ring_header().add(1)) };
+        if used_index == self.last_used_index {
+            return Ok(None);
+        }
+
+        let element = unsafe {
+            let ring = self.used_ring_header().add(2) as *mut UsedElement;
+            read_volatile(ring.add((self.last_used_index % self.size) as usize))
+        };
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+
+        if element.id >= self.size as u32 {
+            return Err(QueueError::InvalidUsed);
+        }
+        let head = element.id as u16;
+        self.free_chain(head)?;
+        Ok(Some(Completion {
+            head,
+            len: element.len,
+        }))
+    }
+
+    /// `head`から始まるディスクリプタの並びを、空きの連結リストの先頭に戻す
+    fn free_chain(&mut self, head: u16) -> Result<(), QueueError> {
+        // 並びは最大でも`size`個なので、それより長ければ循環している
+        let mut current = head;
+        let mut count = 1;
+        loop {
+            let descriptor = unsafe { read_volatile(self.descriptor(current)) };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            if descriptor.next >= self.size || count >= self.size {
+                return Err(QueueError::InvalidUsed);
+            }
+            current = descriptor.next;
+            count += 1;
+        }
+        // 空きのディスクリプタを返された場合は、空きの数が`size`を超える
+        if self.free_count + count > self.size {
+            return Err(QueueError::InvalidUsed);
+        }
+
+        unsafe {
+            write_volatile(
+                addr_of_mut!((*self.descriptor(current)).next),
+                self.free_head,
+            )
+        };
+        self.free_head = head;
+        self.free_count += count;
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+//! virtio-pciのトランスポート
+//!
+//! モダンなデバイスは、ベンダー固有のPCIケーパビリティで示されたBAR内の各構造体（共通設定・通知・ISR・デバイス固有設定）を
+//! メモリマップして操作する。レガシーなデバイスは、BAR0のI/Oポートに並んだレジスタを操作する
+
+use common_lib::pci::{bar::Bar, capability::id, PciDevice};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use super::{feature, queue::VirtQueue, status, VirtioError};
+use crate::memory::mmio::{self, CacheType, IoMem};
+
+/// virtio-pciのケーパビリティが示す構造体の種類
+mod cfg_type {
+    pub const COMMON: u8 = 1;
+    pub const NOTIFY: u8 = 2;
+    pub const ISR: u8 = 3;
+    pub const DEVICE: u8 = 4;
+}
+
+/// 共通設定構造体のレジスタのオフセット
+mod common {
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    pub const DEVICE_FEATURE: usize = 0x04;
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    pub const DRIVER_FEATURE: usize = 0x0c;
+    pub const DEVICE_STATUS: usize = 0x14;
+    pub const CONFIG_GENERATION: usize = 0x15;
+    pub const QUEUE_SELECT: usize = 0x16;
+    pub const QUEUE_SIZE: usize = 0x18;
+    pub const QUEUE_MSIX_VECTOR: usize = 0x1a;
+    pub const QUEUE_ENABLE: usize = 0x1c;
+    pub const QUEUE_NOTIFY_OFF: usize = 0x1e;
+    pub const QUEUE_DESC: usize = 0x20;
+    pub const QUEUE_DRIVER: usize = 0x28;
+    pub const QUEUE_DEVICE: usize = 0x30;
+}
+
+/// レガシーなデバイスのI/Oポートのオフセット
+mod legacy {
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const GUEST_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0c;
+    pub const QUEUE_SELECT: u16 = 0x0e;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+    pub const ISR_STATUS: u16 = 0x13;
+    /// MSI-Xを無効にしている場合の、デバイス固有設定の先頭
+    pub const DEVICE_CONFIG: u16 = 0x14;
+    /// 仮想キューの物理アドレスを書き込む単位
+    pub const QUEUE_ADDRESS_SHIFT: u32 = 12;
+}
+
+/// MSI-Xのベクタを割り当てないことを表す値
+const NO_VECTOR: u16 = 0xffff;
+
+/// virtio 1.0以降のモダンなトランスポート
+#[derive(Debug)]
+pub struct ModernTransport {
+    common: IoMem,
+    notify: IoMem,
+    notify_off_multiplier: u32,
+    isr: IoMem,
+    device: Option<IoMem>,
+}
+
+/// virtio 0.9.5のレガシーなトランスポート
+#[derive(Debug)]
+pub struct LegacyTransport {
+    base: u16,
+}
+
+/// virtioデバイスとのやり取りの方法
+#[derive(Debug)]
+pub enum Transport {
+    Modern(ModernTransport),
+    Legacy(LegacyTransport),
+}
+
+impl Transport {
+    /// デバイスに合ったトランスポートを作る
+    ///
+    /// ベンダー固有のケーパビリティがあればモダンなトランスポートを、無くBAR0がI/O空間であればレガシーなトランスポートを使う
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        if device
+            .find_capabilities(id::VENDOR_SPECIFIC)
+            .any(|cap| device.read_config_u8(cap.offset as u16 + 3) == cfg_type::COMMON)
+        {
+            device.enable_memory_space();
+            return ModernTransport::new(device).map(Transport::Modern);
+        }
+
+        match device.bar(0) {
+            Some(Bar::Io { port, .. }) => {
+                device.enable_io_space();
+                Ok(Transport::Legacy(LegacyTransport { base: port as u16 }))
+            }
+            _ => Err(VirtioError::MissingCapability("virtio-pci capabilities")),
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Transport::Legacy(_))
+    }
+
+    pub fn status(&self) -> u8 {
+        match self {
+            Transport::Modern(t) => t.common.read::<u8>(common::DEVICE_STATUS),
+            Transport::Legacy(t) => unsafe { t.port::<u8>(legacy::DEVICE_STATUS).read() },
+        }
+    }
+
+    pub fn set_status(&self, value: u8) {
+        match self {
+            Transport::Modern(t) => t.common.write::<u8>(common::DEVICE_STATUS, value),
+            Transport::Legacy(t) => unsafe { t.port::<u8>(legacy::DEVICE_STATUS).write(value) },
+        }
+    }
+
+    /// デバイスステータスにビットを加える
+    pub fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    /// デバイスをリセットする。ステータスが0に戻るまで待つ
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// デバイスが対応している機能ビット
+    pub fn device_features(&self) -> u64 {
+        match self {
+            Transport::Modern(t) => {
+                t.common.write::<u32>(common::DEVICE_FEATURE_SELECT, 0);
+                let low = t.common.read::<u32>(common::DEVICE_FEATURE) as u64;
+                t.common.write::<u32>(common::DEVICE_FEATURE_SELECT, 1);
+                let high = t.common.read::<u32>(common::DEVICE_FEATURE) as u64;
+                high << 32 | low
+            }
+            // レガシーなデバイスは下位32ビットしか持たない
+            Transport::Legacy(t) => unsafe { t.port::<u32>(legacy::DEVICE_FEATURES).read() as u64 },
+        }
+    }
+
+    /// ドライバが使う機能ビットを書き込む
+    pub fn set_driver_features(&self, features: u64) {
+        match self {
+            Transport::Modern(t) => {
+                t.common.write::<u32>(common::DRIVER_FEATURE_SELECT, 0);
+                t.common
+                    .write::<u32>(common::DRIVER_FEATURE, features as u32);
+                t.common.write::<u32>(common::DRIVER_FEATURE_SELECT, 1);
+                t.common
+                    .write::<u32>(common::DRIVER_FEATURE, (features >> 32) as u32);
+            }
+            Transport::Legacy(t) => unsafe {
+                t.port::<u32>(legacy::GUEST_FEATURES).write(features as u32)
+            },
+        }
+    }
+
+    /// 初期化手順の前半を行う。デバイスをリセットし、`required`を含み`optional`のうち対応するものを加えた機能を選ぶ
+    ///
+    /// モダンなデバイスではVERSION_1を必須とする。選んだ機能ビットを返す
+    pub fn negotiate(&self, required: u64, optional: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(status::ACKNOWLEDGE | status::DRIVER);
+
+        let required = match self {
+            Transport::Modern(_) => required | feature::VERSION_1,
+            Transport::Legacy(_) => required,
+        };
+        let offered = self.device_features();
+        if offered & required != required {
+            self.add_status(status::FAILED);
+            return Err(VirtioError::FeatureNotSupported(required & !offered));
+        }
+
+        let features = required | (offered & optional);
+        self.set_driver_features(features);
+
+        // レガシーなデバイスにはFEATURES_OKの手順が無い
+        if let Transport::Modern(_) = self {
+            self.add_status(status::FEATURES_OK);
+            if self.status() & status::FEATURES_OK == 0 {
+                self.add_status(status::FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+
+        Ok(features)
+    }
+
+    /// `index`番目の仮想キューの、デバイスが扱える最大の大きさ。キューが無ければ0を返す
+    pub fn max_queue_size(&self, index: u16) -> u16 {
+        match self {
+            Transport::Modern(t) => {
+                t.common.write::<u16>(common::QUEUE_SELECT, index);
+                t.common.read::<u16>(common::QUEUE_SIZE)
+            }
+            Transport::Legacy(t) => unsafe {
+                t.port::<u16>(legacy::QUEUE_SELECT).write(index);
+                t.port::<u16>(legacy::QUEUE_SIZE).read()
+            },
+        }
+    }
+
+    /// `index`番目の仮想キューを、`limit`個以下のディスクリプタで作って有効にする
+    ///
+    /// レガシーなデバイスはキューの大きさを変えられないため、`limit`は無視する
+    pub fn setup_queue(&self, index: u16, limit: u16) -> Result<VirtQueue, VirtioError> {
+        let max_size = self.max_queue_size(index);
+        if max_size == 0 {
+            return Err(VirtioError::QueueUnavailable(index));
+        }
+
+        match self {
+            Transport::Modern(t) => {
+                // 大きさは2の冪でなければならない
+                let size = max_size.min(limit);
+                let size = 1 << (u16::BITS - 1 - size.leading_zeros());
+                let queue = VirtQueue::new(index, size)?;
+
+                t.common.write::<u16>(common::QUEUE_SIZE, size);
+                t.common.write::<u16>(common::QUEUE_MSIX_VECTOR, NO_VECTOR);
+                t.common.write::<u64>(
+                    common::QUEUE_DESC,
+                    queue.descriptor_table_address().as_u64(),
+                );
+                t.common
+                    .write::<u64>(common::QUEUE_DRIVER, queue.avail_ring_address().as_u64());
+                t.common
+                    .write::<u64>(common::QUEUE_DEVICE, queue.used_ring_address().as_u64());
+                t.common.write::<u16>(common::QUEUE_ENABLE, 1);
+                Ok(queue)
+            }
+            Transport::Legacy(t) => {
+                let queue = VirtQueue::new(index, max_size)?;
+                let pfn = queue.descriptor_table_address().as_u64() >> legacy::QUEUE_ADDRESS_SHIFT;
+                unsafe { t.port::<u32>(legacy::QUEUE_ADDRESS).write(pfn as u32) };
+                Ok(queue)
+            }
+        }
+    }
+
+    /// デバイスに`queue`へバッファを追加したことを知らせる
+    pub fn notify(&self, queue: &VirtQueue) {
+        match self {
+            Transport::Modern(t) => {
+                t.common.write::<u16>(common::QUEUE_SELECT, queue.index());
+                let offset = t.common.read::<u16>(common::QUEUE_NOTIFY_OFF) as usize;
+                t.notify
+                    .write::<u16>(offset * t.notify_off_multiplier as usize, queue.index());
+            }
+            Transport::Legacy(t) => unsafe {
+                t.port::<u16>(legacy::QUEUE_NOTIFY).write(queue.index())
+            },
+        }
+    }
+
+    /// ISRステータスを読み出す。読み出すとデバイスの割り込みが解除される
+    pub fn read_isr(&self) -> u8 {
+        match self {
+            Transport::Modern(t) => t.isr.read::<u8>(0),
+            Transport::Legacy(t) => unsafe { t.port::<u8>(legacy::ISR_STATUS).read() },
+        }
+    }
+
+    /// デバイス固有設定の`offset`バイト目から4バイトを読む
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        match self {
+            Transport::Modern(t) => t.device.as_ref().map_or(0, |d| d.read::<u32>(offset)),
+            Transport::Legacy(t) => unsafe {
+                t.port::<u32>(legacy::DEVICE_CONFIG + offset as u16).read()
+            },
+        }
+    }
+
+    /// デバイス固有設定の`offset`バイト目から8バイトを読む
+    ///
+    /// 読んでいる途中でデバイスが設定を変えた場合は読み直す
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let low = self.read_config_u32(offset) as u64;
+            let high = self.read_config_u32(offset + 4) as u64;
+            if generation == self.config_generation() {
+                return high << 32 | low;
+            }
+        }
+    }
+
+    /// デバイス固有設定の世代。レガシーなデバイスでは常に0を返す
+    fn config_generation(&self) -> u8 {
+        match self {
+            Transport::Modern(t) => t.common.read::<u8>(common::CONFIG_GENERATION),
+            Transport::Legacy(_) => 0,
+        }
+    }
+}
+
+impl ModernTransport {
+    fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_config = None;
+
+        for capability in device.find_capabilities(id::VENDOR_SPECIFIC) {
+            let offset = capability.offset as u16;
+            let kind = device.read_config_u8(offset + 3);
+
+            // 同じ種類の構造体が複数ある場合は、最初のものを使う
+            let slot = match kind {
+                cfg_type::COMMON => &mut common,
+                cfg_type::NOTIFY => &mut notify,
+                cfg_type::ISR => &mut isr,
+                cfg_type::DEVICE => &mut device_config,
+                _ => continue,
+            };
+            if slot.is_some() {
+                continue;
+            }
+
+            let bar_index = device.read_config_u8(offset + 4) as usize;
+            let region_offset = device.read_config(offset + 8) as u64;
+            let region_len = device.read_config(offset + 12) as usize;
+            let Some(Bar::Memory { address, .. }) = device.bar(bar_index) else {
+                continue;
+            };
+            if region_len == 0 {
+                continue;
+            }
+
+            let memory = unsafe {
+                mmio::ioremap(
+                    "virtio-pci",
+                    PhysAddr::new(address + region_offset),
+                    region_len,
+                    CacheType::Uncached,
+                )?
+            };
+            let multiplier = if kind == cfg_type::NOTIFY {
+                device.read_config(offset + 16)
+            } else {
+                0
+            };
+            *slot = Some((memory, multiplier));
+        }
+
+        let (common, _) = common.ok_or(VirtioError::MissingCapability("common configuration"))?;
+        let (notify, notify_off_multiplier) =
+            notify.ok_or(VirtioError::MissingCapability("notification structure"))?;
+        let (isr, _) = isr.ok_or(VirtioError::MissingCapability("ISR status"))?;
+
+        Ok(ModernTransport {
+            common,
+            notify,
+            notify_off_multiplier,
+            isr,
+            device: device_config.map(|(memory, _)| memory),
+        })
+    }
+}
+
+impl LegacyTransport {
+    fn port<T>(&self, offset: u16) -> Port<T> {
+        Port::new(self.base + offset)
+    }
+}
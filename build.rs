@@ -1,15 +1,35 @@
 use bootloader::DiskImageBuilder;
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+/// virtio-blkの動作確認に使うテスト用ディスクの大きさ
+const TEST_DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// 初期RAMディスクに詰めるディレクトリ
+const INITRD_DIR: &str = "initrd";
+
+/// tarアーカイブのヘッダやデータを区切る単位
+const TAR_BLOCK_SIZE: usize = 512;
 
 fn main() {
     // set by cargo for the kernel artifact dependency
     // 実行ファイル名を変えた際は`CARGO_BIN_FILE_KERNEL`の`KERNEL`の部分を変更すること
     // see https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel_path = env::var("CARGO_BIN_FILE_KERNEL").unwrap();
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
+    let mut disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
 
     // specify output paths
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    // pack the initrd directory into a tar archive and load it as the ramdisk
+    let initrd_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join(INITRD_DIR);
+    let initrd_path = out_dir.join("emer-initrd.tar");
+    fs::write(&initrd_path, pack_tar(&initrd_dir)).unwrap();
+    disk_builder.set_ramdisk(initrd_path);
+    println!("cargo:rerun-if-changed={}", INITRD_DIR);
     let uefi_path = out_dir.join("emer-uefi.img");
     let bios_path = out_dir.join("emer-bios.img");
 
@@ -17,7 +37,104 @@ fn main() {
     disk_builder.create_uefi_image(&uefi_path).unwrap();
     disk_builder.create_bios_image(&bios_path).unwrap();
 
+    // create an empty test disk for virtio-blk, keeping its contents across rebuilds
+    let test_disk_path = out_dir.join("emer-test-disk.img");
+    let test_disk = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&test_disk_path)
+        .unwrap();
+    if test_disk.metadata().unwrap().len() < TEST_DISK_SIZE {
+        test_disk.set_len(TEST_DISK_SIZE).unwrap();
+    }
+
     // pass the disk image paths via environment variables
     println!("cargo:rustc-env=UEFI_IMAGE={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_IMAGE={}", bios_path.display());
+    println!(
+        "cargo:rustc-env=TEST_DISK_IMAGE={}",
+        test_disk_path.display()
+    );
+}
+
+/// `dir`の中身を、`dir`を根とするustar形式のtarアーカイブにする
+///
+/// `.`で始まる名前の項目は含めない。項目は名前順に並べ、更新日時などは0にして、同じ内容からは同じアーカイブを作る
+fn pack_tar(dir: &Path) -> Vec<u8> {
+    let mut archive = Vec::new();
+    append_dir(&mut archive, dir, "");
+    // 終わりを示す、0で埋められた2ブロック
+    archive.resize(archive.len() + TAR_BLOCK_SIZE * 2, 0);
+    archive
+}
+
+fn append_dir(archive: &mut Vec<u8>, dir: &Path, prefix: &str) {
+    let mut entries = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap())
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .unwrap_or_else(|name| panic!("{:?} is not a valid UTF-8 file name", name));
+        let path = format!("{}{}", prefix, name);
+        let file_type = entry.file_type().unwrap();
+
+        if file_type.is_symlink() {
+            let target = fs::read_link(entry.path()).unwrap();
+            let target = target.to_str().expect("symlink target is not valid UTF-8");
+            append_header(archive, &path, b'2', 0, target);
+        } else if file_type.is_dir() {
+            append_header(archive, &format!("{}/", path), b'5', 0, "");
+            append_dir(archive, &entry.path(), &format!("{}/", path));
+        } else {
+            let data = fs::read(entry.path()).unwrap();
+            append_header(archive, &path, b'0', data.len(), "");
+            archive.extend_from_slice(&data);
+            archive.resize(archive.len().next_multiple_of(TAR_BLOCK_SIZE), 0);
+        }
+    }
+}
+
+fn append_header(archive: &mut Vec<u8>, path: &str, type_flag: u8, size: usize, link: &str) {
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+
+    // 100バイトに収まらない名前は、`/`の位置で`prefix`と`name`に分ける
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path[..path.len() - 1]
+            .char_indices()
+            .filter(|&(i, c)| c == '/' && i <= 155 && path.len() - i - 1 <= 100)
+            .map(|(i, _)| i)
+            .next()
+            .unwrap_or_else(|| panic!("{} is too long for a tar archive", path));
+        (&path[..split], &path[split + 1..])
+    };
+    assert!(link.len() <= 100, "symlink target {} is too long", link);
+
+    let mode = if type_flag == b'0' { 0o644 } else { 0o755 };
+    header[0..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(format!("{:07o}\0", mode).as_bytes());
+    header[108..116].copy_from_slice(b"0000000\0"); // uid
+    header[116..124].copy_from_slice(b"0000000\0"); // gid
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0"); // mtime
+    header[156] = type_flag;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // チェックサムは、チェックサム欄を空白とみなして全バイトを足したもの
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    archive.extend_from_slice(&header);
 }
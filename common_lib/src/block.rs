@@ -0,0 +1,157 @@
+//! ブロックデバイスを扱うモジュール
+//!
+//! ディスクなど、一定の大きさのセクタ単位で読み書きするデバイスを`BlockDevice`トレイトで抽象化する。
+//! ドライバは初期化したデバイスを`register()`で登録し、ファイルシステムなどは名前で引いて使う。
+//! ディスク上のパーティションは`partition::scan()`で見つけ、それぞれを独立したブロックデバイスとして扱える
+
+pub mod cache;
+pub mod partition;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+use spin::Mutex;
+
+/// ブロックデバイスの操作が失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// デバイスの範囲外のセクタを指定した
+    OutOfRange { sector: u64, count: u64 },
+    /// バッファの長さがセクタの大きさの倍数でない
+    UnalignedBuffer { len: usize },
+    /// 読み込み専用のデバイスに書き込もうとした
+    ReadOnly,
+    /// デバイスが対応していない操作をした
+    Unsupported,
+    /// デバイスが入出力エラーを返した
+    Io,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange { sector, count } => write!(
+                f,
+                "sectors {}..{} are out of range",
+                sector,
+                sector.saturating_add(*count)
+            ),
+            BlockError::UnalignedBuffer { len } => write!(
+                f,
+                "buffer length {} is not a multiple of the sector size",
+                len
+            ),
+            BlockError::ReadOnly => write!(f, "device is read-only"),
+            BlockError::Unsupported => write!(f, "operation is not supported"),
+            BlockError::Io => write!(f, "I/O error"),
+        }
+    }
+}
+
+/// セクタ単位で読み書きするデバイス
+pub trait BlockDevice: Send + Sync {
+    /// `vda`のような、デバイスを識別する名前
+    fn name(&self) -> &str;
+
+    /// 1セクタのバイト数
+    fn sector_size(&self) -> usize;
+
+    /// デバイス全体のセクタ数
+    fn sector_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// `sector`から`buffer`の長さ分のセクタを読み込む
+    ///
+    /// `buffer`の長さはセクタの大きさの倍数でなければならない
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// `sector`から`buffer`の長さ分のセクタに書き込む
+    ///
+    /// `buffer`の長さはセクタの大きさの倍数でなければならない
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// デバイスのキャッシュに溜まった書き込みを、記録媒体に反映させる
+    fn flush(&self) -> Result<(), BlockError>;
+
+    /// パーティションであれば、それが置かれたディスクを返す
+    fn parent(&self) -> Option<&Arc<dyn BlockDevice>> {
+        None
+    }
+
+    /// デバイス全体のバイト数
+    fn size(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+}
+
+/// `read_sectors()`や`write_sectors()`の引数を確かめ、読み書きするセクタ数を返す
+///
+/// ドライバの実装で使う
+pub fn check_request<D: BlockDevice + ?Sized>(
+    device: &D,
+    sector: u64,
+    len: usize,
+) -> Result<u64, BlockError> {
+    let sector_size = device.sector_size();
+    if !len.is_multiple_of(sector_size) {
+        return Err(BlockError::UnalignedBuffer { len });
+    }
+
+    let count = (len / sector_size) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange { sector, count }),
+    }
+}
+
+/// `sector`から1セクタを読み込み、新しいバッファとして返す
+pub fn read_sector<D: BlockDevice + ?Sized>(
+    device: &D,
+    sector: u64,
+) -> Result<Vec<u8>, BlockError> {
+    let mut buffer = vec![0; device.sector_size()];
+    device.read_sectors(sector, &mut buffer)?;
+    Ok(buffer)
+}
+
+/// 登録済みのブロックデバイス
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// ブロックデバイスを登録する
+///
+/// ## Panic
+/// 同じ名前のデバイスがすでに登録されている場合はパニックを起こす
+pub fn register(device: Arc<dyn BlockDevice>) {
+    let mut devices = DEVICES.lock();
+    assert!(
+        devices.iter().all(|d| d.name() != device.name()),
+        "Block device {} is already registered",
+        device.name()
+    );
+
+    log::info!(
+        "block: {} registered ({} sectors of {} bytes{})",
+        device.name(),
+        device.sector_count(),
+        device.sector_size(),
+        if device.is_read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    devices.push(device);
+}
+
+/// 名前が`name`のブロックデバイスを返す
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|d| d.name() == name).cloned()
+}
+
+/// 登録済みのすべてのブロックデバイスを、登録した順に返す
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
@@ -0,0 +1,288 @@
+//! セクタ単位のバッファキャッシュ
+//!
+//! 読み書きしたセクタを一定数まで保持し、同じセクタへの読み込みをデバイスに出さずに済ませる。
+//! 書き込みはキャッシュに溜めておき（ライトバック）、追い出すときか`flush()`のときにデバイスへ書き出す。
+//! 保持する数を超えた場合は、最も長い間使われていないセクタから追い出す（LRU）
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+use spin::Mutex;
+
+use super::{check_request, BlockDevice, BlockError};
+
+/// キャッシュしたセクタ1つ分
+struct Entry {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: BTreeMap<u64, Entry>,
+    /// 最後に使われた時刻からセクタ番号を引くための索引
+    recency: BTreeMap<u64, u64>,
+    /// 使われるたびに増える論理時刻
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// ブロックデバイスの前に置くバッファキャッシュ。それ自体も`BlockDevice`として使える
+///
+/// 同じデバイスをキャッシュを通さずに読み書きすると、内容が食い違うことがある
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl BufferCache {
+    /// `device`の前に、最大`capacity`セクタを保持するキャッシュを置く
+    ///
+    /// ## Panic
+    /// `capacity`が0の場合はパニックを起こす
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        assert!(capacity > 0, "Buffer cache capacity must not be zero");
+        BufferCache {
+            device,
+            capacity,
+            state: Mutex::new(CacheState {
+                entries: BTreeMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// キャッシュの下にあるデバイス
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    /// 保持しているセクタの数を返す
+    pub fn len(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    /// 何も保持していなければ`true`を返す
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().entries.is_empty()
+    }
+
+    /// キャッシュに当たったセクタ数と外れたセクタ数を返す
+    pub fn hit_stats(&self) -> (u64, u64) {
+        let state = self.state.lock();
+        (state.hits, state.misses)
+    }
+
+    /// 書き出していないセクタをデバイスに書き出し、すべてのセクタを捨てる
+    pub fn invalidate(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        self.write_back(&mut state)?;
+        state.entries.clear();
+        state.recency.clear();
+        Ok(())
+    }
+
+    /// セクタを使ったことを記録する
+    fn touch(state: &mut CacheState, sector: u64) {
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(entry) = state.entries.get_mut(&sector) {
+            state.recency.remove(&entry.last_used);
+            entry.last_used = clock;
+            state.recency.insert(clock, sector);
+        }
+    }
+
+    /// セクタをキャッシュに加え、必要なら古いセクタを追い出す
+    fn insert(
+        &self,
+        state: &mut CacheState,
+        sector: u64,
+        data: Vec<u8>,
+        dirty: bool,
+    ) -> Result<(), BlockError> {
+        while state.entries.len() >= self.capacity {
+            self.evict_oldest(state)?;
+        }
+
+        state.clock += 1;
+        let clock = state.clock;
+        state.entries.insert(
+            sector,
+            Entry {
+                data,
+                dirty,
+                last_used: clock,
+            },
+        );
+        state.recency.insert(clock, sector);
+        Ok(())
+    }
+
+    /// 最も長い間使われていないセクタを追い出す。書き出していなければ先に書き出す
+    fn evict_oldest(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let Some((&clock, &sector)) = state.recency.first_key_value() else {
+            return Ok(());
+        };
+
+        if let Some(entry) = state.entries.get(&sector) {
+            if entry.dirty {
+                self.device.write_sectors(sector, &entry.data)?;
+            }
+        }
+        state.recency.remove(&clock);
+        state.entries.remove(&sector);
+        Ok(())
+    }
+
+    /// 書き出していないセクタを、連続したものはまとめてデバイスに書き出す
+    ///
+    /// 書き出しに失敗したセクタは書き出していないままにしておき、次の書き出しで再び試す
+    fn write_back(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let mut run_start = 0;
+        let mut run_sectors = Vec::new();
+        let mut run = Vec::new();
+
+        let dirty: Vec<u64> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&sector, _)| sector)
+            .collect();
+        for sector in dirty {
+            if !run_sectors.is_empty() && run_start + run_sectors.len() as u64 != sector {
+                self.write_run(state, run_start, &run_sectors, &run)?;
+                run_sectors.clear();
+                run.clear();
+            }
+            if run_sectors.is_empty() {
+                run_start = sector;
+            }
+            run_sectors.push(sector);
+            run.extend_from_slice(&state.entries[&sector].data);
+        }
+
+        if !run_sectors.is_empty() {
+            self.write_run(state, run_start, &run_sectors, &run)?;
+        }
+        Ok(())
+    }
+
+    /// 連続したセクタをまとめて書き出し、書き出せたら書き出し済みにする
+    fn write_run(
+        &self,
+        state: &mut CacheState,
+        start: u64,
+        sectors: &[u64],
+        data: &[u8],
+    ) -> Result<(), BlockError> {
+        self.device.write_sectors(start, data)?;
+        for sector in sectors {
+            if let Some(entry) = state.entries.get_mut(sector) {
+                entry.dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_request(self, sector, buffer.len())?;
+        let sector_size = self.sector_size();
+        let mut state = self.state.lock();
+
+        let mut index = 0;
+        while index < count {
+            let current = sector + index;
+            let offset = index as usize * sector_size;
+
+            if let Some(entry) = state.entries.get(&current) {
+                buffer[offset..offset + sector_size].copy_from_slice(&entry.data);
+                state.hits += 1;
+                Self::touch(&mut state, current);
+                index += 1;
+                continue;
+            }
+
+            // キャッシュに無いセクタが続く間は、まとめて読み込む
+            let missing = (index..count)
+                .take_while(|&i| !state.entries.contains_key(&(sector + i)))
+                .count();
+            let end = offset + missing * sector_size;
+            self.device
+                .read_sectors(current, &mut buffer[offset..end])?;
+            state.misses += missing as u64;
+
+            for (i, data) in buffer[offset..end].chunks(sector_size).enumerate() {
+                self.insert(&mut state, current + i as u64, data.to_vec(), false)?;
+            }
+            index += missing as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        check_request(self, sector, buffer.len())?;
+        let mut state = self.state.lock();
+
+        for (i, data) in buffer.chunks(self.sector_size()).enumerate() {
+            let current = sector + i as u64;
+            match state.entries.get_mut(&current) {
+                Some(entry) => {
+                    entry.data.copy_from_slice(data);
+                    entry.dirty = true;
+                    Self::touch(&mut state, current);
+                }
+                None => self.insert(&mut state, current, data.to_vec(), true)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.write_back(&mut self.state.lock())?;
+        self.device.flush()
+    }
+
+    fn parent(&self) -> Option<&Arc<dyn BlockDevice>> {
+        self.device.parent()
+    }
+}
+
+impl Drop for BufferCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::warn!(
+                "{}: failed to write back cached sectors: {}",
+                self.name(),
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! パーティションテーブルの解析
+//!
+//! ディスクの先頭のMBRを読み、保護MBRであればGPTを、そうでなければMBRのパーティションを列挙する。
+//! 見つかったパーティションは、それぞれディスクの一部を切り出した`BlockDevice`として使える
+
+pub mod gpt;
+pub mod mbr;
+#[cfg(test)]
+mod test_disk;
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::fmt;
+
+use super::{check_request, BlockDevice, BlockError};
+
+/// GPTで使うGUID。ディスク上の並び（先頭3つのフィールドがリトルエンディアン）のまま持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const ZERO: Guid = Guid([0; 16]);
+
+    /// EFIシステムパーティション (C12A7328-F81F-11D2-BA4B-00A0C93EC93B)
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(
+        0xc12a7328,
+        0xf81f,
+        0x11d2,
+        [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+    );
+
+    /// Microsoftの基本データパーティション (EBD0A0A2-B9E5-4433-87C0-68B6B72699C7)。FATのパーティションにも使われる
+    pub const MICROSOFT_BASIC_DATA: Guid = Guid::from_fields(
+        0xebd0a0a2,
+        0xb9e5,
+        0x4433,
+        [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7],
+    );
+
+    /// 表記どおりのフィールドからGUIDを作る
+    pub const fn from_fields(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3],
+            data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Guid::ZERO
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// パーティションテーブルの種類ごとの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// MBRのパーティション。論理パーティションも含む
+    Mbr { partition_type: u8, bootable: bool },
+    /// GPTのパーティション
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        attributes: u64,
+        name: String,
+    },
+}
+
+impl PartitionKind {
+    /// EFIシステムパーティションであれば`true`を返す
+    pub fn is_efi_system(&self) -> bool {
+        match self {
+            PartitionKind::Mbr { partition_type, .. } => *partition_type == mbr::TYPE_EFI_SYSTEM,
+            PartitionKind::Gpt { type_guid, .. } => *type_guid == Guid::EFI_SYSTEM,
+        }
+    }
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionKind::Mbr {
+                partition_type,
+                bootable,
+            } => write!(
+                f,
+                "MBR type {:#04x}{}",
+                partition_type,
+                if *bootable { ", bootable" } else { "" }
+            ),
+            PartitionKind::Gpt {
+                type_guid, name, ..
+            } => write!(f, "GPT type {} \"{}\"", type_guid, name),
+        }
+    }
+}
+
+/// パーティションテーブルの解析に失敗した理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionError {
+    /// ディスクを読み込めなかった
+    Block(BlockError),
+    /// GPTのヘッダかパーティションエントリが壊れている
+    InvalidGpt(&'static str),
+    /// パーティションがディスクの範囲外にある
+    OutOfDisk { number: u32 },
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionError::Block(e) => write!(f, "failed to read the disk: {}", e),
+            PartitionError::InvalidGpt(reason) => write!(f, "invalid GPT: {}", reason),
+            PartitionError::OutOfDisk { number } => {
+                write!(f, "partition {} extends beyond the disk", number)
+            }
+        }
+    }
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(e: BlockError) -> Self {
+        PartitionError::Block(e)
+    }
+}
+
+/// パーティションテーブルの項目。`scan()`の中で`Partition`になる
+#[derive(Debug, Clone)]
+pub struct PartitionEntry {
+    /// 1から始まるパーティションの番号
+    pub number: u32,
+    /// 先頭のセクタ
+    pub start: u64,
+    pub sector_count: u64,
+    pub kind: PartitionKind,
+}
+
+/// ディスクの一部を切り出したブロックデバイス
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    name: String,
+    number: u32,
+    start: u64,
+    sector_count: u64,
+    kind: PartitionKind,
+}
+
+impl Partition {
+    /// パーティションが置かれたディスク
+    pub fn disk(&self) -> &Arc<dyn BlockDevice> {
+        &self.disk
+    }
+
+    /// 1から始まるパーティションの番号
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// ディスク上の先頭のセクタ
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn kind(&self) -> &PartitionKind {
+        &self.kind
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        self.disk.read_sectors(self.start + sector, buffer)
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        self.disk.write_sectors(self.start + sector, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+
+    fn parent(&self) -> Option<&Arc<dyn BlockDevice>> {
+        Some(&self.disk)
+    }
+}
+
+/// `disk`のパーティションテーブルを解析し、パーティションを番号順に返す
+///
+/// パーティションテーブルが無ければ空を返す。
+/// パーティションの名前は、ディスクの名前に番号を付けたもの（`vda1`など）になる
+pub fn scan(disk: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, PartitionError> {
+    let mut entries = match mbr::read(disk.as_ref())? {
+        Some(table) if table.is_protective() => gpt::read(disk.as_ref())?,
+        Some(table) => table.into_entries(disk.as_ref())?,
+        None => Vec::new(),
+    };
+    entries.sort_by_key(|entry| entry.number);
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let end = entry.start.checked_add(entry.sector_count);
+            if end.is_none_or(|end| end > disk.sector_count()) {
+                return Err(PartitionError::OutOfDisk {
+                    number: entry.number,
+                });
+            }
+
+            Ok(Partition {
+                disk: disk.clone(),
+                name: format!("{}{}", disk.name(), entry.number),
+                number: entry.number,
+                start: entry.start,
+                sector_count: entry.sector_count,
+                kind: entry.kind,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,296 @@
+//! GPT (GUID Partition Table)
+//!
+//! LBA 1のヘッダと、ヘッダが指すパーティションエントリの配列を読む。
+//! ヘッダとエントリの配列はCRC32で検査し、主ヘッダが壊れていればディスク末尾の予備ヘッダを使う
+
+use alloc::{string::String, vec, vec::Vec};
+
+use super::{Guid, PartitionEntry, PartitionError, PartitionKind};
+use crate::block::{read_sector, BlockDevice};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// ヘッダの最小の大きさ
+const MIN_HEADER_SIZE: usize = 92;
+/// パーティションエントリの最小の大きさ
+const MIN_ENTRY_SIZE: usize = 128;
+/// 読むパーティションエントリの数の上限
+const MAX_ENTRIES: u32 = 1024;
+/// パーティション名の、UTF-16での最大の長さ
+const NAME_UNITS: usize = 36;
+
+/// GPTのヘッダ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GptHeader {
+    pub disk_guid: Guid,
+    pub current_lba: u64,
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub entries_lba: u64,
+    pub entry_count: u32,
+    pub entry_size: u32,
+    pub entries_crc32: u32,
+}
+
+impl GptHeader {
+    /// 1セクタ分のデータからヘッダを読み、署名とCRC32を検査する
+    pub fn parse(sector: &[u8]) -> Result<Self, PartitionError> {
+        if sector.len() < MIN_HEADER_SIZE || &sector[0..8] != SIGNATURE {
+            return Err(PartitionError::InvalidGpt("missing signature"));
+        }
+
+        let header_size = read_u32(sector, 12) as usize;
+        if !(MIN_HEADER_SIZE..=sector.len()).contains(&header_size) {
+            return Err(PartitionError::InvalidGpt("invalid header size"));
+        }
+
+        // CRC32はヘッダのCRC32欄を0とみなして計算する
+        let mut header = sector[..header_size].to_vec();
+        header[16..20].fill(0);
+        if crc32(&header) != read_u32(sector, 16) {
+            return Err(PartitionError::InvalidGpt("header checksum mismatch"));
+        }
+
+        let mut disk_guid = [0; 16];
+        disk_guid.copy_from_slice(&sector[56..72]);
+
+        let parsed = GptHeader {
+            disk_guid: Guid(disk_guid),
+            current_lba: read_u64(sector, 24),
+            backup_lba: read_u64(sector, 32),
+            first_usable_lba: read_u64(sector, 40),
+            last_usable_lba: read_u64(sector, 48),
+            entries_lba: read_u64(sector, 72),
+            entry_count: read_u32(sector, 80),
+            entry_size: read_u32(sector, 84),
+            entries_crc32: read_u32(sector, 88),
+        };
+
+        if (parsed.entry_size as usize) < MIN_ENTRY_SIZE || parsed.entry_size % 8 != 0 {
+            return Err(PartitionError::InvalidGpt("invalid partition entry size"));
+        }
+        if parsed.entry_count > MAX_ENTRIES {
+            return Err(PartitionError::InvalidGpt("too many partition entries"));
+        }
+        Ok(parsed)
+    }
+}
+
+/// `lba`にあるヘッダと、それが指すパーティションエントリの配列を読む
+fn read_table<D: BlockDevice + ?Sized>(
+    disk: &D,
+    lba: u64,
+) -> Result<(GptHeader, Vec<u8>), PartitionError> {
+    let header = GptHeader::parse(&read_sector(disk, lba)?)?;
+
+    let sector_size = disk.sector_size();
+    let table_len = header.entry_count as usize * header.entry_size as usize;
+    let mut table = vec![0; table_len.next_multiple_of(sector_size)];
+    let table_end = header
+        .entries_lba
+        .checked_add((table.len() / sector_size) as u64);
+    if table_end.is_none_or(|end| end > disk.sector_count()) {
+        return Err(PartitionError::InvalidGpt(
+            "partition entries are out of disk",
+        ));
+    }
+    disk.read_sectors(header.entries_lba, &mut table)?;
+    table.truncate(table_len);
+
+    if crc32(&table) != header.entries_crc32 {
+        return Err(PartitionError::InvalidGpt(
+            "partition entry checksum mismatch",
+        ));
+    }
+    Ok((header, table))
+}
+
+/// GPTを読み、使われているパーティションエントリを返す。番号はエントリの位置に1を足したものになる
+pub fn read<D: BlockDevice + ?Sized>(disk: &D) -> Result<Vec<PartitionEntry>, PartitionError> {
+    let (header, table) = match read_table(disk, 1) {
+        Ok(table) => table,
+        Err(primary_error) => {
+            let backup_lba = disk.sector_count().saturating_sub(1);
+            log::warn!(
+                "{}: primary GPT is unusable ({}), trying the backup",
+                disk.name(),
+                primary_error
+            );
+            read_table(disk, backup_lba).map_err(|_| primary_error)?
+        }
+    };
+
+    let mut partitions = Vec::new();
+    for (i, entry) in table.chunks_exact(header.entry_size as usize).enumerate() {
+        let mut type_guid = [0; 16];
+        type_guid.copy_from_slice(&entry[0..16]);
+        let type_guid = Guid(type_guid);
+        if type_guid.is_zero() {
+            continue;
+        }
+
+        let mut unique_guid = [0; 16];
+        unique_guid.copy_from_slice(&entry[16..32]);
+        let first_lba = read_u64(entry, 32);
+        let last_lba = read_u64(entry, 40);
+        if last_lba < first_lba {
+            return Err(PartitionError::InvalidGpt(
+                "partition ends before it starts",
+            ));
+        }
+
+        let units = (0..NAME_UNITS)
+            .map(|unit| u16::from_le_bytes([entry[56 + unit * 2], entry[57 + unit * 2]]))
+            .take_while(|&unit| unit != 0);
+        let name: String = char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        partitions.push(PartitionEntry {
+            number: i as u32 + 1,
+            start: first_lba,
+            sector_count: last_lba - first_lba + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: Guid(unique_guid),
+                attributes: read_u64(entry, 48),
+                name,
+            },
+        });
+    }
+
+    Ok(partitions)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// GPTで使うCRC32（IEEE 802.3, 反転多項式0xEDB88320）
+pub(super) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::block::{
+        partition::{
+            scan,
+            test_disk::{uefi_image, write_gpt, MemDisk, GPT_ENTRY_SECTORS},
+        },
+        BlockDevice,
+    };
+
+    const SECTORS: u64 = 4096;
+
+    fn names(partitions: &[PartitionEntry]) -> Vec<(u32, u64, String)> {
+        partitions
+            .iter()
+            .map(|entry| match &entry.kind {
+                PartitionKind::Gpt { name, .. } => (entry.number, entry.start, name.clone()),
+                PartitionKind::Mbr { .. } => panic!("not a GPT partition"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn protective_mbr_hands_off_to_gpt() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(uefi_image(SECTORS));
+        let partitions = scan(&disk).unwrap();
+
+        assert_eq!(partitions.len(), 1);
+        let boot = &partitions[0];
+        assert_eq!(boot.name(), "mem1");
+        assert_eq!(boot.start(), 2 + GPT_ENTRY_SECTORS);
+        assert_eq!(boot.sector_count(), SECTORS - 3 - 2 * GPT_ENTRY_SECTORS);
+        assert!(boot.kind().is_efi_system());
+    }
+
+    #[test]
+    fn reads_entries_in_table_order() {
+        let disk = MemDisk::new(SECTORS);
+        write_gpt(
+            &disk,
+            &[
+                (Guid::EFI_SYSTEM, 40, 99, "esp"),
+                (Guid::ZERO, 0, 0, ""),
+                (Guid::MICROSOFT_BASIC_DATA, 100, 199, "データ"),
+            ],
+        );
+
+        assert_eq!(
+            names(&read(&disk).unwrap()),
+            [
+                (1, 40, String::from("esp")),
+                (3, 100, String::from("データ"))
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_backup_header() {
+        let disk = uefi_image(SECTORS);
+        // 主ヘッダのディスクGUIDを壊す
+        disk.corrupt(1, 60);
+
+        assert_eq!(
+            names(&read(&disk).unwrap()),
+            [(1, 2 + GPT_ENTRY_SECTORS, String::from("boot"))]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_backup_entries() {
+        let disk = uefi_image(SECTORS);
+        disk.corrupt(2, 0);
+
+        assert_eq!(read(&disk).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_header_checksum_mismatch() {
+        let disk = uefi_image(SECTORS);
+        disk.corrupt(1, 60);
+        disk.corrupt(SECTORS - 1, 60);
+
+        assert_eq!(
+            read(&disk).unwrap_err(),
+            PartitionError::InvalidGpt("header checksum mismatch")
+        );
+    }
+
+    #[test]
+    fn rejects_entry_checksum_mismatch() {
+        let disk = uefi_image(SECTORS);
+        disk.corrupt(2, 0);
+        disk.corrupt(SECTORS - 1 - GPT_ENTRY_SECTORS, 0);
+
+        assert_eq!(
+            read(&disk).unwrap_err(),
+            PartitionError::InvalidGpt("partition entry checksum mismatch")
+        );
+    }
+
+    #[test]
+    fn crc32_matches_known_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
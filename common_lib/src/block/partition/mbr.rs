@@ -0,0 +1,278 @@
+//! MBR (Master Boot Record) のパーティションテーブル
+//!
+//! 先頭セクタの4つの基本パーティションと、拡張パーティション内のEBRを辿って見つかる論理パーティションを読む。
+//! 論理パーティションには5から番号を振る
+
+use alloc::vec::Vec;
+
+use super::{PartitionEntry, PartitionError, PartitionKind};
+use crate::block::{read_sector, BlockDevice};
+
+/// パーティションテーブルの位置
+const TABLE_OFFSET: usize = 446;
+/// パーティションテーブルの1項目の大きさ
+const ENTRY_SIZE: usize = 16;
+/// ブートシグネチャの位置
+const SIGNATURE_OFFSET: usize = 510;
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// 辿る論理パーティションの数の上限。EBRの連鎖が循環していても止まるようにする
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+/// 空の項目
+pub const TYPE_EMPTY: u8 = 0x00;
+/// 拡張パーティション（CHS）
+pub const TYPE_EXTENDED: u8 = 0x05;
+/// 拡張パーティション（LBA）
+pub const TYPE_EXTENDED_LBA: u8 = 0x0f;
+/// Linuxの拡張パーティション
+pub const TYPE_LINUX_EXTENDED: u8 = 0x85;
+/// EFIシステムパーティション
+pub const TYPE_EFI_SYSTEM: u8 = 0xef;
+/// GPTの保護MBR
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// MBRのパーティションテーブルの1項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MbrEntry {
+    pub bootable: bool,
+    pub partition_type: u8,
+    /// テーブルを含むセクタから見た先頭のセクタ
+    pub start: u32,
+    pub sector_count: u32,
+}
+
+impl MbrEntry {
+    fn parse(bytes: &[u8]) -> Self {
+        MbrEntry {
+            bootable: bytes[0] & 0x80 != 0,
+            partition_type: bytes[4],
+            start: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            sector_count: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partition_type == TYPE_EMPTY || self.sector_count == 0
+    }
+
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self.partition_type,
+            TYPE_EXTENDED | TYPE_EXTENDED_LBA | TYPE_LINUX_EXTENDED
+        )
+    }
+}
+
+/// MBRのパーティションテーブル
+#[derive(Debug, Clone, Copy)]
+pub struct MbrTable {
+    pub entries: [MbrEntry; 4],
+}
+
+impl MbrTable {
+    /// 1セクタ分のデータからパーティションテーブルを読む。ブートシグネチャが無ければ`None`を返す
+    pub fn parse(sector: &[u8]) -> Option<Self> {
+        if sector.len() < SIGNATURE_OFFSET + 2
+            || sector[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2] != SIGNATURE
+        {
+            return None;
+        }
+
+        let entry = |i: usize| {
+            let offset = TABLE_OFFSET + i * ENTRY_SIZE;
+            MbrEntry::parse(&sector[offset..offset + ENTRY_SIZE])
+        };
+        Some(MbrTable {
+            entries: [entry(0), entry(1), entry(2), entry(3)],
+        })
+    }
+
+    /// GPTを保護するためのMBRであれば`true`を返す
+    pub fn is_protective(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.partition_type == TYPE_GPT_PROTECTIVE)
+    }
+
+    /// 基本パーティションと論理パーティションを列挙する
+    pub fn into_entries<D: BlockDevice + ?Sized>(
+        self,
+        disk: &D,
+    ) -> Result<Vec<PartitionEntry>, PartitionError> {
+        let mut partitions = Vec::new();
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.is_empty() {
+                continue;
+            }
+            if entry.is_extended() {
+                read_logical(disk, entry.start as u64, &mut partitions)?;
+                continue;
+            }
+            partitions.push(to_partition(i as u32 + 1, 0, entry));
+        }
+
+        Ok(partitions)
+    }
+}
+
+fn to_partition(number: u32, base: u64, entry: &MbrEntry) -> PartitionEntry {
+    PartitionEntry {
+        number,
+        start: base + entry.start as u64,
+        sector_count: entry.sector_count as u64,
+        kind: PartitionKind::Mbr {
+            partition_type: entry.partition_type,
+            bootable: entry.bootable,
+        },
+    }
+}
+
+/// 拡張パーティション内のEBRの連鎖を辿り、論理パーティションを`partitions`に加える
+///
+/// EBRの1項目目は自身のEBRから、2項目目（次のEBR）は拡張パーティションの先頭から見た位置を表す
+fn read_logical<D: BlockDevice + ?Sized>(
+    disk: &D,
+    extended_start: u64,
+    partitions: &mut Vec<PartitionEntry>,
+) -> Result<(), PartitionError> {
+    let mut ebr_sector = extended_start;
+
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        if ebr_sector >= disk.sector_count() {
+            break;
+        }
+        let Some(table) = MbrTable::parse(&read_sector(disk, ebr_sector)?) else {
+            break;
+        };
+
+        let [logical, next, ..] = table.entries;
+        if !logical.is_empty() {
+            partitions.push(to_partition(number, ebr_sector, &logical));
+        }
+        if next.is_empty() || !next.is_extended() {
+            break;
+        }
+        ebr_sector = extended_start + next.start as u64;
+    }
+
+    Ok(())
+}
+
+/// ディスクの先頭セクタからMBRを読む。ブートシグネチャが無ければ`None`を返す
+pub fn read<D: BlockDevice + ?Sized>(disk: &D) -> Result<Option<MbrTable>, PartitionError> {
+    if disk.sector_count() == 0 {
+        return Ok(None);
+    }
+    Ok(MbrTable::parse(&read_sector(disk, 0)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::block::partition::{
+        scan,
+        test_disk::{mbr_sector, MemDisk},
+    };
+
+    const SECTORS: u64 = 1024;
+    /// FAT32（LBA）
+    const TYPE_FAT32_LBA: u8 = 0x0c;
+
+    fn layout(partitions: &[PartitionEntry]) -> Vec<(u32, u64, u64)> {
+        partitions
+            .iter()
+            .map(|entry| (entry.number, entry.start, entry.sector_count))
+            .collect()
+    }
+
+    #[test]
+    fn reads_primary_partitions() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(MemDisk::new(SECTORS));
+        disk.write_sectors(
+            0,
+            &mbr_sector(&[(false, 0x83, 4, 64), (true, TYPE_FAT32_LBA, 100, 900)]),
+        )
+        .unwrap();
+
+        let partitions = scan(&disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[1].name(), "mem2");
+        assert_eq!(
+            partitions[1].kind(),
+            &PartitionKind::Mbr {
+                partition_type: TYPE_FAT32_LBA,
+                bootable: true
+            }
+        );
+    }
+
+    #[test]
+    fn follows_ebr_chain() {
+        let disk = MemDisk::new(SECTORS);
+        disk.put(
+            0,
+            &mbr_sector(&[
+                (false, TYPE_FAT32_LBA, 1, 99),
+                (false, TYPE_EXTENDED_LBA, 100, 300),
+            ]),
+        );
+        // 論理パーティションはEBRから、次のEBRは拡張パーティションの先頭から数える
+        disk.put(
+            100,
+            &mbr_sector(&[(false, 0x83, 1, 49), (false, TYPE_EXTENDED, 50, 100)]),
+        );
+        disk.put(
+            150,
+            &mbr_sector(&[(false, 0x83, 2, 48), (false, TYPE_EXTENDED, 150, 100)]),
+        );
+        disk.put(250, &mbr_sector(&[(false, 0x83, 1, 99)]));
+
+        let partitions = read(&disk).unwrap().unwrap().into_entries(&disk).unwrap();
+        assert_eq!(
+            layout(&partitions),
+            [(1, 1, 99), (5, 101, 49), (6, 152, 48), (7, 251, 99)]
+        );
+    }
+
+    #[test]
+    fn stops_at_missing_ebr() {
+        let disk = MemDisk::new(SECTORS);
+        disk.put(0, &mbr_sector(&[(false, TYPE_EXTENDED_LBA, 100, 300)]));
+        disk.put(
+            100,
+            &mbr_sector(&[(false, 0x83, 1, 49), (false, TYPE_EXTENDED, 50, 100)]),
+        );
+
+        let partitions = read(&disk).unwrap().unwrap().into_entries(&disk).unwrap();
+        assert_eq!(layout(&partitions), [(5, 101, 49)]);
+    }
+
+    #[test]
+    fn stops_at_looping_ebr_chain() {
+        let disk = MemDisk::new(SECTORS);
+        disk.put(0, &mbr_sector(&[(false, TYPE_EXTENDED_LBA, 100, 300)]));
+        disk.put(
+            100,
+            &mbr_sector(&[(false, 0x83, 1, 49), (false, TYPE_EXTENDED, 0, 300)]),
+        );
+
+        let partitions = read(&disk).unwrap().unwrap().into_entries(&disk).unwrap();
+        assert_eq!(partitions.len(), MAX_LOGICAL_PARTITIONS as usize);
+    }
+
+    #[test]
+    fn detects_protective_mbr() {
+        let table = MbrTable::parse(&mbr_sector(&[(false, TYPE_GPT_PROTECTIVE, 1, 1023)])).unwrap();
+        assert!(table.is_protective());
+    }
+
+    #[test]
+    fn requires_boot_signature() {
+        let mut sector = mbr_sector(&[(true, TYPE_FAT32_LBA, 1, 99)]);
+        sector[511] = 0;
+        assert!(MbrTable::parse(&sector).is_none());
+    }
+}
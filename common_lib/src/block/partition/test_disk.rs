@@ -0,0 +1,149 @@
+//! パーティションテーブルのテストに使う、メモリ上のディスクとディスクイメージの組み立て
+//!
+//! `build.rs`が作るイメージはカーネルのビルドを必要とするため、テストでは同じ配置のイメージをその場で組み立てる
+
+use alloc::{vec, vec::Vec};
+
+use spin::Mutex;
+
+use super::{gpt, Guid};
+use crate::block::{check_request, BlockDevice, BlockError};
+
+pub const SECTOR_SIZE: usize = 512;
+/// GPTのパーティションエントリの数と大きさ
+const GPT_ENTRY_COUNT: u32 = 128;
+const GPT_ENTRY_SIZE: u32 = 128;
+/// パーティションエントリの配列が占めるセクタ数
+pub const GPT_ENTRY_SECTORS: u64 = (GPT_ENTRY_COUNT * GPT_ENTRY_SIZE) as u64 / SECTOR_SIZE as u64;
+
+/// メモリ上のディスク
+pub struct MemDisk {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemDisk {
+    pub fn new(sectors: u64) -> Self {
+        MemDisk {
+            data: Mutex::new(vec![0; sectors as usize * SECTOR_SIZE]),
+        }
+    }
+
+    /// `lba`から`bytes`を書き込む
+    pub fn put(&self, lba: u64, bytes: &[u8]) {
+        let start = lba as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// `lba`のセクタの`offset`バイト目の各ビットを反転して、壊れたデータにする
+    pub fn corrupt(&self, lba: u64, offset: usize) {
+        self.data.lock()[lba as usize * SECTOR_SIZE + offset] ^= 0xff;
+    }
+}
+
+impl BlockDevice for MemDisk {
+    fn name(&self) -> &str {
+        "mem"
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        self.put(sector, buffer);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// MBRのパーティションテーブルの1項目: (起動可能, 種類, 先頭のセクタ, セクタ数)
+pub type MbrItem = (bool, u8, u32, u32);
+
+/// パーティションテーブルとブートシグネチャだけを持つMBR（またはEBR）のセクタを作る
+pub fn mbr_sector(items: &[MbrItem]) -> [u8; SECTOR_SIZE] {
+    let mut sector = [0; SECTOR_SIZE];
+    for (i, &(bootable, partition_type, start, count)) in items.iter().enumerate() {
+        let entry = &mut sector[446 + i * 16..446 + (i + 1) * 16];
+        entry[0] = if bootable { 0x80 } else { 0 };
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&count.to_le_bytes());
+    }
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    sector
+}
+
+/// GPTのパーティション: (種類, 先頭のLBA, 末尾のLBA, 名前)
+pub type GptItem<'a> = (Guid, u64, u64, &'a str);
+
+/// 保護MBRと、主・予備のGPTのヘッダとパーティションエントリの配列を書き込む
+pub fn write_gpt(disk: &MemDisk, items: &[GptItem]) {
+    let last_lba = disk.sector_count() - 1;
+    let protective = last_lba.min(u32::MAX as u64) as u32;
+    disk.put(0, &mbr_sector(&[(false, 0xee, 1, protective)]));
+
+    let mut entries = vec![0; (GPT_ENTRY_COUNT * GPT_ENTRY_SIZE) as usize];
+    for (i, &(type_guid, first, last, name)) in items.iter().enumerate() {
+        let entry = &mut entries[i * GPT_ENTRY_SIZE as usize..(i + 1) * GPT_ENTRY_SIZE as usize];
+        entry[0..16].copy_from_slice(&type_guid.0);
+        entry[16..32].fill(i as u8 + 1);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (unit, bytes) in name.encode_utf16().zip(entry[56..].as_chunks_mut::<2>().0) {
+            bytes.copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entries_crc32 = gpt::crc32(&entries);
+
+    let backup_entries_lba = last_lba - GPT_ENTRY_SECTORS;
+    let first_usable = 2 + GPT_ENTRY_SECTORS;
+    let last_usable = backup_entries_lba - 1;
+    let header = |current: u64, backup: u64, entries_lba: u64| {
+        let mut sector = [0; SECTOR_SIZE];
+        sector[0..8].copy_from_slice(b"EFI PART");
+        sector[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        sector[12..16].copy_from_slice(&92u32.to_le_bytes());
+        sector[24..32].copy_from_slice(&current.to_le_bytes());
+        sector[32..40].copy_from_slice(&backup.to_le_bytes());
+        sector[40..48].copy_from_slice(&first_usable.to_le_bytes());
+        sector[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        sector[56..72].fill(0xab);
+        sector[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        sector[80..84].copy_from_slice(&GPT_ENTRY_COUNT.to_le_bytes());
+        sector[84..88].copy_from_slice(&GPT_ENTRY_SIZE.to_le_bytes());
+        sector[88..92].copy_from_slice(&entries_crc32.to_le_bytes());
+        let crc32 = gpt::crc32(&sector[..92]);
+        sector[16..20].copy_from_slice(&crc32.to_le_bytes());
+        sector
+    };
+
+    disk.put(1, &header(1, last_lba, 2));
+    disk.put(2, &entries);
+    disk.put(backup_entries_lba, &entries);
+    disk.put(last_lba, &header(last_lba, 1, backup_entries_lba));
+}
+
+/// `build.rs`が作るUEFI用のイメージと同じく、保護MBRとEFIシステムパーティションが1つだけのGPTを持つディスク
+pub fn uefi_image(sectors: u64) -> MemDisk {
+    let disk = MemDisk::new(sectors);
+    let first = 2 + GPT_ENTRY_SECTORS;
+    let last = sectors - 2 - GPT_ENTRY_SECTORS;
+    write_gpt(&disk, &[(Guid::EFI_SYSTEM, first, last, "boot")]);
+    disk
+}
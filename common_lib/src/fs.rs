@@ -0,0 +1,499 @@
+//! ファイルシステムを扱うモジュール
+//!
+//! 各ファイルシステムは`FileSystem`を実装し、ファイルやディレクトリを`Inode`として公開する。
+//! ファイルシステムを`mount()`でディレクトリツリーに組み込むと、`open()`や`stat()`などでパスを指定して操作できる。
+//! 相対パスは`set_current_dir()`で設定したディレクトリを基準にする
+
+pub mod dentry;
+pub mod devfs;
+pub mod fat;
+pub mod file;
+pub mod mount;
+pub mod path;
+pub mod tar;
+pub mod tmpfs;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::block::{BlockDevice, BlockError};
+
+pub use dentry::Dentry;
+pub use file::{OpenFile, OpenOptions, SeekFrom};
+pub use mount::{mounts, Mount};
+
+/// ファイルシステムの操作が失敗した理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsError {
+    /// ファイルやディレクトリが存在しない
+    NotFound,
+    /// 同じ名前のファイルやディレクトリがすでに存在する
+    AlreadyExists,
+    /// ディレクトリでないものをディレクトリとして扱おうとした
+    NotADirectory,
+    /// ディレクトリをファイルとして扱おうとした
+    IsADirectory,
+    /// 空でないディレクトリを削除しようとした
+    DirectoryNotEmpty,
+    /// 読み込み専用のファイルシステムやファイルに書き込もうとした
+    ReadOnly,
+    /// ファイル名に使えない文字が含まれているか、空である
+    InvalidName,
+    /// ファイル名が長すぎる
+    NameTooLong,
+    /// 空き容量が無い
+    NoSpace,
+    /// ファイルが大きすぎる
+    FileTooLarge,
+    /// 引数が正しくない
+    InvalidArgument,
+    /// ファイルシステムが対応していない操作をした
+    NotSupported,
+    /// 読み書きが許されていない
+    PermissionDenied,
+    /// デバイスに読めるデータが無い
+    WouldBlock,
+    /// マウントポイントとして使われている
+    Busy,
+    /// シンボリックリンクをたどる回数が多すぎる
+    TooManyLinks,
+    /// 別のファイルシステムにまたがる操作をした
+    CrossDevice,
+    /// ファイルシステムの内容が壊れている
+    Corrupted(&'static str),
+    /// ブロックデバイスの読み書きに失敗した
+    Io(BlockError),
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::AlreadyExists => write!(f, "file exists"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::DirectoryNotEmpty => write!(f, "directory not empty"),
+            FsError::ReadOnly => write!(f, "read-only file system"),
+            FsError::InvalidName => write!(f, "invalid file name"),
+            FsError::NameTooLong => write!(f, "file name too long"),
+            FsError::NoSpace => write!(f, "no space left on device"),
+            FsError::FileTooLarge => write!(f, "file too large"),
+            FsError::InvalidArgument => write!(f, "invalid argument"),
+            FsError::NotSupported => write!(f, "operation not supported"),
+            FsError::PermissionDenied => write!(f, "permission denied"),
+            FsError::WouldBlock => write!(f, "resource temporarily unavailable"),
+            FsError::Busy => write!(f, "device or resource busy"),
+            FsError::TooManyLinks => write!(f, "too many levels of symbolic links"),
+            FsError::CrossDevice => write!(f, "cross-device link"),
+            FsError::Corrupted(reason) => write!(f, "file system is corrupted: {}", reason),
+            FsError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(e: BlockError) -> Self {
+        FsError::Io(e)
+    }
+}
+
+/// ファイルの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    /// バイト単位で順に読み書きするデバイス
+    CharDevice,
+    /// セクタ単位で読み書きするデバイス
+    BlockDevice,
+}
+
+/// ファイルの属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    /// ファイルシステムの中でファイルを識別する番号
+    pub inode: u64,
+    /// バイト数
+    pub size: u64,
+    /// このファイルを指すディレクトリの項目の数
+    pub links: u32,
+    pub read_only: bool,
+}
+
+/// ディレクトリの項目
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+    pub inode: u64,
+}
+
+/// ファイルシステム上のファイル・ディレクトリ・シンボリックリンク
+///
+/// 種類に合わない操作は、既定では`NotSupported`などのエラーを返す
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    /// ディレクトリから`name`という項目を探す
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// ディレクトリに`name`という空のファイルかディレクトリを作る
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// ディレクトリに、`target`を指す`name`というシンボリックリンクを作る
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// ディレクトリから`name`という項目を削除する。ディレクトリは空でなければならない
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// ディレクトリの`old_name`という項目を、同じファイルシステムのディレクトリ`new_parent`へ`new_name`という名前で移す
+    ///
+    /// `new_name`がすでにある場合は置き換える。ディレクトリは空のディレクトリだけを置き換えられる
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_parent: &dyn Inode,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// ディレクトリに、同じファイルシステムのファイル`target`を指す`name`という項目（ハードリンク）を作る
+    fn link(&self, _name: &str, _target: &dyn Inode) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// ディレクトリの項目を列挙する。`.`と`..`は含まない
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// ファイルの`offset`バイト目から読み込み、読んだバイト数を返す。ファイルの終わりでは0を返す
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// ファイルの`offset`バイト目から書き込み、書いたバイト数を返す。必要ならファイルを伸ばす
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// ファイルの大きさを`size`バイトにする。伸ばした部分は0で埋める
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// シンボリックリンクの指す先を返す
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// 書き込みを記録媒体に反映させる
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// ファイルシステム
+pub trait FileSystem: Send + Sync {
+    /// `vfat`のような、ファイルシステムの種類の名前
+    fn name(&self) -> &'static str;
+
+    /// ルートディレクトリ
+    fn root(&self) -> Arc<dyn Inode>;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// 書き込みを記録媒体に反映させる
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// ファイルシステムを読み書きしているブロックデバイス。キャッシュを通している場合はキャッシュを返す
+    fn device(&self) -> Option<Arc<dyn BlockDevice>> {
+        None
+    }
+}
+
+/// 相対パスの基準にするディレクトリ。`None`ならルートディレクトリ
+static CURRENT_DIR: Mutex<Option<Arc<Dentry>>> = Mutex::new(None);
+
+/// 相対パスの基準にするディレクトリ
+pub fn current_dir() -> Result<Arc<Dentry>, FsError> {
+    match CURRENT_DIR.lock().clone() {
+        Some(dentry) => Ok(dentry),
+        None => mount::root(),
+    }
+}
+
+/// 相対パスの基準にするディレクトリを`path`にする
+pub fn set_current_dir(path: &str) -> Result<(), FsError> {
+    let dentry = lookup(path)?;
+    if dentry.kind()? != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    *CURRENT_DIR.lock() = Some(dentry);
+    Ok(())
+}
+
+/// `path`をたどって`Dentry`を返す。最後の項目がシンボリックリンクならその先をたどる
+pub fn lookup(path: &str) -> Result<Arc<Dentry>, FsError> {
+    path::resolve(&current_dir()?, path, true)
+}
+
+/// `fs`を`path`のディレクトリにマウントする
+///
+/// 最初のマウントは`/`に対して行い、それがルートファイルシステムになる。
+/// すでにファイルシステムがマウントされているディレクトリには`Busy`を返す
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>, read_only: bool) -> Result<Arc<Mount>, FsError> {
+    let mount = match mount::root() {
+        Err(_) if !path.is_empty() && path.bytes().all(|c| c == b'/') => {
+            mount::add(None, fs, read_only)?
+        }
+        Err(e) => return Err(e),
+        Ok(_) => {
+            let mountpoint = lookup(path)?;
+            if mountpoint.kind()? != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+            mount::add(Some(&mountpoint), fs, read_only)?
+        }
+    };
+
+    log::info!(
+        "fs: mounted {} on {}{}",
+        mount.fs().name(),
+        mount.path(),
+        if mount.is_read_only() {
+            " (read-only)"
+        } else {
+            ""
+        }
+    );
+    Ok(mount)
+}
+
+/// `path`にマウントしたファイルシステムを外し、書き込みを反映させる
+///
+/// その中にほかのファイルシステムがマウントされている場合は`Busy`を返す
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let root = lookup(path)?;
+    if !mount::is_mount_root(&root) {
+        return Err(FsError::InvalidArgument);
+    }
+    let mount = mount::remove(&root)?;
+
+    let mut current_dir = CURRENT_DIR.lock();
+    if current_dir
+        .as_ref()
+        .is_some_and(|dentry| dentry.mount().id() == mount.id())
+    {
+        *current_dir = None;
+    }
+    drop(current_dir);
+
+    log::info!("fs: unmounted {} from {}", mount.fs().name(), mount.path());
+    mount.fs().sync()
+}
+
+/// `path`のファイルを`options`のモードで開く
+pub fn open(path: &str, options: OpenOptions) -> Result<OpenFile, FsError> {
+    let base = current_dir()?;
+    let dentry = if options.creates() {
+        let (parent, name) = path::resolve_parent(&base, path)?;
+        match path::resolve(&parent, name, options.follows_last()) {
+            Ok(_) if options.is_exclusive() => return Err(FsError::AlreadyExists),
+            Ok(dentry) => dentry,
+            Err(FsError::NotFound) => {
+                check_writable(&parent)?;
+                let inode = parent.inode().create(name, FileType::File)?;
+                parent.child(name, inode)
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        path::resolve(&base, path, options.follows_last())?
+    };
+
+    let kind = dentry.kind()?;
+    if options.requires_directory() && kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    if kind == FileType::Symlink {
+        return Err(FsError::TooManyLinks);
+    }
+    if options.is_writable() {
+        if kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        check_writable(&dentry)?;
+    }
+    if options.truncates() {
+        dentry.inode().truncate(0)?;
+    }
+
+    Ok(OpenFile::new(dentry, options))
+}
+
+/// `path`の属性を返す。最後の項目がシンボリックリンクならその先の属性を返す
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    lookup(path)?.metadata()
+}
+
+/// `path`の属性を返す。最後の項目がシンボリックリンクならリンクそのものの属性を返す
+pub fn lstat(path: &str) -> Result<Metadata, FsError> {
+    path::resolve(&current_dir()?, path, false)?.metadata()
+}
+
+/// ディレクトリ`path`の項目を列挙する。`.`と`..`は含まない
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    lookup(path)?.inode().read_dir()
+}
+
+/// シンボリックリンク`path`の指す先を返す
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    path::resolve(&current_dir()?, path, false)?
+        .inode()
+        .read_link()
+}
+
+/// ディレクトリ`path`を作る
+pub fn create_dir(path: &str) -> Result<Arc<Dentry>, FsError> {
+    create(path, |parent, name| {
+        parent.create(name, FileType::Directory)
+    })
+}
+
+/// `target`を指すシンボリックリンク`path`を作る
+pub fn symlink(target: &str, path: &str) -> Result<Arc<Dentry>, FsError> {
+    create(path, |parent, name| parent.symlink(name, target))
+}
+
+/// `path`のファイルやシンボリックリンク、空のディレクトリを削除する
+///
+/// ファイルシステムがマウントされているディレクトリには`Busy`を返す
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::resolve_parent(&current_dir()?, path)?;
+    let dentry = path::resolve(&parent, name, false)?;
+    if mount::is_mount_root(&dentry) {
+        return Err(FsError::Busy);
+    }
+    check_writable(&parent)?;
+    parent.inode().unlink(name)
+}
+
+/// `from`を`to`に移す。`to`がすでにある場合は置き換える
+///
+/// 別のファイルシステムへは移せず、`CrossDevice`を返す
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let base = current_dir()?;
+    let (old_parent, old_name) = path::resolve_parent(&base, from)?;
+    let (new_parent, new_name) = path::resolve_parent(&base, to)?;
+
+    let source = path::resolve(&old_parent, old_name, false)?;
+    if mount::is_mount_root(&source) {
+        return Err(FsError::Busy);
+    }
+    if old_parent.mount().id() != new_parent.mount().id() {
+        return Err(FsError::CrossDevice);
+    }
+    match path::resolve(&new_parent, new_name, false) {
+        Ok(target) if mount::is_mount_root(&target) => return Err(FsError::Busy),
+        Ok(_) | Err(FsError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    check_writable(&old_parent)?;
+
+    // ディレクトリを自分自身の中へは移せない
+    if source.kind()? == FileType::Directory {
+        let source_inode = source.metadata()?.inode;
+        let mut ancestor = Some(new_parent.clone());
+        while let Some(dentry) = ancestor {
+            if dentry.mount().id() != source.mount().id() {
+                break;
+            }
+            if dentry.metadata()?.inode == source_inode {
+                return Err(FsError::InvalidArgument);
+            }
+            ancestor = dentry.parent().cloned();
+        }
+    }
+
+    old_parent
+        .inode()
+        .rename(old_name, new_parent.inode().as_ref(), new_name)
+}
+
+/// ファイル`original`を指すハードリンク`link`を作る
+///
+/// `original`がシンボリックリンクの場合は、リンクそのものを指す
+pub fn hard_link(original: &str, link: &str) -> Result<(), FsError> {
+    let base = current_dir()?;
+    let target = path::resolve(&base, original, false)?;
+    if target.kind()? == FileType::Directory {
+        return Err(FsError::PermissionDenied);
+    }
+
+    let (parent, name) = path::resolve_parent(&base, link)?;
+    match path::resolve(&parent, name, false) {
+        Ok(_) => return Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    if parent.mount().id() != target.mount().id() {
+        return Err(FsError::CrossDevice);
+    }
+    check_writable(&parent)?;
+    parent.inode().link(name, target.inode().as_ref())
+}
+
+/// マウントしているすべてのファイルシステムの書き込みを反映させる
+///
+/// 失敗したファイルシステムがあっても残りを続け、最初のエラーを返す
+pub fn sync_all() -> Result<(), FsError> {
+    let mut result = Ok(());
+    for mount in mounts() {
+        if let Err(e) = mount.fs().sync() {
+            log::warn!("fs: failed to sync {}: {}", mount.path(), e);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+fn create(
+    path: &str,
+    f: impl FnOnce(&dyn Inode, &str) -> Result<Arc<dyn Inode>, FsError>,
+) -> Result<Arc<Dentry>, FsError> {
+    let (parent, name) = path::resolve_parent(&current_dir()?, path)?;
+    match path::resolve(&parent, name, false) {
+        Ok(_) => return Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    check_writable(&parent)?;
+    let inode = f(parent.inode().as_ref(), name)?;
+    Ok(parent.child(name, inode))
+}
+
+fn check_writable(dentry: &Dentry) -> Result<(), FsError> {
+    if dentry.is_read_only() {
+        Err(FsError::ReadOnly)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! パス上の位置を表すディレクトリエントリ
+//!
+//! `Inode`はファイルシステムの中のファイルそのものを表すが、名前や親ディレクトリ、どのマウントに属するかは知らない。
+//! `Dentry`はそれらを`Inode`と組にして、`..`の解決やパスの復元に使う
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt;
+
+use super::{mount::Mount, FileType, FsError, Inode, Metadata};
+
+/// パス上の1つの名前と、それが指す`Inode`の組
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    /// 親ディレクトリ。ルートディレクトリでは`None`
+    ///
+    /// マウントしたファイルシステムのルートでは、マウントポイントの親を指す
+    parent: Option<Arc<Dentry>>,
+    mount: Arc<Mount>,
+}
+
+impl Dentry {
+    /// マウントしたファイルシステムのルートディレクトリを表す`Dentry`を作る
+    ///
+    /// `mountpoint`はマウント先の`Dentry`で、ルートファイルシステムでは`None`
+    pub(super) fn mount_root(mount: Arc<Mount>, mountpoint: Option<&Arc<Dentry>>) -> Arc<Self> {
+        let (name, parent) = match mountpoint {
+            Some(mountpoint) => (mountpoint.name.clone(), mountpoint.parent.clone()),
+            None => (String::from("/"), None),
+        };
+        Arc::new(Dentry {
+            name,
+            inode: mount.fs().root(),
+            parent,
+            mount,
+        })
+    }
+
+    /// このディレクトリの中の`name`という項目を表す`Dentry`を作る
+    pub(super) fn child(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Self> {
+        Arc::new(Dentry {
+            name: name.to_string(),
+            inode,
+            parent: Some(self.clone()),
+            mount: self.mount.clone(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// 親ディレクトリ。ルートディレクトリでは`None`
+    pub fn parent(&self) -> Option<&Arc<Dentry>> {
+        self.parent.as_ref()
+    }
+
+    /// この`Dentry`が属するマウント
+    pub fn mount(&self) -> &Arc<Mount> {
+        &self.mount
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, FsError> {
+        self.inode.metadata()
+    }
+
+    pub fn kind(&self) -> Result<FileType, FsError> {
+        Ok(self.inode.metadata()?.kind)
+    }
+
+    /// 書き込めないマウントに属しているかどうか
+    pub fn is_read_only(&self) -> bool {
+        self.mount.is_read_only()
+    }
+
+    /// ルートディレクトリからの絶対パス
+    pub fn path(&self) -> String {
+        let mut names = Vec::new();
+        let mut current = self;
+        while let Some(parent) = &current.parent {
+            names.push(current.name.as_str());
+            current = parent;
+        }
+
+        if names.is_empty() {
+            return String::from("/");
+        }
+
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        path
+    }
+}
+
+impl fmt::Debug for Dentry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dentry")
+            .field("path", &self.path())
+            .field("mount", &self.mount.id())
+            .finish()
+    }
+}
@@ -0,0 +1,325 @@
+//! デバイスをファイルとして見せるファイルシステム
+//!
+//! ドライバは初期化したキャラクタデバイスを`register()`で登録する。
+//! ブロックデバイスは`block::register()`で登録されたものがそのまま現れる。
+//! ファイルシステムがマウントされたブロックデバイスは、そのファイルシステムと同じキャッシュを通して読み書きする。
+//! ディレクトリの内容は参照するたびに登録済みのデバイスから作るため、マウントした後に登録したデバイスも見える
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use super::{mount, DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{self, BlockDevice};
+
+/// ルートディレクトリのinode番号
+const ROOT_INODE: u64 = 1;
+/// ブロックデバイスのinode番号の始まり。キャラクタデバイスの番号とは重ならない
+const BLOCK_INODE_BASE: u64 = 1 << 32;
+
+/// バイト単位で順に読み書きするデバイス
+pub trait CharDevice: Send + Sync {
+    /// 読めるだけ読み込み、読んだバイト数を返す。読めるデータが無ければ`WouldBlock`を返す
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// 書き込み、書いたバイト数を返す
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError>;
+}
+
+struct Registered {
+    inode: u64,
+    device: Arc<dyn CharDevice>,
+}
+
+/// 登録済みのキャラクタデバイス
+static CHAR_DEVICES: Mutex<BTreeMap<String, Registered>> = Mutex::new(BTreeMap::new());
+
+/// キャラクタデバイスを`name`という名前で登録する
+///
+/// ## Panic
+/// 同じ名前のデバイスがすでに登録されている場合はパニックを起こす
+pub fn register(name: &str, device: Arc<dyn CharDevice>) {
+    let mut devices = CHAR_DEVICES.lock();
+    assert!(
+        !devices.contains_key(name),
+        "Character device {} is already registered",
+        name
+    );
+
+    let inode = ROOT_INODE + 1 + devices.len() as u64;
+    devices.insert(name.to_string(), Registered { inode, device });
+    log::info!("devfs: {} registered", name);
+}
+
+/// 名前が`name`のキャラクタデバイスを返す
+pub fn find(name: &str) -> Option<Arc<dyn CharDevice>> {
+    CHAR_DEVICES
+        .lock()
+        .get(name)
+        .map(|registered| registered.device.clone())
+}
+
+/// 書き込みを捨て、読むと常にファイルの終わりになるデバイス
+pub struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        Ok(buffer.len())
+    }
+}
+
+/// 書き込みを捨て、読むと0が続くデバイス
+pub struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        Ok(buffer.len())
+    }
+}
+
+/// デバイスファイルシステム
+pub struct DevFileSystem;
+
+impl DevFileSystem {
+    pub fn new() -> Arc<Self> {
+        Arc::new(DevFileSystem)
+    }
+}
+
+impl FileSystem for DevFileSystem {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevNode::Root)
+    }
+}
+
+/// デバイスファイルシステム上のノード
+pub enum DevNode {
+    Root,
+    Char {
+        inode: u64,
+        device: Arc<dyn CharDevice>,
+    },
+    Block {
+        inode: u64,
+        device: Arc<dyn BlockDevice>,
+    },
+}
+
+impl DevNode {
+    /// 登録済みのデバイスを名前順に列挙する
+    fn entries() -> Vec<(String, DevNode)> {
+        let mut entries: Vec<_> = CHAR_DEVICES
+            .lock()
+            .iter()
+            .map(|(name, registered)| {
+                let node = DevNode::Char {
+                    inode: registered.inode,
+                    device: registered.device.clone(),
+                };
+                (name.clone(), node)
+            })
+            .collect();
+        entries.extend(block::devices().into_iter().enumerate().map(|(i, device)| {
+            let node = DevNode::Block {
+                inode: BLOCK_INODE_BASE + i as u64,
+                device: device.clone(),
+            };
+            (device.name().to_string(), node)
+        }));
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+
+    fn kind(&self) -> FileType {
+        match self {
+            DevNode::Root => FileType::Directory,
+            DevNode::Char { .. } => FileType::CharDevice,
+            DevNode::Block { .. } => FileType::BlockDevice,
+        }
+    }
+
+    fn inode(&self) -> u64 {
+        match self {
+            DevNode::Root => ROOT_INODE,
+            DevNode::Char { inode, .. } | DevNode::Block { inode, .. } => *inode,
+        }
+    }
+}
+
+impl Inode for DevNode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let (size, read_only) = match self {
+            DevNode::Block { device, .. } => (device.size(), device.is_read_only()),
+            _ => (0, false),
+        };
+        Ok(Metadata {
+            kind: self.kind(),
+            inode: self.inode(),
+            size,
+            links: 1,
+            read_only,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let DevNode::Root = self else {
+            return Err(FsError::NotADirectory);
+        };
+        DevNode::entries()
+            .into_iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, node)| Arc::new(node) as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let DevNode::Root = self else {
+            return Err(FsError::NotADirectory);
+        };
+        Ok(DevNode::entries()
+            .into_iter()
+            .map(|(name, node)| DirEntry {
+                name,
+                kind: node.kind(),
+                inode: node.inode(),
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match self {
+            DevNode::Root => Err(FsError::IsADirectory),
+            // キャラクタデバイスは位置を持たない
+            DevNode::Char { device, .. } => device.read(buffer),
+            DevNode::Block { device, .. } => {
+                read_bytes(mounted_path(device, false)?.as_ref(), offset, buffer)
+            }
+        }
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        match self {
+            DevNode::Root => Err(FsError::IsADirectory),
+            DevNode::Char { device, .. } => device.write(buffer),
+            DevNode::Block { device, .. } => {
+                write_bytes(mounted_path(device, true)?.as_ref(), offset, buffer)
+            }
+        }
+    }
+
+    /// デバイスの大きさは変えられないため、何もしない
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        match self {
+            DevNode::Root => Err(FsError::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        match self {
+            DevNode::Block { device, .. } => Ok(mounted_path(device, false)?.flush()?),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `device`を読み書きするときに通すデバイスを返す
+///
+/// `device`にファイルシステムがマウントされていれば、そのファイルシステムと同じキャッシュを通す。
+/// `device`上のパーティションにマウントされていれば、書き込みは`Busy`を返し、読み込みの前にはそのキャッシュを書き出す
+fn mounted_path(
+    device: &Arc<dyn BlockDevice>,
+    write: bool,
+) -> Result<Arc<dyn BlockDevice>, FsError> {
+    let mut path = device.clone();
+    for mount in mount::mounts() {
+        let Some(mounted) = mount.fs().device() else {
+            continue;
+        };
+        if mounted.name() == device.name() {
+            path = mounted;
+        } else if mounted
+            .parent()
+            .is_some_and(|disk| disk.name() == device.name())
+        {
+            if write {
+                return Err(FsError::Busy);
+            }
+            mounted.flush()?;
+        }
+    }
+    Ok(path)
+}
+
+/// ブロックデバイスの`offset`バイト目から読み込み、読んだバイト数を返す。デバイスの終わりでは0を返す
+fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+    let size = device.size();
+    if offset >= size {
+        return Ok(0);
+    }
+    let len = buffer.len().min((size - offset) as usize);
+
+    let sector_size = device.sector_size();
+    let mut sector_buffer = vec![0; sector_size];
+    let mut done = 0;
+    while done < len {
+        let position = offset + done as u64;
+        let sector = position / sector_size as u64;
+        let start = (position % sector_size as u64) as usize;
+        let chunk = (sector_size - start).min(len - done);
+        device.read_sectors(sector, &mut sector_buffer)?;
+        buffer[done..done + chunk].copy_from_slice(&sector_buffer[start..start + chunk]);
+        done += chunk;
+    }
+    Ok(len)
+}
+
+/// ブロックデバイスの`offset`バイト目に書き込み、書いたバイト数を返す
+///
+/// セクタの一部だけを書く場合は、そのセクタを読んでから書き戻す。デバイスの終わりを超える場合は`NoSpace`を返す
+fn write_bytes(device: &dyn BlockDevice, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+    if device.is_read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    let size = device.size();
+    if offset >= size && !buffer.is_empty() {
+        return Err(FsError::NoSpace);
+    }
+    let len = buffer.len().min((size - offset.min(size)) as usize);
+
+    let sector_size = device.sector_size();
+    let mut sector_buffer = vec![0; sector_size];
+    let mut done = 0;
+    while done < len {
+        let position = offset + done as u64;
+        let sector = position / sector_size as u64;
+        let start = (position % sector_size as u64) as usize;
+        let chunk = (sector_size - start).min(len - done);
+        if chunk < sector_size {
+            device.read_sectors(sector, &mut sector_buffer)?;
+        }
+        sector_buffer[start..start + chunk].copy_from_slice(&buffer[done..done + chunk]);
+        device.write_sectors(sector, &sector_buffer)?;
+        done += chunk;
+    }
+    Ok(len)
+}
@@ -0,0 +1,666 @@
+//! FAT12/16/32ファイルシステム
+//!
+//! 長いファイル名（LFN）に対応し、ファイルとディレクトリの作成・削除、読み書き、切り詰めができる。
+//! ブロックデバイスへのアクセスはバイト単位で行うため、`BufferCache`の上に置くことを想定している。
+//!
+//! 同じファイルを指す`FatNode`は1つだけになるよう、ディレクトリエントリの位置をキーにして共有する。
+//! 操作はファイルシステム全体のロックを取って1つずつ行う
+
+pub mod boot_sector;
+mod dir;
+mod name;
+mod table;
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use self::{
+    boot_sector::{BootSector, FatType},
+    dir::{DirLocation, FatDirEntry, ShortEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_READ_ONLY},
+};
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{self, BlockDevice};
+
+/// ルートディレクトリのinode番号
+const ROOT_INODE: u64 = 1;
+
+/// ファイルシステム全体で共有する、変更される状態
+struct FatState {
+    /// 次に空きクラスタを探し始める位置
+    next_free: u32,
+    /// 空きクラスタの数。分からなければ`None`
+    free_clusters: Option<u32>,
+    /// FSInfoセクタに書き出していない変更がある
+    fs_info_dirty: bool,
+    /// 使われているノード。親ディレクトリと、その中の短いファイル名のエントリの位置をキーにする
+    nodes: BTreeMap<(DirLocation, u32), Weak<FatNode>>,
+}
+
+/// FATファイルシステム
+pub struct FatFileSystem {
+    device: Arc<dyn BlockDevice>,
+    boot: BootSector,
+    read_only: bool,
+    state: Mutex<FatState>,
+    this: Weak<FatFileSystem>,
+}
+
+impl FatFileSystem {
+    /// `device`上のFATファイルシステムを開く
+    ///
+    /// デバイスが読み込み専用であれば、ファイルシステムも読み込み専用になる
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let boot = BootSector::parse(&block::read_sector(device.as_ref(), 0)?)?;
+        if boot.bytes_per_sector as usize != device.sector_size() {
+            return Err(FsError::NotSupported);
+        }
+        if boot.total_sectors as u64 > device.sector_count() {
+            return Err(FsError::Corrupted("volume is larger than the device"));
+        }
+
+        let read_only = device.is_read_only();
+        let fs = Arc::new_cyclic(|this| FatFileSystem {
+            device,
+            boot,
+            read_only,
+            state: Mutex::new(FatState {
+                next_free: 2,
+                free_clusters: None,
+                fs_info_dirty: false,
+                nodes: BTreeMap::new(),
+            }),
+            this: this.clone(),
+        });
+
+        let (free_clusters, next_free) = fs.read_fs_info()?;
+        let mut state = fs.state.lock();
+        state.free_clusters = free_clusters;
+        state.next_free = next_free.unwrap_or(2);
+        drop(state);
+
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.boot.fat_type
+    }
+
+    /// ボリュームラベル。空白で埋められた部分は取り除く
+    pub fn volume_label(&self) -> &str {
+        &self.boot.volume_label
+    }
+
+    pub fn bytes_per_cluster(&self) -> u32 {
+        self.boot.bytes_per_cluster()
+    }
+
+    /// データ領域のクラスタ数
+    pub fn cluster_count(&self) -> u32 {
+        self.boot.cluster_count
+    }
+
+    /// 空きクラスタの数を返す。分からなければFAT全体を数える
+    pub fn free_clusters(&self) -> Result<u32, FsError> {
+        let mut state = self.state.lock();
+        if let Some(free) = state.free_clusters {
+            return Ok(free);
+        }
+        let free = self.count_free_clusters()?;
+        state.free_clusters = Some(free);
+        Ok(free)
+    }
+
+    fn arc(&self) -> Arc<FatFileSystem> {
+        // `self`が存在する間は、必ず`Arc`から参照されている
+        self.this.upgrade().unwrap()
+    }
+
+    /// ルートディレクトリの置き場所
+    fn root_location(&self) -> DirLocation {
+        match self.boot.fat_type {
+            FatType::Fat32 => DirLocation::Cluster(self.boot.root_cluster),
+            _ => DirLocation::FixedRoot,
+        }
+    }
+
+    /// ボリューム先頭から`position`バイト目から読み込む
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let sector_size = self.device.sector_size();
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let current = position + done as u64;
+            let sector = current / sector_size as u64;
+            let within = (current % sector_size as u64) as usize;
+            let remaining = buffer.len() - done;
+
+            // セクタ境界に揃った部分は、まとめて直接読み込む
+            if within == 0 && remaining >= sector_size {
+                let len = remaining - remaining % sector_size;
+                self.device
+                    .read_sectors(sector, &mut buffer[done..done + len])?;
+                done += len;
+                continue;
+            }
+
+            let data = block::read_sector(self.device.as_ref(), sector)?;
+            let len = (sector_size - within).min(remaining);
+            buffer[done..done + len].copy_from_slice(&data[within..within + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// ボリューム先頭から`position`バイト目に書き込む
+    fn write_bytes(&self, position: u64, buffer: &[u8]) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+
+        let sector_size = self.device.sector_size();
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let current = position + done as u64;
+            let sector = current / sector_size as u64;
+            let within = (current % sector_size as u64) as usize;
+            let remaining = buffer.len() - done;
+
+            if within == 0 && remaining >= sector_size {
+                let len = remaining - remaining % sector_size;
+                self.device
+                    .write_sectors(sector, &buffer[done..done + len])?;
+                done += len;
+                continue;
+            }
+
+            // セクタの一部だけを書き換える
+            let mut data = block::read_sector(self.device.as_ref(), sector)?;
+            let len = (sector_size - within).min(remaining);
+            data[within..within + len].copy_from_slice(&buffer[done..done + len]);
+            self.device.write_sectors(sector, &data)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// クラスタの中身を0で埋める
+    fn zero_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        let position = self.boot.cluster_sector(cluster) * self.boot.bytes_per_sector as u64;
+        self.write_bytes(position, &vec![0; self.boot.bytes_per_cluster() as usize])
+    }
+
+    /// クラスタチェーン上の`offset`バイト目から`len`バイトの範囲を、クラスタごとに区切って`f`に渡す
+    ///
+    /// `f`にはボリューム先頭からのバイト位置と、範囲の先頭から見たバイト位置の範囲が渡される
+    fn for_each_span(
+        &self,
+        chain: &[u32],
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, core::ops::Range<usize>) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let bytes_per_cluster = self.boot.bytes_per_cluster() as u64;
+        let mut done = 0;
+
+        while done < len {
+            let current = offset + done as u64;
+            let cluster = *chain
+                .get((current / bytes_per_cluster) as usize)
+                .ok_or(FsError::Corrupted("cluster chain is shorter than the file"))?;
+            let within = current % bytes_per_cluster;
+            let span = ((bytes_per_cluster - within) as usize).min(len - done);
+
+            let position =
+                self.boot.cluster_sector(cluster) * self.boot.bytes_per_sector as u64 + within;
+            f(position, done..done + span)?;
+            done += span;
+        }
+        Ok(())
+    }
+
+    /// クラスタチェーンを`clusters`個まで伸ばす。チェーンが空であれば先頭を割り当てる
+    fn extend_chain(
+        &self,
+        state: &mut FatState,
+        chain: &mut Vec<u32>,
+        clusters: usize,
+    ) -> Result<(), FsError> {
+        while chain.len() < clusters {
+            let cluster = self.allocate_cluster(state, chain.last().copied())?;
+            chain.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// ディレクトリの項目に対応するノードを返す。使われているノードがあればそれを共有する
+    fn node(&self, state: &mut FatState, dir: DirLocation, entry: &FatDirEntry) -> Arc<FatNode> {
+        let key = (dir, entry.index);
+        if let Some(node) = state.nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+
+        let node = Arc::new(FatNode {
+            fs: self.arc(),
+            entry: Some(key),
+            kind: entry.short.kind(),
+            state: Mutex::new(NodeState {
+                first_cluster: entry.short.first_cluster,
+                size: entry.short.size,
+                attributes: entry.short.attributes,
+                removed: false,
+            }),
+        });
+
+        // 使われなくなったノードを取り除いてから加える
+        state.nodes.retain(|_, node| node.strong_count() > 0);
+        state.nodes.insert(key, Arc::downgrade(&node));
+        node
+    }
+
+    fn write_back(&self) -> Result<(), FsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.write_fs_info(&mut self.state.lock())?;
+        Ok(self.device.flush()?)
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let first_cluster = match self.root_location() {
+            DirLocation::Cluster(cluster) => cluster,
+            DirLocation::FixedRoot => 0,
+        };
+        Arc::new(FatNode {
+            fs: self.arc(),
+            entry: None,
+            kind: FileType::Directory,
+            state: Mutex::new(NodeState {
+                first_cluster,
+                size: 0,
+                attributes: ATTR_DIRECTORY,
+                removed: false,
+            }),
+        })
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.write_back()
+    }
+
+    fn device(&self) -> Option<Arc<dyn BlockDevice>> {
+        Some(self.device.clone())
+    }
+}
+
+impl Drop for FatFileSystem {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_back() {
+            log::warn!("vfat: failed to write back on unmount: {}", e);
+        }
+    }
+}
+
+/// ディレクトリエントリの位置からinode番号を作る。ルートディレクトリは`None`で表す
+fn inode_number(entry: Option<(DirLocation, u32)>) -> u64 {
+    match entry {
+        None => ROOT_INODE,
+        Some((dir, index)) => {
+            let dir = match dir {
+                DirLocation::FixedRoot => 0,
+                DirLocation::Cluster(cluster) => cluster as u64,
+            };
+            (dir << 32 | index as u64) + ROOT_INODE + 1
+        }
+    }
+}
+
+/// ノードごとの、変更される状態
+struct NodeState {
+    first_cluster: u32,
+    size: u32,
+    attributes: u8,
+    /// 削除済みである
+    removed: bool,
+}
+
+/// FATファイルシステム上のファイルやディレクトリ
+pub struct FatNode {
+    fs: Arc<FatFileSystem>,
+    /// 親ディレクトリと、その中の短いファイル名のエントリの位置。ルートディレクトリでは`None`
+    entry: Option<(DirLocation, u32)>,
+    kind: FileType,
+    state: Mutex<NodeState>,
+}
+
+impl FatNode {
+    /// ディレクトリとしての置き場所を返す。ディレクトリでなければエラーを返す
+    ///
+    /// ルート以外のディレクトリの先頭クラスタがデータ領域を指していなければ`Corrupted`を返す
+    fn dir_location(&self, state: &NodeState) -> Result<DirLocation, FsError> {
+        if self.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        if state.removed {
+            return Err(FsError::NotFound);
+        }
+        match self.entry {
+            None => Ok(self.fs.root_location()),
+            Some(_) if !self.fs.boot.is_valid_cluster(state.first_cluster) => {
+                Err(FsError::Corrupted("directory has an invalid first cluster"))
+            }
+            Some(_) => Ok(DirLocation::Cluster(state.first_cluster)),
+        }
+    }
+
+    /// ファイルとして操作できるか確かめる
+    fn check_file(&self, state: &NodeState) -> Result<(), FsError> {
+        if self.kind != FileType::File {
+            return Err(FsError::IsADirectory);
+        }
+        if state.removed {
+            return Err(FsError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// 書き込めるか確かめる
+    fn check_writable(&self, state: &NodeState) -> Result<(), FsError> {
+        if self.fs.read_only || state.attributes & ATTR_READ_ONLY != 0 {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// 先頭クラスタと大きさを、ディレクトリエントリに書き戻す
+    fn update_entry(&self, state: &NodeState) -> Result<(), FsError> {
+        let Some((dir, index)) = self.entry else {
+            return Ok(());
+        };
+        self.fs.update_entry(dir, index, |short| {
+            short.first_cluster = state.first_cluster;
+            short.size = state.size;
+            short.attributes |= ATTR_ARCHIVE;
+        })
+    }
+
+    /// `offset`から`data`を書き込む。クラスタは割り当て済みでなければならない
+    fn write_data(&self, chain: &[u32], offset: u64, data: &[u8]) -> Result<(), FsError> {
+        self.fs
+            .for_each_span(chain, offset, data.len(), |position, range| {
+                self.fs.write_bytes(position, &data[range])
+            })
+    }
+
+    /// `from`から`to`までを0で埋める。クラスタは割り当て済みでなければならない
+    fn zero_range(&self, chain: &[u32], from: u64, to: u64) -> Result<(), FsError> {
+        if from >= to {
+            return Ok(());
+        }
+        let zeros = vec![0; self.fs.boot.bytes_per_cluster() as usize];
+        self.fs
+            .for_each_span(chain, from, (to - from) as usize, |position, range| {
+                self.fs.write_bytes(position, &zeros[..range.len()])
+            })
+    }
+
+    /// ファイルのクラスタチェーンを`clusters`個まで伸ばす
+    ///
+    /// 途中で空きが尽きた場合も、それまでに割り当てたクラスタをディレクトリエントリに記録してからエラーを返す
+    fn extend(
+        &self,
+        fs_state: &mut FatState,
+        state: &mut NodeState,
+        chain: &mut Vec<u32>,
+        clusters: usize,
+    ) -> Result<(), FsError> {
+        let result = self.fs.extend_chain(fs_state, chain, clusters);
+        if let Some(&first) = chain.first() {
+            if state.first_cluster != first {
+                state.first_cluster = first;
+                if result.is_err() {
+                    self.update_entry(state)?;
+                }
+            }
+        }
+        result
+    }
+
+    /// 大きさ`size`を収めるのに必要なクラスタ数
+    fn clusters_for(&self, size: u64) -> usize {
+        size.div_ceil(self.fs.boot.bytes_per_cluster() as u64) as usize
+    }
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let state = self.state.lock();
+        if state.removed {
+            return Err(FsError::NotFound);
+        }
+        Ok(Metadata {
+            kind: self.kind,
+            inode: inode_number(self.entry),
+            size: if self.kind == FileType::File {
+                state.size as u64
+            } else {
+                0
+            },
+            links: 1,
+            read_only: self.fs.read_only || state.attributes & ATTR_READ_ONLY != 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut fs_state = self.fs.state.lock();
+        let dir = self.dir_location(&self.state.lock())?;
+        let entry = self.fs.find_entry(dir, name)?.ok_or(FsError::NotFound)?;
+        Ok(self.fs.node(&mut fs_state, dir, &entry))
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let mut fs_state = self.fs.state.lock();
+        let state = self.state.lock();
+        let dir = self.dir_location(&state)?;
+        self.check_writable(&state)?;
+
+        let placeholder = [b' '; 11];
+        let (short, cluster) = match kind {
+            FileType::File => (ShortEntry::new(placeholder, 0, ATTR_ARCHIVE, 0), None),
+            FileType::Directory => {
+                let cluster = self.fs.allocate_cluster(&mut fs_state, None)?;
+                let parent_cluster = match self.entry {
+                    None => 0,
+                    Some(_) => state.first_cluster,
+                };
+                if let Err(e) = self.fs.init_dir(cluster, parent_cluster) {
+                    self.fs.free_chain(&mut fs_state, cluster)?;
+                    return Err(e);
+                }
+                (
+                    ShortEntry::new(placeholder, 0, ATTR_DIRECTORY, cluster),
+                    Some(cluster),
+                )
+            }
+            _ => return Err(FsError::NotSupported),
+        };
+
+        let index = match self.fs.insert_entry(&mut fs_state, dir, name, short) {
+            Ok(index) => index,
+            Err(e) => {
+                if let Some(cluster) = cluster {
+                    self.fs.free_chain(&mut fs_state, cluster)?;
+                }
+                return Err(e);
+            }
+        };
+
+        let entry = FatDirEntry {
+            name: name.into(),
+            short,
+            index,
+            first_index: index,
+        };
+        Ok(self.fs.node(&mut fs_state, dir, &entry))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut fs_state = self.fs.state.lock();
+        let state = self.state.lock();
+        let dir = self.dir_location(&state)?;
+        self.check_writable(&state)?;
+
+        let entry = self.fs.find_entry(dir, name)?.ok_or(FsError::NotFound)?;
+        if entry.short.attributes & ATTR_READ_ONLY != 0 {
+            return Err(FsError::ReadOnly);
+        }
+        if entry.short.is_directory()
+            && !self
+                .fs
+                .list_dir(DirLocation::Cluster(entry.short.first_cluster))?
+                .is_empty()
+        {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.fs.remove_entry(dir, &entry)?;
+        if entry.short.first_cluster != 0 {
+            self.fs
+                .free_chain(&mut fs_state, entry.short.first_cluster)?;
+        }
+
+        // 開かれたままのノードは、以降の操作で失敗するようにする
+        if let Some(node) = fs_state
+            .nodes
+            .remove(&(dir, entry.index))
+            .and_then(|node| node.upgrade())
+        {
+            node.state.lock().removed = true;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let _fs_state = self.fs.state.lock();
+        let dir = self.dir_location(&self.state.lock())?;
+
+        Ok(self
+            .fs
+            .list_dir(dir)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                inode: inode_number(Some((dir, entry.index))),
+                kind: entry.short.kind(),
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let _fs_state = self.fs.state.lock();
+        let state = self.state.lock();
+        self.check_file(&state)?;
+
+        let size = state.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((size - offset) as usize);
+
+        let chain = self.fs.cluster_chain(state.first_cluster)?;
+        self.fs
+            .for_each_span(&chain, offset, len, |position, range| {
+                self.fs.read_bytes(position, &mut buffer[range])
+            })?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut fs_state = self.fs.state.lock();
+        let mut state = self.state.lock();
+        self.check_file(&state)?;
+        self.check_writable(&state)?;
+
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(FsError::FileTooLarge)?;
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let first_cluster = state.first_cluster;
+        let mut chain = self.fs.cluster_chain(state.first_cluster)?;
+        self.extend(
+            &mut fs_state,
+            &mut state,
+            &mut chain,
+            self.clusters_for(end),
+        )?;
+
+        // ファイルの終わりより後ろから書き込む場合は、間を0で埋める
+        let result = self
+            .zero_range(&chain, state.size as u64, offset)
+            .and_then(|_| self.write_data(&chain, offset, buffer));
+        if let Err(e) = result {
+            // 割り当てたクラスタが失われないよう、先頭クラスタをディレクトリエントリに記録しておく
+            if state.first_cluster != first_cluster {
+                self.update_entry(&state)?;
+            }
+            return Err(e);
+        }
+
+        state.size = state.size.max(end as u32);
+        self.update_entry(&state)?;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut fs_state = self.fs.state.lock();
+        let mut state = self.state.lock();
+        self.check_file(&state)?;
+        self.check_writable(&state)?;
+        if size > u32::MAX as u64 {
+            return Err(FsError::FileTooLarge);
+        }
+
+        let mut chain = self.fs.cluster_chain(state.first_cluster)?;
+        let needed = self.clusters_for(size);
+
+        if needed < chain.len() {
+            if needed == 0 {
+                self.fs.free_chain(&mut fs_state, chain[0])?;
+                state.first_cluster = 0;
+            } else {
+                self.fs.truncate_chain(&mut fs_state, chain[needed - 1])?;
+            }
+            chain.truncate(needed);
+        } else {
+            self.extend(&mut fs_state, &mut state, &mut chain, needed)?;
+        }
+
+        self.zero_range(&chain, state.size as u64, size)?;
+        state.size = size as u32;
+        self.update_entry(&state)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.fs.write_back()
+    }
+}
@@ -0,0 +1,171 @@
+//! ブートセクタのBPB (BIOS Parameter Block)
+//!
+//! FATの種類は、BPBに書かれた文字列ではなくデータ領域のクラスタ数で決まる
+
+use alloc::string::String;
+
+use crate::fs::FsError;
+
+/// FAT32で表せる最大のクラスタ数。FATの項目の下位28ビットのうち、0〜1と0x0ffffff7以上は予約されている
+const MAX_CLUSTER_COUNT: u32 = 0x0fff_fff5;
+
+/// FATの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// クラスタ数からFATの種類を決める
+    fn from_cluster_count(cluster_count: u32) -> Self {
+        if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+}
+
+/// 解析したBPBと、そこから計算した各領域の位置
+#[derive(Debug, Clone)]
+pub struct BootSector {
+    pub fat_type: FatType,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_count: u32,
+    /// FAT12/16のルートディレクトリの項目数。FAT32では0
+    pub root_entry_count: u32,
+    pub total_sectors: u32,
+    pub sectors_per_fat: u32,
+    /// FAT32のルートディレクトリの先頭クラスタ。FAT12/16では0
+    pub root_cluster: u32,
+    /// FAT32のFSInfoセクタ。無ければ0
+    pub fs_info_sector: u32,
+    pub volume_label: String,
+    /// FAT12/16のルートディレクトリ領域のセクタ数
+    pub root_dir_sectors: u32,
+    /// データ領域（クラスタ2）の先頭セクタ
+    pub first_data_sector: u32,
+    /// データ領域のクラスタ数
+    pub cluster_count: u32,
+}
+
+impl BootSector {
+    /// 先頭セクタからBPBを読む
+    pub fn parse(sector: &[u8]) -> Result<Self, FsError> {
+        if sector.len() < 512 || sector[510] != 0x55 || sector[511] != 0xaa {
+            return Err(FsError::Corrupted("missing boot signature"));
+        }
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]) as u32;
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                sector[offset],
+                sector[offset + 1],
+                sector[offset + 2],
+                sector[offset + 3],
+            ])
+        };
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved_sectors = u16_at(14);
+        let fat_count = sector[16] as u32;
+        let root_entry_count = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            count => count,
+        };
+        let sectors_per_fat = match u16_at(22) {
+            0 => u32_at(36),
+            count => count,
+        };
+
+        if !(512..=4096).contains(&bytes_per_sector) || !bytes_per_sector.is_power_of_two() {
+            return Err(FsError::Corrupted("invalid bytes per sector"));
+        }
+        if sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two() {
+            return Err(FsError::Corrupted("invalid sectors per cluster"));
+        }
+        if reserved_sectors == 0 || fat_count == 0 || sectors_per_fat == 0 {
+            return Err(FsError::Corrupted("invalid BPB"));
+        }
+
+        let root_dir_sectors = (root_entry_count * 32).div_ceil(bytes_per_sector);
+        let first_data_sector = fat_count
+            .checked_mul(sectors_per_fat)
+            .and_then(|fat_sectors| fat_sectors.checked_add(reserved_sectors))
+            .and_then(|sectors| sectors.checked_add(root_dir_sectors))
+            .ok_or(FsError::Corrupted("FAT region is too large"))?;
+        if total_sectors <= first_data_sector {
+            return Err(FsError::Corrupted("no data region"));
+        }
+        let cluster_count = (total_sectors - first_data_sector) / sectors_per_cluster;
+        if cluster_count == 0 {
+            return Err(FsError::Corrupted("data region is smaller than a cluster"));
+        }
+        if cluster_count > MAX_CLUSTER_COUNT {
+            return Err(FsError::Corrupted("too many clusters"));
+        }
+        let fat_type = FatType::from_cluster_count(cluster_count);
+
+        let (root_cluster, fs_info_sector, label_offset) = match fat_type {
+            FatType::Fat32 => (u32_at(44), u16_at(48), 71),
+            _ => (0, 0, 43),
+        };
+        if fat_type == FatType::Fat32
+            && (!(2..cluster_count + 2).contains(&root_cluster) || root_entry_count != 0)
+        {
+            return Err(FsError::Corrupted("invalid FAT32 root directory"));
+        }
+        if fat_type != FatType::Fat32 && root_entry_count == 0 {
+            return Err(FsError::Corrupted("missing root directory region"));
+        }
+
+        let volume_label = String::from_utf8_lossy(&sector[label_offset..label_offset + 11])
+            .trim_end()
+            .into();
+
+        Ok(BootSector {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            root_entry_count,
+            total_sectors,
+            sectors_per_fat,
+            root_cluster,
+            fs_info_sector,
+            volume_label,
+            root_dir_sectors,
+            first_data_sector,
+            cluster_count,
+        })
+    }
+
+    pub fn bytes_per_cluster(&self) -> u32 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// クラスタの先頭セクタ
+    pub fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.first_data_sector as u64 + (cluster as u64 - 2) * self.sectors_per_cluster as u64
+    }
+
+    /// FAT12/16のルートディレクトリ領域の先頭セクタ
+    pub fn root_dir_sector(&self) -> u64 {
+        (self.reserved_sectors + self.fat_count * self.sectors_per_fat) as u64
+    }
+
+    /// データ領域のクラスタ番号であれば`true`を返す
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+}
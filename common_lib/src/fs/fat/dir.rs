@@ -0,0 +1,423 @@
+//! ディレクトリエントリの読み書き
+//!
+//! ディレクトリは32バイトのエントリの配列で、FAT12/16のルートディレクトリだけは固定の領域に、
+//! それ以外はクラスタチェーンに置かれる
+
+use alloc::{string::String, vec::Vec};
+
+use super::{name, FatFileSystem, FatState};
+use crate::fs::{FileType, FsError};
+
+/// ディレクトリエントリの大きさ
+pub const ENTRY_SIZE: u32 = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// LFNエントリを表す属性の組み合わせ
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// これ以降にエントリが無いことを表す、名前の先頭バイト
+const END_OF_DIRECTORY: u8 = 0x00;
+/// 削除済みのエントリを表す、名前の先頭バイト
+const DELETED: u8 = 0xe5;
+
+/// 時刻を記録できないため、作成・更新日時には1980年1月1日 00:00:00を使う
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+const DEFAULT_TIME: u16 = 0;
+
+/// ディレクトリの置き場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DirLocation {
+    /// FAT12/16のルートディレクトリ領域
+    FixedRoot,
+    /// 先頭クラスタ
+    Cluster(u32),
+}
+
+/// ディレクトリのうち、ディスク上で連続している領域
+#[derive(Debug, Clone, Copy)]
+struct DirRegion {
+    /// ボリューム先頭からのバイト位置
+    position: u64,
+    /// 領域に入るエントリの数
+    entries: u32,
+    /// 領域のクラスタ。FAT12/16のルートディレクトリ領域であれば`None`
+    cluster: Option<u32>,
+}
+
+/// 短いファイル名のエントリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortEntry {
+    pub name: [u8; 11],
+    pub attributes: u8,
+    pub nt_res: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// 時刻などの、解釈しないフィールドを含む元のエントリ
+    raw: [u8; 32],
+}
+
+impl ShortEntry {
+    pub fn parse(raw: &[u8; 32]) -> Self {
+        let mut name = [0; 11];
+        name.copy_from_slice(&raw[0..11]);
+        let high = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+        let low = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+        ShortEntry {
+            name,
+            attributes: raw[11],
+            nt_res: raw[12],
+            first_cluster: high << 16 | low,
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+            raw: *raw,
+        }
+    }
+
+    /// 新しいエントリを作る
+    pub fn new(name: [u8; 11], nt_res: u8, attributes: u8, first_cluster: u32) -> Self {
+        let mut raw = [0; 32];
+        for offset in [14, 22] {
+            raw[offset..offset + 2].copy_from_slice(&DEFAULT_TIME.to_le_bytes());
+        }
+        for offset in [16, 18, 24] {
+            raw[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        }
+        ShortEntry {
+            name,
+            attributes,
+            nt_res,
+            first_cluster,
+            size: 0,
+            raw,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut raw = self.raw;
+        raw[0..11].copy_from_slice(&self.name);
+        raw[11] = self.attributes;
+        raw[12] = self.nt_res;
+        raw[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&self.size.to_le_bytes());
+        raw
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn kind(&self) -> FileType {
+        if self.is_directory() {
+            FileType::Directory
+        } else {
+            FileType::File
+        }
+    }
+}
+
+/// 名前を解決したディレクトリの項目
+#[derive(Debug, Clone)]
+pub struct FatDirEntry {
+    /// 長いファイル名があればそれを、無ければ短いファイル名を表示用にしたもの
+    pub name: String,
+    pub short: ShortEntry,
+    /// 短いファイル名のエントリの位置
+    pub index: u32,
+    /// 項目の最初のエントリ（LFNがあればその先頭）の位置
+    pub first_index: u32,
+}
+
+impl FatDirEntry {
+    /// 長いファイル名と短いファイル名のどちらかが`name`と一致すれば`true`を返す
+    pub fn matches(&self, name: &str) -> bool {
+        name::eq_ignore_case(&self.name, name)
+            || name::eq_ignore_case(&name::short_name_to_string(&self.short.name, 0), name)
+    }
+}
+
+impl FatFileSystem {
+    /// ディレクトリの置き場所を、連続した領域の列で返す
+    ///
+    /// クラスタチェーンは一度だけたどる。同じディレクトリに何度もアクセスする操作では、これを使い回す
+    fn dir_regions(&self, dir: DirLocation) -> Result<Vec<DirRegion>, FsError> {
+        Ok(match dir {
+            DirLocation::FixedRoot => alloc::vec![DirRegion {
+                position: self.boot.root_dir_sector() * self.boot.bytes_per_sector as u64,
+                entries: self.boot.root_entry_count,
+                cluster: None,
+            }],
+            DirLocation::Cluster(first) => self
+                .cluster_chain(first)?
+                .into_iter()
+                .map(|cluster| self.cluster_region(cluster))
+                .collect(),
+        })
+    }
+
+    /// ディレクトリのクラスタ1つ分の置き場所
+    fn cluster_region(&self, cluster: u32) -> DirRegion {
+        DirRegion {
+            position: self.boot.cluster_sector(cluster) * self.boot.bytes_per_sector as u64,
+            entries: self.boot.bytes_per_cluster() / ENTRY_SIZE,
+            cluster: Some(cluster),
+        }
+    }
+
+    /// `index`番目のエントリの、ボリューム先頭からのバイト位置を返す
+    fn entry_position(regions: &[DirRegion], index: u32) -> Result<u64, FsError> {
+        let mut index = index;
+        for region in regions {
+            if index < region.entries {
+                return Ok(region.position + index as u64 * ENTRY_SIZE as u64);
+            }
+            index -= region.entries;
+        }
+        Err(FsError::Corrupted("directory entry out of range"))
+    }
+
+    /// ディレクトリの`index`番目のエントリを書き込む
+    fn write_raw_entry(
+        &self,
+        regions: &[DirRegion],
+        index: u32,
+        entry: &[u8; 32],
+    ) -> Result<(), FsError> {
+        self.write_bytes(Self::entry_position(regions, index)?, entry)
+    }
+
+    /// ディレクトリのすべてのエントリを読む。終端のエントリ以降は読まない
+    fn read_raw_entries(&self, dir: DirLocation) -> Result<Vec<[u8; 32]>, FsError> {
+        let mut entries = Vec::new();
+
+        // クラスタごとにまとめて読む
+        for region in self.dir_regions(dir)? {
+            let mut buffer = alloc::vec![0; (region.entries * ENTRY_SIZE) as usize];
+            self.read_bytes(region.position, &mut buffer)?;
+            for chunk in buffer.as_chunks::<{ ENTRY_SIZE as usize }>().0 {
+                if chunk[0] == END_OF_DIRECTORY {
+                    return Ok(entries);
+                }
+                entries.push(*chunk);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// ディレクトリの項目を列挙する。`.`と`..`、ボリュームラベルは含まない
+    pub(super) fn list_dir(&self, dir: DirLocation) -> Result<Vec<FatDirEntry>, FsError> {
+        let mut result = Vec::new();
+        // 集めている途中のLFN: (最初のエントリの位置, チェックサム, 次に来るべき順序番号, 文字)
+        let mut long_name: Option<(u32, u8, u8, Vec<u16>)> = None;
+
+        for (index, raw) in self.read_raw_entries(dir)?.iter().enumerate() {
+            let index = index as u32;
+            if raw[0] == DELETED {
+                long_name = None;
+                continue;
+            }
+
+            if raw[11] & 0x3f == ATTR_LONG_NAME {
+                let order = raw[0] & !name::LAST_LONG_ENTRY;
+                let units = name::long_name_units(raw);
+                if order == 0 || order as usize > name::MAX_LONG_ENTRIES {
+                    long_name = None;
+                } else if raw[0] & name::LAST_LONG_ENTRY != 0 {
+                    let mut all = alloc::vec![0xffff; order as usize * name::UNITS_PER_ENTRY];
+                    all[(order as usize - 1) * name::UNITS_PER_ENTRY..].copy_from_slice(&units);
+                    long_name = Some((index, raw[13], order.wrapping_sub(1), all));
+                } else if let Some((_, checksum, expected, all)) = long_name.as_mut() {
+                    if order != *expected || raw[13] != *checksum {
+                        long_name = None;
+                        continue;
+                    }
+                    let start = (order as usize - 1) * name::UNITS_PER_ENTRY;
+                    all[start..start + name::UNITS_PER_ENTRY].copy_from_slice(&units);
+                    *expected -= 1;
+                }
+                continue;
+            }
+
+            let short = ShortEntry::parse(raw);
+            let pending = long_name.take();
+            if short.attributes & ATTR_VOLUME_ID != 0 || short.name[0] == b'.' {
+                continue;
+            }
+
+            let (name, first_index) = match pending {
+                Some((first_index, checksum, 0, units))
+                    if checksum == name::checksum(&short.name) =>
+                {
+                    (name::decode_long_name(&units), first_index)
+                }
+                _ => (name::short_name_to_string(&short.name, short.nt_res), index),
+            };
+            result.push(FatDirEntry {
+                name,
+                short,
+                index,
+                first_index,
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// ディレクトリから`name`という項目を探す
+    pub(super) fn find_entry(
+        &self,
+        dir: DirLocation,
+        name: &str,
+    ) -> Result<Option<FatDirEntry>, FsError> {
+        Ok(self
+            .list_dir(dir)?
+            .into_iter()
+            .find(|entry| entry.matches(name)))
+    }
+
+    /// ディレクトリに`name`という項目を加え、短いファイル名のエントリの位置を返す
+    ///
+    /// 必要ならLFNエントリも作る。連続した空きエントリが無ければディレクトリを伸ばす
+    pub(super) fn insert_entry(
+        &self,
+        state: &mut FatState,
+        dir: DirLocation,
+        name: &str,
+        mut short: ShortEntry,
+    ) -> Result<u32, FsError> {
+        name::validate(name)?;
+        let existing = self.list_dir(dir)?;
+        if existing.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let mut entries = match name::exact_short_name(name) {
+            Some((short_name, nt_res)) => {
+                short.name = short_name;
+                short.nt_res = nt_res;
+                Vec::new()
+            }
+            None => {
+                short.name = name::generate_short_name(name, |candidate| {
+                    existing.iter().any(|entry| entry.short.name == *candidate)
+                })?;
+                short.nt_res = 0;
+                name::long_name_entries(name, name::checksum(&short.name))
+            }
+        };
+        entries.push(short.to_bytes());
+
+        let mut regions = self.dir_regions(dir)?;
+        let start = self.find_free_entries(state, dir, &mut regions, entries.len() as u32)?;
+        for (i, entry) in entries.iter().enumerate() {
+            self.write_raw_entry(&regions, start + i as u32, entry)?;
+        }
+        Ok(start + entries.len() as u32 - 1)
+    }
+
+    /// `count`個の連続した空きエントリを探し、先頭の位置を返す
+    ///
+    /// 足りなければディレクトリを伸ばし、足したクラスタを`regions`に加える
+    fn find_free_entries(
+        &self,
+        state: &mut FatState,
+        dir: DirLocation,
+        regions: &mut Vec<DirRegion>,
+        count: u32,
+    ) -> Result<u32, FsError> {
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut index = 0;
+
+        // クラスタごとにまとめて読む
+        for region in regions.iter() {
+            let mut buffer = alloc::vec![0; (region.entries * ENTRY_SIZE) as usize];
+            self.read_bytes(region.position, &mut buffer)?;
+            for chunk in buffer.as_chunks::<{ ENTRY_SIZE as usize }>().0 {
+                if chunk[0] == END_OF_DIRECTORY || chunk[0] == DELETED {
+                    if run_len == 0 {
+                        run_start = index;
+                    }
+                    run_len += 1;
+                    if run_len == count {
+                        return Ok(run_start);
+                    }
+                } else {
+                    run_len = 0;
+                }
+                index += 1;
+            }
+        }
+
+        // ディレクトリの末尾に達したため、クラスタを足して伸ばす
+        let DirLocation::Cluster(first) = dir else {
+            return Err(FsError::NoSpace);
+        };
+        let mut last_cluster = regions.last().map_or(Some(first), |region| region.cluster);
+        if run_len == 0 {
+            run_start = index;
+        }
+        while run_len < count {
+            let cluster = self.allocate_cluster(state, last_cluster)?;
+            let region = self.cluster_region(cluster);
+            run_len += region.entries;
+            regions.push(region);
+            last_cluster = Some(cluster);
+        }
+        Ok(run_start)
+    }
+
+    /// 短いファイル名のエントリを書き換える
+    pub(super) fn update_entry(
+        &self,
+        dir: DirLocation,
+        index: u32,
+        update: impl FnOnce(&mut ShortEntry),
+    ) -> Result<(), FsError> {
+        let position = Self::entry_position(&self.dir_regions(dir)?, index)?;
+        let mut raw = [0; 32];
+        self.read_bytes(position, &mut raw)?;
+        let mut short = ShortEntry::parse(&raw);
+        update(&mut short);
+        self.write_bytes(position, &short.to_bytes())
+    }
+
+    /// 項目のエントリ（LFNエントリを含む）を削除済みにする
+    pub(super) fn remove_entry(
+        &self,
+        dir: DirLocation,
+        entry: &FatDirEntry,
+    ) -> Result<(), FsError> {
+        let regions = self.dir_regions(dir)?;
+        for index in entry.first_index..=entry.index {
+            self.write_bytes(Self::entry_position(&regions, index)?, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// 新しいディレクトリのクラスタに、`.`と`..`のエントリを書き込む
+    ///
+    /// `parent_cluster`は親ディレクトリの先頭クラスタで、親がルートディレクトリであれば0にする
+    pub(super) fn init_dir(&self, cluster: u32, parent_cluster: u32) -> Result<(), FsError> {
+        let regions = [self.cluster_region(cluster)];
+        let mut dot = *b"           ";
+        dot[0] = b'.';
+        let mut dot_dot = dot;
+        dot_dot[1] = b'.';
+
+        self.write_raw_entry(
+            &regions,
+            0,
+            &ShortEntry::new(dot, 0, ATTR_DIRECTORY, cluster).to_bytes(),
+        )?;
+        self.write_raw_entry(
+            &regions,
+            1,
+            &ShortEntry::new(dot_dot, 0, ATTR_DIRECTORY, parent_cluster).to_bytes(),
+        )
+    }
+}
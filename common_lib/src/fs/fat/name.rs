@@ -0,0 +1,228 @@
+//! 短いファイル名（8.3形式）と長いファイル名（LFN）
+//!
+//! 長いファイル名はUTF-16で、1つのLFNエントリに13文字ずつ、短いファイル名のエントリの直前に逆順で並べる。
+//! 各LFNエントリは、対応する短いファイル名から計算したチェックサムを持つ
+
+use alloc::{string::String, vec::Vec};
+
+use crate::fs::FsError;
+
+/// 長いファイル名の最大の長さ（UTF-16の単位数）
+pub const MAX_NAME_UNITS: usize = 255;
+/// 1つのLFNエントリに入る文字数
+pub const UNITS_PER_ENTRY: usize = 13;
+/// 1つの名前に使うLFNエントリの最大の数
+pub const MAX_LONG_ENTRIES: usize = MAX_NAME_UNITS.div_ceil(UNITS_PER_ENTRY);
+/// LFNエントリ内の各文字の位置
+const UNIT_OFFSETS: [usize; UNITS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// 最後のLFNエントリ（名前の末尾を含み、ディレクトリ上では最初に並ぶ）であることを表すビット
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+
+/// NTResのうち、基本名が小文字であることを表すビット
+pub const LOWERCASE_BASE: u8 = 0x08;
+/// NTResのうち、拡張子が小文字であることを表すビット
+pub const LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// 短いファイル名に使えない文字
+const INVALID_SHORT_CHARS: &[u8] = b"\"*+,./:;<=>?[\\]|";
+/// 長いファイル名に使えない文字
+const INVALID_LONG_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// 短いファイル名のチェックサム
+pub fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// ディスク上の短いファイル名を、表示用の文字列にする
+pub fn short_name_to_string(short_name: &[u8; 11], nt_res: u8) -> String {
+    let mut name = short_name[..8].to_vec();
+    if name[0] == 0x05 {
+        name[0] = 0xe5;
+    }
+    let convert = |part: &[u8], lower: bool| -> String {
+        let part = part.trim_ascii_end();
+        part.iter()
+            .map(|&c| {
+                let c = if lower { c.to_ascii_lowercase() } else { c };
+                // ASCII以外はOEMコードページに依存するため、置き換える
+                if c.is_ascii() {
+                    c as char
+                } else {
+                    char::REPLACEMENT_CHARACTER
+                }
+            })
+            .collect()
+    };
+
+    let base = convert(&name, nt_res & LOWERCASE_BASE != 0);
+    let extension = convert(&short_name[8..], nt_res & LOWERCASE_EXTENSION != 0);
+    if extension.is_empty() {
+        base
+    } else {
+        alloc::format!("{}.{}", base, extension)
+    }
+}
+
+/// 長いファイル名として使えるか確かめる
+pub fn validate(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidName);
+    }
+    if name.encode_utf16().count() > MAX_NAME_UNITS {
+        return Err(FsError::NameTooLong);
+    }
+    if name
+        .chars()
+        .any(|c| c.is_control() || INVALID_LONG_CHARS.contains(&c))
+    {
+        return Err(FsError::InvalidName);
+    }
+    // 末尾の空白とピリオドはWindowsが取り除いてしまう
+    if name.ends_with(' ') || name.ends_with('.') {
+        return Err(FsError::InvalidName);
+    }
+    Ok(())
+}
+
+/// 名前がそのまま8.3形式で表せる場合は、短いファイル名とNTResを返す
+///
+/// 基本名と拡張子のそれぞれが、すべて大文字かすべて小文字であれば表せる
+pub fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    let mut nt_res = 0;
+    for (part, range, lower_flag) in [
+        (base, 0..8, LOWERCASE_BASE),
+        (extension, 8..11, LOWERCASE_EXTENSION),
+    ] {
+        let bytes = part.as_bytes();
+        if bytes
+            .iter()
+            .any(|&c| !c.is_ascii_graphic() || INVALID_SHORT_CHARS.contains(&c))
+        {
+            return None;
+        }
+
+        let has_lower = bytes.iter().any(u8::is_ascii_lowercase);
+        let has_upper = bytes.iter().any(u8::is_ascii_uppercase);
+        if has_lower && has_upper {
+            return None;
+        }
+        if has_lower {
+            nt_res |= lower_flag;
+        }
+        for (dst, &c) in short_name[range].iter_mut().zip(bytes) {
+            *dst = c.to_ascii_uppercase();
+        }
+    }
+
+    if short_name[0] == 0xe5 {
+        short_name[0] = 0x05;
+    }
+    Some((short_name, nt_res))
+}
+
+/// 長いファイル名から、`~1`のような番号付きの短いファイル名を作る
+///
+/// `exists`が`true`を返す間は番号を増やしていく
+pub fn generate_short_name(
+    name: &str,
+    mut exists: impl FnMut(&[u8; 11]) -> bool,
+) -> Result<[u8; 11], FsError> {
+    let strip = |part: &str, max: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii_graphic() && !INVALID_SHORT_CHARS.contains(&(c as u8)) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(max)
+            .collect()
+    };
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rsplit_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (trimmed, ""),
+    };
+    let mut base = strip(base, 8);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let extension = strip(extension, 3);
+
+    for number in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", number);
+        let keep = base.len().min(8 - tail.len());
+
+        let mut short_name = [b' '; 11];
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short_name[8..8 + extension.len()].copy_from_slice(&extension);
+        if short_name[0] == 0xe5 {
+            short_name[0] = 0x05;
+        }
+
+        if !exists(&short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FsError::AlreadyExists)
+}
+
+/// 長いファイル名を格納するLFNエントリを、ディレクトリ上に並べる順に作る
+pub fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; 32]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(UNITS_PER_ENTRY);
+    // 13文字に満たない最後のエントリは、0で終端して残りを0xFFFFで埋める
+    if !units.len().is_multiple_of(UNITS_PER_ENTRY) {
+        units.push(0);
+    }
+    units.resize(count * UNITS_PER_ENTRY, 0xffff);
+
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0; 32];
+            entry[0] = (i + 1) as u8 | if i + 1 == count { LAST_LONG_ENTRY } else { 0 };
+            entry[11] = super::dir::ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (j, &offset) in UNIT_OFFSETS.iter().enumerate() {
+                let unit = units[i * UNITS_PER_ENTRY + j];
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// LFNエントリから文字を取り出す
+pub fn long_name_units(entry: &[u8; 32]) -> [u16; UNITS_PER_ENTRY] {
+    UNIT_OFFSETS.map(|offset| u16::from_le_bytes([entry[offset], entry[offset + 1]]))
+}
+
+/// 集めたLFNエントリの文字から、長いファイル名を組み立てる
+pub fn decode_long_name(units: &[u16]) -> String {
+    let end = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+    char::decode_utf16(units[..end].iter().copied())
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// FATのファイル名の比較。ASCIIの大文字と小文字を区別しない
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
@@ -0,0 +1,302 @@
+//! FAT（ファイルアロケーションテーブル）の読み書きとクラスタの割り当て
+//!
+//! FATのコピーが複数ある場合は、読み込みは1つ目から行い、書き込みはすべてに行う
+
+use alloc::vec::Vec;
+
+use super::{boot_sector::FatType, FatFileSystem, FatState};
+use crate::{block, fs::FsError};
+
+/// FAT32のエントリのうち、クラスタ番号として使う下位28ビット
+const FAT32_MASK: u32 = 0x0fff_ffff;
+
+/// FSInfoセクタの署名と、空きクラスタ数・次の空きクラスタの位置
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_NEXT_FREE: usize = 492;
+/// FSInfoの値が不明であることを表す
+const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// 1つ目のFATからエントリを読む
+///
+/// 最後に読んだセクタを持っておき、同じセクタにあるエントリはデバイスから読み直さない。
+/// チェーンをたどる間や、FATを順に調べる間は同じ`FatReader`を使う
+struct FatReader<'a> {
+    fs: &'a FatFileSystem,
+    /// `data`に読み込んであるセクタの番号
+    sector: Option<u64>,
+    data: Vec<u8>,
+}
+
+impl<'a> FatReader<'a> {
+    fn new(fs: &'a FatFileSystem) -> Self {
+        FatReader {
+            fs,
+            sector: None,
+            data: Vec::new(),
+        }
+    }
+
+    /// ボリューム先頭から`position`バイト目の1バイトを読む
+    fn byte(&mut self, position: u64) -> Result<u8, FsError> {
+        let sector_size = self.fs.boot.bytes_per_sector as u64;
+        let sector = position / sector_size;
+        if self.sector != Some(sector) {
+            self.data = block::read_sector(self.fs.device.as_ref(), sector)?;
+            self.sector = Some(sector);
+        }
+        Ok(self.data[(position % sector_size) as usize])
+    }
+
+    /// `cluster`のエントリを読む
+    fn entry(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.fs.fat_byte(0, self.fs.entry_offset(cluster));
+        // FAT12のエントリはセクタ境界をまたぐことがあるため、1バイトずつ読む
+        let mut bytes = [0; 4];
+        let len = match self.fs.boot.fat_type {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
+        for (i, byte) in bytes[..len].iter_mut().enumerate() {
+            *byte = self.byte(offset + i as u64)?;
+        }
+        let value = u32::from_le_bytes(bytes);
+
+        Ok(match self.fs.boot.fat_type {
+            FatType::Fat12 if cluster & 1 == 0 => value & 0xfff,
+            FatType::Fat12 => value >> 4,
+            FatType::Fat16 => value,
+            FatType::Fat32 => value & FAT32_MASK,
+        })
+    }
+
+    /// `cluster`の次のクラスタを返す。終端であれば`None`を返す
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let value = self.entry(cluster)?;
+        if self.fs.is_end_of_chain(value) {
+            Ok(None)
+        } else if self.fs.boot.is_valid_cluster(value) {
+            Ok(Some(value))
+        } else {
+            Err(FsError::Corrupted("invalid cluster in chain"))
+        }
+    }
+}
+
+impl FatFileSystem {
+    /// 終端を表すFATエントリの値
+    fn end_of_chain(&self) -> u32 {
+        match self.boot.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => FAT32_MASK,
+        }
+    }
+
+    /// `value`がチェーンの終端を表していれば`true`を返す
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.boot.fat_type {
+            FatType::Fat12 => value >= 0xff8,
+            FatType::Fat16 => value >= 0xfff8,
+            FatType::Fat32 => value >= 0x0fff_fff8,
+        }
+    }
+
+    /// `cluster`のエントリの、FAT先頭からのバイト位置
+    fn entry_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        match self.boot.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// `index`番目のFATの先頭からのバイト位置を、ボリューム先頭からのバイト位置にする
+    fn fat_byte(&self, index: u32, offset: u64) -> u64 {
+        let start = self.boot.reserved_sectors + index * self.boot.sectors_per_fat;
+        start as u64 * self.boot.bytes_per_sector as u64 + offset
+    }
+
+    /// すべてのFATの`cluster`のエントリに`value`を書き込む
+    pub(super) fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for index in 0..self.boot.fat_count {
+            let offset = self.fat_byte(index, self.entry_offset(cluster));
+            match self.boot.fat_type {
+                FatType::Fat12 => {
+                    // 12ビットのエントリは、隣のエントリと1バイトを共有する
+                    let mut bytes = [0; 2];
+                    self.read_bytes(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let new = if cluster & 1 == 0 {
+                        (old & 0xf000) | (value as u16 & 0x0fff)
+                    } else {
+                        (old & 0x000f) | ((value as u16) << 4)
+                    };
+                    self.write_bytes(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write_bytes(offset, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // 上位4ビットは予約されているため、元の値を残す
+                    let mut bytes = [0; 4];
+                    self.read_bytes(offset, &mut bytes)?;
+                    let old = u32::from_le_bytes(bytes);
+                    let new = (old & !FAT32_MASK) | (value & FAT32_MASK);
+                    self.write_bytes(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `cluster`の次のクラスタを返す。終端であれば`None`を返す
+    pub(super) fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        FatReader::new(self).next_cluster(cluster)
+    }
+
+    /// `first`から始まるクラスタチェーンを列挙する。`first`が0なら空を返す
+    pub(super) fn cluster_chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut reader = FatReader::new(self);
+        let mut current = match first {
+            0 => return Ok(chain),
+            cluster if self.boot.is_valid_cluster(cluster) => Some(cluster),
+            _ => return Err(FsError::Corrupted("invalid first cluster")),
+        };
+
+        while let Some(cluster) = current {
+            // 循環したチェーンで止まらなくならないようにする
+            if chain.len() > self.boot.cluster_count as usize {
+                return Err(FsError::Corrupted("cluster chain loops"));
+            }
+            chain.push(cluster);
+            current = reader.next_cluster(cluster)?;
+        }
+        Ok(chain)
+    }
+
+    /// 空きクラスタを1つ割り当てて終端にし、`previous`があればその後ろに繋ぐ
+    ///
+    /// 割り当てたクラスタの中身は0で埋める
+    pub(super) fn allocate_cluster(
+        &self,
+        state: &mut FatState,
+        previous: Option<u32>,
+    ) -> Result<u32, FsError> {
+        let count = self.boot.cluster_count;
+        let start = state.next_free.clamp(2, count + 1);
+
+        let mut reader = FatReader::new(self);
+        let mut found = None;
+        for i in 0..count {
+            let cluster = 2 + (start - 2 + i) % count;
+            if reader.entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.set_fat_entry(cluster, self.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        self.zero_cluster(cluster)?;
+
+        state.next_free = cluster + 1;
+        if let Some(free) = state.free_clusters.as_mut() {
+            *free = free.saturating_sub(1);
+        }
+        state.fs_info_dirty = true;
+        Ok(cluster)
+    }
+
+    /// `first`から始まるクラスタチェーンをすべて解放する
+    pub(super) fn free_chain(&self, state: &mut FatState, first: u32) -> Result<(), FsError> {
+        for cluster in self.cluster_chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+            if let Some(free) = state.free_clusters.as_mut() {
+                *free += 1;
+            }
+        }
+        state.fs_info_dirty = true;
+        Ok(())
+    }
+
+    /// `last`を終端にし、その後ろのクラスタを解放する
+    pub(super) fn truncate_chain(&self, state: &mut FatState, last: u32) -> Result<(), FsError> {
+        if let Some(next) = self.next_cluster(last)? {
+            self.set_fat_entry(last, self.end_of_chain())?;
+            self.free_chain(state, next)?;
+        }
+        Ok(())
+    }
+
+    /// 空きクラスタを数える
+    pub(super) fn count_free_clusters(&self) -> Result<u32, FsError> {
+        let mut reader = FatReader::new(self);
+        let mut free = 0;
+        for cluster in 2..self.boot.cluster_count + 2 {
+            if reader.entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /// FAT32のFSInfoセクタから、空きクラスタ数と次の空きクラスタの位置を読む
+    pub(super) fn read_fs_info(&self) -> Result<(Option<u32>, Option<u32>), FsError> {
+        let Some(offset) = self.fs_info_offset() else {
+            return Ok((None, None));
+        };
+
+        let mut info = [0; 512];
+        self.read_bytes(offset, &mut info)?;
+        let u32_at =
+            |i: usize| u32::from_le_bytes([info[i], info[i + 1], info[i + 2], info[i + 3]]);
+        if u32_at(0) != FS_INFO_LEAD_SIGNATURE || u32_at(484) != FS_INFO_STRUCT_SIGNATURE {
+            return Ok((None, None));
+        }
+
+        let known = |value: u32| (value != FS_INFO_UNKNOWN).then_some(value);
+        Ok((
+            known(u32_at(FS_INFO_FREE_COUNT)).filter(|&free| free <= self.boot.cluster_count),
+            known(u32_at(FS_INFO_NEXT_FREE)).filter(|&next| self.boot.is_valid_cluster(next)),
+        ))
+    }
+
+    /// FAT32のFSInfoセクタに、空きクラスタ数と次の空きクラスタの位置を書き込む
+    pub(super) fn write_fs_info(&self, state: &mut FatState) -> Result<(), FsError> {
+        let Some(offset) = self.fs_info_offset() else {
+            return Ok(());
+        };
+        if !state.fs_info_dirty {
+            return Ok(());
+        }
+
+        let mut signature = [0; 4];
+        self.read_bytes(offset, &mut signature)?;
+        if u32::from_le_bytes(signature) != FS_INFO_LEAD_SIGNATURE {
+            return Ok(());
+        }
+
+        let free = state.free_clusters.unwrap_or(FS_INFO_UNKNOWN);
+        self.write_bytes(offset + FS_INFO_FREE_COUNT as u64, &free.to_le_bytes())?;
+        self.write_bytes(
+            offset + FS_INFO_NEXT_FREE as u64,
+            &state.next_free.to_le_bytes(),
+        )?;
+        state.fs_info_dirty = false;
+        Ok(())
+    }
+
+    fn fs_info_offset(&self) -> Option<u64> {
+        match (self.boot.fat_type, self.boot.fs_info_sector) {
+            (FatType::Fat32, sector) if sector != 0 && sector != 0xffff => {
+                Some(sector as u64 * self.boot.bytes_per_sector as u64)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! 開いたファイル
+//!
+//! `OpenFile`は開いたときのモードと現在のオフセットを持ち、`read()`や`write()`のたびにオフセットを進める。
+//! 複数の利用者で共有する場合は`Arc`に入れて使う
+
+use alloc::{string::String, sync::Arc};
+use core::fmt;
+
+use spin::Mutex;
+
+use super::{dentry::Dentry, DirEntry, FileType, FsError, Metadata};
+
+/// ファイルを開くときのモード
+///
+/// 既定ではどのモードも無効で、シンボリックリンクはたどる
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    directory: bool,
+    no_follow: bool,
+}
+
+impl OpenOptions {
+    pub const fn new() -> Self {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            directory: false,
+            no_follow: false,
+        }
+    }
+
+    /// 読み込めるように開く
+    pub const fn read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+
+    /// 書き込めるように開く
+    pub const fn write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// 書き込むたびにオフセットをファイルの終わりに移す。`write`も有効になる
+    pub const fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// 開いたときにファイルを空にする。`write`が有効でなければ無視される
+    pub const fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// ファイルが無ければ作る
+    pub const fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// ファイルを新しく作る。すでにある場合は`AlreadyExists`を返す
+    pub const fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// ディレクトリでなければ`NotADirectory`を返す
+    pub const fn directory(mut self, directory: bool) -> Self {
+        self.directory = directory;
+        self
+    }
+
+    /// パスの最後の項目がシンボリックリンクでもたどらない
+    pub const fn no_follow(mut self, no_follow: bool) -> Self {
+        self.no_follow = no_follow;
+        self
+    }
+
+    pub(super) fn is_writable(&self) -> bool {
+        self.write || self.append
+    }
+
+    pub(super) fn creates(&self) -> bool {
+        self.create || self.create_new
+    }
+
+    pub(super) fn is_exclusive(&self) -> bool {
+        self.create_new
+    }
+
+    pub(super) fn truncates(&self) -> bool {
+        self.truncate && self.is_writable()
+    }
+
+    pub(super) fn requires_directory(&self) -> bool {
+        self.directory
+    }
+
+    pub(super) fn follows_last(&self) -> bool {
+        !self.no_follow
+    }
+}
+
+/// `OpenFile::seek()`で移動する位置の基準
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// ファイルの先頭から
+    Start(u64),
+    /// ファイルの終わりから
+    End(i64),
+    /// 現在のオフセットから
+    Current(i64),
+}
+
+/// 開いたファイル
+pub struct OpenFile {
+    dentry: Arc<Dentry>,
+    options: OpenOptions,
+    /// 次に読み書きするバイト位置。ディレクトリでは次に返す項目の番号
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub(super) fn new(dentry: Arc<Dentry>, options: OpenOptions) -> Self {
+        OpenFile {
+            dentry,
+            options,
+            offset: Mutex::new(0),
+        }
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    /// 開いたファイルの絶対パス
+    pub fn path(&self) -> String {
+        self.dentry.path()
+    }
+
+    pub fn options(&self) -> OpenOptions {
+        self.options
+    }
+
+    pub fn offset(&self) -> u64 {
+        *self.offset.lock()
+    }
+
+    pub fn stat(&self) -> Result<Metadata, FsError> {
+        self.dentry.metadata()
+    }
+
+    /// 現在のオフセットから読み込み、読んだ分だけオフセットを進める
+    ///
+    /// 読んだバイト数を返す。ファイルの終わりでは0を返す
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        let len = self.read_at(*offset, buffer)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// 現在のオフセットに書き込み、書いた分だけオフセットを進める
+    ///
+    /// `append`で開いた場合は、ファイルの終わりに書き込む
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        if self.options.append {
+            *offset = self.dentry.metadata()?.size;
+        }
+        let len = self.write_at(*offset, buffer)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// オフセットを変えずに、`offset`バイト目から読み込む
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if !self.options.read {
+            return Err(FsError::PermissionDenied);
+        }
+        self.dentry.inode().read_at(offset, buffer)
+    }
+
+    /// オフセットを変えずに、`offset`バイト目に書き込む
+    pub fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        self.dentry.inode().write_at(offset, buffer)
+    }
+
+    /// オフセットを移動し、移動後のオフセットを返す
+    ///
+    /// ファイルの終わりより先に移動してもよい。その位置に書き込むと、間は0で埋まる
+    pub fn seek(&self, position: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let new_offset = match position {
+            SeekFrom::Start(start) => Some(start),
+            SeekFrom::End(delta) => self.dentry.metadata()?.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+        };
+        *offset = new_offset.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    /// ディレクトリの次の項目を返し、オフセットを進める。最後まで読んだら`None`を返す
+    ///
+    /// `.`と`..`は含まない。`seek(SeekFrom::Start(0))`で最初に戻る
+    pub fn read_dir(&self) -> Result<Option<DirEntry>, FsError> {
+        let mut offset = self.offset.lock();
+        let entries = self.dentry.inode().read_dir()?;
+        let Some(entry) = usize::try_from(*offset)
+            .ok()
+            .and_then(|index| entries.into_iter().nth(index))
+        else {
+            return Ok(None);
+        };
+        *offset += 1;
+        Ok(Some(entry))
+    }
+
+    /// ファイルの大きさを`size`バイトにする
+    pub fn set_len(&self, size: u64) -> Result<(), FsError> {
+        self.check_writable()?;
+        self.dentry.inode().truncate(size)
+    }
+
+    /// 書き込みを記録媒体に反映させる
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.dentry.inode().sync()
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if !self.options.is_writable() {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.dentry.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if self.dentry.kind()? == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenFile")
+            .field("path", &self.path())
+            .field("options", &self.options)
+            .field("offset", &self.offset())
+            .finish()
+    }
+}
@@ -0,0 +1,183 @@
+//! マウントテーブル
+//!
+//! マウントしたファイルシステムを、マウント先のディレクトリと組にして記録する。
+//! パスをたどる途中でマウント先のディレクトリに来たら、マウントしたファイルシステムのルートに乗り換える
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::fmt;
+
+use spin::RwLock;
+
+use super::{dentry::Dentry, FileSystem, FsError};
+
+/// マウントしたファイルシステム1つ分
+pub struct Mount {
+    id: usize,
+    path: String,
+    fs: Arc<dyn FileSystem>,
+    read_only: bool,
+}
+
+impl Mount {
+    /// マウントごとに異なる番号
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// マウントした時点のマウント先の絶対パス
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn fs(&self) -> &Arc<dyn FileSystem> {
+        &self.fs
+    }
+
+    /// 読み込み専用でマウントしたか、ファイルシステム自体が読み込み専用かどうか
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || self.fs.is_read_only()
+    }
+}
+
+impl fmt::Debug for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mount")
+            .field("id", &self.id)
+            .field("path", &self.path)
+            .field("fs", &self.fs.name())
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+struct MountEntry {
+    mount: Arc<Mount>,
+    /// マウントしたファイルシステムのルートディレクトリ
+    root: Arc<Dentry>,
+    /// マウント先のディレクトリが属するマウントの番号と、そのinode番号。ルートファイルシステムでは`None`
+    covered: Option<(usize, u64)>,
+}
+
+struct MountTable {
+    entries: Vec<MountEntry>,
+    next_id: usize,
+}
+
+static MOUNTS: RwLock<MountTable> = RwLock::new(MountTable {
+    entries: Vec::new(),
+    next_id: 0,
+});
+
+/// ルートファイルシステムのルートディレクトリ
+///
+/// まだ何もマウントしていない場合は`NotFound`を返す
+pub(super) fn root() -> Result<Arc<Dentry>, FsError> {
+    MOUNTS
+        .read()
+        .entries
+        .iter()
+        .find(|entry| entry.covered.is_none())
+        .map(|entry| entry.root.clone())
+        .ok_or(FsError::NotFound)
+}
+
+/// `dentry`の上にファイルシステムがマウントされていれば、そのルートディレクトリを返す
+pub(super) fn mounted_on(dentry: &Dentry) -> Result<Option<Arc<Dentry>>, FsError> {
+    let mount_id = dentry.mount().id();
+    let table = MOUNTS.read();
+    let mut candidates = table
+        .entries
+        .iter()
+        .filter(|entry| entry.covered.is_some_and(|(id, _)| id == mount_id))
+        .peekable();
+    if candidates.peek().is_none() {
+        return Ok(None);
+    }
+
+    let inode = dentry.metadata()?.inode;
+    Ok(candidates
+        .find(|entry| entry.covered == Some((mount_id, inode)))
+        .map(|entry| entry.root.clone()))
+}
+
+/// `dentry`がマウントしたファイルシステムのルートディレクトリかどうか
+pub(super) fn is_mount_root(dentry: &Arc<Dentry>) -> bool {
+    MOUNTS
+        .read()
+        .entries
+        .iter()
+        .any(|entry| Arc::ptr_eq(&entry.root, dentry))
+}
+
+/// `fs`を`mountpoint`にマウントする。`mountpoint`が`None`ならルートファイルシステムとしてマウントする
+///
+/// `mountpoint`はディレクトリで、ほかのファイルシステムがマウントされていてはならない
+pub(super) fn add(
+    mountpoint: Option<&Arc<Dentry>>,
+    fs: Arc<dyn FileSystem>,
+    read_only: bool,
+) -> Result<Arc<Mount>, FsError> {
+    let covered = match mountpoint {
+        Some(dentry) => Some((dentry.mount().id(), dentry.metadata()?.inode)),
+        None => None,
+    };
+
+    let mut table = MOUNTS.write();
+    let busy = match (mountpoint, covered) {
+        (Some(dentry), Some(covered)) => table
+            .entries
+            .iter()
+            .any(|entry| entry.covered == Some(covered) || Arc::ptr_eq(&entry.root, dentry)),
+        _ => table.entries.iter().any(|entry| entry.covered.is_none()),
+    };
+    if busy {
+        return Err(FsError::Busy);
+    }
+
+    let mount = Arc::new(Mount {
+        id: table.next_id,
+        path: mountpoint.map_or_else(|| String::from("/"), |dentry| dentry.path()),
+        fs,
+        read_only,
+    });
+    table.next_id += 1;
+    table.entries.push(MountEntry {
+        mount: mount.clone(),
+        root: Dentry::mount_root(mount.clone(), mountpoint),
+        covered,
+    });
+    Ok(mount)
+}
+
+/// ルートディレクトリが`root`であるマウントを外す
+///
+/// その中にほかのファイルシステムがマウントされている場合は`Busy`を返す
+pub(super) fn remove(root: &Arc<Dentry>) -> Result<Arc<Mount>, FsError> {
+    let mut table = MOUNTS.write();
+    let index = table
+        .entries
+        .iter()
+        .position(|entry| Arc::ptr_eq(&entry.root, root))
+        .ok_or(FsError::InvalidArgument)?;
+
+    let id = table.entries[index].mount.id;
+    if table
+        .entries
+        .iter()
+        .any(|entry| entry.covered.is_some_and(|(covered, _)| covered == id))
+    {
+        return Err(FsError::Busy);
+    }
+
+    Ok(table.entries.remove(index).mount)
+}
+
+/// マウントしているファイルシステムを、マウントした順に返す
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS
+        .read()
+        .entries
+        .iter()
+        .map(|entry| entry.mount.clone())
+        .collect()
+}
@@ -0,0 +1,115 @@
+//! パスの解決
+//!
+//! `/`で始まるパスはルートディレクトリから、それ以外は基準のディレクトリからたどる。
+//! `.`と`..`を処理し、途中のシンボリックリンクとマウントポイントをたどる
+
+use alloc::sync::Arc;
+
+use super::{dentry::Dentry, mount, FileType, FsError};
+
+/// 1回のパス解決でシンボリックリンクをたどる回数の上限
+pub const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// `base`を基準に`path`をたどり、最後の項目の`Dentry`を返す
+///
+/// `follow_last`が`false`の場合、最後の項目がシンボリックリンクでもたどらずにそのまま返す。
+/// ただし、パスが`/`で終わる場合は常にたどる
+pub(super) fn resolve(
+    base: &Arc<Dentry>,
+    path: &str,
+    follow_last: bool,
+) -> Result<Arc<Dentry>, FsError> {
+    let mut depth = 0;
+    walk(base, path, follow_last, &mut depth)
+}
+
+/// `base`を基準に、`path`の最後の項目を含むディレクトリをたどり、そのディレクトリと最後の項目の名前を返す
+///
+/// 最後の項目が`.`や`..`である場合やパスが`/`そのものの場合は`InvalidArgument`を返す
+pub(super) fn resolve_parent<'a>(
+    base: &Arc<Dentry>,
+    path: &'a str,
+) -> Result<(Arc<Dentry>, &'a str), FsError> {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return Err(if path.is_empty() {
+            FsError::NotFound
+        } else {
+            FsError::InvalidArgument
+        });
+    }
+
+    let (dir, name) = match trimmed.rfind('/') {
+        Some(index) => (&trimmed[..=index], &trimmed[index + 1..]),
+        None => (".", trimmed),
+    };
+    if name == "." || name == ".." {
+        return Err(FsError::InvalidArgument);
+    }
+
+    Ok((resolve(base, dir, true)?, name))
+}
+
+fn walk(
+    base: &Arc<Dentry>,
+    path: &str,
+    follow_last: bool,
+    depth: &mut usize,
+) -> Result<Arc<Dentry>, FsError> {
+    if path.is_empty() {
+        return Err(FsError::NotFound);
+    }
+
+    let mut current = if path.starts_with('/') {
+        mount::root()?
+    } else {
+        base.clone()
+    };
+    let must_be_directory = path.ends_with('/');
+
+    let mut components = path.split('/').filter(|name| !name.is_empty()).peekable();
+    while let Some(name) = components.next() {
+        let follow = follow_last || must_be_directory || components.peek().is_some();
+        current = step(&current, name, follow, depth)?;
+    }
+
+    if must_be_directory && current.kind()? != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    Ok(current)
+}
+
+/// ディレクトリ`current`から`name`を1つたどる
+fn step(
+    current: &Arc<Dentry>,
+    name: &str,
+    follow: bool,
+    depth: &mut usize,
+) -> Result<Arc<Dentry>, FsError> {
+    if current.kind()? != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    match name {
+        "." => Ok(current.clone()),
+        // マウントしたファイルシステムのルートの親は、マウントポイントの親を指している
+        ".." => Ok(current.parent().unwrap_or(current).clone()),
+        _ => {
+            let mut child = current.child(name, current.inode().lookup(name)?);
+            if let Some(root) = mount::mounted_on(&child)? {
+                child = root;
+            }
+
+            if follow && child.kind()? == FileType::Symlink {
+                *depth += 1;
+                if *depth > MAX_SYMLINK_DEPTH {
+                    return Err(FsError::TooManyLinks);
+                }
+                // 相対パスのリンク先は、リンクを置いたディレクトリを基準にする
+                let target = child.inode().read_link()?;
+                child = walk(current, &target, true, depth)?;
+            }
+            Ok(child)
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! tarアーカイブを読み込み専用のファイルシステムとして扱う
+//!
+//! 初期RAMディスクのように、メモリ上に置かれたアーカイブをそのまま使う。
+//! ustar形式とGNU形式の長いファイル名に対応する。ファイルの中身はコピーせず、アーカイブを直接参照する
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+
+/// ヘッダやデータを区切る単位
+const BLOCK_SIZE: usize = 512;
+
+/// ルートディレクトリのinode番号
+const ROOT_INODE: u64 = 1;
+
+/// ヘッダの`typeflag`の値
+mod type_flag {
+    pub const REGULAR: u8 = b'0';
+    /// 古い形式の通常ファイル
+    pub const REGULAR_OLD: u8 = 0;
+    pub const HARD_LINK: u8 = b'1';
+    pub const SYMLINK: u8 = b'2';
+    pub const DIRECTORY: u8 = b'5';
+    /// 次の項目の長いファイル名（GNU形式）
+    pub const GNU_LONG_NAME: u8 = b'L';
+    /// 次の項目の長いリンク先（GNU形式）
+    pub const GNU_LONG_LINK: u8 = b'K';
+}
+
+/// ヘッダ1つ分
+struct Header<'a> {
+    name: &'a [u8],
+    size: usize,
+    type_flag: u8,
+    link_name: &'a [u8],
+    prefix: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    fn parse(block: &'a [u8]) -> Result<Self, FsError> {
+        let checksum = parse_octal(&block[148..156])?;
+        let sum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+            .sum();
+        if checksum != sum {
+            return Err(FsError::Corrupted("tar header checksum mismatch"));
+        }
+
+        Ok(Header {
+            name: trim_nul(&block[0..100]),
+            size: parse_octal(&block[124..136])?
+                .try_into()
+                .map_err(|_| FsError::FileTooLarge)?,
+            type_flag: block[156],
+            link_name: trim_nul(&block[157..257]),
+            // GNU形式では`prefix`の位置に別の情報が入っている
+            prefix: if &block[257..263] == b"ustar\0" {
+                trim_nul(&block[345..500])
+            } else {
+                &[]
+            },
+        })
+    }
+}
+
+/// NUL終端または空白埋めされた8進数を読む
+fn parse_octal(field: &[u8]) -> Result<u64, FsError> {
+    let digits = trim_nul(field);
+    let digits = digits
+        .iter()
+        .copied()
+        .skip_while(|&c| c == b' ')
+        .take_while(|&c| c != b' ');
+    let mut value: u64 = 0;
+    for c in digits {
+        if !(b'0'..=b'7').contains(&c) {
+            return Err(FsError::Corrupted("invalid number in tar header"));
+        }
+        value = value
+            .checked_mul(8)
+            .ok_or(FsError::Corrupted("number in tar header is too large"))?
+            + (c - b'0') as u64;
+    }
+    Ok(value)
+}
+
+fn trim_nul(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+fn to_str(bytes: &[u8]) -> Result<&str, FsError> {
+    core::str::from_utf8(bytes).map_err(|_| FsError::Corrupted("file name is not valid UTF-8"))
+}
+
+/// パスを項目の名前に分ける。先頭の`/`や`./`、空の項目は取り除く
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+}
+
+/// アーカイブを読む間だけ使う、組み立て途中のノード
+#[derive(Default)]
+struct PendingNode {
+    kind: Option<FileType>,
+    data: &'static [u8],
+    link_target: String,
+    children: BTreeMap<String, PendingNode>,
+}
+
+impl PendingNode {
+    fn directory() -> Self {
+        PendingNode {
+            kind: Some(FileType::Directory),
+            ..Default::default()
+        }
+    }
+
+    /// `path`の位置にあるノードを返す。途中のディレクトリが無ければ作る
+    fn entry(&mut self, path: &str) -> Result<&mut PendingNode, FsError> {
+        let mut node = self;
+        for name in components(path) {
+            if node.kind != Some(FileType::Directory) {
+                return Err(FsError::Corrupted("path goes through a non-directory"));
+            }
+            if name == ".." {
+                return Err(FsError::Corrupted("path contains .."));
+            }
+            node = node
+                .children
+                .entry(name.to_string())
+                .or_insert_with(PendingNode::directory);
+        }
+        Ok(node)
+    }
+
+    fn find(&self, path: &str) -> Option<&PendingNode> {
+        let mut node = self;
+        for name in components(path) {
+            node = node.children.get(name)?;
+        }
+        Some(node)
+    }
+
+    fn build(self, next_inode: &mut u64) -> Arc<TarNode> {
+        let inode = *next_inode;
+        *next_inode += 1;
+        let children = self
+            .children
+            .into_iter()
+            .map(|(name, child)| (name, child.build(next_inode)))
+            .collect();
+        Arc::new(TarNode {
+            inode,
+            kind: self.kind.unwrap_or(FileType::Directory),
+            data: self.data,
+            link_target: self.link_target,
+            children,
+        })
+    }
+}
+
+/// メモリ上のtarアーカイブを読み込み専用で見せるファイルシステム
+pub struct TarFileSystem {
+    root: Arc<TarNode>,
+}
+
+impl TarFileSystem {
+    /// `archive`を読み、ファイルシステムとして開く
+    ///
+    /// 途中のディレクトリがアーカイブに含まれていなくても作る。デバイスファイルなど、対応していない種類の項目は無視する
+    pub fn new(archive: &'static [u8]) -> Result<Arc<Self>, FsError> {
+        let mut root = PendingNode::directory();
+        let mut long_name: Option<&'static [u8]> = None;
+        let mut long_link: Option<&'static [u8]> = None;
+        let mut offset = 0;
+
+        while offset + BLOCK_SIZE <= archive.len() {
+            let block = &archive[offset..offset + BLOCK_SIZE];
+            // 終わりは0で埋められたブロックで示される
+            if block.iter().all(|&b| b == 0) {
+                break;
+            }
+
+            let header = Header::parse(block)?;
+            let data_start = offset + BLOCK_SIZE;
+            let data =
+                archive
+                    .get(data_start..data_start + header.size)
+                    .ok_or(FsError::Corrupted(
+                        "tar entry runs past the end of the archive",
+                    ))?;
+            offset = data_start + header.size.next_multiple_of(BLOCK_SIZE);
+
+            match header.type_flag {
+                type_flag::GNU_LONG_NAME => {
+                    long_name = Some(trim_nul(data));
+                    continue;
+                }
+                type_flag::GNU_LONG_LINK => {
+                    long_link = Some(trim_nul(data));
+                    continue;
+                }
+                _ => {}
+            }
+
+            let mut path = String::new();
+            match long_name.take() {
+                Some(name) => path.push_str(to_str(name)?),
+                None => {
+                    if !header.prefix.is_empty() {
+                        path.push_str(to_str(header.prefix)?);
+                        path.push('/');
+                    }
+                    path.push_str(to_str(header.name)?);
+                }
+            }
+            let link_name = to_str(long_link.take().unwrap_or(header.link_name))?;
+
+            let (kind, data, link_target) = match header.type_flag {
+                type_flag::REGULAR | type_flag::REGULAR_OLD if path.ends_with('/') => {
+                    (FileType::Directory, &[][..], String::new())
+                }
+                type_flag::REGULAR | type_flag::REGULAR_OLD => {
+                    (FileType::File, data, String::new())
+                }
+                type_flag::DIRECTORY => (FileType::Directory, &[][..], String::new()),
+                type_flag::SYMLINK => (FileType::Symlink, &[][..], link_name.to_string()),
+                // ハードリンクは、先に現れたファイルの中身を共有する
+                type_flag::HARD_LINK => {
+                    let target = root
+                        .find(link_name)
+                        .filter(|node| node.kind == Some(FileType::File))
+                        .ok_or(FsError::Corrupted("hard link to a missing file"))?;
+                    (FileType::File, target.data, String::new())
+                }
+                _ => continue,
+            };
+
+            let node = root.entry(&path)?;
+            if kind == FileType::Directory {
+                if node.kind != Some(FileType::Directory) {
+                    return Err(FsError::Corrupted("directory replaces a file"));
+                }
+                continue;
+            }
+            if !node.children.is_empty() {
+                return Err(FsError::Corrupted("file replaces a non-empty directory"));
+            }
+            // 同じパスが複数回現れた場合は、後のものを使う
+            *node = PendingNode {
+                kind: Some(kind),
+                data,
+                link_target,
+                children: BTreeMap::new(),
+            };
+        }
+
+        let mut next_inode = ROOT_INODE;
+        Ok(Arc::new(TarFileSystem {
+            root: root.build(&mut next_inode),
+        }))
+    }
+}
+
+impl FileSystem for TarFileSystem {
+    fn name(&self) -> &'static str {
+        "tar"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+/// tarアーカイブ中のファイルやディレクトリ
+pub struct TarNode {
+    inode: u64,
+    kind: FileType,
+    data: &'static [u8],
+    link_target: String,
+    children: BTreeMap<String, Arc<TarNode>>,
+}
+
+impl TarNode {
+    fn check_directory(&self) -> Result<(), FsError> {
+        if self.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(())
+    }
+}
+
+impl Inode for TarNode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            kind: self.kind,
+            inode: self.inode,
+            size: match self.kind {
+                FileType::File => self.data.len() as u64,
+                FileType::Symlink => self.link_target.len() as u64,
+                _ => 0,
+            },
+            links: 1,
+            read_only: true,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.check_directory()?;
+        match self.children.get(name) {
+            Some(child) => Ok(child.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        self.check_directory()?;
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.check_directory()?;
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        self.check_directory()?;
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.check_directory()?;
+        Ok(self
+            .children
+            .iter()
+            .map(|(name, child)| DirEntry {
+                name: name.clone(),
+                kind: child.kind,
+                inode: child.inode,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if self.kind != FileType::File {
+            return Err(FsError::IsADirectory);
+        }
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(self.data.len());
+        let len = buffer.len().min(self.data.len() - start);
+        buffer[..len].copy_from_slice(&self.data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        match self.kind {
+            FileType::File => Err(FsError::ReadOnly),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        match self.kind {
+            FileType::File => Err(FsError::ReadOnly),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match self.kind {
+            FileType::Symlink => Ok(self.link_target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
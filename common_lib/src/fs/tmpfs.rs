@@ -0,0 +1,543 @@
+//! カーネルのヒープ上に置く、書き込み可能なファイルシステム
+//!
+//! ファイルの中身はページ単位のバッファに、ディレクトリは名前からノードへのマップに保持する。
+//! 一度も書き込んでいないページは割り当てず、読むと0になる。
+//! 使えるバイト数には上限があり、ファイルの中身のほか、ノードやディレクトリの項目、シンボリックリンクの指す先に使う分も数える。
+//! 上限を超える場合やヒープから割り当てられない場合は`NoSpace`を返す
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+
+/// ファイルの中身を割り当てる単位
+pub const PAGE_SIZE: usize = 4096;
+
+/// ルートディレクトリのinode番号
+const ROOT_INODE: u64 = 1;
+
+/// 名前の最大バイト数
+const MAX_NAME_LEN: usize = 255;
+
+/// ノード1つに使うバイト数の見積もり。ノード本体と、inode番号の表の項目を含む
+const NODE_COST: usize = size_of::<TmpNode>() + 64;
+/// ディレクトリの項目1つに使うバイト数の見積もり。名前の分は別に数える
+const ENTRY_COST: usize = size_of::<(String, Arc<TmpNode>)>() + 16;
+/// ページの表の1項目に使うバイト数
+const PAGE_SLOT_COST: usize = size_of::<Option<Vec<u8>>>();
+
+/// 名前が`name`のディレクトリの項目に使うバイト数
+fn entry_cost(name: &str) -> usize {
+    ENTRY_COST + name.len()
+}
+
+/// ファイルシステムの全ノードで共有する状態
+struct Shared {
+    /// 使えるバイト数の上限
+    limit: usize,
+    used: AtomicUsize,
+    next_inode: AtomicU64,
+    /// 複数のノードのロックを取る操作は、先にこのロックを取って1つずつ行う
+    namespace: Mutex<()>,
+    /// inode番号からノードを引くための表。`rename()`や`link()`で渡された`Inode`を特定するのに使う
+    nodes: Mutex<BTreeMap<u64, Weak<TmpNode>>>,
+}
+
+impl Shared {
+    /// `bytes`バイト分の使用量を予約する。上限を超える場合は`NoSpace`を返す
+    fn reserve(&self, bytes: usize) -> Result<(), FsError> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let total = used
+                .checked_add(bytes)
+                .filter(|&total| total <= self.limit)
+                .ok_or(FsError::NoSpace)?;
+            match self
+                .used
+                .compare_exchange_weak(used, total, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return Ok(()),
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// ノードを作る。`NODE_COST`と中身に使う分は、呼び出し元が予約しておくこと
+    fn new_node(self: &Arc<Self>, content: Content) -> Arc<TmpNode> {
+        let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+        let node = Arc::new(TmpNode {
+            shared: self.clone(),
+            inode,
+            kind: content.kind(),
+            state: Mutex::new(NodeState { links: 1, content }),
+        });
+        self.nodes.lock().insert(inode, Arc::downgrade(&node));
+        node
+    }
+
+    /// `inode`がこのファイルシステムのノードであれば、そのノードを返す
+    fn find(&self, inode: &dyn Inode) -> Result<Arc<TmpNode>, FsError> {
+        let number = inode.metadata()?.inode;
+        // 最後の参照を落とすとノードの解放で`nodes`をロックするため、先にロックを外しておく
+        let node = self.nodes.lock().get(&number).and_then(Weak::upgrade);
+        node.filter(|node| core::ptr::addr_eq(Arc::as_ptr(node), inode as *const dyn Inode))
+            .ok_or(FsError::CrossDevice)
+    }
+}
+
+/// ヒープ上のファイルシステム
+pub struct TmpFileSystem {
+    shared: Arc<Shared>,
+    root: Arc<TmpNode>,
+}
+
+impl TmpFileSystem {
+    /// 最大`limit`バイトまで使える、空のファイルシステムを作る
+    ///
+    /// ルートディレクトリに使う分も上限に含める
+    pub fn new(limit: usize) -> Arc<Self> {
+        let shared = Arc::new(Shared {
+            limit,
+            used: AtomicUsize::new(NODE_COST),
+            next_inode: AtomicU64::new(ROOT_INODE),
+            namespace: Mutex::new(()),
+            nodes: Mutex::new(BTreeMap::new()),
+        });
+        let root = shared.new_node(Content::Directory(BTreeMap::new()));
+        Arc::new(TmpFileSystem { shared, root })
+    }
+
+    /// 使えるバイト数の上限
+    pub fn limit(&self) -> usize {
+        self.shared.limit
+    }
+
+    /// ファイルの中身やノード、名前などに使っているバイト数
+    pub fn used(&self) -> usize {
+        self.shared.used.load(Ordering::Relaxed)
+    }
+}
+
+impl FileSystem for TmpFileSystem {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// ファイルの中身
+#[derive(Default)]
+struct FileData {
+    /// ページごとのバッファ。`None`のページは0で埋まっているものとして扱う
+    pages: Vec<Option<Vec<u8>>>,
+    size: u64,
+}
+
+impl FileData {
+    /// ページとページの表に使っているバイト数
+    fn cost(&self) -> usize {
+        let allocated = self.pages.iter().filter(|page| page.is_some()).count();
+        allocated * PAGE_SIZE + self.pages.len() * PAGE_SLOT_COST
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+        let len = buffer.len().min((self.size - offset) as usize);
+        let offset = offset as usize;
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let (index, start) = (position / PAGE_SIZE, position % PAGE_SIZE);
+            let chunk = (PAGE_SIZE - start).min(len - done);
+            let dest = &mut buffer[done..done + chunk];
+            match self.pages.get(index) {
+                Some(Some(page)) => dest.copy_from_slice(&page[start..start + chunk]),
+                _ => dest.fill(0),
+            }
+            done += chunk;
+        }
+        len
+    }
+
+    /// `offset`バイト目から書き込み、書いたバイト数を返す
+    ///
+    /// 途中でページを割り当てられなくなった場合は、そこまでに書いたバイト数を返す。1バイトも書けなければエラーを返す
+    fn write_at(&mut self, shared: &Shared, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .filter(|&end| usize::try_from(end).is_ok_and(|end| end <= isize::MAX as usize))
+            .ok_or(FsError::FileTooLarge)?;
+        let offset = offset as usize;
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done;
+            let (index, start) = (position / PAGE_SIZE, position % PAGE_SIZE);
+            let chunk = (PAGE_SIZE - start).min(buffer.len() - done);
+            match self.page_mut(shared, index) {
+                Ok(page) => page[start..start + chunk].copy_from_slice(&buffer[done..done + chunk]),
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+            done += chunk;
+        }
+
+        self.size = self.size.max(if done == buffer.len() {
+            end
+        } else {
+            (offset + done) as u64
+        });
+        Ok(done)
+    }
+
+    /// `index`番目のページを返す。割り当てていなければ割り当てる
+    fn page_mut(&mut self, shared: &Shared, index: usize) -> Result<&mut Vec<u8>, FsError> {
+        if index >= self.pages.len() {
+            let added = index + 1 - self.pages.len();
+            shared.reserve(added * PAGE_SLOT_COST)?;
+            if self.pages.try_reserve(added).is_err() {
+                shared.release(added * PAGE_SLOT_COST);
+                return Err(FsError::NoSpace);
+            }
+            self.pages.resize_with(index + 1, || None);
+        }
+
+        let slot = &mut self.pages[index];
+        if slot.is_none() {
+            shared.reserve(PAGE_SIZE)?;
+            let mut page = Vec::new();
+            if page.try_reserve_exact(PAGE_SIZE).is_err() {
+                shared.release(PAGE_SIZE);
+                return Err(FsError::NoSpace);
+            }
+            page.resize(PAGE_SIZE, 0);
+            *slot = Some(page);
+        }
+        Ok(slot.as_mut().unwrap())
+    }
+
+    fn truncate(&mut self, shared: &Shared, size: u64) -> Result<(), FsError> {
+        if !usize::try_from(size).is_ok_and(|size| size <= isize::MAX as usize) {
+            return Err(FsError::FileTooLarge);
+        }
+
+        if size < self.size {
+            let size = size as usize;
+            let keep = size.div_ceil(PAGE_SIZE).min(self.pages.len());
+            let released = self.pages[keep..]
+                .iter()
+                .filter(|page| page.is_some())
+                .count();
+            let removed = self.pages.len() - keep;
+            self.pages.truncate(keep);
+            shared.release(released * PAGE_SIZE + removed * PAGE_SLOT_COST);
+
+            // 後で伸ばしたときに0が見えるよう、最後のページの残りを消す
+            if let Some(Some(page)) = self.pages.get_mut(size / PAGE_SIZE) {
+                page[size % PAGE_SIZE..].fill(0);
+            }
+        }
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// ノードの種類ごとの中身
+enum Content {
+    File(FileData),
+    Directory(BTreeMap<String, Arc<TmpNode>>),
+    Symlink(String),
+}
+
+impl Content {
+    /// ノード本体を除いて、中身に使っているバイト数
+    fn cost(&self) -> usize {
+        match self {
+            Content::File(data) => data.cost(),
+            Content::Directory(entries) => entries.keys().map(|name| entry_cost(name)).sum(),
+            Content::Symlink(target) => target.len(),
+        }
+    }
+
+    fn kind(&self) -> FileType {
+        match self {
+            Content::File(_) => FileType::File,
+            Content::Directory(_) => FileType::Directory,
+            Content::Symlink(_) => FileType::Symlink,
+        }
+    }
+}
+
+struct NodeState {
+    /// このノードを指すディレクトリの項目の数
+    links: u32,
+    content: Content,
+}
+
+/// ヒープ上のファイルシステムのファイル・ディレクトリ・シンボリックリンク
+pub struct TmpNode {
+    shared: Arc<Shared>,
+    inode: u64,
+    kind: FileType,
+    state: Mutex<NodeState>,
+}
+
+impl TmpNode {
+    /// 名前が正しく、まだ使われていないことを確かめる
+    fn check_new_name(entries: &BTreeMap<String, Arc<TmpNode>>, name: &str) -> Result<(), FsError> {
+        validate_name(name)?;
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(())
+    }
+
+    /// ディレクトリに新しいノードを加える
+    fn insert(&self, name: &str, content: Content) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.state.lock();
+        let entries = directory_mut(&mut state)?;
+        Self::check_new_name(entries, name)?;
+
+        self.shared
+            .reserve(NODE_COST + content.cost() + entry_cost(name))?;
+        let node = self.shared.new_node(content);
+        entries.insert(name.to_string(), node.clone());
+        Ok(node)
+    }
+
+    /// 置き換えられる項目`target`が、`source`で置き換えてよいものかどうかを確かめる
+    fn check_replace(source: &TmpNode, target: &TmpNode) -> Result<(), FsError> {
+        match (source.kind, target.kind) {
+            (FileType::Directory, FileType::Directory) => match &target.state.lock().content {
+                Content::Directory(entries) if !entries.is_empty() => {
+                    Err(FsError::DirectoryNotEmpty)
+                }
+                _ => Ok(()),
+            },
+            (FileType::Directory, _) => Err(FsError::NotADirectory),
+            (_, FileType::Directory) => Err(FsError::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    fn with_file<T>(
+        &self,
+        f: impl FnOnce(&mut FileData) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        match &mut self.state.lock().content {
+            Content::File(data) => f(data),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+}
+
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        let cost = NODE_COST + self.state.get_mut().content.cost();
+        self.shared.release(cost);
+        self.shared.nodes.lock().remove(&self.inode);
+    }
+}
+
+/// ディレクトリの項目を返す。ディレクトリでなければ`NotADirectory`を返す
+fn directory_mut(state: &mut NodeState) -> Result<&mut BTreeMap<String, Arc<TmpNode>>, FsError> {
+    match &mut state.content {
+        Content::Directory(entries) => Ok(entries),
+        _ => Err(FsError::NotADirectory),
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FsError::InvalidName);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+impl Inode for TmpNode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let state = self.state.lock();
+        let (size, links) = match &state.content {
+            Content::File(data) => (data.size, state.links),
+            Content::Symlink(target) => (target.len() as u64, state.links),
+            // ディレクトリは、親からの項目と自身の`.`、子ディレクトリの`..`から指される
+            Content::Directory(entries) => (
+                0,
+                2 + entries
+                    .values()
+                    .filter(|child| child.kind == FileType::Directory)
+                    .count() as u32,
+            ),
+        };
+        Ok(Metadata {
+            kind: self.kind,
+            inode: self.inode,
+            size,
+            links,
+            read_only: false,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.state.lock();
+        match directory_mut(&mut state)?.get(name) {
+            Some(node) => Ok(node.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let content = match kind {
+            FileType::File => Content::File(FileData::default()),
+            FileType::Directory => Content::Directory(BTreeMap::new()),
+            FileType::Symlink => return Err(FsError::InvalidArgument),
+            _ => return Err(FsError::NotSupported),
+        };
+        self.insert(name, content)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if target.is_empty() {
+            return Err(FsError::NotFound);
+        }
+        self.insert(name, Content::Symlink(target.to_string()))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let _namespace = self.shared.namespace.lock();
+        let mut state = self.state.lock();
+        let entries = directory_mut(&mut state)?;
+        let node = entries.get(name).ok_or(FsError::NotFound)?;
+
+        let mut node_state = node.state.lock();
+        if let Content::Directory(children) = &node_state.content {
+            if !children.is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        node_state.links -= 1;
+        drop(node_state);
+
+        // 開いている利用者がいなければ、ここでノードが解放される
+        entries.remove(name);
+        self.shared.release(entry_cost(name));
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_parent: &dyn Inode,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let new_parent = self.shared.find(new_parent)?;
+        validate_name(new_name)?;
+
+        let _namespace = self.shared.namespace.lock();
+        let same_dir = new_parent.inode == self.inode;
+        let mut first = self.state.lock();
+        let mut second = (!same_dir).then(|| new_parent.state.lock());
+
+        let source = directory_mut(&mut first)?
+            .get(old_name)
+            .cloned()
+            .ok_or(FsError::NotFound)?;
+        if Arc::ptr_eq(&source, &new_parent) {
+            return Err(FsError::InvalidArgument);
+        }
+        let target_entries = match &mut second {
+            Some(state) => directory_mut(state)?,
+            None => directory_mut(&mut first)?,
+        };
+
+        match target_entries.get(new_name) {
+            Some(target) => {
+                if Arc::ptr_eq(target, &source) {
+                    return Ok(());
+                }
+                // 移す元のディレクトリは、移すものを含んでいるので空ではない
+                if target.inode == self.inode {
+                    return Err(FsError::DirectoryNotEmpty);
+                }
+                Self::check_replace(&source, target)?;
+                target.state.lock().links -= 1;
+            }
+            None => self.shared.reserve(entry_cost(new_name))?,
+        }
+        target_entries.insert(new_name.to_string(), source);
+        directory_mut(&mut first)?.remove(old_name);
+        self.shared.release(entry_cost(old_name));
+        Ok(())
+    }
+
+    fn link(&self, name: &str, target: &dyn Inode) -> Result<(), FsError> {
+        let target = self.shared.find(target)?;
+        if target.kind == FileType::Directory {
+            return Err(FsError::PermissionDenied);
+        }
+
+        let _namespace = self.shared.namespace.lock();
+        let mut state = self.state.lock();
+        let entries = directory_mut(&mut state)?;
+        Self::check_new_name(entries, name)?;
+        self.shared.reserve(entry_cost(name))?;
+
+        target.state.lock().links += 1;
+        entries.insert(name.to_string(), target);
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut state = self.state.lock();
+        Ok(directory_mut(&mut state)?
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.kind,
+                inode: node.inode,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        self.with_file(|data| Ok(data.read_at(offset, buffer)))
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.with_file(|data| data.write_at(&self.shared, offset, buffer))
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.with_file(|data| data.truncate(&self.shared, size))
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &self.state.lock().content {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
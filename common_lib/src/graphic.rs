@@ -1 +1,4 @@
+pub mod ansi;
 pub mod console;
+pub mod damage;
+pub mod glyph_cache;
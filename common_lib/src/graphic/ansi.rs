@@ -0,0 +1,437 @@
+//! ANSI/VT100エスケープシーケンスを解釈するモジュール
+//!
+//! 一文字ずつ`AnsiParser::advance()`に渡すと、コンソールが実行すべき操作を`Action`として返す。
+//! SGR（文字の装飾）はパーサの内部で`Attribute`として保持するため、`Action`としては返さない
+//!
+//! 対応しているシーケンスは以下の通り:
+//! - SGR（`CSI n m`）: リセット、太字、16色・256色・24bitカラーの前景色と背景色。
+//!   拡張色は`38;5;n`の形式に加えて、`:`で区切る`38:5:n`、`38:2::r:g:b`の形式も受け付ける
+//! - CUP（`CSI n ; m H`、`CSI n ; m f`）、CUU/CUD/CUF/CUB（`CSI n A`〜`CSI n D`）
+//! - ED（`CSI n J`）、EL（`CSI n K`）
+//! - カーソル位置の保存と復元（`ESC 7`、`ESC 8`、`CSI s`、`CSI u`）
+//! - RIS（`ESC c`）
+
+use super::console::FontType;
+
+/// CSIシーケンスで受け付けるパラメータの最大数。これを超えた分は無視する
+const MAX_PARAMS: usize = 16;
+
+/// 既定の前景色
+pub const DEFAULT_FOREGROUND: [u8; 3] = [255, 255, 255];
+/// 既定の背景色
+pub const DEFAULT_BACKGROUND: [u8; 3] = [0, 0, 0];
+
+/// 16色のパレット。前半8色が通常、後半8色が明るい色
+const BASIC_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [205, 49, 49],
+    [13, 188, 121],
+    [229, 229, 16],
+    [36, 114, 200],
+    [188, 63, 188],
+    [17, 168, 205],
+    [229, 229, 229],
+    [102, 102, 102],
+    [241, 76, 76],
+    [35, 209, 139],
+    [245, 245, 67],
+    [59, 142, 234],
+    [214, 112, 214],
+    [41, 184, 219],
+    [255, 255, 255],
+];
+
+/// 消去する範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseMode {
+    /// カーソル位置から末尾まで
+    ToEnd,
+
+    /// 先頭からカーソル位置まで
+    ToStart,
+
+    /// 全体
+    All,
+
+    /// 画面全体とスクロールバック履歴（EDのみ）
+    AllWithScrollback,
+}
+
+impl EraseMode {
+    fn from_param(param: u16) -> Option<Self> {
+        match param {
+            0 => Some(EraseMode::ToEnd),
+            1 => Some(EraseMode::ToStart),
+            2 => Some(EraseMode::All),
+            3 => Some(EraseMode::AllWithScrollback),
+            _ => None,
+        }
+    }
+}
+
+/// コンソールが実行すべき操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// 文字を現在の`Attribute`で描画する
+    Print(char),
+
+    /// 制御文字（`\n`、`\r`、`\x08`、`\t`など）を実行する
+    Execute(char),
+
+    /// カーソルを指定した位置へ移動する。座標は左上を(0,0)とする
+    CursorPosition { column: usize, row: usize },
+
+    /// カーソルを上へ移動する
+    CursorUp(usize),
+
+    /// カーソルを下へ移動する
+    CursorDown(usize),
+
+    /// カーソルを右へ移動する
+    CursorForward(usize),
+
+    /// カーソルを左へ移動する
+    CursorBack(usize),
+
+    /// 行内を消去する
+    EraseInLine(EraseMode),
+
+    /// 画面を消去する
+    EraseInDisplay(EraseMode),
+
+    /// カーソル位置を保存する
+    SaveCursor,
+
+    /// 保存したカーソル位置に戻す
+    RestoreCursor,
+
+    /// 画面と状態をすべて初期化する
+    Reset,
+}
+
+/// 文字の装飾
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attribute {
+    pub font_type: FontType,
+    pub foreground: [u8; 3],
+    pub background: [u8; 3],
+}
+
+impl Attribute {
+    /// 既定の装飾（白の通常文字、黒背景）
+    pub const DEFAULT: Attribute = Attribute {
+        font_type: FontType::Text,
+        foreground: DEFAULT_FOREGROUND,
+        background: DEFAULT_BACKGROUND,
+    };
+}
+
+impl Default for Attribute {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// パーサの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// 通常の文字列
+    Ground,
+
+    /// `ESC`を受け取った直後
+    Escape,
+
+    /// `ESC [`以降のパラメータを読んでいる
+    Csi,
+
+    /// 解釈できないCSIシーケンスを終端まで読み飛ばしている
+    CsiIgnore,
+}
+
+/// エスケープシーケンスのパーサ
+#[derive(Debug, Clone)]
+pub struct AnsiParser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    /// `:`で区切られた、直前のパラメータの副パラメータであれば`true`
+    subparams: [bool; MAX_PARAMS],
+    /// `?`などのプライベートなシーケンスであれば`true`
+    private: bool,
+    attribute: Attribute,
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        AnsiParser {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            subparams: [false; MAX_PARAMS],
+            private: false,
+            attribute: Attribute::DEFAULT,
+        }
+    }
+
+    /// 現在の文字の装飾を返す
+    pub fn attribute(&self) -> &Attribute {
+        &self.attribute
+    }
+
+    /// 文字の装飾とパーサの状態を初期化する
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// 一文字読み進め、実行すべき操作があれば返す
+    pub fn advance(&mut self, character: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match character {
+                '\u{1b}' => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\u{0}'..='\u{1f}' | '\u{7f}' => Some(Action::Execute(character)),
+                _ => Some(Action::Print(character)),
+            },
+            State::Escape => {
+                self.state = State::Ground;
+                match character {
+                    '[' => {
+                        self.state = State::Csi;
+                        self.params = [0; MAX_PARAMS];
+                        self.param_count = 0;
+                        self.subparams = [false; MAX_PARAMS];
+                        self.private = false;
+                        None
+                    }
+                    '7' => Some(Action::SaveCursor),
+                    '8' => Some(Action::RestoreCursor),
+                    'c' => {
+                        self.reset();
+                        Some(Action::Reset)
+                    }
+                    // 未対応のシーケンスは捨てる
+                    _ => None,
+                }
+            }
+            State::Csi => self.advance_csi(character),
+            State::CsiIgnore => {
+                if is_final_byte(character) {
+                    self.state = State::Ground;
+                }
+                None
+            }
+        }
+    }
+
+    fn advance_csi(&mut self, character: char) -> Option<Action> {
+        match character {
+            '0'..='9' => {
+                if self.param_count == 0 {
+                    self.param_count = 1;
+                }
+                if let Some(param) = self.params.get_mut(self.param_count - 1) {
+                    let digit = character as u16 - '0' as u16;
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                }
+                None
+            }
+            ';' => {
+                // 空のパラメータは0として扱う
+                self.param_count = self.param_count.max(1) + 1;
+                None
+            }
+            ':' => {
+                self.param_count = self.param_count.max(1) + 1;
+                if let Some(subparam) = self.subparams.get_mut(self.param_count - 1) {
+                    *subparam = true;
+                }
+                None
+            }
+            '<' | '=' | '>' | '?' => {
+                self.private = true;
+                None
+            }
+            // 中間バイトを含むシーケンスには対応していない
+            ' '..='/' => {
+                self.state = State::CsiIgnore;
+                None
+            }
+            _ if is_final_byte(character) => {
+                self.state = State::Ground;
+                if self.private {
+                    None
+                } else {
+                    self.dispatch_csi(character)
+                }
+            }
+            // 制御文字はシーケンスの途中でも実行する
+            '\u{0}'..='\u{1f}' => Some(Action::Execute(character)),
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+
+    /// `index`番目のパラメータを返す。省略されていれば`default`を返す
+    fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params[..self.param_count.min(MAX_PARAMS)].get(index) {
+            Some(&0) | None => default,
+            Some(&param) => param,
+        }
+    }
+
+    fn dispatch_csi(&mut self, final_byte: char) -> Option<Action> {
+        let count = self.param(0, 1) as usize;
+
+        match final_byte {
+            'A' => Some(Action::CursorUp(count)),
+            'B' => Some(Action::CursorDown(count)),
+            'C' => Some(Action::CursorForward(count)),
+            'D' => Some(Action::CursorBack(count)),
+            'H' | 'f' => Some(Action::CursorPosition {
+                row: self.param(0, 1) as usize - 1,
+                column: self.param(1, 1) as usize - 1,
+            }),
+            'J' => EraseMode::from_param(self.param(0, 0)).map(Action::EraseInDisplay),
+            'K' => match EraseMode::from_param(self.param(0, 0)) {
+                Some(EraseMode::AllWithScrollback) | None => None,
+                Some(mode) => Some(Action::EraseInLine(mode)),
+            },
+            'm' => {
+                self.select_graphic_rendition();
+                None
+            }
+            's' => Some(Action::SaveCursor),
+            'u' => Some(Action::RestoreCursor),
+            _ => None,
+        }
+    }
+
+    /// SGRのパラメータに従って文字の装飾を変更する
+    fn select_graphic_rendition(&mut self) {
+        let count = self.param_count.clamp(1, MAX_PARAMS);
+        let mut index = 0;
+
+        while index < count {
+            let param = self.params[index];
+            index += 1;
+
+            // `:`で区切られた副パラメータは、拡張色のものだけを解釈する
+            let subparams = self.subparams[index..count]
+                .iter()
+                .take_while(|&&subparam| subparam)
+                .count();
+            if subparams > 0 {
+                if let (38 | 48, Some(color)) = (
+                    param,
+                    subparam_color(&self.params[index..index + subparams]),
+                ) {
+                    self.set_color(param, color);
+                }
+                index += subparams;
+                continue;
+            }
+
+            match param {
+                0 => self.attribute = Attribute::DEFAULT,
+                1 => self.attribute.font_type = FontType::Bold,
+                22 => self.attribute.font_type = FontType::Text,
+                30..=37 => self.attribute.foreground = BASIC_COLORS[(param - 30) as usize],
+                39 => self.attribute.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.attribute.background = BASIC_COLORS[(param - 40) as usize],
+                49 => self.attribute.background = DEFAULT_BACKGROUND,
+                90..=97 => self.attribute.foreground = BASIC_COLORS[(param - 90 + 8) as usize],
+                100..=107 => self.attribute.background = BASIC_COLORS[(param - 100 + 8) as usize],
+                38 | 48 => {
+                    let (color, used) = self.extended_color(index, count);
+                    index += used;
+                    if let Some(color) = color {
+                        self.set_color(param, color);
+                    }
+                }
+                // 未対応の装飾は無視する
+                _ => {}
+            }
+        }
+    }
+
+    /// SGRの`38`であれば前景色を、`48`であれば背景色を変更する
+    fn set_color(&mut self, param: u16, color: [u8; 3]) {
+        if param == 38 {
+            self.attribute.foreground = color;
+        } else {
+            self.attribute.background = color;
+        }
+    }
+
+    /// `38;5;n`（256色）または`38;2;r;g;b`（24bitカラー）形式の色を読む
+    ///
+    /// 色と、読み進めたパラメータの数を返す。範囲外の値を含む色は`None`になる
+    fn extended_color(&self, index: usize, count: usize) -> (Option<[u8; 3]>, usize) {
+        let rest = &self.params[index..count];
+
+        match rest {
+            [5, n, ..] => (palette_color(*n), 2),
+            [2, r, g, b, ..] => (rgb_color(*r, *g, *b), 4),
+            [5] | [2, ..] => (None, rest.len()),
+            _ => (None, 0),
+        }
+    }
+}
+
+impl Default for AnsiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CSIシーケンスの終端となる文字であれば`true`を返す
+fn is_final_byte(character: char) -> bool {
+    matches!(character, '@'..='~')
+}
+
+/// `38:5:n`、`38:2:r:g:b`、`38:2:色空間:r:g:b`形式の副パラメータから色を読む
+fn subparam_color(subparams: &[u16]) -> Option<[u8; 3]> {
+    match subparams {
+        [5, n] => palette_color(*n),
+        [2, r, g, b] => rgb_color(*r, *g, *b),
+        // 色空間の指定は無視する
+        [2, _, r, g, b, ..] => rgb_color(*r, *g, *b),
+        _ => None,
+    }
+}
+
+/// 256色パレットの`n`番目の色を返す。範囲外であれば`None`を返す
+fn palette_color(n: u16) -> Option<[u8; 3]> {
+    u8::try_from(n).ok().map(palette_256)
+}
+
+/// 各成分が0〜255に収まっていれば、24bitカラーとして返す
+fn rgb_color(r: u16, g: u16, b: u16) -> Option<[u8; 3]> {
+    Some([
+        u8::try_from(r).ok()?,
+        u8::try_from(g).ok()?,
+        u8::try_from(b).ok()?,
+    ])
+}
+
+/// 256色パレットの色を返す
+pub fn palette_256(index: u8) -> [u8; 3] {
+    match index {
+        0..=15 => BASIC_COLORS[index as usize],
+        // 6x6x6のカラーキューブ
+        16..=231 => {
+            const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+            let n = index as usize - 16;
+            [LEVELS[n / 36], LEVELS[(n / 6) % 6], LEVELS[n % 6]]
+        }
+        // 24段階のグレースケール
+        232..=255 => {
+            let level = 8 + (index - 232) * 10;
+            [level, level, level]
+        }
+    }
+}
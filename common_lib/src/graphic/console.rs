@@ -2,6 +2,8 @@
 
 // use alloc::vec::Vec;
 
+use super::ansi::EraseMode;
+
 /// コンソール機能を定義するトレイト
 pub trait Console {
     /// フレームバッファに文字を一文字出力するメソッド
@@ -19,10 +21,35 @@ pub trait Console {
 
     /// 画面表示をすべて消し、カーソルを初期位置に戻すメソッド
     fn reset(&mut self);
+
+    /// スクロールバック履歴を指定した行数だけ遡って表示するメソッド。
+    /// 履歴の先頭を超えて遡ることはない
+    fn scroll_back(&mut self, lines: usize);
+
+    /// 遡っていた表示を指定した行数だけ最新の出力側へ戻すメソッド
+    fn scroll_forward(&mut self, lines: usize);
+
+    /// 以降に出力する文字の背景色を設定するメソッド
+    fn set_background(&mut self, red_green_blue: [u8; 3]);
+
+    /// カーソルの位置を返すメソッド。左上を(0,0)とし、`(桁, 行)`の順に並ぶ
+    fn cursor_position(&self) -> (usize, usize);
+
+    /// カーソルを指定した位置へ移動するメソッド。画面外を指定した場合は画面の端に留める
+    fn move_cursor(&mut self, column: usize, row: usize);
+
+    /// カーソルのある行を消去するメソッド
+    fn erase_in_line(&mut self, mode: EraseMode);
+
+    /// 画面を消去するメソッド
+    fn erase_in_display(&mut self, mode: EraseMode);
+
+    /// 描画した内容のうち、まだ画面に反映していない部分を反映するメソッド
+    fn flush(&mut self);
 }
 
 /// フォントの種類を定義する
-#[derive(Debug, Default, Clone, Copy, Hash)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum FontType {
     /// 通常
     #[default]
@@ -0,0 +1,129 @@
+//! 画面のうち書き換えられた領域（ダメージ）を記録するモジュール
+//!
+//! 描画のたびに画面全体を転送するのではなく、書き換えた矩形だけを転送するために使う
+
+use alloc::vec::Vec;
+
+/// ピクセル単位の矩形。`x`と`y`は左上の座標
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// 右端の座標（この座標自体は含まない）
+    pub const fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    /// 下端の座標（この座標自体は含まない）
+    pub const fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// 二つの矩形を両方とも含む最小の矩形を返す
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
+
+    /// 二つの矩形が重なっているか、辺で接していれば`true`を返す
+    pub fn touches(&self, other: &Rect) -> bool {
+        self.x <= other.right()
+            && other.x <= self.right()
+            && self.y <= other.bottom()
+            && other.y <= self.bottom()
+    }
+
+    /// `width`×`height`の画面に収まるように切り詰めた矩形を返す
+    pub fn clip(&self, width: usize, height: usize) -> Rect {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Rect::new(
+            x,
+            y,
+            self.right().min(width) - x,
+            self.bottom().min(height) - y,
+        )
+    }
+}
+
+/// 書き換えられた矩形の集まり
+///
+/// 重なったり接したりしている矩形は一つにまとめる。
+/// 矩形の数が上限を超えた場合は、すべてを含む一つの矩形にまとめる
+pub struct Damage {
+    rects: Vec<Rect>,
+    max_rects: usize,
+}
+
+impl Damage {
+    /// 最大で`max_rects`個の矩形を個別に記録する
+    pub const fn new(max_rects: usize) -> Self {
+        Damage {
+            rects: Vec::new(),
+            max_rects,
+        }
+    }
+
+    /// 書き換えた矩形を追加する
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+
+        // 接している矩形を取り込んでいき、これ以上まとめられなくなったら追加する
+        let mut merged = rect;
+        while let Some(index) = self.rects.iter().position(|r| r.touches(&merged)) {
+            merged = merged.union(&self.rects.swap_remove(index));
+        }
+        self.rects.push(merged);
+
+        if self.rects.len() > self.max_rects {
+            let bounding = self
+                .rects
+                .iter()
+                .skip(1)
+                .fold(self.rects[0], |acc, r| acc.union(r));
+            self.rects.clear();
+            self.rects.push(bounding);
+        }
+    }
+
+    /// 書き換えられた矩形があれば`true`を返す
+    pub fn is_dirty(&self) -> bool {
+        !self.rects.is_empty()
+    }
+
+    /// 記録した矩形を取り出し、記録を空にする
+    pub fn take(&mut self) -> Vec<Rect> {
+        core::mem::take(&mut self.rects)
+    }
+
+    /// 記録を空にする
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
@@ -0,0 +1,177 @@
+//! ラスタライズ済みのグリフを保持しておくキャッシュ
+//!
+//! フォントの輪郭からのラスタライズは浮動小数点演算が多く重いため、一度描いた文字の濃淡を保存して使い回す。
+//! 保持する量はバイト数で制限し、超えた場合は最も長い間使われていないものから捨てる（LRU）
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::console::FontType;
+
+/// 一つのグリフを保持するのにかかる、濃淡データ以外の大まかなバイト数
+const ENTRY_OVERHEAD: usize = 64;
+
+/// キャッシュのキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GlyphKey {
+    pub character: char,
+    pub font_type: FontType,
+    /// 文字の大きさ。`f32`は`Ord`を実装していないため、ビット列として持つ
+    scale_bits: u32,
+}
+
+impl GlyphKey {
+    pub fn new(character: char, font_type: FontType, scale: f32) -> Self {
+        GlyphKey {
+            character,
+            font_type,
+            scale_bits: scale.to_bits(),
+        }
+    }
+
+    /// 文字の大きさを返す
+    pub fn scale(&self) -> f32 {
+        f32::from_bits(self.scale_bits)
+    }
+}
+
+/// ラスタライズ済みのグリフ。ベースライン上の原点からの相対位置で描画する
+#[derive(Debug, Clone, Default)]
+pub struct GlyphBitmap {
+    /// 原点から見た左上の位置（ピクセル）
+    pub offset: (i32, i32),
+    pub width: usize,
+    pub height: usize,
+    /// 左上から行ごとに並べた濃淡。0が透明、255が不透明
+    pub coverage: Vec<u8>,
+}
+
+impl GlyphBitmap {
+    /// 輪郭を持たない（空白などの）グリフ
+    pub const EMPTY: GlyphBitmap = GlyphBitmap {
+        offset: (0, 0),
+        width: 0,
+        height: 0,
+        coverage: Vec::new(),
+    };
+
+    /// 指定した位置の濃淡を返す
+    #[inline(always)]
+    pub fn coverage_at(&self, x: usize, y: usize) -> u8 {
+        self.coverage[y * self.width + x]
+    }
+
+    fn cost(&self) -> usize {
+        self.coverage.len() + ENTRY_OVERHEAD
+    }
+}
+
+/// バイト数で容量を制限したLRUキャッシュ
+pub struct GlyphCache {
+    entries: BTreeMap<GlyphKey, (GlyphBitmap, u64)>,
+    /// 最後に使われた時刻からキーを引くための索引
+    recency: BTreeMap<u64, GlyphKey>,
+    /// 使われるたびに増える論理時刻
+    clock: u64,
+    used_bytes: usize,
+    capacity_bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl GlyphCache {
+    /// `capacity_bytes`バイトまでグリフを保持するキャッシュを作る
+    pub const fn new(capacity_bytes: usize) -> Self {
+        GlyphCache {
+            entries: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            used_bytes: 0,
+            capacity_bytes,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// キーに対応するグリフを返す。無ければ`rasterize`で作って保存する
+    pub fn get_or_insert_with<F>(&mut self, key: GlyphKey, rasterize: F) -> &GlyphBitmap
+    where
+        F: FnOnce() -> GlyphBitmap,
+    {
+        self.clock += 1;
+        let clock = self.clock;
+
+        if let Some((_, last_used)) = self.entries.get_mut(&key) {
+            self.hits += 1;
+            self.recency.remove(last_used);
+            *last_used = clock;
+            self.recency.insert(clock, key);
+        } else {
+            self.misses += 1;
+            let bitmap = rasterize();
+            let cost = bitmap.cost();
+
+            // 空きができるまで古いものから捨てる
+            while self.used_bytes + cost > self.capacity_bytes {
+                if !self.evict_oldest() {
+                    break;
+                }
+            }
+
+            self.used_bytes += cost;
+            self.entries.insert(key, (bitmap, clock));
+            self.recency.insert(clock, key);
+        }
+
+        &self.entries[&key].0
+    }
+
+    /// キーに対応するグリフがキャッシュにあれば`true`を返す
+    pub fn contains(&self, key: &GlyphKey) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// 最も長い間使われていないグリフを捨てる。捨てるものが無ければ`false`を返す
+    fn evict_oldest(&mut self) -> bool {
+        match self.recency.pop_first() {
+            Some((_, key)) => {
+                if let Some((bitmap, _)) = self.entries.remove(&key) {
+                    self.used_bytes -= bitmap.cost();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// すべてのグリフを捨てる
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.used_bytes = 0;
+    }
+
+    /// 保持しているグリフの数を返す
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 何も保持していなければ`true`を返す
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 使用中のバイト数を返す
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    /// 容量（バイト数）を返す
+    pub fn capacity_bytes(&self) -> usize {
+        self.capacity_bytes
+    }
+
+    /// キャッシュに当たった回数と外れた回数を返す
+    pub fn hit_stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
@@ -0,0 +1,51 @@
+//! 入力デバイスからの入力を扱うモジュール
+//!
+//! 割り込みハンドラで解釈したキー入力は入力キューに積まれ、カーネルの各所から取り出して使う。
+//! キューはロックを取らないため、割り込みハンドラからも安全に積むことができる。
+//! 非同期タスクからは`key_stream()`で、キー入力を待つ`Stream`として取り出せる
+
+pub mod keyboard;
+
+use spin::Once;
+
+use self::keyboard::DecodedKey;
+use crate::task::queue::{QueueStream, WakeQueue};
+
+static INPUT_QUEUE: Once<WakeQueue<DecodedKey>> = Once::new();
+
+/// 入力キューの初期化。ヒープを使うため、ヒープの初期化が済んでから呼ぶこと
+pub fn init(capacity: usize) {
+    INPUT_QUEUE.call_once(|| WakeQueue::new(capacity));
+}
+
+/// 入力キューにキー入力を積む
+///
+/// キューが初期化されていないか満杯の場合は積まずに`false`を返す
+pub fn push_key(key: DecodedKey) -> bool {
+    match INPUT_QUEUE.get() {
+        Some(queue) => queue.push(key),
+        None => false,
+    }
+}
+
+/// 入力キューから最も古いキー入力を取り出す
+pub fn pop_key() -> Option<DecodedKey> {
+    INPUT_QUEUE.get()?.pop()
+}
+
+/// 入力キューから最も古い文字を取り出す。文字に対応しないキー入力は読み捨てる
+pub fn pop_char() -> Option<char> {
+    while let Some(key) = pop_key() {
+        if let DecodedKey::Unicode(c) = key {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/// キー入力を順に取り出す`Stream`を返す。入力キューが初期化されていなければ`None`を返す
+///
+/// キー入力を待てるタスクは一つだけなので、複数のタスクから同時に待たないこと
+pub fn key_stream() -> Option<QueueStream<'static, DecodedKey>> {
+    INPUT_QUEUE.get().map(WakeQueue::stream)
+}
@@ -0,0 +1,396 @@
+//! キーボード入力を文字に変換するモジュール
+//!
+//! スキャンコードの解釈（`scancode`）と、キー配列に応じた文字への変換（`layout`）を組み合わせて使う
+
+pub mod layout;
+pub mod scancode;
+
+use self::{layout::KeyboardLayout, scancode::ScancodeDecoder};
+
+/// キーの物理的な位置を表すコード
+///
+/// 記号キーの名前はUS配列での刻印に従う。JIS配列での意味は`KeyboardLayout::remap()`で読み替える
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    /// US配列の`、JIS配列では半角/全角キーの位置
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    /// US配列の=、JIS配列の^
+    Equals,
+    Backspace,
+
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    /// US配列の[、JIS配列の@
+    LeftBracket,
+    /// US配列の]、JIS配列の[
+    RightBracket,
+    /// US配列の\、JIS配列の]
+    Backslash,
+
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    /// US配列の'、JIS配列の:
+    Quote,
+    Enter,
+
+    LeftShift,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+
+    LeftControl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Apps,
+    RightControl,
+
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+
+    NumLock,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadSubtract,
+    NumpadAdd,
+    NumpadEnter,
+    NumpadDecimal,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+
+    // 以下はJIS配列にのみ存在するキー
+    /// 半角/全角
+    HankakuZenkaku,
+    /// 無変換
+    Muhenkan,
+    /// 変換
+    Henkan,
+    /// カタカナ/ひらがな（かな）
+    KatakanaHiragana,
+    /// ¥
+    Yen,
+    /// ろ（\ _）
+    Ro,
+}
+
+/// キーが押されたか離されたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyState {
+    Down,
+    Up,
+}
+
+/// スキャンコードから得られる、一つのキーの状態変化
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+}
+
+/// 修飾キーとロックキーの状態
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_control: bool,
+    pub right_control: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    /// かな入力モード。JIS配列のかなキーで切り替わる
+    pub kana_lock: bool,
+}
+
+impl Modifiers {
+    /// いずれかのShiftキーが押されているか
+    pub const fn is_shifted(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    /// いずれかのCtrlキーが押されているか
+    pub const fn is_control(&self) -> bool {
+        self.left_control || self.right_control
+    }
+
+    /// いずれかのAltキーが押されているか
+    pub const fn is_alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+}
+
+/// キー入力を解釈した結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DecodedKey {
+    /// 文字として入力されたキー
+    Unicode(char),
+    /// 文字に対応しないキー（矢印キーや変換キーなど）
+    RawKey(KeyCode),
+}
+
+/// スキャンコードを受け取り、修飾キーの状態を追跡しながら文字に変換する構造体
+pub struct Keyboard {
+    decoder: ScancodeDecoder,
+    layout: &'static dyn KeyboardLayout,
+    modifiers: Modifiers,
+}
+
+impl Keyboard {
+    /// スキャンコードの解釈器とキー配列を指定して作る
+    pub const fn new(decoder: ScancodeDecoder, layout: &'static dyn KeyboardLayout) -> Self {
+        Keyboard {
+            decoder,
+            layout,
+            modifiers: Modifiers {
+                left_shift: false,
+                right_shift: false,
+                left_control: false,
+                right_control: false,
+                left_alt: false,
+                right_alt: false,
+                caps_lock: false,
+                num_lock: true,
+                kana_lock: false,
+            },
+        }
+    }
+
+    /// 現在の修飾キーの状態を返す
+    pub fn modifiers(&self) -> &Modifiers {
+        &self.modifiers
+    }
+
+    /// キー配列を変更する
+    pub fn set_layout(&mut self, layout: &'static dyn KeyboardLayout) {
+        self.layout = layout;
+    }
+
+    /// スキャンコードを1バイト受け取る。キーの状態変化が確定した場合にそれを返す
+    pub fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        self.decoder.advance(byte).map(|event| KeyEvent {
+            code: self.layout.remap(event.code),
+            state: event.state,
+        })
+    }
+
+    /// キーの状態変化を受け取り、修飾キーの状態を更新した上で入力を解釈する
+    ///
+    /// 修飾キーそのものや、キーが離された場合は`None`を返す
+    pub fn process_event(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        let down = event.state == KeyState::Down;
+        let modifiers = &mut self.modifiers;
+
+        match event.code {
+            KeyCode::LeftShift => modifiers.left_shift = down,
+            KeyCode::RightShift => modifiers.right_shift = down,
+            KeyCode::LeftControl => modifiers.left_control = down,
+            KeyCode::RightControl => modifiers.right_control = down,
+            KeyCode::LeftAlt => modifiers.left_alt = down,
+            KeyCode::RightAlt => modifiers.right_alt = down,
+            KeyCode::CapsLock if down => modifiers.caps_lock = !modifiers.caps_lock,
+            KeyCode::NumLock if down => modifiers.num_lock = !modifiers.num_lock,
+            KeyCode::KatakanaHiragana if down => {
+                modifiers.kana_lock = !modifiers.kana_lock;
+                return Some(DecodedKey::RawKey(event.code));
+            }
+            code if down => return Some(self.decode(code)),
+            _ => {}
+        }
+
+        None
+    }
+
+    /// 押されたキーを、修飾キーの状態に応じて文字に変換する
+    fn decode(&self, code: KeyCode) -> DecodedKey {
+        let modifiers = &self.modifiers;
+        let shifted = modifiers.is_shifted();
+
+        if let Some(c) = control_char(code) {
+            return DecodedKey::Unicode(c);
+        }
+
+        if modifiers.num_lock {
+            if let Some(c) = numpad_digit(code) {
+                return DecodedKey::Unicode(c);
+            }
+        }
+        if let Some(c) = numpad_operator(code) {
+            return DecodedKey::Unicode(c);
+        }
+
+        if modifiers.kana_lock && !modifiers.is_control() {
+            if let Some(c) = self.layout.map_kana(code, shifted) {
+                return DecodedKey::Unicode(c);
+            }
+        }
+
+        if let Some(letter) = letter(code) {
+            // Ctrl+英字は対応する制御文字 (0x01-0x1a)になる
+            if modifiers.is_control() {
+                let c = (letter as u8 - b'a' + 1) as char;
+                return DecodedKey::Unicode(c);
+            }
+
+            let upper = shifted != modifiers.caps_lock;
+            let c = if upper {
+                letter.to_ascii_uppercase()
+            } else {
+                letter
+            };
+            return DecodedKey::Unicode(c);
+        }
+
+        match self.layout.map_char(code, shifted) {
+            Some(c) => DecodedKey::Unicode(c),
+            None => DecodedKey::RawKey(code),
+        }
+    }
+}
+
+/// どのキー配列でも共通の制御文字
+const fn control_char(code: KeyCode) -> Option<char> {
+    match code {
+        KeyCode::Escape => Some('\u{1b}'),
+        KeyCode::Backspace => Some('\u{8}'),
+        KeyCode::Tab => Some('\t'),
+        KeyCode::Enter | KeyCode::NumpadEnter => Some('\n'),
+        KeyCode::Space => Some(' '),
+        KeyCode::Delete => Some('\u{7f}'),
+        _ => None,
+    }
+}
+
+/// NumLockが有効な時のテンキーの数字
+const fn numpad_digit(code: KeyCode) -> Option<char> {
+    match code {
+        KeyCode::Numpad0 => Some('0'),
+        KeyCode::Numpad1 => Some('1'),
+        KeyCode::Numpad2 => Some('2'),
+        KeyCode::Numpad3 => Some('3'),
+        KeyCode::Numpad4 => Some('4'),
+        KeyCode::Numpad5 => Some('5'),
+        KeyCode::Numpad6 => Some('6'),
+        KeyCode::Numpad7 => Some('7'),
+        KeyCode::Numpad8 => Some('8'),
+        KeyCode::Numpad9 => Some('9'),
+        KeyCode::NumpadDecimal => Some('.'),
+        _ => None,
+    }
+}
+
+/// テンキーの演算子
+const fn numpad_operator(code: KeyCode) -> Option<char> {
+    match code {
+        KeyCode::NumpadDivide => Some('/'),
+        KeyCode::NumpadMultiply => Some('*'),
+        KeyCode::NumpadSubtract => Some('-'),
+        KeyCode::NumpadAdd => Some('+'),
+        _ => None,
+    }
+}
+
+/// 英字キーの小文字
+const fn letter(code: KeyCode) -> Option<char> {
+    let c = match code {
+        KeyCode::A => 'a',
+        KeyCode::B => 'b',
+        KeyCode::C => 'c',
+        KeyCode::D => 'd',
+        KeyCode::E => 'e',
+        KeyCode::F => 'f',
+        KeyCode::G => 'g',
+        KeyCode::H => 'h',
+        KeyCode::I => 'i',
+        KeyCode::J => 'j',
+        KeyCode::K => 'k',
+        KeyCode::L => 'l',
+        KeyCode::M => 'm',
+        KeyCode::N => 'n',
+        KeyCode::O => 'o',
+        KeyCode::P => 'p',
+        KeyCode::Q => 'q',
+        KeyCode::R => 'r',
+        KeyCode::S => 's',
+        KeyCode::T => 't',
+        KeyCode::U => 'u',
+        KeyCode::V => 'v',
+        KeyCode::W => 'w',
+        KeyCode::X => 'x',
+        KeyCode::Y => 'y',
+        KeyCode::Z => 'z',
+        _ => return None,
+    };
+    Some(c)
+}
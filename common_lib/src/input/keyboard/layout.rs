@@ -0,0 +1,168 @@
+//! キー配列を定義するモジュール
+
+use super::KeyCode;
+
+/// キー配列を定義するトレイト
+///
+/// 英字・テンキー・制御文字はどの配列でも共通なので`Keyboard`側で処理し、
+/// ここでは配列によって刻印が異なる記号キーのみを扱う
+pub trait KeyboardLayout: Sync {
+    /// 物理的な位置を表すキーコードを、この配列でのキーに読み替える
+    fn remap(&self, code: KeyCode) -> KeyCode {
+        code
+    }
+
+    /// 記号キーをShiftの状態に応じて文字に変換する
+    fn map_char(&self, code: KeyCode, shifted: bool) -> Option<char>;
+
+    /// かな入力モードでのキーを文字に変換する。かな入力に対応しない配列では`None`を返す
+    fn map_kana(&self, _code: KeyCode, _shifted: bool) -> Option<char> {
+        None
+    }
+}
+
+/// US 104キー配列
+#[derive(Debug, Clone, Copy)]
+pub struct Us104;
+
+impl KeyboardLayout for Us104 {
+    fn map_char(&self, code: KeyCode, shifted: bool) -> Option<char> {
+        let (normal, shift) = match code {
+            KeyCode::Backtick => ('`', '~'),
+            KeyCode::Key1 => ('1', '!'),
+            KeyCode::Key2 => ('2', '@'),
+            KeyCode::Key3 => ('3', '#'),
+            KeyCode::Key4 => ('4', '$'),
+            KeyCode::Key5 => ('5', '%'),
+            KeyCode::Key6 => ('6', '^'),
+            KeyCode::Key7 => ('7', '&'),
+            KeyCode::Key8 => ('8', '*'),
+            KeyCode::Key9 => ('9', '('),
+            KeyCode::Key0 => ('0', ')'),
+            KeyCode::Minus => ('-', '_'),
+            KeyCode::Equals => ('=', '+'),
+            KeyCode::LeftBracket => ('[', '{'),
+            KeyCode::RightBracket => (']', '}'),
+            KeyCode::Backslash => ('\\', '|'),
+            KeyCode::Semicolon => (';', ':'),
+            KeyCode::Quote => ('\'', '"'),
+            KeyCode::Comma => (',', '<'),
+            KeyCode::Period => ('.', '>'),
+            KeyCode::Slash => ('/', '?'),
+            _ => return None,
+        };
+
+        Some(if shifted { shift } else { normal })
+    }
+}
+
+/// JIS 106/109キー配列
+///
+/// ¥キーは、パスの区切りなどで使いやすいよう¥ではなく`\`を入力する
+#[derive(Debug, Clone, Copy)]
+pub struct Jis109;
+
+impl KeyboardLayout for Jis109 {
+    fn remap(&self, code: KeyCode) -> KeyCode {
+        match code {
+            KeyCode::Backtick => KeyCode::HankakuZenkaku,
+            code => code,
+        }
+    }
+
+    fn map_char(&self, code: KeyCode, shifted: bool) -> Option<char> {
+        let (normal, shift) = match code {
+            KeyCode::Key1 => ('1', Some('!')),
+            KeyCode::Key2 => ('2', Some('"')),
+            KeyCode::Key3 => ('3', Some('#')),
+            KeyCode::Key4 => ('4', Some('$')),
+            KeyCode::Key5 => ('5', Some('%')),
+            KeyCode::Key6 => ('6', Some('&')),
+            KeyCode::Key7 => ('7', Some('\'')),
+            KeyCode::Key8 => ('8', Some('(')),
+            KeyCode::Key9 => ('9', Some(')')),
+            // Shift+0には何も割り当てられていない
+            KeyCode::Key0 => ('0', None),
+            KeyCode::Minus => ('-', Some('=')),
+            KeyCode::Equals => ('^', Some('~')),
+            KeyCode::Yen => ('\\', Some('|')),
+            KeyCode::LeftBracket => ('@', Some('`')),
+            KeyCode::RightBracket => ('[', Some('{')),
+            KeyCode::Backslash => (']', Some('}')),
+            KeyCode::Semicolon => (';', Some('+')),
+            KeyCode::Quote => (':', Some('*')),
+            KeyCode::Comma => (',', Some('<')),
+            KeyCode::Period => ('.', Some('>')),
+            KeyCode::Slash => ('/', Some('?')),
+            KeyCode::Ro => ('\\', Some('_')),
+            _ => return None,
+        };
+
+        if shifted {
+            shift
+        } else {
+            Some(normal)
+        }
+    }
+
+    fn map_kana(&self, code: KeyCode, shifted: bool) -> Option<char> {
+        let (normal, shift) = match code {
+            KeyCode::Key1 => ('ぬ', None),
+            KeyCode::Key2 => ('ふ', None),
+            KeyCode::Key3 => ('あ', Some('ぁ')),
+            KeyCode::Key4 => ('う', Some('ぅ')),
+            KeyCode::Key5 => ('え', Some('ぇ')),
+            KeyCode::Key6 => ('お', Some('ぉ')),
+            KeyCode::Key7 => ('や', Some('ゃ')),
+            KeyCode::Key8 => ('ゆ', Some('ゅ')),
+            KeyCode::Key9 => ('よ', Some('ょ')),
+            KeyCode::Key0 => ('わ', Some('を')),
+            KeyCode::Minus => ('ほ', None),
+            KeyCode::Equals => ('へ', None),
+            KeyCode::Yen => ('ー', None),
+            KeyCode::Q => ('た', None),
+            KeyCode::W => ('て', None),
+            KeyCode::E => ('い', Some('ぃ')),
+            KeyCode::R => ('す', None),
+            KeyCode::T => ('か', None),
+            KeyCode::Y => ('ん', None),
+            KeyCode::U => ('な', None),
+            KeyCode::I => ('に', None),
+            KeyCode::O => ('ら', None),
+            KeyCode::P => ('せ', None),
+            KeyCode::LeftBracket => ('゛', None),
+            KeyCode::RightBracket => ('゜', Some('「')),
+            KeyCode::A => ('ち', None),
+            KeyCode::S => ('と', None),
+            KeyCode::D => ('し', None),
+            KeyCode::F => ('は', None),
+            KeyCode::G => ('き', None),
+            KeyCode::H => ('く', None),
+            KeyCode::J => ('ま', None),
+            KeyCode::K => ('の', None),
+            KeyCode::L => ('り', None),
+            KeyCode::Semicolon => ('れ', None),
+            KeyCode::Quote => ('け', None),
+            KeyCode::Backslash => ('む', Some('」')),
+            KeyCode::Z => ('つ', Some('っ')),
+            KeyCode::X => ('さ', None),
+            KeyCode::C => ('そ', None),
+            KeyCode::V => ('ひ', None),
+            KeyCode::B => ('こ', None),
+            KeyCode::N => ('み', None),
+            KeyCode::M => ('も', None),
+            KeyCode::Comma => ('ね', Some('、')),
+            KeyCode::Period => ('る', Some('。')),
+            KeyCode::Slash => ('め', Some('・')),
+            KeyCode::Ro => ('ろ', None),
+            _ => return None,
+        };
+
+        // Shift版の無いキーは、Shiftを押していても通常の文字を入力する
+        Some(if shifted {
+            shift.unwrap_or(normal)
+        } else {
+            normal
+        })
+    }
+}
@@ -0,0 +1,405 @@
+//! PS/2キーボードのスキャンコードを解釈するモジュール
+//!
+//! スキャンコードセット1（XT互換）とセット2（AT互換）に対応する
+
+use super::{KeyCode, KeyEvent, KeyState};
+
+/// スキャンコードセットの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// セット1。PS/2コントローラが変換を有効にしている場合はこちらが届く
+    Set1,
+    /// セット2。キーボードが実際に送出するコード
+    Set2,
+}
+
+/// 複数バイトにまたがるスキャンコードの途中状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodeState {
+    Start,
+    /// 0xe0の後
+    Extended,
+    /// セット2の0xf0の後
+    Release,
+    /// セット2の0xe0 0xf0の後
+    ExtendedRelease,
+    /// Pauseキーのシーケンスの途中。残りのバイト数を持つ
+    Pause(u8),
+}
+
+/// スキャンコードを1バイトずつ受け取り、キーの状態変化に変換する構造体
+#[derive(Debug, Clone)]
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    state: DecodeState,
+}
+
+impl ScancodeDecoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        ScancodeDecoder {
+            set,
+            state: DecodeState::Start,
+        }
+    }
+
+    /// 解釈しているスキャンコードセットを返す
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// スキャンコードを1バイト受け取る。キーの状態変化が確定した場合にそれを返す
+    ///
+    /// 未知のコードは読み捨てる
+    pub fn advance(&mut self, byte: u8) -> Option<KeyEvent> {
+        match self.set {
+            ScancodeSet::Set1 => self.advance_set1(byte),
+            ScancodeSet::Set2 => self.advance_set2(byte),
+        }
+    }
+
+    fn advance_set1(&mut self, byte: u8) -> Option<KeyEvent> {
+        match (self.state, byte) {
+            (DecodeState::Pause(remaining), _) => self.advance_pause(remaining),
+            (DecodeState::Start, 0xe0) => {
+                self.state = DecodeState::Extended;
+                None
+            }
+            // Pause: e1 1d 45 e1 9d c5
+            (DecodeState::Start, 0xe1) => {
+                self.state = DecodeState::Pause(5);
+                None
+            }
+            (DecodeState::Start, _) => {
+                let state = if byte & 0x80 == 0 {
+                    KeyState::Down
+                } else {
+                    KeyState::Up
+                };
+                set1_code(byte & 0x7f).map(|code| KeyEvent { code, state })
+            }
+            (_, _) => {
+                self.state = DecodeState::Start;
+                let state = if byte & 0x80 == 0 {
+                    KeyState::Down
+                } else {
+                    KeyState::Up
+                };
+                set1_extended_code(byte & 0x7f).map(|code| KeyEvent { code, state })
+            }
+        }
+    }
+
+    fn advance_set2(&mut self, byte: u8) -> Option<KeyEvent> {
+        match (self.state, byte) {
+            (DecodeState::Pause(remaining), _) => self.advance_pause(remaining),
+            (DecodeState::Start, 0xe0) => {
+                self.state = DecodeState::Extended;
+                None
+            }
+            // Pause: e1 14 77 e1 f0 14 f0 77
+            (DecodeState::Start, 0xe1) => {
+                self.state = DecodeState::Pause(7);
+                None
+            }
+            (DecodeState::Start, 0xf0) => {
+                self.state = DecodeState::Release;
+                None
+            }
+            (DecodeState::Extended, 0xf0) => {
+                self.state = DecodeState::ExtendedRelease;
+                None
+            }
+            (DecodeState::Start, _) => set2_code(byte).map(|code| KeyEvent {
+                code,
+                state: KeyState::Down,
+            }),
+            (DecodeState::Release, _) => {
+                self.state = DecodeState::Start;
+                set2_code(byte).map(|code| KeyEvent {
+                    code,
+                    state: KeyState::Up,
+                })
+            }
+            (DecodeState::Extended, _) => {
+                self.state = DecodeState::Start;
+                set2_extended_code(byte).map(|code| KeyEvent {
+                    code,
+                    state: KeyState::Down,
+                })
+            }
+            (DecodeState::ExtendedRelease, _) => {
+                self.state = DecodeState::Start;
+                set2_extended_code(byte).map(|code| KeyEvent {
+                    code,
+                    state: KeyState::Up,
+                })
+            }
+        }
+    }
+
+    /// Pauseキーは押下のみが送られ、離した時のコードは無い
+    fn advance_pause(&mut self, remaining: u8) -> Option<KeyEvent> {
+        if remaining > 1 {
+            self.state = DecodeState::Pause(remaining - 1);
+            None
+        } else {
+            self.state = DecodeState::Start;
+            Some(KeyEvent {
+                code: KeyCode::Pause,
+                state: KeyState::Down,
+            })
+        }
+    }
+}
+
+/// スキャンコードセット1の通常のコード
+const fn set1_code(code: u8) -> Option<KeyCode> {
+    let key = match code {
+        0x01 => KeyCode::Escape,
+        0x02 => KeyCode::Key1,
+        0x03 => KeyCode::Key2,
+        0x04 => KeyCode::Key3,
+        0x05 => KeyCode::Key4,
+        0x06 => KeyCode::Key5,
+        0x07 => KeyCode::Key6,
+        0x08 => KeyCode::Key7,
+        0x09 => KeyCode::Key8,
+        0x0a => KeyCode::Key9,
+        0x0b => KeyCode::Key0,
+        0x0c => KeyCode::Minus,
+        0x0d => KeyCode::Equals,
+        0x0e => KeyCode::Backspace,
+        0x0f => KeyCode::Tab,
+        0x10 => KeyCode::Q,
+        0x11 => KeyCode::W,
+        0x12 => KeyCode::E,
+        0x13 => KeyCode::R,
+        0x14 => KeyCode::T,
+        0x15 => KeyCode::Y,
+        0x16 => KeyCode::U,
+        0x17 => KeyCode::I,
+        0x18 => KeyCode::O,
+        0x19 => KeyCode::P,
+        0x1a => KeyCode::LeftBracket,
+        0x1b => KeyCode::RightBracket,
+        0x1c => KeyCode::Enter,
+        0x1d => KeyCode::LeftControl,
+        0x1e => KeyCode::A,
+        0x1f => KeyCode::S,
+        0x20 => KeyCode::D,
+        0x21 => KeyCode::F,
+        0x22 => KeyCode::G,
+        0x23 => KeyCode::H,
+        0x24 => KeyCode::J,
+        0x25 => KeyCode::K,
+        0x26 => KeyCode::L,
+        0x27 => KeyCode::Semicolon,
+        0x28 => KeyCode::Quote,
+        0x29 => KeyCode::Backtick,
+        0x2a => KeyCode::LeftShift,
+        0x2b => KeyCode::Backslash,
+        0x2c => KeyCode::Z,
+        0x2d => KeyCode::X,
+        0x2e => KeyCode::C,
+        0x2f => KeyCode::V,
+        0x30 => KeyCode::B,
+        0x31 => KeyCode::N,
+        0x32 => KeyCode::M,
+        0x33 => KeyCode::Comma,
+        0x34 => KeyCode::Period,
+        0x35 => KeyCode::Slash,
+        0x36 => KeyCode::RightShift,
+        0x37 => KeyCode::NumpadMultiply,
+        0x38 => KeyCode::LeftAlt,
+        0x39 => KeyCode::Space,
+        0x3a => KeyCode::CapsLock,
+        0x3b => KeyCode::F1,
+        0x3c => KeyCode::F2,
+        0x3d => KeyCode::F3,
+        0x3e => KeyCode::F4,
+        0x3f => KeyCode::F5,
+        0x40 => KeyCode::F6,
+        0x41 => KeyCode::F7,
+        0x42 => KeyCode::F8,
+        0x43 => KeyCode::F9,
+        0x44 => KeyCode::F10,
+        0x45 => KeyCode::NumLock,
+        0x46 => KeyCode::ScrollLock,
+        0x47 => KeyCode::Numpad7,
+        0x48 => KeyCode::Numpad8,
+        0x49 => KeyCode::Numpad9,
+        0x4a => KeyCode::NumpadSubtract,
+        0x4b => KeyCode::Numpad4,
+        0x4c => KeyCode::Numpad5,
+        0x4d => KeyCode::Numpad6,
+        0x4e => KeyCode::NumpadAdd,
+        0x4f => KeyCode::Numpad1,
+        0x50 => KeyCode::Numpad2,
+        0x51 => KeyCode::Numpad3,
+        0x52 => KeyCode::Numpad0,
+        0x53 => KeyCode::NumpadDecimal,
+        0x57 => KeyCode::F11,
+        0x58 => KeyCode::F12,
+        0x70 => KeyCode::KatakanaHiragana,
+        0x73 => KeyCode::Ro,
+        0x79 => KeyCode::Henkan,
+        0x7b => KeyCode::Muhenkan,
+        0x7d => KeyCode::Yen,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// スキャンコードセット1の0xe0に続くコード
+///
+/// PrintScreenなどに付随する偽のShift（0x2a, 0x36）は読み捨てる
+const fn set1_extended_code(code: u8) -> Option<KeyCode> {
+    let key = match code {
+        0x1c => KeyCode::NumpadEnter,
+        0x1d => KeyCode::RightControl,
+        0x35 => KeyCode::NumpadDivide,
+        0x37 => KeyCode::PrintScreen,
+        0x38 => KeyCode::RightAlt,
+        0x47 => KeyCode::Home,
+        0x48 => KeyCode::ArrowUp,
+        0x49 => KeyCode::PageUp,
+        0x4b => KeyCode::ArrowLeft,
+        0x4d => KeyCode::ArrowRight,
+        0x4f => KeyCode::End,
+        0x50 => KeyCode::ArrowDown,
+        0x51 => KeyCode::PageDown,
+        0x52 => KeyCode::Insert,
+        0x53 => KeyCode::Delete,
+        0x5b => KeyCode::LeftGui,
+        0x5c => KeyCode::RightGui,
+        0x5d => KeyCode::Apps,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// スキャンコードセット2の通常のコード
+const fn set2_code(code: u8) -> Option<KeyCode> {
+    let key = match code {
+        0x01 => KeyCode::F9,
+        0x03 => KeyCode::F5,
+        0x04 => KeyCode::F3,
+        0x05 => KeyCode::F1,
+        0x06 => KeyCode::F2,
+        0x07 => KeyCode::F12,
+        0x09 => KeyCode::F10,
+        0x0a => KeyCode::F8,
+        0x0b => KeyCode::F6,
+        0x0c => KeyCode::F4,
+        0x0d => KeyCode::Tab,
+        0x0e => KeyCode::Backtick,
+        0x11 => KeyCode::LeftAlt,
+        0x12 => KeyCode::LeftShift,
+        0x13 => KeyCode::KatakanaHiragana,
+        0x14 => KeyCode::LeftControl,
+        0x15 => KeyCode::Q,
+        0x16 => KeyCode::Key1,
+        0x1a => KeyCode::Z,
+        0x1b => KeyCode::S,
+        0x1c => KeyCode::A,
+        0x1d => KeyCode::W,
+        0x1e => KeyCode::Key2,
+        0x21 => KeyCode::C,
+        0x22 => KeyCode::X,
+        0x23 => KeyCode::D,
+        0x24 => KeyCode::E,
+        0x25 => KeyCode::Key4,
+        0x26 => KeyCode::Key3,
+        0x29 => KeyCode::Space,
+        0x2a => KeyCode::V,
+        0x2b => KeyCode::F,
+        0x2c => KeyCode::T,
+        0x2d => KeyCode::R,
+        0x2e => KeyCode::Key5,
+        0x31 => KeyCode::N,
+        0x32 => KeyCode::B,
+        0x33 => KeyCode::H,
+        0x34 => KeyCode::G,
+        0x35 => KeyCode::Y,
+        0x36 => KeyCode::Key6,
+        0x3a => KeyCode::M,
+        0x3b => KeyCode::J,
+        0x3c => KeyCode::U,
+        0x3d => KeyCode::Key7,
+        0x3e => KeyCode::Key8,
+        0x41 => KeyCode::Comma,
+        0x42 => KeyCode::K,
+        0x43 => KeyCode::I,
+        0x44 => KeyCode::O,
+        0x45 => KeyCode::Key0,
+        0x46 => KeyCode::Key9,
+        0x49 => KeyCode::Period,
+        0x4a => KeyCode::Slash,
+        0x4b => KeyCode::L,
+        0x4c => KeyCode::Semicolon,
+        0x4d => KeyCode::P,
+        0x4e => KeyCode::Minus,
+        0x51 => KeyCode::Ro,
+        0x52 => KeyCode::Quote,
+        0x54 => KeyCode::LeftBracket,
+        0x55 => KeyCode::Equals,
+        0x58 => KeyCode::CapsLock,
+        0x59 => KeyCode::RightShift,
+        0x5a => KeyCode::Enter,
+        0x5b => KeyCode::RightBracket,
+        0x5d => KeyCode::Backslash,
+        0x64 => KeyCode::Henkan,
+        0x66 => KeyCode::Backspace,
+        0x67 => KeyCode::Muhenkan,
+        0x69 => KeyCode::Numpad1,
+        0x6a => KeyCode::Yen,
+        0x6b => KeyCode::Numpad4,
+        0x6c => KeyCode::Numpad7,
+        0x70 => KeyCode::Numpad0,
+        0x71 => KeyCode::NumpadDecimal,
+        0x72 => KeyCode::Numpad2,
+        0x73 => KeyCode::Numpad5,
+        0x74 => KeyCode::Numpad6,
+        0x75 => KeyCode::Numpad8,
+        0x76 => KeyCode::Escape,
+        0x77 => KeyCode::NumLock,
+        0x78 => KeyCode::F11,
+        0x79 => KeyCode::NumpadAdd,
+        0x7a => KeyCode::Numpad3,
+        0x7b => KeyCode::NumpadSubtract,
+        0x7c => KeyCode::NumpadMultiply,
+        0x7d => KeyCode::Numpad9,
+        0x7e => KeyCode::ScrollLock,
+        0x83 => KeyCode::F7,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// スキャンコードセット2の0xe0に続くコード
+///
+/// PrintScreenなどに付随する偽のShift（0x12, 0x59）は読み捨てる
+const fn set2_extended_code(code: u8) -> Option<KeyCode> {
+    let key = match code {
+        0x11 => KeyCode::RightAlt,
+        0x14 => KeyCode::RightControl,
+        0x1f => KeyCode::LeftGui,
+        0x27 => KeyCode::RightGui,
+        0x2f => KeyCode::Apps,
+        0x4a => KeyCode::NumpadDivide,
+        0x5a => KeyCode::NumpadEnter,
+        0x69 => KeyCode::End,
+        0x6b => KeyCode::ArrowLeft,
+        0x6c => KeyCode::Home,
+        0x70 => KeyCode::Insert,
+        0x71 => KeyCode::Delete,
+        0x72 => KeyCode::ArrowDown,
+        0x74 => KeyCode::ArrowRight,
+        0x75 => KeyCode::ArrowUp,
+        0x7a => KeyCode::PageDown,
+        0x7c => KeyCode::PrintScreen,
+        0x7d => KeyCode::PageUp,
+        _ => return None,
+    };
+    Some(key)
+}
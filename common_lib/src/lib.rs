@@ -1,6 +1,16 @@
 #![no_std]
 #![feature(const_mut_refs)]
 
+extern crate alloc;
+
+pub mod block;
+pub mod fs;
 pub mod graphic;
+pub mod input;
 pub mod locked;
+pub mod logger;
 pub mod memory;
+pub mod pci;
+pub mod sync;
+pub mod task;
+pub mod time;
@@ -1,18 +1,35 @@
-/// 外部トレイトを実装するためのハックとしてspin::Mutexをラップする型
+use crate::sync::{IrqMutex, IrqMutexGuard, LockLevel};
+
+/// 外部トレイトを実装するためのハックとして`IrqMutex`をラップする型
+///
+/// グローバルアロケータやコンソールなど、割り込みハンドラからも使われうるものを収めるため、
+/// ロックを取っている間は割り込みを無効にする
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqMutex<A>,
 }
 
 impl<A> Locked<A> {
     /// 初期化関数。ロックしたい型をこれに収める
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqMutex::new(inner),
+        }
+    }
+
+    /// 取得順序の階層を指定してロックしたい型を収める。`name`は順序違反などの報告に使う
+    pub const fn with_level(inner: A, name: &'static str, level: LockLevel) -> Self {
+        Locked {
+            inner: IrqMutex::with_level(inner, name, level),
         }
     }
 
     /// ミューテックス式のロックを実行する
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqMutexGuard<A> {
         self.inner.lock()
     }
+
+    /// ロックが取れる場合に限りロックする。取れなければ`None`を返す
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
 }
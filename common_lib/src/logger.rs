@@ -0,0 +1,211 @@
+//! `log`クレートのファサードを実装するモジュール
+//!
+//! ログは登録された出力先（シリアル、画面など）と、メモリ上のリングバッファの両方へ書き出される。
+//! 各行には`time`モジュールから得た起動時からの経過時間が付く
+//!
+//! 出力するレベルはモジュールごとに、`env_logger`と同じ書式の文字列で指定する:
+//!
+//! ```text
+//! info,amd64_lib::timer=debug,kernel::memory=trace
+//! ```
+//!
+//! モジュール名のない項目は既定のレベルとなり、複数の項目に一致する場合は最も長いモジュール名のものを使う
+
+pub mod ring_buffer;
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::{
+    sync::{IrqMutex, LockLevel},
+    time::Instant,
+};
+
+use self::ring_buffer::LogRingBuffer;
+
+pub use log::{debug, error, info, trace, warn, Level};
+
+/// 出力先を登録できる数の上限
+pub const MAX_SINKS: usize = 4;
+
+/// ログの出力先
+pub trait LogSink: Sync {
+    /// 整形済みの一行を書き出す。末尾の改行は含まれない
+    fn write_line(&self, line: fmt::Arguments);
+}
+
+/// モジュールごとのレベル指定
+struct Directive {
+    module: String,
+    level: LevelFilter,
+}
+
+/// ログを出力するかどうかの判定に使うフィルタ
+struct Filter {
+    default: LevelFilter,
+    directives: Vec<Directive>,
+}
+
+impl Filter {
+    const fn new(default: LevelFilter) -> Self {
+        Filter {
+            default,
+            directives: Vec::new(),
+        }
+    }
+
+    /// `env_logger`形式の文字列を解釈する。解釈できない項目は無視する
+    fn parse(spec: &str) -> Self {
+        let mut filter = Filter::new(LevelFilter::Info);
+
+        for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match item.split_once('=') {
+                Some((module, level)) => {
+                    if let Ok(level) = level.trim().parse() {
+                        filter.directives.push(Directive {
+                            module: String::from(module.trim()),
+                            level,
+                        });
+                    }
+                }
+                None => {
+                    // レベルだけならば既定値、そうでなければモジュール名だけの指定とみなす
+                    match item.parse() {
+                        Ok(level) => filter.default = level,
+                        Err(_) => filter.directives.push(Directive {
+                            module: String::from(item),
+                            level: LevelFilter::Trace,
+                        }),
+                    }
+                }
+            }
+        }
+
+        filter
+    }
+
+    /// `target`に適用されるレベルを返す
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|d| is_module_prefix(&d.module, target))
+            .max_by_key(|d| d.module.len())
+            .map_or(self.default, |d| d.level)
+    }
+
+    /// 最も詳細なレベルを返す。`log::set_max_level()`に使う
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|d| d.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// `module`が`target`自身か、その親モジュールであれば`true`を返す
+fn is_module_prefix(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+/// 登録済みの出力先と、その出力先に書き出す最大のレベル
+#[derive(Clone, Copy)]
+struct Sink {
+    sink: &'static dyn LogSink,
+    level: LevelFilter,
+}
+
+/// カーネルのロガー本体
+///
+/// ログは割り込みハンドラからも出力されるため、フィルタと出力先の一覧も`IrqMutex`で守る
+struct KernelLogger {
+    filter: IrqMutex<Filter>,
+    sinks: IrqMutex<[Option<Sink>; MAX_SINKS]>,
+    ring_buffer: IrqMutex<LogRingBuffer>,
+}
+
+static LOGGER: KernelLogger = KernelLogger {
+    filter: IrqMutex::with_level(
+        Filter::new(LevelFilter::Info),
+        "log filter",
+        LockLevel::LOGGER_CONFIG,
+    ),
+    sinks: IrqMutex::with_level([None; MAX_SINKS], "log sinks", LockLevel::LOGGER_CONFIG),
+    ring_buffer: IrqMutex::with_level(LogRingBuffer::new(), "log buffer", LockLevel::LOG_BUFFER),
+};
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.lock().level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = Instant::now().since_boot();
+        let line = format_args!(
+            "[{:>5}.{:06}] {:<5} {}: {}",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        // ログの整形中に出力されたログで行き詰まらないよう、ロックが取れなければ記録を諦める
+        if let Some(mut ring_buffer) = self.ring_buffer.try_lock() {
+            let _ = writeln!(ring_buffer, "{}", line);
+        }
+
+        let sinks = *self.sinks.lock();
+        for sink in sinks.iter().flatten() {
+            if record.level() <= sink.level {
+                sink.sink.write_line(line);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// ロガーを登録する。ヒープを使うため、ヒープの初期化後に呼ぶこと
+///
+/// `filter`はモジュールごとのレベル指定、`ring_buffer_size`はリングバッファの容量（バイト数）
+pub fn init(filter: &str, ring_buffer_size: usize) -> Result<(), SetLoggerError> {
+    LOGGER.ring_buffer.lock().resize(ring_buffer_size);
+    set_filter(filter);
+    log::set_logger(&LOGGER)
+}
+
+/// レベル指定を変更する
+pub fn set_filter(spec: &str) {
+    let filter = Filter::parse(spec);
+    log::set_max_level(filter.max_level());
+    // 古いフィルタの解放でアロケータのロックを取るため、ロックを放してから破棄する
+    let old = core::mem::replace(&mut *LOGGER.filter.lock(), filter);
+    drop(old);
+}
+
+/// 出力先を追加する。`level`より詳細なログはその出力先には書き出さない
+///
+/// ## Panic
+/// 登録数が`MAX_SINKS`を超えた場合はパニックする
+pub fn add_sink(sink: &'static dyn LogSink, level: LevelFilter) {
+    let mut sinks = LOGGER.sinks.lock();
+    let slot = sinks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many log sinks");
+    *slot = Some(Sink { sink, level });
+}
+
+/// リングバッファに溜まっているログを文字列として取り出す
+pub fn dump_ring_buffer() -> String {
+    LOGGER.ring_buffer.lock().to_string_lossy()
+}
@@ -0,0 +1,141 @@
+//! ログを溜めておくためのリングバッファ
+
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt;
+
+/// 固定長のバイト列にログを書き込み、一杯になったら古いものから上書きしていくリングバッファ
+pub struct LogRingBuffer {
+    buffer: Vec<u8>,
+    /// 最も古いバイトの位置
+    head: usize,
+    /// 保持しているバイト数
+    len: usize,
+    /// 上書きによって失われたバイト数の合計
+    dropped: usize,
+}
+
+impl LogRingBuffer {
+    /// 空のリングバッファを作る。`resize()`で容量を確保するまでは何も記録しない
+    pub const fn new() -> Self {
+        LogRingBuffer {
+            buffer: Vec::new(),
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// 容量を`capacity`バイトに変更する。それまでの内容は破棄される
+    pub fn resize(&mut self, capacity: usize) {
+        self.buffer = vec![0; capacity];
+        self.head = 0;
+        self.len = 0;
+        self.dropped = 0;
+    }
+
+    /// 容量（バイト数）を返す
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// 保持しているバイト数を返す
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// 何も保持していなければ`true`を返す
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 上書きによって失われたバイト数を返す
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// バイト列を書き込む。容量を超えた分は古いものから上書きする
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return;
+        }
+
+        // 容量より長い場合は末尾だけを残せば十分
+        let bytes = if bytes.len() > capacity {
+            self.dropped += bytes.len() - capacity;
+            &bytes[bytes.len() - capacity..]
+        } else {
+            bytes
+        };
+
+        for &byte in bytes {
+            let tail = (self.head + self.len) % capacity;
+            self.buffer[tail] = byte;
+
+            if self.len == capacity {
+                self.head = (self.head + 1) % capacity;
+                self.dropped += 1;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+
+    /// 保持している内容を古い順に二つのスライスとして返す
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let capacity = self.capacity();
+        if self.head + self.len <= capacity {
+            (&self.buffer[self.head..self.head + self.len], &[])
+        } else {
+            let wrapped = self.head + self.len - capacity;
+            (&self.buffer[self.head..], &self.buffer[..wrapped])
+        }
+    }
+
+    /// 保持している内容を文字列として取り出す
+    ///
+    /// 上書きによって先頭の行が途中から始まっている場合は、その行を読み飛ばす
+    pub fn to_string_lossy(&self) -> String {
+        let (first, second) = self.as_slices();
+        let mut bytes = Vec::with_capacity(self.len);
+        bytes.extend_from_slice(first);
+        bytes.extend_from_slice(second);
+
+        let start = if self.dropped > 0 {
+            bytes
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(bytes.len(), |pos| pos + 1)
+        } else {
+            0
+        };
+
+        let mut text = String::with_capacity(bytes.len() - start);
+        for chunk in bytes[start..].utf8_chunks() {
+            text.push_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                text.push(char::REPLACEMENT_CHARACTER);
+            }
+        }
+        text
+    }
+
+    /// 内容を空にする
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+impl Default for LogRingBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for LogRingBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_bytes(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! PCIバスのデバイスを列挙し、ドライバと結び付けるモジュール
+//!
+//! コンフィギュレーション空間へのアクセス方法（I/Oポートかメモリマップか）はアーキテクチャごとに異なるため、
+//! `ConfigAccess`トレイトとして外から与える。`init()`でバスを列挙した後は、
+//! `driver::register()`で登録したドライバが、IDやクラスの一致するデバイスに結び付けられる
+
+pub mod bar;
+pub mod capability;
+pub mod device;
+pub mod driver;
+
+use alloc::vec::Vec;
+use core::{fmt, ops::RangeInclusive};
+
+use spin::Once;
+
+pub use self::device::PciDevice;
+
+/// PCIのデバイスが存在しない場合にベンダーIDとして読める値
+const INVALID_VENDOR_ID: u16 = 0xffff;
+/// 1バスあたりのデバイス数
+const DEVICES_PER_BUS: u8 = 32;
+/// 1デバイスあたりのファンクション数
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+/// コンフィギュレーション空間のレジスタのオフセット
+pub mod register {
+    pub const VENDOR_ID: u16 = 0x00;
+    pub const DEVICE_ID: u16 = 0x02;
+    pub const COMMAND: u16 = 0x04;
+    pub const STATUS: u16 = 0x06;
+    pub const REVISION_ID: u16 = 0x08;
+    pub const PROG_IF: u16 = 0x09;
+    pub const SUBCLASS: u16 = 0x0a;
+    pub const CLASS: u16 = 0x0b;
+    pub const HEADER_TYPE: u16 = 0x0e;
+    pub const BAR0: u16 = 0x10;
+    pub const SUBSYSTEM_VENDOR_ID: u16 = 0x2c;
+    pub const SUBSYSTEM_ID: u16 = 0x2e;
+    pub const CAPABILITIES_POINTER: u16 = 0x34;
+    pub const INTERRUPT_LINE: u16 = 0x3c;
+    pub const INTERRUPT_PIN: u16 = 0x3d;
+}
+
+/// コマンドレジスタのビット
+pub mod command {
+    pub const IO_SPACE: u16 = 1 << 0;
+    pub const MEMORY_SPACE: u16 = 1 << 1;
+    pub const BUS_MASTER: u16 = 1 << 2;
+    pub const INTERRUPT_DISABLE: u16 = 1 << 10;
+}
+
+/// ステータスレジスタのうち、ケーパビリティリストを持つことを表すビット
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+/// PCIのファンクションの位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// コンフィギュレーション空間へのアクセス方法
+///
+/// 読み書きは4バイト単位で、`offset`は4の倍数でなければならない
+pub trait ConfigAccess: Sync {
+    fn read(&self, address: PciAddress, offset: u16) -> u32;
+
+    fn write(&self, address: PciAddress, offset: u16, value: u32);
+
+    /// PCI Expressの拡張コンフィギュレーション空間（0x100〜0xfff）にアクセスできれば`true`を返す
+    fn has_extended_space(&self) -> bool;
+
+    fn read_u16(&self, address: PciAddress, offset: u16) -> u16 {
+        (self.read(address, offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+    }
+
+    fn read_u8(&self, address: PciAddress, offset: u16) -> u8 {
+        (self.read(address, offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+    }
+
+    /// 2バイトだけを書き込む。`offset`は2の倍数でなければならない
+    ///
+    /// COMMANDとSTATUSのように、同じ4バイトに1を書くと消えるビットを持つレジスタが並ぶため、
+    /// 4バイトの読み書きで代用してはならない
+    fn write_u16(&self, address: PciAddress, offset: u16, value: u16);
+}
+
+/// アクセス方法ごとの、列挙するバスの範囲
+#[derive(Debug, Clone)]
+pub struct BusRange {
+    pub segment: u16,
+    pub buses: RangeInclusive<u8>,
+}
+
+/// 列挙済みのPCIバス
+struct PciBus {
+    devices: Vec<PciDevice>,
+}
+
+static PCI_BUS: Once<PciBus> = Once::new();
+
+/// `ranges`に含まれるすべてのバスを列挙し、見つかったデバイスを保存する
+///
+/// 二度目以降の呼び出しでは何もせず、最初に列挙したデバイスを返す
+pub fn init(access: &'static dyn ConfigAccess, ranges: &[BusRange]) -> &'static [PciDevice] {
+    let bus = PCI_BUS.call_once(|| PciBus {
+        devices: enumerate(access, ranges),
+    });
+
+    // 先に登録されていたドライバを結び付ける
+    driver::probe_all(&bus.devices);
+    &bus.devices
+}
+
+/// バスを列挙済みであれば`true`を返す
+pub fn is_initialized() -> bool {
+    PCI_BUS.is_completed()
+}
+
+/// 列挙済みのデバイスを返す。`init()`の前は空になる
+pub fn devices() -> &'static [PciDevice] {
+    PCI_BUS.get().map_or(&[], |bus| &bus.devices)
+}
+
+/// `ranges`に含まれるすべてのバスのデバイスを列挙する
+///
+/// ブリッジを辿らず、範囲内のバスをすべて調べる
+fn enumerate(access: &'static dyn ConfigAccess, ranges: &[BusRange]) -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+
+    for range in ranges {
+        for bus in range.buses.clone() {
+            for device in 0..DEVICES_PER_BUS {
+                let address = PciAddress::new(range.segment, bus, device, 0);
+                if access.read_u16(address, register::VENDOR_ID) == INVALID_VENDOR_ID {
+                    continue;
+                }
+
+                // ヘッダタイプの最上位ビットが立っていれば、複数のファンクションを持つ
+                let multifunction = access.read_u8(address, register::HEADER_TYPE) & 0x80 != 0;
+                let functions = if multifunction {
+                    FUNCTIONS_PER_DEVICE
+                } else {
+                    1
+                };
+
+                for function in 0..functions {
+                    let address = PciAddress::new(range.segment, bus, device, function);
+                    if access.read_u16(address, register::VENDOR_ID) != INVALID_VENDOR_ID {
+                        devices.push(PciDevice::probe(access, address));
+                    }
+                }
+            }
+        }
+    }
+
+    devices
+}
+
+/// クラスコードとサブクラスコードの大まかな名前を返す
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        (0x0d, _) => "Wireless controller",
+        _ => "Unknown device",
+    }
+}
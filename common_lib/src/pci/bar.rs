@@ -0,0 +1,163 @@
+//! ベースアドレスレジスタ (Base Address Register, 以下BAR)
+
+use core::fmt;
+
+use super::{command, register, ConfigAccess, PciAddress};
+
+/// 通常のデバイス（ヘッダタイプ0）が持つBARの数
+pub const MAX_BARS: usize = 6;
+
+/// BARが表す領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// メモリ空間の領域
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// 次のBARと合わせて64ビットのアドレスを表す
+        is_64bit: bool,
+    },
+    /// I/O空間の領域
+    Io { port: u32, size: u32 },
+}
+
+impl Bar {
+    /// 領域の先頭アドレス
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Memory { address, .. } => address,
+            Bar::Io { port, .. } => port as u64,
+        }
+    }
+
+    /// 領域の大きさ（バイト数）
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+                is_64bit,
+            } => write!(
+                f,
+                "Memory at {:#x} ({}-bit, {}) [size={:#x}]",
+                address,
+                if is_64bit { 64 } else { 32 },
+                if prefetchable {
+                    "prefetchable"
+                } else {
+                    "non-prefetchable"
+                },
+                size
+            ),
+            Bar::Io { port, size } => write!(f, "I/O ports at {:#x} [size={:#x}]", port, size),
+        }
+    }
+}
+
+/// `count`個のBARを読み出し、それぞれの大きさを調べる
+///
+/// 大きさを調べる間はデバイスのデコードを止めておく。64ビットのBARが占める2つ目の枠は`None`になる
+pub(super) fn read_bars(
+    access: &dyn ConfigAccess,
+    address: PciAddress,
+    count: usize,
+) -> [Option<Bar>; MAX_BARS] {
+    let mut bars = [None; MAX_BARS];
+
+    let command = access.read_u16(address, register::COMMAND);
+    access.write_u16(
+        address,
+        register::COMMAND,
+        command & !(command::IO_SPACE | command::MEMORY_SPACE),
+    );
+
+    let mut index = 0;
+    while index < count.min(MAX_BARS) {
+        let offset = register::BAR0 + index as u16 * 4;
+        let (bar, slots) = read_bar(access, address, offset, index + 1 < count);
+        bars[index] = bar;
+        index += slots;
+    }
+
+    access.write_u16(address, register::COMMAND, command);
+    bars
+}
+
+/// 1つのBARを読み出す。BARが占める枠の数も返す
+fn read_bar(
+    access: &dyn ConfigAccess,
+    address: PciAddress,
+    offset: u16,
+    has_next: bool,
+) -> (Option<Bar>, usize) {
+    let original = access.read(address, offset);
+    let size_mask = probe_size(access, address, offset, original);
+
+    // I/O空間
+    if original & 0x1 != 0 {
+        let size = !(size_mask & !0x3) as u16 as u32 + 1;
+        if size_mask & !0x3 == 0 {
+            return (None, 1);
+        }
+        return (
+            Some(Bar::Io {
+                port: original & !0x3,
+                size,
+            }),
+            1,
+        );
+    }
+
+    let prefetchable = original & 0x8 != 0;
+    let is_64bit = (original >> 1) & 0x3 == 0x2 && has_next;
+
+    let (address_value, mask) = if is_64bit {
+        let upper_offset = offset + 4;
+        let upper = access.read(address, upper_offset);
+        let upper_mask = probe_size(access, address, upper_offset, upper);
+        (
+            (upper as u64) << 32 | (original & !0xf) as u64,
+            (upper_mask as u64) << 32 | (size_mask & !0xf) as u64,
+        )
+    } else {
+        (
+            (original & !0xf) as u64,
+            0xffff_ffff_0000_0000 | (size_mask & !0xf) as u64,
+        )
+    };
+
+    let slots = if is_64bit { 2 } else { 1 };
+    if mask & 0xffff_ffff == 0 && (!is_64bit || mask == 0) {
+        // 実装されていないBAR
+        return (None, slots);
+    }
+
+    (
+        Some(Bar::Memory {
+            address: address_value,
+            size: (!mask).wrapping_add(1),
+            prefetchable,
+            is_64bit,
+        }),
+        slots,
+    )
+}
+
+/// BARにすべて1を書き込んで読み戻し、元の値に戻す。読み戻した値から大きさが分かる
+fn probe_size(access: &dyn ConfigAccess, address: PciAddress, offset: u16, original: u32) -> u32 {
+    access.write(address, offset, 0xffff_ffff);
+    let mask = access.read(address, offset);
+    access.write(address, offset, original);
+    mask
+}
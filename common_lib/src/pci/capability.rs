@@ -0,0 +1,173 @@
+//! ケーパビリティリストとMSI/MSI-X
+
+use alloc::vec::Vec;
+
+use super::{register, ConfigAccess, PciAddress};
+
+/// ケーパビリティリストを辿る回数の上限。壊れたリストで無限ループに陥らないようにする
+const MAX_CAPABILITIES: usize = 48;
+
+/// ケーパビリティのID
+pub mod id {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR_SPECIFIC: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSI_X: u8 = 0x11;
+}
+
+/// ケーパビリティリストの1項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// コンフィギュレーション空間内のオフセット
+    pub offset: u8,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self.id {
+            id::POWER_MANAGEMENT => "Power Management",
+            id::MSI => "MSI",
+            id::VENDOR_SPECIFIC => "Vendor Specific",
+            id::PCI_EXPRESS => "PCI Express",
+            id::MSI_X => "MSI-X",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// ケーパビリティリストを読み出す
+pub(super) fn read_capabilities(access: &dyn ConfigAccess, address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    let mut offset = access.read_u8(address, register::CAPABILITIES_POINTER) & !0x3;
+
+    while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = access.read_u16(address, offset as u16);
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) as u8 & !0x3;
+    }
+
+    capabilities
+}
+
+/// MSIケーパビリティ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    pub offset: u8,
+    /// メッセージアドレスが64ビットである
+    pub is_64bit: bool,
+    /// ベクタごとのマスクに対応している
+    pub per_vector_masking: bool,
+    /// 要求できるベクタ数
+    pub max_vectors: u8,
+}
+
+/// MSIのメッセージ制御レジスタの、有効化ビット
+const MSI_ENABLE: u16 = 1 << 0;
+
+impl Msi {
+    pub(super) fn parse(access: &dyn ConfigAccess, address: PciAddress, offset: u8) -> Self {
+        let control = access.read_u16(address, offset as u16 + 2);
+        Msi {
+            offset,
+            is_64bit: control & (1 << 7) != 0,
+            per_vector_masking: control & (1 << 8) != 0,
+            max_vectors: 1 << ((control >> 1) & 0x7),
+        }
+    }
+
+    /// 割り込みメッセージのアドレスとデータを書き込み、単一のベクタでMSIを有効化する
+    pub fn enable(
+        &self,
+        access: &dyn ConfigAccess,
+        address: PciAddress,
+        message_address: u64,
+        message_data: u16,
+    ) {
+        let offset = self.offset as u16;
+        access.write(address, offset + 4, message_address as u32);
+        let data_offset = if self.is_64bit {
+            access.write(address, offset + 8, (message_address >> 32) as u32);
+            offset + 12
+        } else {
+            offset + 8
+        };
+        access.write_u16(address, data_offset, message_data);
+
+        // 要求するベクタ数は1つ（Multiple Message Enable = 0）
+        let control = access.read_u16(address, offset + 2);
+        access.write_u16(address, offset + 2, (control & !(0x7 << 4)) | MSI_ENABLE);
+    }
+
+    /// MSIを無効化する
+    pub fn disable(&self, access: &dyn ConfigAccess, address: PciAddress) {
+        let offset = self.offset as u16 + 2;
+        let control = access.read_u16(address, offset);
+        access.write_u16(address, offset, control & !MSI_ENABLE);
+    }
+}
+
+/// MSI-Xケーパビリティ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiX {
+    pub offset: u8,
+    /// テーブルの項目数
+    pub table_size: u16,
+    /// テーブルが置かれたBARの番号
+    pub table_bar: u8,
+    /// BARの先頭から見たテーブルのオフセット
+    pub table_offset: u32,
+    /// PBA (Pending Bit Array) が置かれたBARの番号
+    pub pba_bar: u8,
+    /// BARの先頭から見たPBAのオフセット
+    pub pba_offset: u32,
+}
+
+/// MSI-Xのメッセージ制御レジスタの、有効化ビット
+const MSI_X_ENABLE: u16 = 1 << 15;
+/// MSI-Xのメッセージ制御レジスタの、全ベクタをマスクするビット
+const MSI_X_FUNCTION_MASK: u16 = 1 << 14;
+
+impl MsiX {
+    /// MSI-Xテーブルの1項目の大きさ（バイト数）
+    pub const TABLE_ENTRY_SIZE: usize = 16;
+
+    pub(super) fn parse(access: &dyn ConfigAccess, address: PciAddress, offset: u8) -> Self {
+        let control = access.read_u16(address, offset as u16 + 2);
+        let table = access.read(address, offset as u16 + 4);
+        let pba = access.read(address, offset as u16 + 8);
+        MsiX {
+            offset,
+            table_size: (control & 0x7ff) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as u8,
+            pba_offset: pba & !0x7,
+        }
+    }
+
+    /// MSI-Xを有効化する。`masked`が`true`の場合は、すべてのベクタをマスクしたままにする
+    ///
+    /// テーブルの各項目は、ドライバがBARをマップして書き込む
+    pub fn enable(&self, access: &dyn ConfigAccess, address: PciAddress, masked: bool) {
+        let offset = self.offset as u16 + 2;
+        let mut control = access.read_u16(address, offset) | MSI_X_ENABLE;
+        if masked {
+            control |= MSI_X_FUNCTION_MASK;
+        } else {
+            control &= !MSI_X_FUNCTION_MASK;
+        }
+        access.write_u16(address, offset, control);
+    }
+
+    /// MSI-Xを無効化する
+    pub fn disable(&self, access: &dyn ConfigAccess, address: PciAddress) {
+        let offset = self.offset as u16 + 2;
+        let control = access.read_u16(address, offset);
+        access.write_u16(address, offset, control & !MSI_X_ENABLE);
+    }
+}
@@ -0,0 +1,233 @@
+//! 列挙されたPCIのファンクション
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{
+    bar::{self, Bar, MAX_BARS},
+    capability::{self, id, Capability, Msi, MsiX},
+    class_name, command, register, ConfigAccess, PciAddress, STATUS_CAPABILITIES_LIST,
+};
+
+/// ヘッダタイプ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    /// 通常のデバイス
+    General,
+    /// PCI-PCIブリッジ
+    PciBridge,
+    /// CardBusブリッジ
+    CardBusBridge,
+    Unknown(u8),
+}
+
+impl HeaderType {
+    fn from_raw(raw: u8) -> Self {
+        match raw & 0x7f {
+            0x00 => HeaderType::General,
+            0x01 => HeaderType::PciBridge,
+            0x02 => HeaderType::CardBusBridge,
+            other => HeaderType::Unknown(other),
+        }
+    }
+
+    /// このヘッダタイプが持つBARの数
+    fn bar_count(&self) -> usize {
+        match self {
+            HeaderType::General => MAX_BARS,
+            HeaderType::PciBridge => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// PCIのファンクション1つ分の情報
+///
+/// 列挙時にIDやBAR、ケーパビリティを読み出しておく。
+/// コマンドレジスタなどの状態は、必要になるたびにコンフィギュレーション空間から読み書きする
+pub struct PciDevice {
+    access: &'static dyn ConfigAccess,
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: HeaderType,
+    /// レガシーな割り込みピン（0は使わない、1〜4がINTA#〜INTD#）
+    pub interrupt_pin: u8,
+    /// ファームウェアが設定した割り込み線の番号
+    pub interrupt_line: u8,
+    bars: [Option<Bar>; MAX_BARS],
+    capabilities: Vec<Capability>,
+}
+
+impl PciDevice {
+    /// `address`にあるファンクションの情報を読み出す
+    pub(super) fn probe(access: &'static dyn ConfigAccess, address: PciAddress) -> Self {
+        let header_type = HeaderType::from_raw(access.read_u8(address, register::HEADER_TYPE));
+
+        let (subsystem_vendor_id, subsystem_id) = if header_type == HeaderType::General {
+            (
+                access.read_u16(address, register::SUBSYSTEM_VENDOR_ID),
+                access.read_u16(address, register::SUBSYSTEM_ID),
+            )
+        } else {
+            (0, 0)
+        };
+
+        let capabilities =
+            if access.read_u16(address, register::STATUS) & STATUS_CAPABILITIES_LIST != 0 {
+                capability::read_capabilities(access, address)
+            } else {
+                Vec::new()
+            };
+
+        PciDevice {
+            access,
+            address,
+            vendor_id: access.read_u16(address, register::VENDOR_ID),
+            device_id: access.read_u16(address, register::DEVICE_ID),
+            subsystem_vendor_id,
+            subsystem_id,
+            class: access.read_u8(address, register::CLASS),
+            subclass: access.read_u8(address, register::SUBCLASS),
+            prog_if: access.read_u8(address, register::PROG_IF),
+            revision: access.read_u8(address, register::REVISION_ID),
+            header_type,
+            interrupt_pin: access.read_u8(address, register::INTERRUPT_PIN),
+            interrupt_line: access.read_u8(address, register::INTERRUPT_LINE),
+            bars: bar::read_bars(access, address, header_type.bar_count()),
+            capabilities,
+        }
+    }
+
+    /// `index`番目のBARを返す。実装されていないか、64ビットのBARの上位側であれば`None`を返す
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// 実装されているBARを、番号と共に列挙する
+    pub fn bars(&self) -> impl Iterator<Item = (usize, Bar)> + '_ {
+        self.bars
+            .iter()
+            .enumerate()
+            .filter_map(|(index, bar)| bar.map(|bar| (index, bar)))
+    }
+
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// IDが`id`であるケーパビリティを列挙する
+    pub fn find_capabilities(&self, id: u8) -> impl Iterator<Item = Capability> + '_ {
+        self.capabilities
+            .iter()
+            .copied()
+            .filter(move |capability| capability.id == id)
+    }
+
+    /// IDが`id`である最初のケーパビリティを返す
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.find_capabilities(id).next()
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        self.find_capability(id::MSI)
+            .map(|capability| Msi::parse(self.access, self.address, capability.offset))
+    }
+
+    pub fn msi_x(&self) -> Option<MsiX> {
+        self.find_capability(id::MSI_X)
+            .map(|capability| MsiX::parse(self.access, self.address, capability.offset))
+    }
+
+    /// コンフィギュレーション空間から4バイトを読む
+    pub fn read_config(&self, offset: u16) -> u32 {
+        self.access.read(self.address, offset)
+    }
+
+    /// コンフィギュレーション空間に4バイトを書く
+    pub fn write_config(&self, offset: u16, value: u32) {
+        self.access.write(self.address, offset, value)
+    }
+
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        self.access.read_u16(self.address, offset)
+    }
+
+    pub fn write_config_u16(&self, offset: u16, value: u16) {
+        self.access.write_u16(self.address, offset, value)
+    }
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        self.access.read_u8(self.address, offset)
+    }
+
+    /// このデバイスのコンフィギュレーション空間へのアクセス方法
+    pub fn config_access(&self) -> &'static dyn ConfigAccess {
+        self.access
+    }
+
+    /// コマンドレジスタのビットを立て、他のビットはそのままにする
+    fn set_command(&self, bits: u16) {
+        let value = self.read_config_u16(register::COMMAND);
+        self.write_config_u16(register::COMMAND, value | bits);
+    }
+
+    /// メモリ空間のデコードを有効にする
+    pub fn enable_memory_space(&self) {
+        self.set_command(command::MEMORY_SPACE);
+    }
+
+    /// I/O空間のデコードを有効にする
+    pub fn enable_io_space(&self) {
+        self.set_command(command::IO_SPACE);
+    }
+
+    /// デバイスがバスマスタとしてDMAを行えるようにする
+    pub fn enable_bus_master(&self) {
+        self.set_command(command::BUS_MASTER);
+    }
+
+    /// レガシーな割り込み（INTx#）を止める。MSI/MSI-Xを使う場合に呼ぶ
+    pub fn disable_legacy_interrupt(&self) {
+        self.set_command(command::INTERRUPT_DISABLE);
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+impl fmt::Debug for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PciDevice")
+            .field("address", &self.address)
+            .field("vendor_id", &self.vendor_id)
+            .field("device_id", &self.device_id)
+            .field("class", &self.class)
+            .field("subclass", &self.subclass)
+            .field("prog_if", &self.prog_if)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: [{:04x}:{:04x}] (rev {:02x})",
+            self.address,
+            self.class_name(),
+            self.class,
+            self.subclass,
+            self.vendor_id,
+            self.device_id,
+            self.revision
+        )
+    }
+}
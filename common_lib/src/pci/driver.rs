@@ -0,0 +1,181 @@
+//! PCIデバイスドライバの登録と、デバイスとの結び付け
+//!
+//! ドライバは`PciDriver`を実装し、対応するデバイスを`DeviceMatch`の一覧で表す。
+//! `register()`されたドライバは、列挙済みのデバイスのうち一致するものについて`probe()`を呼ばれる。
+//! 1つのデバイスに結び付くドライバは1つだけで、先に登録されたものが優先される
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Mutex;
+
+use super::{devices, PciAddress, PciDevice};
+
+/// デバイスとの一致条件。`None`の項目はどの値とも一致する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl DeviceMatch {
+    /// ベンダーIDとデバイスIDで一致させる
+    pub const fn id(vendor_id: u16, device_id: u16) -> Self {
+        DeviceMatch {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// ベンダーIDだけで一致させる
+    pub const fn vendor(vendor_id: u16) -> Self {
+        DeviceMatch {
+            vendor_id: Some(vendor_id),
+            device_id: None,
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// クラスコードとサブクラスコードで一致させる
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        DeviceMatch {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    /// クラスコード、サブクラスコード、プログラミングインタフェースで一致させる
+    pub const fn class_with_prog_if(class: u8, subclass: u8, prog_if: u8) -> Self {
+        DeviceMatch {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: Some(prog_if),
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn field<T: PartialEq>(condition: Option<T>, value: T) -> bool {
+            condition.is_none_or(|condition| condition == value)
+        }
+
+        field(self.vendor_id, device.vendor_id)
+            && field(self.device_id, device.device_id)
+            && field(self.class, device.class)
+            && field(self.subclass, device.subclass)
+            && field(self.prog_if, device.prog_if)
+    }
+}
+
+/// `probe()`が失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// 一致したが、このドライバでは扱えないデバイスだった
+    Unsupported,
+    /// デバイスの初期化に失敗した
+    InitializationFailed(&'static str),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::Unsupported => write!(f, "unsupported device"),
+            ProbeError::InitializationFailed(reason) => {
+                write!(f, "initialization failed: {}", reason)
+            }
+        }
+    }
+}
+
+/// PCIデバイスドライバ
+pub trait PciDriver: Sync {
+    /// ログに使うドライバの名前
+    fn name(&self) -> &'static str;
+
+    /// 対応するデバイスの一覧
+    fn id_table(&self) -> &[DeviceMatch];
+
+    /// 一致したデバイスを初期化する。`Ok`を返すと、デバイスはこのドライバに結び付く
+    fn probe(&self, device: &'static PciDevice) -> Result<(), ProbeError>;
+}
+
+/// 登録済みのドライバと、結び付いたデバイス
+struct Registry {
+    drivers: Vec<&'static dyn PciDriver>,
+    bindings: Vec<(PciAddress, &'static dyn PciDriver)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    drivers: Vec::new(),
+    bindings: Vec::new(),
+});
+
+/// ドライバを登録し、列挙済みのデバイスのうち一致するものに結び付ける
+pub fn register(driver: &'static dyn PciDriver) {
+    REGISTRY.lock().drivers.push(driver);
+    for device in devices() {
+        try_bind(driver, device);
+    }
+}
+
+/// 登録済みのすべてのドライバを、`devices`のうちまだ結び付いていないものに結び付ける
+pub(super) fn probe_all(devices: &'static [PciDevice]) {
+    let drivers = REGISTRY.lock().drivers.clone();
+    for device in devices {
+        for &driver in &drivers {
+            if try_bind(driver, device) {
+                break;
+            }
+        }
+    }
+}
+
+/// `device`に結び付いているドライバの名前を返す
+pub fn bound_driver(address: PciAddress) -> Option<&'static str> {
+    REGISTRY
+        .lock()
+        .bindings
+        .iter()
+        .find(|(bound, _)| *bound == address)
+        .map(|(_, driver)| driver.name())
+}
+
+/// `driver`が`device`に一致すれば`probe()`を呼ぶ。結び付いた場合は`true`を返す
+///
+/// `probe()`の中でドライバが他のデバイスを調べられるよう、呼び出し中はロックを外しておく
+fn try_bind(driver: &'static dyn PciDriver, device: &'static PciDevice) -> bool {
+    if bound_driver(device.address).is_some()
+        || !driver.id_table().iter().any(|id| id.matches(device))
+    {
+        return false;
+    }
+
+    match driver.probe(device) {
+        Ok(()) => {
+            log::info!("pci {}: bound to {}", device.address, driver.name());
+            REGISTRY.lock().bindings.push((device.address, driver));
+            true
+        }
+        Err(e) => {
+            log::warn!(
+                "pci {}: {} probe failed: {}",
+                device.address,
+                driver.name(),
+                e
+            );
+            false
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! 割り込みハンドラと共有するデータのための同期機構
+//!
+//! 単純なスピンロックは、ロックを持ったまま割り込まれ、割り込みハンドラが同じロックを取ろうとすると行き詰まる。
+//! `IrqMutex`はロックを持っている間は割り込みを無効にしておくことで、これを防ぐ。
+//!
+//! デバッグビルドでは、同じロックの二重取得と、`LockLevel`で定めた取得順序に反するロックの取得を検出してパニックを起こす
+
+pub mod irq_mutex;
+#[cfg(debug_assertions)]
+mod lock_order;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub use self::irq_mutex::{IrqMutex, IrqMutexGuard, LockLevel};
+
+/// パニックの処理を始めている
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// パニックの処理を始めたことを記録する。すでに始めていれば`true`を返す
+///
+/// これ以降はロックの取得順序を検査しない。パニックはどのロックを持った状態でも起こりうるため、
+/// パニックハンドラの中で順序違反を検出すると、パニックが繰り返されて何も出力できなくなる
+pub fn begin_panic() -> bool {
+    PANICKING.swap(true, Ordering::Relaxed)
+}
+
+/// パニックの処理を始めていれば`true`を返す
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// 割り込みを無効にし、それまで割り込みが有効だったかを返す
+#[inline(always)]
+pub fn save_and_disable_interrupts() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let rflags: u64;
+        // `cli`より前のメモリアクセスが後ろへ移動しないよう、`nomem`は付けない
+        unsafe {
+            core::arch::asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+        }
+        // RFLAGSの割り込み許可フラグ (IF)
+        rflags & (1 << 9) != 0
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+}
+
+/// `save_and_disable_interrupts()`が返した状態に割り込みの有効・無効を戻す
+#[inline(always)]
+pub fn restore_interrupts(enabled: bool) {
+    #[cfg(target_arch = "x86_64")]
+    if enabled {
+        unsafe {
+            core::arch::asm!("sti", options(nostack));
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = enabled;
+}
@@ -0,0 +1,214 @@
+//! 取得している間は割り込みを無効にするスピンロック
+
+use core::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use super::{restore_interrupts, save_and_disable_interrupts};
+
+/// 取得中の`IrqMutex`の数
+static NESTING: AtomicUsize = AtomicUsize::new(0);
+/// 最初の`IrqMutex`を取得する前に、割り込みが有効だったか
+static OUTER_INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// 割り込みを無効にし、取得中のロックの数を一つ増やす
+///
+/// ロックは取得と逆順に解放されるとは限らないため、割り込みの状態はロックごとではなく、
+/// 最初のロックを取得する前のものを一つだけ覚えておき、最後のロックを解放したときに戻す
+fn enter() {
+    let enabled = save_and_disable_interrupts();
+    if NESTING.fetch_add(1, Ordering::Relaxed) == 0 {
+        OUTER_INTERRUPTS_ENABLED.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// 取得中のロックの数を一つ減らし、最後の一つであれば割り込みの状態を元に戻す
+fn exit() {
+    if NESTING.fetch_sub(1, Ordering::Relaxed) == 1 {
+        restore_interrupts(OUTER_INTERRUPTS_ENABLED.load(Ordering::Relaxed));
+    }
+}
+
+/// ロックを取る順序を定める階層
+///
+/// 階層を持つロックを取っている間は、それより大きな階層のロックしか取ってはならない。
+/// デバッグビルドでは、これに反するとパニックを起こす
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LockLevel(pub u32);
+
+impl LockLevel {
+    /// コンソールのテキストバッファ
+    pub const CONSOLE: LockLevel = LockLevel(10);
+    /// フレームバッファ。テキストバッファから書き出すときに取る
+    pub const FRAME_BUFFER: LockLevel = LockLevel(20);
+    /// ログのリングバッファ
+    pub const LOG_BUFFER: LockLevel = LockLevel(30);
+    /// シリアルポート
+    pub const SERIAL: LockLevel = LockLevel(40);
+    /// PCIのコンフィギュレーション空間（I/Oポート経由）
+    pub const PCI_CONFIG: LockLevel = LockLevel(50);
+    /// キーボードのスキャンコードの解釈状態
+    pub const KEYBOARD: LockLevel = LockLevel(60);
+    /// グローバルアロケータ。ほかのどのロックの中からでも取られうる
+    pub const ALLOCATOR: LockLevel = LockLevel(1000);
+    /// ページテーブルと物理フレームアロケータ。ヒープへのアクセスで起きたページフォルトから取られるため、
+    /// アロケータより大きい
+    pub const MEMORY_MANAGER: LockLevel = LockLevel(2000);
+    /// ロガーのフィルタと出力先の一覧。ログはどのロックの中からでも出力されうるため、最も大きい。
+    /// 取っている間にほかのロックを取ってはならない
+    pub const LOGGER_CONFIG: LockLevel = LockLevel(3000);
+}
+
+/// 取得している間は割り込みを無効にするスピンロック
+///
+/// 割り込みハンドラと共有するデータを守るのに使う。すべての`IrqMutex`を解放すると、取得前の割り込みの状態に戻す
+pub struct IrqMutex<T: ?Sized> {
+    name: &'static str,
+    level: Option<LockLevel>,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    /// 取得順序を定めないロックを作る
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            name: "<unnamed>",
+            level: None,
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// 取得順序の階層を持つロックを作る。`name`は順序違反などの報告に使う
+    pub const fn with_level(value: T, name: &'static str, level: LockLevel) -> Self {
+        IrqMutex {
+            name,
+            level: Some(level),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// ロックを破棄し、中身を取り出す
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    /// 割り込みを無効にしてからロックを取得する
+    ///
+    /// ## Panic
+    /// デバッグビルドでは、すでに同じロックを取得している場合と、取得順序に反する場合はパニックを起こす。
+    /// パニックの処理を始めた後は検査しない
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        enter();
+
+        #[cfg(debug_assertions)]
+        if !super::is_panicking() {
+            if let Err(violation) = super::lock_order::check(self.address(), self.name, self.level)
+            {
+                exit();
+                panic!("{}", violation);
+            }
+        }
+
+        let guard = self.inner.lock();
+        self.acquired(guard)
+    }
+
+    /// ロックが取れる場合に限り、割り込みを無効にしてからロックを取得する
+    ///
+    /// 取れなかった場合は割り込みの状態を元に戻して`None`を返す。待たないため、取得順序は検査しない
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        enter();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.acquired(guard)),
+            None => {
+                exit();
+                None
+            }
+        }
+    }
+
+    /// ロックが取得されていれば`true`を返す
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    fn acquired<'a>(&'a self, guard: spin::MutexGuard<'a, T>) -> IrqMutexGuard<'a, T> {
+        #[cfg(debug_assertions)]
+        super::lock_order::push(self.address(), self.name, self.level);
+
+        IrqMutexGuard {
+            #[cfg(debug_assertions)]
+            address: self.address(),
+            guard: ManuallyDrop::new(guard),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn address(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+}
+
+impl<T: Default> Default for IrqMutex<T> {
+    fn default() -> Self {
+        IrqMutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f
+                .debug_struct("IrqMutex")
+                .field("name", &self.name)
+                .field("data", &&*guard)
+                .finish(),
+            None => f
+                .debug_struct("IrqMutex")
+                .field("name", &self.name)
+                .field("data", &format_args!("<locked>"))
+                .finish(),
+        }
+    }
+}
+
+/// `IrqMutex`のロックを保持している間、中身を参照するための型
+///
+/// 破棄されるとロックを解放し、取得中のロックが無くなれば割り込みの状態を元に戻す
+pub struct IrqMutexGuard<'a, T: ?Sized + 'a> {
+    #[cfg(debug_assertions)]
+    address: usize,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // ロックを解放してから割り込みを戻す。逆にすると、解放前に割り込まれて行き詰まる
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        #[cfg(debug_assertions)]
+        super::lock_order::release(self.address);
+
+        exit();
+    }
+}
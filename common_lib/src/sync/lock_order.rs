@@ -0,0 +1,115 @@
+//! デバッグビルドでのロックの取得順序の検査
+//!
+//! 取得中のロックを記録しておき、新たに取得しようとするロックがすでに記録されていないか（二重取得）と、
+//! 記録されているロックより大きな階層を持つか（順序違反）を調べる。
+//! `IrqMutex`は取得中に割り込みを無効にするため、取得中のロックはCPUに対して一つの列として記録できる
+
+use core::fmt;
+
+use super::LockLevel;
+
+/// 同時に記録できるロックの数
+const MAX_HELD_LOCKS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    address: usize,
+    name: &'static str,
+    level: Option<LockLevel>,
+}
+
+struct HeldLocks {
+    locks: [Option<HeldLock>; MAX_HELD_LOCKS],
+}
+
+/// 記録そのものを守るロック。割り込みが無効な状態でしか触らないため、単純なスピンロックでよい
+static HELD_LOCKS: spin::Mutex<HeldLocks> = spin::Mutex::new(HeldLocks {
+    locks: [None; MAX_HELD_LOCKS],
+});
+
+/// ロックの取得順序に関する違反
+pub(super) enum Violation {
+    /// 同じロックを二重に取得しようとした
+    Recursive { name: &'static str },
+    /// 取得中のロック以下の階層のロックを取得しようとした
+    Order {
+        name: &'static str,
+        level: LockLevel,
+        held_name: &'static str,
+        held_level: LockLevel,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Recursive { name } => {
+                write!(f, "Deadlock: lock `{}` is already held by this CPU", name)
+            }
+            Violation::Order {
+                name,
+                level,
+                held_name,
+                held_level,
+            } => write!(
+                f,
+                "Lock order violation: acquiring `{}` (level {}) while holding `{}` (level {})",
+                name, level.0, held_name, held_level.0
+            ),
+        }
+    }
+}
+
+/// `address`のロックを取得してよいかを調べる
+pub(super) fn check(
+    address: usize,
+    name: &'static str,
+    level: Option<LockLevel>,
+) -> Result<(), Violation> {
+    let held_locks = HELD_LOCKS.lock();
+
+    for held in held_locks.locks.iter().flatten() {
+        if held.address == address {
+            return Err(Violation::Recursive { name });
+        }
+
+        if let (Some(level), Some(held_level)) = (level, held.level) {
+            if level <= held_level {
+                return Err(Violation::Order {
+                    name,
+                    level,
+                    held_name: held.name,
+                    held_level,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// 取得したロックを記録する。記録が満杯の場合は記録を諦める
+pub(super) fn push(address: usize, name: &'static str, level: Option<LockLevel>) {
+    let mut held_locks = HELD_LOCKS.lock();
+
+    if let Some(slot) = held_locks.locks.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(HeldLock {
+            address,
+            name,
+            level,
+        });
+    }
+}
+
+/// 解放したロックの記録を消す。ロックは取得と逆順に解放されるとは限らない
+pub(super) fn release(address: usize) {
+    let mut held_locks = HELD_LOCKS.lock();
+
+    if let Some(slot) = held_locks
+        .locks
+        .iter_mut()
+        .find(|slot| matches!(slot, Some(held) if held.address == address))
+    {
+        *slot = None;
+    }
+}
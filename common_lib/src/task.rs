@@ -0,0 +1,53 @@
+//! 割り込み駆動のドライバなどを`async`で扱うための、協調的な非同期ランタイム
+//!
+//! `Executor`は起こされたタスクだけを実行し、実行できるタスクが無くなると割り込みが来るまでCPUを休ませる。
+//! どのように休ませるかはアーキテクチャに依存するため、`Executor::new()`に関数として渡す
+
+pub mod executor;
+pub mod queue;
+pub mod stream;
+pub mod timer;
+pub mod waker;
+
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+/// タスクの識別子
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// 実行器が扱うタスク。戻り値の無いFutureを包む
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    /// タスクの識別子を返す
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
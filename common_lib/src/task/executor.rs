@@ -0,0 +1,162 @@
+//! 起こされたタスクだけを実行する実行器
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+};
+use core::{
+    future::Future,
+    task::{Context, Poll, Waker},
+};
+
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+
+use super::{timer, Task, TaskId};
+
+/// 実行可能なタスクを並べておくキューの大きさ
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// 実行可能なタスクが無いときにCPUを休ませる関数
+///
+/// 引数の関数が`true`を返す場合に限り、次の割り込みまでCPUを休ませること。
+/// 確認してから休ませるまでの間に割り込みが入ると起床を取りこぼすため、その間は割り込みを無効にしておく必要がある
+pub type IdleFn = fn(&dyn Fn() -> bool);
+
+/// タスクの実行器
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    /// 起こされたタスク。`Waker`から（割り込みハンドラの中でも）積まれる
+    ready: Arc<ArrayQueue<TaskId>>,
+    /// `Spawner`から追加されたが、まだ実行器に取り込んでいないタスク
+    spawned: Arc<Mutex<VecDeque<Task>>>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+    idle: IdleFn,
+}
+
+impl Executor {
+    /// 最大で`capacity`個のタスクを扱える実行器を作る
+    pub fn new(capacity: usize, idle: IdleFn) -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            ready: Arc::new(ArrayQueue::new(capacity)),
+            spawned: Arc::new(Mutex::new(VecDeque::new())),
+            waker_cache: BTreeMap::new(),
+            idle,
+        }
+    }
+
+    /// タスクを追加する
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) {
+        self.insert(Task::new(future));
+    }
+
+    /// 実行中のタスクなど、実行器の外からタスクを追加するためのハンドルを返す
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: self.spawned.clone(),
+        }
+    }
+
+    /// 実行中のタスクの数を返す
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn insert(&mut self, task: Task) {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("Task with the same ID already exists");
+        }
+        self.ready.push(id).expect("Task queue is full");
+    }
+
+    /// `Spawner`から追加されたタスクを取り込む
+    fn accept_spawned(&mut self) {
+        loop {
+            let Some(task) = self.spawned.lock().pop_front() else {
+                break;
+            };
+            self.insert(task);
+        }
+    }
+
+    /// 起こされたタスクをすべて一度ずつ実行する
+    pub fn run_ready_tasks(&mut self) {
+        self.accept_spawned();
+        timer::wake_expired();
+
+        while let Some(id) = self.ready.pop() {
+            // 完了済みのタスクが再び起こされた場合は無視する
+            let Some(task) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+
+            let waker = self
+                .waker_cache
+                .entry(id)
+                .or_insert_with(|| TaskWaker::new_waker(id, self.ready.clone()));
+            let mut context = Context::from_waker(waker);
+
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                self.tasks.remove(&id);
+                self.waker_cache.remove(&id);
+            }
+        }
+    }
+
+    /// タスクを実行し続ける。実行できるタスクが無いときはCPUを休ませる
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        let ready = &self.ready;
+        let spawned = &self.spawned;
+        (self.idle)(&|| ready.is_empty() && spawned.lock().is_empty() && !timer::has_expired());
+    }
+}
+
+/// 実行器の外からタスクを追加するためのハンドル
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Arc<Mutex<VecDeque<Task>>>,
+}
+
+impl Spawner {
+    /// タスクを追加する。次に実行器が休止から戻ったときに実行される
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        self.spawned.lock().push_back(Task::new(future));
+    }
+}
+
+/// タスクを実行可能なキューに積み直す`Waker`
+struct TaskWaker {
+    id: TaskId,
+    ready: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new_waker(id: TaskId, ready: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { id, ready }))
+    }
+
+    fn wake_task(&self) {
+        // すでに積まれている場合に満杯になることはあるが、その場合でもいずれ実行される
+        let _ = self.ready.push(self.id);
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,77 @@
+//! 割り込みハンドラから値を積み、タスクから`Stream`として取り出すためのキュー
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crossbeam_queue::ArrayQueue;
+
+use super::{stream::Stream, waker::AtomicWaker};
+
+/// 値が積まれるたびに、待っているタスクを起こすキュー
+///
+/// 積む側も取り出す側もロックを取らないため、割り込みハンドラから積んでもよい
+pub struct WakeQueue<T> {
+    queue: ArrayQueue<T>,
+    waker: AtomicWaker,
+}
+
+impl<T> WakeQueue<T> {
+    /// 最大で`capacity`個の値を保持するキューを作る。ヒープを使う
+    pub fn new(capacity: usize) -> Self {
+        WakeQueue {
+            queue: ArrayQueue::new(capacity),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// 値を積み、待っているタスクを起こす。満杯の場合は積まずに`false`を返す
+    pub fn push(&self, value: T) -> bool {
+        let pushed = self.queue.push(value).is_ok();
+        self.waker.wake();
+        pushed
+    }
+
+    /// 最も古い値を取り出す
+    pub fn pop(&self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    /// 何も積まれていなければ`true`を返す
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// 積まれた値を順に取り出す`Stream`を返す
+    ///
+    /// 起こせるタスクは一つだけなので、同時に複数の`Stream`から待たないこと
+    pub fn stream(&self) -> QueueStream<'_, T> {
+        QueueStream { queue: self }
+    }
+}
+
+/// `WakeQueue::stream()`が返す`Stream`。値が尽きることはない
+pub struct QueueStream<'a, T> {
+    queue: &'a WakeQueue<T>,
+}
+
+impl<T> Stream for QueueStream<'_, T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(value) = self.queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        // 登録と確認の間に積まれた値を取りこぼさないよう、登録してからもう一度確認する
+        self.queue.waker.register(cx.waker());
+        match self.queue.pop() {
+            Some(value) => {
+                self.queue.waker.take();
+                Poll::Ready(Some(value))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! 値を非同期に次々と生み出す`Stream`トレイト
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// 値を非同期に次々と生み出すトレイト。`Iterator`の非同期版にあたる
+pub trait Stream {
+    type Item;
+
+    /// 次の値を取り出す。まだ無ければ`Poll::Pending`を返し、値が用意できたときに`cx`の`Waker`を起こす。
+    /// これ以上値が無ければ`Poll::Ready(None)`を返す
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+}
+
+/// `Stream`を`async`の中で使うための便利メソッド
+pub trait StreamExt: Stream {
+    /// 次の値を待つFutureを返す
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+/// `StreamExt::next()`が返すFuture
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
@@ -0,0 +1,147 @@
+//! 時間の経過を待つFuture
+//!
+//! 待っているタスクの`Waker`は期限の順に登録しておき、`Executor`が休止から戻るたびに`wake_expired()`で起こす。
+//! そのため時刻の精度は、タイマ割り込みの周期に等しい
+
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+use super::stream::Stream;
+use crate::time::{Duration, Instant};
+
+/// 期限と登録順の組から、待っているタスクの`Waker`を引く表
+static TIMERS: Mutex<BTreeMap<(Instant, u64), Waker>> = Mutex::new(BTreeMap::new());
+
+/// 期限を過ぎたタイマのタスクを起こす
+pub fn wake_expired() {
+    let now = Instant::now();
+
+    loop {
+        // `wake()`の中でタイマが登録されることもあるため、ロックを外してから起こす
+        let waker = {
+            let mut timers = TIMERS.lock();
+            match timers.first_key_value() {
+                Some((&(deadline, _), _)) if deadline <= now => timers.pop_first().map(|(_, w)| w),
+                _ => None,
+            }
+        };
+
+        match waker {
+            Some(waker) => waker.wake(),
+            None => break,
+        }
+    }
+}
+
+/// 期限を過ぎたタイマがあれば`true`を返す
+pub fn has_expired() -> bool {
+    let now = Instant::now();
+    TIMERS
+        .lock()
+        .first_key_value()
+        .is_some_and(|(&(deadline, _), _)| deadline <= now)
+}
+
+/// 指定した時間が経過すると完了するFutureを返す
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// 指定した時刻になると完了するFutureを返す
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    static NEXT_KEY: AtomicU64 = AtomicU64::new(0);
+
+    Sleep {
+        deadline,
+        key: NEXT_KEY.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+/// `sleep()`と`sleep_until()`が返すFuture
+pub struct Sleep {
+    deadline: Instant,
+    /// 同じ期限のタイマを区別するための番号
+    key: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    /// 完了する時刻を返す
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// 完了する時刻を変更する
+    pub fn reset(&mut self, deadline: Instant) {
+        self.unregister();
+        self.deadline = deadline;
+    }
+
+    fn unregister(&mut self) {
+        if self.registered {
+            TIMERS.lock().remove(&(self.deadline, self.key));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        TIMERS
+            .lock()
+            .insert((self.deadline, self.key), cx.waker().clone());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// 一定の周期で時刻を生み出す`Stream`を返す。最初の値は`period`後に生み出す
+pub fn interval(period: Duration) -> Interval {
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+/// `interval()`が返す`Stream`
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let fired = self.sleep.deadline();
+                let next = fired + self.period;
+                self.sleep.reset(next);
+                Poll::Ready(Some(fired))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
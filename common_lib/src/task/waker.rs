@@ -0,0 +1,98 @@
+//! 割り込みハンドラから安全にタスクを起こすための`Waker`の置き場所
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU8, Ordering},
+    task::Waker,
+};
+
+/// 登録も起床も行われていない
+const WAITING: u8 = 0;
+/// `register()`が`Waker`を書き換えている
+const REGISTERING: u8 = 0b01;
+/// `wake()`が`Waker`を取り出している
+const WAKING: u8 = 0b10;
+
+/// 一つの`Waker`を保持し、ロックを取らずに登録と起床を行える型
+///
+/// 登録はタスクから、起床は割り込みハンドラから行うことを想定している。
+/// 起床が登録と重なった場合は、登録した側が直ちに起こす
+pub struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// `waker`へのアクセスは`state`によって排他されている
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        AtomicWaker {
+            state: AtomicU8::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// 次の`wake()`で起こす`Waker`を登録する
+    pub fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                unsafe {
+                    let slot = &mut *self.waker.get();
+                    if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                        *slot = Some(waker.clone());
+                    }
+                }
+
+                // 書き換えている間に`wake()`が呼ばれていれば、ここで起こす
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.store(WAITING, Ordering::Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // 起床の最中であれば、登録せずに直ちに起こす
+            Err(WAKING) => waker.wake_by_ref(),
+            // 他で登録中であれば何もしない
+            Err(_) => {}
+        }
+    }
+
+    /// 登録されている`Waker`があれば起こす
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    /// 登録されている`Waker`を取り出す
+    pub fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            WAITING => {
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Ordering::Release);
+                waker
+            }
+            // 登録中か、他で起床中の場合は、そちらに任せる
+            _ => None,
+        }
+    }
+}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
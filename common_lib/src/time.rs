@@ -0,0 +1,166 @@
+//! 起動時からの経過時間を扱うモジュール
+//!
+//! アーキテクチャ側のタイマ割り込みから`tick()`を呼んでもらい、その回数と周期から単調増加する時刻を作る。
+//! 時刻の分解能はタイマ割り込みの周期に等しい
+
+use core::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Once;
+
+pub use core::time::Duration;
+
+/// これまでに発生したタイマ割り込みの回数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// 起動時からの経過時間（ナノ秒）
+static ELAPSED_NANOS: AtomicU64 = AtomicU64::new(0);
+/// 現在のタイマ割り込みの周期（ナノ秒）
+static TICK_PERIOD_NANOS: AtomicU64 = AtomicU64::new(0);
+/// `sleep()`で待機している間にCPUを休ませる関数
+static IDLE: Once<fn()> = Once::new();
+
+/// 時刻管理の初期化。タイマ割り込みを有効化する前に呼ぶこと
+///
+/// `idle`には、次の割り込みまでCPUを休ませる関数（x86_64なら`hlt`命令）を渡す
+pub fn init(tick_period: Duration, idle: fn()) {
+    set_tick_period(tick_period);
+    IDLE.call_once(|| idle);
+}
+
+/// タイマ割り込みの周期を変更する。タイマの切り替え時などに使う
+pub fn set_tick_period(tick_period: Duration) {
+    TICK_PERIOD_NANOS.store(tick_period.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// タイマ割り込みの周期を返す
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(TICK_PERIOD_NANOS.load(Ordering::Relaxed))
+}
+
+/// タイマ割り込みが一回発生したことを記録する。タイマの割り込みハンドラから呼ぶこと
+#[inline(always)]
+pub fn tick() {
+    ELAPSED_NANOS.fetch_add(TICK_PERIOD_NANOS.load(Ordering::Relaxed), Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Release);
+}
+
+/// 起動時からのタイマ割り込みの回数を返す
+#[inline(always)]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// 指定した時間が経過するまで待つ
+///
+/// タイマ割り込みが有効でなければ永遠に戻らないので注意すること
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    sleep_until(deadline);
+}
+
+/// 指定した時刻になるまで待つ
+pub fn sleep_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        match IDLE.get() {
+            Some(idle) => idle(),
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+/// 起動時を基準とした、単調増加する時刻
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    /// 起動時を表す時刻
+    pub const BOOT: Instant = Instant { nanos: 0 };
+
+    /// 現在の時刻を返す
+    #[inline(always)]
+    pub fn now() -> Self {
+        Instant {
+            nanos: ELAPSED_NANOS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 起動時からの経過時間を返す
+    pub const fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// `earlier`からの経過時間を返す。`earlier`の方が後の場合は0を返す
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// `earlier`からの経過時間を返す。`earlier`の方が後の場合は`None`を返す
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos
+            .checked_sub(earlier.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    /// この時刻からの経過時間を返す
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// `duration`だけ後の時刻を返す。表現できない場合は`None`を返す
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(|nanos| Instant { nanos })
+    }
+
+    /// `duration`だけ前の時刻を返す。起動時より前になる場合は`None`を返す
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(|nanos| Instant { nanos })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// ## Panic
+    /// 結果が表現できない場合はパニックを起こす
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    /// ## Panic
+    /// 結果が起動時より前になる場合はパニックを起こす
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
@@ -0,0 +1,26 @@
+use bootloader_api::info::Optional;
+
+/// ACPIテーブルの読み込み。ヒープを使うため、memory::init()の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init(rsdp_addr: Optional<u64>, physical_memory_offset: Optional<u64>) {
+    use amd64_lib::acpi;
+
+    let rsdp_addr = match rsdp_addr {
+        Optional::Some(addr) => addr,
+        Optional::None => panic!("Failed to get RSDP address"),
+    };
+    let physical_memory_offset = match physical_memory_offset {
+        Optional::Some(addr) => addr,
+        Optional::None => panic!("Failed to get physical memory offset"),
+    };
+
+    let info = unsafe { acpi::init(rsdp_addr, physical_memory_offset) }
+        .unwrap_or_else(|e| panic!("Failed to parse ACPI tables: {:?}", e));
+
+    log::info!(
+        "APIC={}, HPET={}, MCFG={}",
+        info.apic().is_some(),
+        info.hpet.is_some(),
+        info.pci_config_regions.is_some()
+    );
+}
@@ -0,0 +1,49 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use common_lib::block::{
+    self,
+    partition::{self, Partition},
+    BlockDevice,
+};
+use once_cell::race::OnceBox;
+
+/// init()で見つけたパーティション
+static PARTITIONS: OnceBox<Vec<Arc<Partition>>> = OnceBox::new();
+
+/// 登録済みのブロックデバイスのパーティションテーブルを読み、各パーティションをブロックデバイスとして登録する
+///
+/// ドライバがディスクを登録した後、つまりpci::init()の後に呼ぶこと
+pub(crate) fn init() {
+    let mut found = Vec::new();
+
+    for disk in block::devices() {
+        let partitions = match partition::scan(&disk) {
+            Ok(partitions) => partitions,
+            Err(e) => {
+                log::warn!("{}: failed to read the partition table: {}", disk.name(), e);
+                continue;
+            }
+        };
+
+        for partition in partitions {
+            log::info!(
+                "{}: sectors {}..{} ({})",
+                partition.name(),
+                partition.start(),
+                partition.start() + partition.sector_count(),
+                partition.kind()
+            );
+            let partition = Arc::new(partition);
+            block::register(partition.clone());
+            found.push(partition);
+        }
+    }
+
+    let _ = PARTITIONS.set(Box::new(found));
+}
+
+/// init()で見つけたパーティションを返す
+pub(crate) fn partitions() -> &'static [Arc<Partition>] {
+    PARTITIONS
+        .get()
+        .map_or(&[], |partitions| partitions.as_slice())
+}
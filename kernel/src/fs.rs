@@ -0,0 +1,161 @@
+use alloc::{borrow::ToOwned, sync::Arc};
+use bootloader_api::info::Optional;
+use common_lib::{
+    block::{self, cache::BufferCache, BlockDevice},
+    fs::{
+        self,
+        devfs::{self, DevFileSystem},
+        fat::FatFileSystem,
+        tar::TarFileSystem,
+        tmpfs::TmpFileSystem,
+        FileSystem, FileType, FsError,
+    },
+};
+
+/// 起動ディスクのキャッシュに置くセクタ数
+const BOOT_CACHE_SECTORS: usize = 2048; // 1 MiB
+
+/// 初期RAMディスクがある場合に、起動ディスクのFATファイルシステムをマウントする場所
+const BOOT_MOUNT_POINT: &str = "/boot";
+
+/// デバイスファイルシステムをマウントする場所
+const DEV_MOUNT_POINT: &str = "/dev";
+
+/// 一時ファイル用のファイルシステムをマウントする場所
+const TMP_MOUNT_POINT: &str = "/tmp";
+/// 一時ファイル用のファイルシステムに置ける中身のバイト数
+const TMP_LIMIT: usize = 16 * 1024 * 1024; // 16 MiB
+
+/// ファイルシステムをマウントし、ルートディレクトリの内容をログに出力する
+///
+/// 初期RAMディスクがあれば、それを読み込み専用でルートにマウントし、起動ディスクのFATファイルシステムを`/boot`にマウントする。
+/// 無ければFATファイルシステムをルートにマウントする。
+/// どちらも無い場合はヒープ上のファイルシステムをルートにし、それ以外では`/tmp`にマウントする。
+/// 最後にデバイスファイルシステムを`/dev`にマウントする。
+/// block::init()の後に呼ぶこと
+pub(crate) fn init(ramdisk_addr: Optional<u64>, ramdisk_len: u64) {
+    let has_root = match ramdisk_addr {
+        Optional::Some(addr) => mount_ramdisk(addr, ramdisk_len),
+        Optional::None => {
+            log::info!("No initial ramdisk was loaded");
+            false
+        }
+    };
+
+    let fat_mount_point = if has_root { BOOT_MOUNT_POINT } else { "/" };
+    if !mount_boot_fat(fat_mount_point) {
+        log::warn!("No FAT file system was found on the block devices");
+    }
+    mount_tmp();
+    mount_dev();
+
+    match fs::read_dir("/") {
+        Ok(entries) => {
+            log::info!("/:");
+            for entry in entries {
+                let suffix = match entry.kind {
+                    FileType::Directory => "/",
+                    FileType::Symlink => "@",
+                    FileType::File | FileType::CharDevice | FileType::BlockDevice => "",
+                };
+                log::info!("  {}{}", entry.name, suffix);
+            }
+        }
+        Err(e) => log::warn!("Failed to read the root directory: {}", e),
+    }
+}
+
+/// ブートローダが読み込んだ初期RAMディスクをtarアーカイブとしてルートにマウントする
+fn mount_ramdisk(addr: u64, len: u64) -> bool {
+    // ブートローダはRAMディスクをカーネルのアドレス空間にマップし、その領域を使用済みとして渡す
+    let archive = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+
+    let result = TarFileSystem::new(archive).and_then(|tar| fs::mount("/", tar, true));
+    match result {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("Failed to mount the initial ramdisk ({} bytes): {}", len, e);
+            false
+        }
+    }
+}
+
+/// ヒープ上のファイルシステムを`/tmp`にマウントする。ルートファイルシステムが無ければルートにマウントする
+fn mount_tmp() {
+    let tmp = TmpFileSystem::new(TMP_LIMIT);
+    if fs::stat("/").is_err() {
+        if let Err(e) = fs::mount("/", tmp, false) {
+            log::warn!("Failed to mount tmpfs as the root file system: {}", e);
+        }
+        return;
+    }
+    mount_on(TMP_MOUNT_POINT, tmp);
+}
+
+/// 標準のキャラクタデバイスを登録し、デバイスファイルシステムを`/dev`にマウントする
+///
+/// 画面やシリアルなどのデバイスは、それぞれの初期化処理で登録される
+#[cfg(target_arch = "x86_64")]
+fn mount_dev() {
+    use amd64_lib::random::RandomDevice;
+
+    devfs::register("null", Arc::new(devfs::Null));
+    devfs::register("zero", Arc::new(devfs::Zero));
+    devfs::register("random", Arc::new(RandomDevice::new()));
+    mount_on(DEV_MOUNT_POINT, DevFileSystem::new());
+}
+
+/// `fs`を`path`にマウントする。`path`が無ければディレクトリを作る
+fn mount_on(path: &str, file_system: Arc<dyn FileSystem>) {
+    let name = file_system.name();
+    let result = match fs::stat(path) {
+        Err(FsError::NotFound) => {
+            fs::create_dir(path).and_then(|_| fs::mount(path, file_system, false))
+        }
+        _ => fs::mount(path, file_system, false),
+    };
+    if let Err(e) = result {
+        log::warn!("Failed to mount {} on {}: {}", name, path, e);
+    }
+}
+
+/// ブロックデバイスからFATファイルシステムを探して`mount_point`にマウントする
+///
+/// EFIシステムパーティション、その他のパーティション、パーティションの無いディスクの順に探す
+fn mount_boot_fat(mount_point: &str) -> bool {
+    let mut partitions = crate::block::partitions().to_vec();
+    partitions.sort_by_key(|partition| !partition.kind().is_efi_system());
+
+    let all = crate::block::partitions();
+    let disks = block::devices().into_iter().filter(|device| {
+        all.iter()
+            .all(|p| p.name() != device.name() && p.disk().name() != device.name())
+    });
+    let candidates = partitions
+        .into_iter()
+        .map(|partition| partition as Arc<dyn BlockDevice>)
+        .chain(disks);
+
+    for device in candidates {
+        let name = device.name().to_owned();
+        let cache: Arc<dyn BlockDevice> = Arc::new(BufferCache::new(device, BOOT_CACHE_SECTORS));
+        let Ok(fat) = FatFileSystem::mount(cache) else {
+            continue;
+        };
+
+        log::info!(
+            "{}: found {:?} volume \"{}\" ({} clusters of {} bytes)",
+            name,
+            fat.fat_type(),
+            fat.volume_label(),
+            fat.cluster_count(),
+            fat.bytes_per_cluster()
+        );
+        match fs::mount(mount_point, fat, false) {
+            Ok(_) => return true,
+            Err(e) => log::warn!("{}: failed to mount on {}: {}", name, mount_point, e),
+        }
+    }
+
+    false
+}
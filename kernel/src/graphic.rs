@@ -17,9 +17,9 @@ pub(crate) mod text_buffer;
 use core::num::NonZeroUsize;
 
 use ab_glyph::FontRef;
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 use bootloader_api::info::FrameBuffer;
-use common_lib::locked::Locked;
+use common_lib::{fs::devfs, locked::Locked, sync::LockLevel};
 
 use self::text_buffer::TextBuffer;
 use crate::{FRAME_BUFFER, FRAME_BUFFER_INFO, TEXT_BUFFER, TEXT_BUFFER_HEIGHT, TEXT_BUFFER_WIDTH};
@@ -27,11 +27,27 @@ use crate::{FRAME_BUFFER, FRAME_BUFFER_INFO, TEXT_BUFFER, TEXT_BUFFER_HEIGHT, TE
 const FONT_TEXT: &[u8; 4259456] = include_bytes!("graphic/resources/PlemolJPConsoleNF-Text.ttf");
 const FONT_BOLD: &[u8; 4257220] = include_bytes!("graphic/resources/PlemolJPConsoleNF-Bold.ttf");
 const FONT_SCALE: f32 = 24.0;
+/// 画面外に保持しておくスクロールバック履歴の行数
+const SCROLLBACK_LINES: usize = 1000;
+/// ラスタライズ済みのグリフを保持しておく容量
+const GLYPH_CACHE_SIZE: usize = 4 * 1024 * 1024;
+/// 起動時にASCII文字と半角カタカナをラスタライズしておくかどうか
+const PREWARM_GLYPH_CACHE: bool = true;
 
 /// 描画モジュールの初期化
 pub(crate) fn init(frame_buffer: &'static mut FrameBuffer) {
     FRAME_BUFFER_INFO.get_or_init(|| Box::new(frame_buffer.info()));
-    FRAME_BUFFER.get_or_init(|| Box::new(Locked::new(frame_buffer.buffer_mut())));
+    #[cfg(target_arch = "x86_64")]
+    let buffer = crate::memory::remap_frame_buffer(frame_buffer.buffer_mut());
+    #[cfg(not(target_arch = "x86_64"))]
+    let buffer = frame_buffer.buffer_mut();
+    FRAME_BUFFER.get_or_init(|| {
+        Box::new(Locked::with_level(
+            buffer,
+            "frame buffer",
+            LockLevel::FRAME_BUFFER,
+        ))
+    });
 
     // Lock
     {
@@ -41,11 +57,29 @@ pub(crate) fn init(frame_buffer: &'static mut FrameBuffer) {
     TEXT_BUFFER.get_or_init(|| {
         let font_text = FontRef::try_from_slice(FONT_TEXT).expect("Failed to load text font data");
         let font_bold = FontRef::try_from_slice(FONT_BOLD).expect("Failed to load bold font data");
-        Box::new(Locked::new(TextBuffer::new(
-            font_text, font_bold, FONT_SCALE,
-        )))
+        Box::new(Locked::with_level(
+            TextBuffer::new(
+                font_text,
+                font_bold,
+                FONT_SCALE,
+                SCROLLBACK_LINES,
+                GLYPH_CACHE_SIZE,
+            ),
+            "console",
+            LockLevel::CONSOLE,
+        ))
     });
 
+    if PREWARM_GLYPH_CACHE {
+        let mut text_buffer = TEXT_BUFFER.get().unwrap().lock();
+        text_buffer.prewarm_glyphs(('\u{21}'..='\u{7e}').chain('\u{ff61}'..='\u{ff9f}'));
+        log::debug!(
+            "Prewarmed {} glyphs ({} bytes)",
+            text_buffer.glyph_cache().len(),
+            text_buffer.glyph_cache().used_bytes()
+        );
+    }
+
     let info = FRAME_BUFFER_INFO.get().unwrap();
 
     let width = info.stride / (FONT_SCALE / 2.0) as usize;
@@ -54,4 +88,6 @@ pub(crate) fn init(frame_buffer: &'static mut FrameBuffer) {
     // WIDTHもHEIGHTも割り算で求めるため、`info.stride`や`info.stride`が0でない限り（まずありえない）0を渡すことは無い
     TEXT_BUFFER_WIDTH.get_or_init(move || unsafe { NonZeroUsize::new_unchecked(width) });
     TEXT_BUFFER_HEIGHT.get_or_init(move || unsafe { NonZeroUsize::new_unchecked(height) });
+
+    devfs::register("console", Arc::new(console::ConsoleDevice::new()));
 }
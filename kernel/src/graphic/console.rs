@@ -1,43 +1,73 @@
 //! コンソール機能を定義するカーネル部分のモジュール
 
+use alloc::vec::Vec;
 use core::fmt::Write;
 
-use common_lib::graphic::console::{Console, FontType};
+use common_lib::{
+    fs::{devfs::CharDevice, FsError},
+    graphic::{
+        ansi::{Action, EraseMode, DEFAULT_BACKGROUND},
+        console::{Console, FontType},
+    },
+    input,
+    logger::LogSink,
+    sync::IrqMutex,
+};
 
 use crate::{FRAME_BUFFER_INFO, TEXT_BUFFER, TEXT_BUFFER_HEIGHT, TEXT_BUFFER_WIDTH};
 
-use super::text_buffer::{TextBuffer, CURSOR_DEFAULT_POSITION};
+use super::text_buffer::{char_width, Cell, TextBuffer, CURSOR_DEFAULT_POSITION};
+
+/// タブ文字で揃える桁数
+const TAB_WIDTH: usize = 8;
 
 impl<'a> Console for TextBuffer<'a> {
     fn put_char(&mut self, character: char, font_type: FontType, r_g_b: [u8; 3]) {
+        let width = TEXT_BUFFER_WIDTH.get().unwrap().get();
+
         match character {
             // 制御文字の場合はそれに従った処理を行う
             '\n' => self.new_line(),
             '\r' => self.carriage_return(),
+            '\u{8}' => self.cursor.0 = self.cursor.0.saturating_sub(1),
+            '\t' => self.cursor.0 = ((self.cursor.0 / TAB_WIDTH + 1) * TAB_WIDTH).min(width - 1),
+            // その他の制御文字は描画しない
+            _ if character.is_control() => {}
             // 制御文字以外はフレームバッファに文字を描画し、カーソルを進める
             _ => {
-                let font = match font_type {
-                    FontType::Text => &self.font_text,
-                    FontType::Bold => &self.font_bold,
-                };
-                let glyph = self.get_glyph(character, font);
+                self.snap_to_bottom();
+
+                let advance = char_width(character);
+                // 全角文字が行末に収まらない場合は、先に改行しておく
+                if self.cursor.0 + advance > width {
+                    self.new_line();
+                }
+
                 let fb_info = FRAME_BUFFER_INFO.get().unwrap();
+                let background = self.background;
 
-                if let Some(g) = &glyph {
-                    self.write_buffer(g, r_g_b, fb_info);
+                // 上書きする場合に備えて、文字を描く前にその領域を背景色で塗りつぶしておく
+                self.fill_cells(
+                    self.cursor.0..self.cursor.0 + advance,
+                    self.cursor.1,
+                    background,
+                    fb_info,
+                );
 
-                    self.cursor.0 += match character {
-                        // ASCII文字または半角カタカナの場合はカーソルを横方向'に１つ進める
-                        '\u{0}'..='\u{7f}' => 1,
-                        '\u{ff61}'..='\u{ff9f}' => 1,
-                        // それ以外の場合は横方向に２つ進める
-                        _ => 2,
-                    };
+                // 空白などの輪郭を持たない文字は、何も描画せずにカーソルだけを進める
+                self.draw_glyph(character, font_type, r_g_b, background, fb_info);
 
-                    let width = TEXT_BUFFER_WIDTH.get().unwrap().get();
-                    if self.cursor.0 >= width {
-                        self.new_line();
-                    }
+                let cell = Cell {
+                    character,
+                    font_type,
+                    red_green_blue: r_g_b,
+                    background,
+                };
+                self.record_cell(cell, advance);
+                self.cursor.0 += advance;
+
+                if self.cursor.0 >= width {
+                    self.new_line();
                 }
             }
         }
@@ -49,10 +79,14 @@ impl<'a> Console for TextBuffer<'a> {
             self.carriage_return()
         }
 
-        if self.cursor.1 >= TEXT_BUFFER_HEIGHT.get().unwrap().get() {
-            todo!()
+        let height = TEXT_BUFFER_HEIGHT.get().unwrap().get();
+        if self.cursor.1 >= height {
+            // 最下行に達していれば、画面全体を一行分上にずらしてカーソルは最下行に留める
+            self.scroll_buffer(FRAME_BUFFER_INFO.get().unwrap());
+            self.push_line(height);
         } else {
-            self.cursor.1 += 1
+            self.cursor.1 += 1;
+            self.extend_rows(self.cursor.1, height);
         }
     }
 
@@ -64,23 +98,169 @@ impl<'a> Console for TextBuffer<'a> {
     fn reset(&mut self) {
         self.clear();
         self.cursor = CURSOR_DEFAULT_POSITION;
+        self.background = DEFAULT_BACKGROUND;
+    }
+
+    fn scroll_back(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_add(lines));
+        self.merge_buffer();
+    }
+
+    fn scroll_forward(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_sub(lines));
+        self.merge_buffer();
+    }
+
+    fn flush(&mut self) {
+        self.merge_buffer();
+    }
+
+    fn set_background(&mut self, red_green_blue: [u8; 3]) {
+        self.background = red_green_blue;
+    }
+
+    fn cursor_position(&self) -> (usize, usize) {
+        (self.cursor.0, self.cursor.1 - CURSOR_DEFAULT_POSITION.1)
+    }
+
+    fn move_cursor(&mut self, column: usize, row: usize) {
+        let width = TEXT_BUFFER_WIDTH.get().unwrap().get();
+        let height = TEXT_BUFFER_HEIGHT.get().unwrap().get();
+
+        self.cursor.0 = column.min(width - 1);
+        self.cursor.1 = row.saturating_add(CURSOR_DEFAULT_POSITION.1).min(height);
+        self.extend_rows(self.cursor.1, height);
+    }
+
+    fn erase_in_line(&mut self, mode: EraseMode) {
+        self.snap_to_bottom();
+
+        let width = TEXT_BUFFER_WIDTH.get().unwrap().get();
+        let columns = match mode {
+            EraseMode::ToEnd => self.cursor.0..width,
+            EraseMode::ToStart => 0..self.cursor.0 + 1,
+            EraseMode::All | EraseMode::AllWithScrollback => 0..width,
+        };
+        self.erase_cells(columns, self.cursor.1);
+    }
+
+    fn erase_in_display(&mut self, mode: EraseMode) {
+        self.snap_to_bottom();
+
+        let width = TEXT_BUFFER_WIDTH.get().unwrap().get();
+        let height = TEXT_BUFFER_HEIGHT.get().unwrap().get();
+        let top = CURSOR_DEFAULT_POSITION.1;
+        let row = self.cursor.1;
+
+        // 既定以外の背景色で塗りつぶす場合は、まだ使っていない行も対象にする
+        if self.background != DEFAULT_BACKGROUND && mode != EraseMode::ToStart {
+            self.extend_rows(height, height);
+        }
+
+        match mode {
+            EraseMode::ToEnd => {
+                self.erase_in_line(EraseMode::ToEnd);
+                for r in row + 1..=self.last_row() {
+                    self.erase_cells(0..width, r);
+                }
+            }
+            EraseMode::ToStart => {
+                for r in top..row {
+                    self.erase_cells(0..width, r);
+                }
+                self.erase_in_line(EraseMode::ToStart);
+            }
+            EraseMode::All => {
+                for r in top..=self.last_row() {
+                    self.erase_cells(0..width, r);
+                }
+            }
+            EraseMode::AllWithScrollback => {
+                let cursor = self.cursor;
+                let background = self.background;
+                self.clear();
+                self.background = background;
+                self.cursor = CURSOR_DEFAULT_POSITION;
+                self.move_cursor(cursor.0, cursor.1 - top);
+                if background != DEFAULT_BACKGROUND {
+                    self.extend_rows(height, height);
+                    for r in top..=height {
+                        self.erase_cells(0..width, r);
+                    }
+                }
+            }
+        }
     }
 }
 
-/// `println!()`などに使う`core::fmt::Write`の実装
+impl<'a> TextBuffer<'a> {
+    /// 履歴を遡って表示している間に出力があった場合は、最新の表示に戻す
+    fn snap_to_bottom(&mut self) {
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.redraw();
+        }
+    }
+
+    /// エスケープシーケンスを解釈しながら一文字出力する
+    fn write_ansi_char(&mut self, character: char) {
+        let Some(action) = self.ansi.advance(character) else {
+            return;
+        };
+
+        match action {
+            Action::Print(c) | Action::Execute(c) => {
+                let attribute = *self.ansi.attribute();
+                self.set_background(attribute.background);
+                self.put_char(c, attribute.font_type, attribute.foreground);
+            }
+            Action::CursorPosition { column, row } => self.move_cursor(column, row),
+            Action::CursorUp(n) => {
+                let (column, row) = self.cursor_position();
+                self.move_cursor(column, row.saturating_sub(n));
+            }
+            Action::CursorDown(n) => {
+                let (column, row) = self.cursor_position();
+                self.move_cursor(column, row.saturating_add(n));
+            }
+            Action::CursorForward(n) => {
+                let (column, row) = self.cursor_position();
+                self.move_cursor(column.saturating_add(n), row);
+            }
+            Action::CursorBack(n) => {
+                let (column, row) = self.cursor_position();
+                self.move_cursor(column.saturating_sub(n), row);
+            }
+            Action::EraseInLine(mode) => self.erase_in_line(mode),
+            Action::EraseInDisplay(mode) => self.erase_in_display(mode),
+            Action::SaveCursor => self.saved_cursor = self.cursor_position(),
+            Action::RestoreCursor => {
+                let (column, row) = self.saved_cursor;
+                self.move_cursor(column, row);
+            }
+            Action::Reset => self.reset(),
+        }
+    }
+}
+
+/// `println!()`などに使う`core::fmt::Write`の実装。ANSIエスケープシーケンスを解釈する
 impl<'a> core::fmt::Write for TextBuffer<'a> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for c in s.chars() {
-            self.put_char(c, FontType::Text, [255, 255, 255]);
+            self.write_ansi_char(c);
+        }
+        if self.auto_flush() {
+            self.merge_buffer();
         }
-        self.merge_buffer();
 
         Ok(())
     }
 
     fn write_char(&mut self, c: char) -> core::fmt::Result {
-        self.put_char(c, FontType::Text, [255, 255, 255]);
-        self.merge_buffer();
+        self.write_ansi_char(c);
+        if self.auto_flush() {
+            self.merge_buffer();
+        }
 
         Ok(())
     }
@@ -99,7 +279,135 @@ macro_rules! println {
     ($($arg:tt)*) => (print!("{}\n", format_args!($($arg)*)));
 }
 
+/// 画面への反映をまとめて行うためのガード。`batch()`で作り、破棄した時点で溜まった描画内容を反映する
+pub(crate) struct BatchGuard {
+    _private: (),
+}
+
+impl Drop for BatchGuard {
+    fn drop(&mut self) {
+        if let Some(text_buffer) = TEXT_BUFFER.get() {
+            text_buffer.lock().end_batch();
+        }
+    }
+}
+
+/// 大量の出力を行う前に呼ぶ。戻り値のガードが破棄されるまで、`print!()`などの出力は画面へ反映されない
+pub(crate) fn batch() -> BatchGuard {
+    if let Some(text_buffer) = TEXT_BUFFER.get() {
+        text_buffer.lock().begin_batch();
+    }
+    BatchGuard { _private: () }
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     TEXT_BUFFER.get().unwrap().lock().write_fmt(args).unwrap();
 }
+
+/// パニックの内容を画面に出力し、まとめて反映している途中でも直ちに反映する
+///
+/// graphic::init()の前や、画面のロックを持ったままパニックを起こした場合は何もしない
+pub(crate) fn write_panic(args: core::fmt::Arguments) {
+    let Some(mut text_buffer) = TEXT_BUFFER
+        .get()
+        .and_then(|text_buffer| text_buffer.try_lock())
+    else {
+        return;
+    };
+    let _ = writeln!(text_buffer, "{}", args);
+    text_buffer.force_flush();
+}
+
+/// ログの出力先として画面を使うための型。graphic::init()の処理が終わるまでは何も出力しない
+pub(crate) struct ConsoleSink;
+
+impl LogSink for ConsoleSink {
+    fn write_line(&self, line: core::fmt::Arguments) {
+        if let Some(text_buffer) = TEXT_BUFFER.get() {
+            let _ = writeln!(text_buffer.lock(), "{}", line);
+        }
+    }
+}
+
+/// 画面とキーボードをファイルとして読み書きするためのデバイス。graphic::init()の処理が終わってから使用すること
+///
+/// 読み込みはキー入力のうち文字だけをUTF-8で返し、入力を待たない
+pub(crate) struct ConsoleDevice {
+    /// 文字に変換したが、まだ読み出されていないバイト
+    input: IrqMutex<Vec<u8>>,
+    /// 書き込まれたが、UTF-8の文字として完結していないバイト
+    output: IrqMutex<Vec<u8>>,
+}
+
+impl ConsoleDevice {
+    pub(crate) const fn new() -> Self {
+        ConsoleDevice {
+            input: IrqMutex::new(Vec::new()),
+            output: IrqMutex::new(Vec::new()),
+        }
+    }
+}
+
+impl CharDevice for ConsoleDevice {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let mut input = self.input.lock();
+        while input.len() < buffer.len() {
+            let Some(c) = input::pop_char() else {
+                break;
+            };
+            input.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        if input.is_empty() {
+            return Err(FsError::WouldBlock);
+        }
+
+        let len = buffer.len().min(input.len());
+        buffer[..len].copy_from_slice(&input[..len]);
+        input.drain(..len);
+        Ok(len)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut output = self.output.lock();
+        output.extend_from_slice(buffer);
+
+        let mut text_buffer = TEXT_BUFFER.get().unwrap().lock();
+        let mut rest = output.as_slice();
+        loop {
+            match core::str::from_utf8(rest) {
+                Ok(s) => {
+                    let _ = text_buffer.write_str(s);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    let _ = text_buffer.write_str(core::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        // 不正なバイト列は置換文字にする
+                        Some(len) => {
+                            let _ = text_buffer.write_char(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // 途中で切れた文字は、続きが書き込まれるまで取っておく
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        drop(text_buffer);
+
+        let pending = rest.len();
+        let consumed = output.len() - pending;
+        output.drain(..consumed);
+        Ok(buffer.len())
+    }
+}
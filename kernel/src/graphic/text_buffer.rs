@@ -3,104 +3,520 @@
 //! I/Oに強く関与しているためか、`bootloader_api`クレートに強く依存しているクレートである。
 //! そのためブートローダを変更するか、あるいは他のフレームバッファを画面描画に利用する場合は大幅に書き直さなければならない
 
-use ab_glyph::{point, Font, FontRef, OutlinedGlyph};
+use core::ops::Range;
+
+use ab_glyph::{point, Font, FontRef, ScaleFont};
+use alloc::collections::VecDeque;
 use alloc::vec;
 use alloc::vec::Vec;
 use bootloader_api::info::FrameBufferInfo;
+use common_lib::graphic::{
+    ansi::{AnsiParser, DEFAULT_BACKGROUND},
+    console::FontType,
+    damage::{Damage, Rect},
+    glyph_cache::{GlyphBitmap, GlyphCache, GlyphKey},
+};
 
 use super::color;
-use crate::FRAME_BUFFER;
+use crate::{FRAME_BUFFER, FRAME_BUFFER_INFO};
+
+/// 個別に記録しておく書き換え領域の数。これを超えると一つの矩形にまとめる
+const MAX_DAMAGE_RECTS: usize = 32;
 
 /// カーソルの初期座標
 pub(super) const CURSOR_DEFAULT_POSITION: (usize, usize) = (0, 1);
 
+/// スクロールバック履歴に記録される一文字分の情報
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Cell {
+    pub(super) character: char,
+    pub(super) font_type: FontType,
+    pub(super) red_green_blue: [u8; 3],
+    pub(super) background: [u8; 3],
+}
+
+/// 文字が占める桁数を返す
+pub(super) fn char_width(character: char) -> usize {
+    match character {
+        // ASCII文字または半角カタカナの場合は１桁
+        '\u{0}'..='\u{7f}' => 1,
+        '\u{ff61}'..='\u{ff9f}' => 1,
+        // それ以外の場合は２桁
+        _ => 2,
+    }
+}
+
+/// フォントの輪郭からグリフをラスタライズする。原点はベースラインの左端とする
+fn rasterize(font: &FontRef, character: char, scale: f32) -> GlyphBitmap {
+    let glyph = font
+        .glyph_id(character)
+        .with_scale_and_position(scale, point(0.0, 0.0));
+    let Some(outlined) = font.outline_glyph(glyph) else {
+        return GlyphBitmap::EMPTY;
+    };
+
+    let bounds = outlined.px_bounds();
+    let width = bounds.width() as usize;
+    let height = bounds.height() as usize;
+    let mut coverage = vec![0; width * height];
+
+    outlined.draw(|x, y, c| {
+        if let Some(pixel) = coverage.get_mut(y as usize * width + x as usize) {
+            *pixel = (c.clamp(0.0, 1.0) * 255.0) as u8;
+        }
+    });
+
+    GlyphBitmap {
+        offset: (bounds.min.x as i32, bounds.min.y as i32),
+        width,
+        height,
+        coverage,
+    }
+}
+
+/// 一行分の文字。インデックスが桁の位置に対応し、全角文字の右半分などの空白は`None`となる
+type Line = Vec<Option<Cell>>;
+
 pub struct TextBuffer<'a> {
-    pub(super) font_text: FontRef<'a>,
-    pub(super) font_bold: FontRef<'a>,
+    font_text: FontRef<'a>,
+    font_bold: FontRef<'a>,
     pub(super) scale: f32,
 
+    /// ラスタライズ済みのグリフ
+    glyph_cache: GlyphCache,
+
     text_buffer: Vec<u8>,
 
     /// 左上を(0,0)とした物理座標。cursor.0がx座標で、cursor.1がy座標
     pub(super) cursor: (usize, usize),
+
+    /// 画面に表示中の行とスクロールバック履歴を合わせたもの。末尾がカーソルのある行となる
+    lines: VecDeque<Line>,
+
+    /// 画面外に保持しておく履歴の最大行数
+    scrollback_limit: usize,
+
+    /// 最下部から何行遡って表示しているか。0なら最新の出力を表示している
+    pub(super) view_offset: usize,
+
+    /// `lines`の末尾に対応する行。カーソルが上に移動しても、これより上の行は変わらない
+    last_row: usize,
+
+    /// 以降に出力する文字の背景色
+    pub(super) background: [u8; 3],
+
+    /// エスケープシーケンスで保存したカーソル位置。`Console::cursor_position()`と同じ座標で持つ
+    pub(super) saved_cursor: (usize, usize),
+
+    /// `core::fmt::Write`で受け取った文字列のエスケープシーケンスを解釈するパーサ
+    pub(super) ansi: AnsiParser,
+
+    /// フレームバッファへまだ反映していない領域
+    damage: Damage,
+
+    /// `begin_batch()`が呼ばれた回数。0でなければ書き込みのたびの反映を行わない
+    batch_depth: usize,
 }
 
 impl<'a> TextBuffer<'a> {
     /// フレームバッファを初期化する
-    pub const fn new(font_text: FontRef<'a>, font_bold: FontRef<'a>, scale: f32) -> Self {
+    ///
+    /// `scrollback_limit`には画面外に保持しておく履歴の行数を、
+    /// `glyph_cache_size`にはグリフキャッシュの容量をバイト単位で指定する
+    pub const fn new(
+        font_text: FontRef<'a>,
+        font_bold: FontRef<'a>,
+        scale: f32,
+        scrollback_limit: usize,
+        glyph_cache_size: usize,
+    ) -> Self {
         TextBuffer {
             font_text,
             font_bold,
             scale,
+            glyph_cache: GlyphCache::new(glyph_cache_size),
             text_buffer: Vec::new(),
             cursor: CURSOR_DEFAULT_POSITION,
+            lines: VecDeque::new(),
+            scrollback_limit,
+            view_offset: 0,
+            last_row: CURSOR_DEFAULT_POSITION.1,
+            background: DEFAULT_BACKGROUND,
+            saved_cursor: (0, 0),
+            ansi: AnsiParser::new(),
+            damage: Damage::new(MAX_DAMAGE_RECTS),
+            batch_depth: 0,
         }
     }
 
     #[cold]
     fn init_textbuffer(&mut self, byte_len: usize) {
         self.text_buffer = vec![0; byte_len];
+        self.damage_all(FRAME_BUFFER_INFO.get().unwrap());
+    }
+
+    /// 画面全体を書き換えたものとして記録する
+    fn damage_all(&mut self, info: &FrameBufferInfo) {
+        self.damage.add(Rect::new(0, 0, info.stride, info.height));
+    }
+
+    #[inline(always)]
+    fn ensure_textbuffer(&mut self, info: &FrameBufferInfo) {
+        if self.text_buffer.len() != info.byte_len {
+            self.init_textbuffer(info.byte_len);
+        }
     }
 
-    pub(super) fn get_glyph(&self, character: char, font: &FontRef) -> Option<OutlinedGlyph> {
+    /// 一行の高さをピクセル単位で返す
+    ///
+    /// スクロール時にピクセル単位でバッファをずらすため、整数に切り捨てている
+    #[inline(always)]
+    pub(super) fn line_height(&self) -> usize {
         // put_char(&mut self)で呼び出した際の文字幅を調節するため、0.9をself.scaleに掛けている
-        let m = self.scale * 0.9;
-        let x = self.cursor.0 as f32 * (m / 2.0);
-        let y = self.cursor.1 as f32 * m;
+        (self.scale * 0.9) as usize
+    }
+
+    /// 一桁の幅をピクセル単位で返す
+    #[inline(always)]
+    fn column_width(&self) -> f32 {
+        self.scale * 0.9 / 2.0
+    }
 
-        let glyph = font
-            .glyph_id(character)
-            .with_scale_and_position(self.scale, point(x, y));
+    /// 指定した行・桁から`width`桁分の文字が占める領域を、ピクセル単位で返す
+    ///
+    /// グリフはベースラインを基準に描画されるため、ベースラインから下にはみ出す分だけ領域を下にずらしている
+    fn cell_area(&self, column: usize, row: usize, width: usize) -> (Range<usize>, Range<usize>) {
+        let descent = -self.font_text.as_scaled(self.scale).descent();
+        let bottom = (row * self.line_height()) as f32 + descent;
+        let top = bottom - self.line_height() as f32;
 
-        font.outline_glyph(glyph)
+        let left = (column as f32 * self.column_width()) as usize;
+        let right = ((column + width) as f32 * self.column_width()) as usize;
+
+        (left..right, top.max(0.0) as usize..bottom.max(0.0) as usize)
     }
 
-    pub(super) fn write_buffer(
+    /// 指定した領域を単色で塗りつぶす
+    pub(super) fn fill_cells(
         &mut self,
-        glyph: &OutlinedGlyph,
+        columns: Range<usize>,
+        row: usize,
         red_green_blue: [u8; 3],
         info: &FrameBufferInfo,
     ) {
-        if self.text_buffer.len() != info.byte_len {
-            self.init_textbuffer(info.byte_len);
+        self.ensure_textbuffer(info);
+
+        let (xs, ys) = self.cell_area(columns.start, row, columns.len());
+        let xs = xs.start.min(info.stride)..xs.end.min(info.stride);
+        let ys = ys.start.min(info.height)..ys.end.min(info.height);
+        self.damage
+            .add(Rect::new(xs.start, ys.start, xs.len(), ys.len()));
+
+        let color = color::encode(red_green_blue, info.pixel_format);
+        let bytes_per_pixel = info.bytes_per_pixel;
+        let color_len = if bytes_per_pixel == 4 { 3 } else { 1 };
+
+        for y in ys {
+            for x in xs.clone() {
+                let buf_index = (y * info.stride + x) * bytes_per_pixel;
+                if let Some(pixel) = self.text_buffer.get_mut(buf_index..buf_index + color_len) {
+                    pixel.copy_from_slice(&color[..color_len]);
+                }
+            }
         }
+    }
+
+    /// カーソル位置に文字を描画する。輪郭の縁は背景色と混ぜ合わせる
+    ///
+    /// ラスタライズ済みのグリフはキャッシュしておき、同じ文字の二回目以降の描画ではそれを使う
+    pub(super) fn draw_glyph(
+        &mut self,
+        character: char,
+        font_type: FontType,
+        red_green_blue: [u8; 3],
+        background: [u8; 3],
+        info: &FrameBufferInfo,
+    ) {
+        self.ensure_textbuffer(info);
+
+        let origin_x = (self.cursor.0 as f32 * self.column_width()) as isize;
+        let origin_y = (self.cursor.1 * self.line_height()) as isize;
+        let scale = self.scale;
+        let font = match font_type {
+            FontType::Text => &self.font_text,
+            FontType::Bold => &self.font_bold,
+        };
+        let glyph = self
+            .glyph_cache
+            .get_or_insert_with(GlyphKey::new(character, font_type, scale), || {
+                rasterize(font, character, scale)
+            });
+
+        let left = (origin_x + glyph.offset.0 as isize).max(0) as usize;
+        let top = (origin_y + glyph.offset.1 as isize).max(0) as usize;
+        let right = (origin_x + glyph.offset.0 as isize + glyph.width as isize).max(0) as usize;
+        let bottom = (origin_y + glyph.offset.1 as isize + glyph.height as isize).max(0) as usize;
+        self.damage
+            .add(Rect::new(left, top, right - left, bottom - top).clip(info.stride, info.height));
 
-        let min_x = glyph.px_bounds().min.x as u32;
-        let min_y = glyph.px_bounds().min.y as u32;
-        let stride = info.stride as u32;
-        let bytes_per_pixel = info.bytes_per_pixel as u32;
+        let stride = info.stride as isize;
+        let bytes_per_pixel = info.bytes_per_pixel;
         let color = color::encode(red_green_blue, info.pixel_format);
+        let background = color::encode(background, info.pixel_format);
 
-        // 描画
-        glyph.draw(move |dx, dy, c| {
-            let color = color.map(|n| (n as f32 * c) as u8);
-            let x = min_x + dx;
-            let y = min_y + dy;
-
-            // 1pixelあたりのデータ量が三色+パディング分の4byte、またはグレースケールの1byteのみであると仮定（決め打ち）した処理
-            // フレームバッファのフォーマットをbootloader_api::info::PixelFormat以外に変えた場合はまずこの部分を見直す事
-            let buf_index = ((y * stride + x) * bytes_per_pixel) as usize;
-            let range = if bytes_per_pixel == 4 {
-                buf_index..buf_index + 3
-            } else {
-                buf_index..buf_index + 1
-            };
+        // 1pixelあたりのデータ量が三色+パディング分の4byte、またはグレースケールの1byteのみであると仮定（決め打ち）した処理
+        // フレームバッファのフォーマットをbootloader_api::info::PixelFormat以外に変えた場合はまずこの部分を見直す事
+        let color_len = if bytes_per_pixel == 4 { 3 } else { 1 };
+
+        for dy in 0..glyph.height {
+            let y = origin_y + glyph.offset.1 as isize + dy as isize;
+            for dx in 0..glyph.width {
+                let x = origin_x + glyph.offset.0 as isize + dx as isize;
+                // 画面の左右からはみ出した部分は描画しない
+                if y < 0 || x < 0 || x >= stride {
+                    continue;
+                }
+
+                let c = glyph.coverage_at(dx, dy) as u16;
+                if c == 0 {
+                    continue;
+                }
+                let mut pixel_color = color;
+                for (n, b) in pixel_color.iter_mut().zip(background) {
+                    *n = ((*n as u16 * c + b as u16 * (255 - c)) / 255) as u8;
+                }
+
+                // 画面下端からはみ出した部分は描画しない
+                let buf_index = (y * stride + x) as usize * bytes_per_pixel;
+                if let Some(pixel) = self.text_buffer.get_mut(buf_index..buf_index + color_len) {
+                    pixel.copy_from_slice(&pixel_color[..color_len]);
+                }
+            }
+        }
+    }
+
+    /// 指定した文字を、通常と太字の両方であらかじめラスタライズしておく
+    pub(super) fn prewarm_glyphs(&mut self, characters: impl Iterator<Item = char>) {
+        let scale = self.scale;
+        for character in characters {
+            for (font_type, font) in [
+                (FontType::Text, &self.font_text),
+                (FontType::Bold, &self.font_bold),
+            ] {
+                self.glyph_cache
+                    .get_or_insert_with(GlyphKey::new(character, font_type, scale), || {
+                        rasterize(font, character, scale)
+                    });
+            }
+        }
+    }
+
+    /// グリフキャッシュの使用状況を返す
+    pub(super) fn glyph_cache(&self) -> &GlyphCache {
+        &self.glyph_cache
+    }
+
+    /// テキストバッファの内容を一行分上にずらし、空いた最下部を消去する
+    pub(super) fn scroll_buffer(&mut self, info: &FrameBufferInfo) {
+        self.ensure_textbuffer(info);
+
+        let line_bytes = self.line_height() * info.stride * info.bytes_per_pixel;
+        let len = self.text_buffer.len();
+        self.damage_all(info);
+
+        if line_bytes >= len {
+            self.text_buffer.fill(0);
+        } else {
+            self.text_buffer.copy_within(line_bytes.., 0);
+            self.text_buffer[len - line_bytes..].fill(0);
+        }
+    }
+
+    /// 画面上の行に対応する履歴の行を返す
+    fn line_mut(&mut self, row: usize) -> &mut Line {
+        if self.lines.is_empty() {
+            self.lines.push_back(Vec::new());
+        }
+
+        let index = (self.lines.len() - 1).saturating_sub(self.last_row.saturating_sub(row));
+        &mut self.lines[index]
+    }
+
+    /// カーソル位置の文字を履歴に記録する
+    pub(super) fn record_cell(&mut self, cell: Cell, width: usize) {
+        let column = self.cursor.0;
+        let line = self.line_mut(self.cursor.1);
+        if line.len() < column + width {
+            line.resize(column + width, None);
+        }
 
-            // self.frame_buffer.lock()[range].copy_from_slice(&color);
-            self.text_buffer[range].copy_from_slice(&color);
+        line[column] = Some(cell);
+        line[column + 1..column + width].fill(None);
+    }
+
+    /// 指定した範囲の文字を履歴と画面から消し、現在の背景色で塗りつぶす
+    pub(super) fn erase_cells(&mut self, columns: Range<usize>, row: usize) {
+        let background = self.background;
+        let blank = (background != DEFAULT_BACKGROUND).then_some(Cell {
+            character: ' ',
+            font_type: FontType::Text,
+            red_green_blue: background,
+            background,
         });
+
+        let line = self.line_mut(row);
+        if blank.is_some() && line.len() < columns.end {
+            line.resize(columns.end, None);
+        }
+        let end = columns.end.min(line.len());
+        if columns.start < end {
+            line[columns.start..end].fill(blank);
+        }
+        // 末尾の空白は保持しておく必要がない
+        while matches!(line.last(), Some(None)) {
+            line.pop();
+        }
+
+        self.fill_cells(columns, row, background, FRAME_BUFFER_INFO.get().unwrap());
     }
 
+    /// カーソルより下の、まだ使われていない行を`row`まで使用済みにする
+    pub(super) fn extend_rows(&mut self, row: usize, visible_lines: usize) {
+        while self.last_row < row {
+            self.last_row += 1;
+            self.push_line(visible_lines);
+        }
+    }
+
+    /// `lines`の末尾に対応する行を返す
     #[inline(always)]
+    pub(super) fn last_row(&self) -> usize {
+        self.last_row
+    }
+
+    /// 履歴に新しい行を追加し、上限を超えた古い行を捨てる
+    ///
+    /// `visible_lines`には画面に表示できる行数を渡す
+    pub(super) fn push_line(&mut self, visible_lines: usize) {
+        if self.lines.is_empty() {
+            self.lines.push_back(Vec::new());
+        }
+        self.lines.push_back(Vec::new());
+
+        while self.lines.len() > visible_lines + self.scrollback_limit {
+            self.lines.pop_front();
+        }
+    }
+
+    /// 表示位置を履歴の範囲内に収めた上で設定し、画面を描き直す
+    pub(super) fn set_view_offset(&mut self, offset: usize) {
+        let max_offset = self.lines.len().saturating_sub(self.last_row);
+        let offset = offset.min(max_offset);
+
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw();
+        }
+    }
+
+    /// 現在の表示位置に従って、履歴からテキストバッファを描き直す
+    pub(super) fn redraw(&mut self) {
+        let info = FRAME_BUFFER_INFO.get().unwrap();
+        self.ensure_textbuffer(info);
+        self.text_buffer.fill(0);
+        self.damage_all(info);
+
+        let cursor = self.cursor;
+        let last_row = self.last_row;
+        let bottom = self.lines.len().saturating_sub(1 + self.view_offset);
+
+        for row in CURSOR_DEFAULT_POSITION.1..=last_row {
+            let Some(index) = bottom.checked_sub(last_row - row) else {
+                continue;
+            };
+
+            // 描画中に`self`を可変で借用するため、一行分だけ複製しておく
+            let line = self.lines[index].clone();
+            for (column, cell) in line.iter().enumerate() {
+                let Some(cell) = cell else {
+                    continue;
+                };
+
+                if cell.background != DEFAULT_BACKGROUND {
+                    let width = char_width(cell.character);
+                    self.fill_cells(column..column + width, row, cell.background, info);
+                }
+
+                self.cursor = (column, row);
+                self.draw_glyph(
+                    cell.character,
+                    cell.font_type,
+                    cell.red_green_blue,
+                    cell.background,
+                    info,
+                );
+            }
+        }
+
+        self.cursor = cursor;
+    }
+
+    /// テキストバッファのうち、書き換えられた領域だけをフレームバッファへ反映する
     pub(super) fn merge_buffer(&mut self) {
-        FRAME_BUFFER
-            .get()
-            .unwrap()
-            .lock()
-            .copy_from_slice(&self.text_buffer);
+        let info = FRAME_BUFFER_INFO.get().unwrap();
+        self.ensure_textbuffer(info);
+
+        if !self.damage.is_dirty() {
+            return;
+        }
+
+        let bytes_per_pixel = info.bytes_per_pixel;
+        let mut frame_buffer = FRAME_BUFFER.get().unwrap().lock();
+        for rect in self.damage.take() {
+            let rect = rect.clip(info.stride, info.height);
+            for y in rect.y..rect.bottom() {
+                let start = (y * info.stride + rect.x) * bytes_per_pixel;
+                let end = (y * info.stride + rect.right()) * bytes_per_pixel;
+                frame_buffer[start..end].copy_from_slice(&self.text_buffer[start..end]);
+            }
+        }
+    }
+
+    /// 書き込みのたびにフレームバッファへ反映するのをやめ、`end_batch()`でまとめて反映するようにする
+    ///
+    /// 入れ子にして呼んでもよい。その場合は最も外側の`end_batch()`で反映する
+    pub(super) fn begin_batch(&mut self) {
+        self.batch_depth += 1;
+    }
+
+    /// `begin_batch()`の対となる関数。溜まった描画内容をフレームバッファへ反映する
+    pub(super) fn end_batch(&mut self) {
+        self.batch_depth = self.batch_depth.saturating_sub(1);
+        if self.batch_depth == 0 {
+            self.merge_buffer();
+        }
+    }
+
+    /// まとめて反映している途中であっても、溜まった描画内容をフレームバッファへ反映し、以降は書き込みのたびに反映する
+    ///
+    /// パニックのように、`BatchGuard`が破棄されないまま処理が終わる場合に使う
+    pub(super) fn force_flush(&mut self) {
+        self.batch_depth = 0;
+        self.merge_buffer();
+    }
+
+    /// 書き込みのたびにフレームバッファへ反映してよければ`true`を返す
+    #[inline(always)]
+    pub(super) fn auto_flush(&self) -> bool {
+        self.batch_depth == 0
     }
 
     pub(super) fn clear(&mut self) {
+        self.text_buffer.fill(0);
+        self.lines.clear();
+        self.view_offset = 0;
+        self.last_row = CURSOR_DEFAULT_POSITION.1;
+        self.damage.clear();
         FRAME_BUFFER.get().unwrap().lock().fill(0);
     }
 }
@@ -1,3 +1,8 @@
+/// 入力キューに溜めておけるキー入力の数
+const INPUT_QUEUE_CAPACITY: usize = 256;
+/// シリアルから受信して溜めておけるバイト数
+const SERIAL_INPUT_CAPACITY: usize = 256;
+
 /// 割り込みなどの初期化
 #[cfg(target_arch = "x86_64")]
 pub(crate) fn init() {
@@ -5,4 +10,77 @@ pub(crate) fn init() {
 
     gdt::init();
     idt::init();
+    idt::set_exception_reporter(report_exception);
+}
+
+/// 画面の初期化が済んでいれば、回復可能な例外の内容を画面にも出力する
+#[cfg(target_arch = "x86_64")]
+fn report_exception(args: core::fmt::Arguments) {
+    if crate::TEXT_BUFFER.get().is_some() {
+        crate::graphic::console::_print(args);
+    }
+}
+
+/// ACPIのMADTに従ってAPICを初期化し、割り込みを有効化する。memory::init()とacpi::init()の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init_apic() {
+    use amd64_lib::{
+        acpi,
+        interrupt::{self, apic},
+    };
+
+    let madt = acpi::info()
+        .and_then(|info| info.apic())
+        .expect("APIC is not described in the MADT");
+
+    unsafe {
+        apic::init(
+            madt.local_apic_address,
+            &madt.io_apics,
+            &madt.interrupt_source_overrides,
+        );
+    }
+
+    interrupt::enable();
+}
+
+/// タイマ割り込みを初期化し、カーネルの時刻を動かし始める。init_apic()の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init_timer() {
+    use amd64_lib::{acpi, timer};
+
+    let hpet_address = acpi::info()
+        .and_then(|info| info.hpet.as_ref())
+        .map(|hpet| hpet.base_address as u64);
+
+    unsafe {
+        timer::init(hpet_address);
+    }
+}
+
+/// 入力キューとキーボードを初期化する。init_apic()の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init_keyboard() {
+    use amd64_lib::keyboard;
+    use common_lib::input::{self, keyboard::layout::Jis109};
+
+    input::init(INPUT_QUEUE_CAPACITY);
+
+    // 日本語環境を主な対象とするため、JIS配列を既定とする
+    unsafe {
+        keyboard::init(&Jis109);
+    }
+}
+
+/// シリアルからの受信を割り込みで受け付け始める。init_apic()の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init_serial_input() {
+    use alloc::sync::Arc;
+    use amd64_lib::serial;
+    use common_lib::fs::devfs;
+
+    unsafe {
+        serial::init_input(SERIAL_INPUT_CAPACITY);
+    }
+    devfs::register("ttyS0", Arc::new(serial::SerialDevice));
 }
@@ -0,0 +1,86 @@
+//! カーネルのログ出力の設定
+//!
+//! 出力するレベルはビルド時の環境変数`EMER_LOG`で指定できる（書式は`common_lib::logger`を参照）。
+//! ファイルシステムをマウントした後は、`/etc/log`があればその内容に切り替える。
+//! 初期RAMディスクに入れる場合は`initrd/etc/log`に置く
+
+use alloc::{string::String, vec, vec::Vec};
+use common_lib::{
+    fs::{self, FsError, OpenOptions},
+    logger,
+};
+use log::LevelFilter;
+
+use crate::graphic::console::ConsoleSink;
+
+/// `EMER_LOG`が指定されなかったときのレベル指定
+const DEFAULT_FILTER: &str = "info";
+/// 起動時に読み込む、レベル指定を書いたファイル
+const FILTER_FILE: &str = "/etc/log";
+/// レベル指定のファイルから読み込む最大のバイト数
+const FILTER_FILE_LIMIT: usize = 4096;
+/// リングバッファの容量
+const RING_BUFFER_SIZE: usize = 64 * 1024;
+/// 画面に出力する最大のレベル。画面への描画は遅いため、シリアルよりも絞っておく
+const CONSOLE_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// ロガーの初期化とシリアル出力の登録。ヒープを使うため、memory::init()の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init() {
+    use amd64_lib::serial::SerialSink;
+
+    let filter = option_env!("EMER_LOG").unwrap_or(DEFAULT_FILTER);
+    logger::init(filter, RING_BUFFER_SIZE).expect("Failed to set the kernel logger");
+
+    static SERIAL_SINK: SerialSink = SerialSink;
+    logger::add_sink(&SERIAL_SINK, LevelFilter::Trace);
+}
+
+/// 画面への出力を登録する。graphic::init()の後に呼ぶこと
+pub(crate) fn init_console() {
+    static CONSOLE_SINK: ConsoleSink = ConsoleSink;
+    logger::add_sink(&CONSOLE_SINK, CONSOLE_LEVEL);
+}
+
+/// レベル指定を変更する。書式は`EMER_LOG`と同じ
+pub(crate) fn set_filter(spec: &str) {
+    logger::set_filter(spec);
+    log::info!("Log filter set to \"{}\"", spec);
+}
+
+/// `/etc/log`があれば、その内容をレベル指定として使う。fs::init()の後に呼ぶこと
+///
+/// 行ごとの指定は`,`で区切ったものとして繋げ、`#`から行末まではコメントとして無視する
+pub(crate) fn load_filter() {
+    let text = match read_filter_file() {
+        Ok(text) => text,
+        Err(FsError::NotFound) => return,
+        Err(e) => {
+            log::warn!("Failed to read {}: {}", FILTER_FILE, e);
+            return;
+        }
+    };
+
+    let items: Vec<&str> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|item| !item.is_empty())
+        .collect();
+    if !items.is_empty() {
+        set_filter(&items.join(","));
+    }
+}
+
+fn read_filter_file() -> Result<String, FsError> {
+    let file = fs::open(FILTER_FILE, OpenOptions::new().read(true))?;
+    let mut buffer = vec![0; FILTER_FILE_LIMIT];
+    let mut len = 0;
+    while len < buffer.len() {
+        match file.read(&mut buffer[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    buffer.truncate(len);
+    String::from_utf8(buffer).map_err(|_| FsError::InvalidArgument)
+}
@@ -7,11 +7,18 @@
 
 extern crate alloc;
 
+mod acpi;
+mod block;
+mod fs;
 mod graphic;
 mod interrupts;
+mod logger;
 mod memory;
+mod pci;
+mod task;
+mod thread;
 
-use amd64_lib::{interrupt::halt, serial_println};
+use amd64_lib::{interrupt::halt, serial};
 use bootloader_api::{config::Mapping, info::FrameBufferInfo, BootloaderConfig};
 use common_lib::locked::Locked;
 use core::panic::PanicInfo;
@@ -45,11 +52,27 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     interrupts::init();
 
     memory::init(boot_info.physical_memory_offset, &boot_info.memory_regions);
+    logger::init();
+    memory::report_usage();
+
+    acpi::init(boot_info.rsdp_addr, boot_info.physical_memory_offset);
+    interrupts::init_apic();
+    interrupts::init_timer();
+    interrupts::init_keyboard();
+    interrupts::init_serial_input();
+    pci::init();
+    block::init();
+    fs::init(boot_info.ramdisk_addr, boot_info.ramdisk_len);
+    logger::load_filter();
+    thread::init();
 
     let frame_buffer = boot_info.framebuffer.as_mut().unwrap();
     graphic::init(frame_buffer);
+    logger::init_console();
+    memory::report_layout();
 
     // println!()マクロと画面の描画テスト
+    let batch = graphic::console::batch();
     println!("Graphic test");
     println!("グラフィックテスト");
     println!("ｸﾞﾗﾌｨｯｸﾃｽﾄ");
@@ -57,16 +80,20 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     println!("がめんにもじをかくよ");
     println!("Framebufferテスト now");
     println!("ｸﾞﾗﾌｨｯｸのﾃｽﾄを実施中");
+    drop(batch);
 
-    loop {
-        halt();
-    }
+    task::run()
 }
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("{}", _info);
-    println!("{}", _info);
+    let nested = common_lib::sync::begin_panic();
+    // パニックはシリアルのロックを持ったまま起こることもあるため、ロックを取らずに書き出す
+    serial::write_unlocked(format_args!("{}\n", _info));
+    // 画面への出力中にパニックを起こした場合は、再び画面に出力しない
+    if !nested {
+        graphic::console::write_panic(format_args!("{}", _info));
+    }
     loop {
         halt();
     }
@@ -2,30 +2,35 @@ use bootloader_api::info::{MemoryRegions, Optional};
 use core::cell::OnceCell;
 
 use common_lib::{
-    locked::Locked,
-    memory::{allocator::fixed_size_block::FixedSizeBlockAllocator, heap::Heap},
+    locked::Locked, memory::allocator::fixed_size_block::FixedSizeBlockAllocator, sync::LockLevel,
 };
 
-const HEAP_START: usize = 0x_4444_4444_0000;
-const HEAP_SIZE: usize = 32000 * 1024; // 32 MiB
+/// ヒープとして予約する仮想アドレスの大きさ。物理フレームは使われた分だけ割り当てる
+const HEAP_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+/// ヒープに割り当てる物理メモリの上限
+const HEAP_LIMIT: usize = 256 * 1024 * 1024; // 256 MiB
 
 // 固定サイズブロックアロケータを使う
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::with_level(
+    FixedSizeBlockAllocator::new(),
+    "allocator",
+    LockLevel::ALLOCATOR,
+);
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    panic!(
+        "OUT OF MEMORY: kernel heap could not satisfy {:?} ({} MiB reserved)",
+        layout,
+        HEAP_SIZE / (1024 * 1024)
+    )
 }
 
 /// メモリ管理機能の初期化
 #[cfg(target_arch = "x86_64")]
 pub(crate) fn init(physical_memory_offset: Optional<u64>, memory_regions: &'static MemoryRegions) {
-    use amd64_lib::memory::{
-        self,
-        heap::{self},
-        paging::BootInfoFrameAllocator,
-    };
+    use amd64_lib::memory::{self, heap};
 
     // まずは物理メモリのオフセットを取り出す
     let physical_memory_offset = match physical_memory_offset {
@@ -36,15 +41,86 @@ pub(crate) fn init(physical_memory_offset: Optional<u64>, memory_regions: &'stat
     // ヒープとアロケータの初期化
     // 手順は以下の通り:
     // 1. ヒープ領域とそのアロケートに使うアロケータの登録
-    // 1. OffsetPageTableの初期化
-    // 1. 引数から渡されたメモリマップからFrameAllocatorを作る
-    // 1. 最後にヒープ領域を初期化する（アロケータも、この時初期化する）
+    // 1. OffsetPageTableと、引数から渡されたメモリマップからFrameAllocatorを作る
+    // 1. 最後にヒープ領域を予約する（アロケータも、この時初期化する）
+    //    物理フレームは、ヒープに初めて触れたときのページフォルトで割り当てられる
+    //    ヒープの仮想アドレスは、仮想アドレス空間の空いている範囲から割り当てる
     let heap_init = OnceCell::new();
     heap_init.get_or_init(|| unsafe {
-        let heap = Heap::new(HEAP_START, HEAP_SIZE, &ALLOCATOR);
-        let mapper = &mut memory::paging::init(physical_memory_offset);
-        let frame_allocator = &mut BootInfoFrameAllocator::init(memory_regions);
+        memory::init(memory_regions, physical_memory_offset);
 
-        heap::init(heap, mapper, frame_allocator).expect("heap initialization failed");
+        heap::init(&ALLOCATOR, HEAP_SIZE, HEAP_LIMIT).expect("heap initialization failed")
     });
 }
+
+/// 物理フレームの使用状況をログに出力する
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn report_usage() {
+    use amd64_lib::memory;
+
+    if let Some(manager) = memory::manager() {
+        // ログの出力中にロックを持ち続けないよう、値を読み出してから出力する
+        let (total, used, free) = {
+            let frame_allocator = &manager.lock().frame_allocator;
+            (
+                frame_allocator.total_frames(),
+                frame_allocator.used_frames(),
+                frame_allocator.free_frames(),
+            )
+        };
+        log::info!(
+            "Physical frames: total={}, used={}, free={}",
+            total,
+            used,
+            free
+        );
+    }
+}
+
+/// カーネルの仮想アドレス空間の配置をログに出力する
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn report_layout() {
+    use amd64_lib::memory::vma;
+
+    log::info!("Kernel address space layout:");
+    vma::for_each(|area| log::info!("  {}", area));
+}
+
+/// ブートローダーがマップしたフレームバッファを、ライトコンバイニングでマップし直す
+///
+/// マップし直せなかった場合は、元のバッファをそのまま返す
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn remap_frame_buffer(buffer: &'static mut [u8]) -> &'static mut [u8] {
+    use amd64_lib::memory::{
+        mmio::{self, CacheType},
+        vma::{self, VmaKind},
+    };
+    use x86_64::VirtAddr;
+
+    let start = buffer.as_ptr() as u64;
+    let len = buffer.len();
+    if let Err(e) = vma::claim_range("frame buffer", VmaKind::FrameBuffer, start, len as u64) {
+        log::warn!("Failed to record the frame buffer mapping: {:?}", e);
+        return buffer;
+    }
+
+    let remapped = unsafe {
+        mmio::remap(
+            "frame buffer",
+            VmaKind::FrameBuffer,
+            VirtAddr::new(start),
+            len,
+            CacheType::WriteCombining,
+        )
+    };
+    match remapped {
+        Ok(io_mem) => io_mem.leak(),
+        Err(e) => {
+            log::warn!(
+                "Failed to remap the frame buffer as write-combining: {:?}",
+                e
+            );
+            buffer
+        }
+    }
+}
@@ -0,0 +1,29 @@
+/// PCIバスを列挙し、見つかったデバイスをログに出力する。memory::init()とacpi::init()の後に呼ぶこと
+///
+/// 組み込みのドライバは列挙の前に登録しておき、列挙と同時にデバイスと結び付ける。
+/// 列挙した後に登録したドライバも、`common_lib::pci::driver::register()`の時点でデバイスと結び付く
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init() {
+    use amd64_lib::{pci, virtio};
+    use common_lib::pci::driver;
+
+    driver::register(&virtio::block::DRIVER);
+
+    let devices = unsafe { pci::init() };
+    log::info!("PCI: {} functions found", devices.len());
+
+    for device in devices {
+        log::info!("  {}", device);
+        for (index, bar) in device.bars() {
+            log::debug!("    BAR{}: {}", index, bar);
+        }
+        for capability in device.capabilities() {
+            log::debug!(
+                "    Capability {:#04x} ({}) at {:#04x}",
+                capability.id,
+                capability.name(),
+                capability.offset
+            );
+        }
+    }
+}
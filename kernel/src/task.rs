@@ -0,0 +1,63 @@
+//! 非同期タスクの設定
+
+use common_lib::task::{executor::Executor, stream::StreamExt};
+
+/// 同時に扱えるタスクの数
+const TASK_CAPACITY: usize = 256;
+
+/// 実行器を作って常駐タスクを登録し、タスクを実行し続ける
+///
+/// 画面とキーボード、シリアルの受信を使うため、それらの初期化の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn run() -> ! {
+    use amd64_lib::interrupt;
+
+    let mut executor = Executor::new(TASK_CAPACITY, interrupt::halt_if);
+    executor.spawn(echo_keyboard());
+    executor.spawn(echo_serial());
+
+    log::info!("Executor started ({} tasks)", executor.task_count());
+    executor.run()
+}
+
+/// キーボードから入力された文字を画面に表示する
+async fn echo_keyboard() {
+    use common_lib::input::{self, keyboard::DecodedKey};
+
+    let Some(mut keys) = input::key_stream() else {
+        log::warn!("Keyboard input is not initialized");
+        return;
+    };
+
+    while let Some(key) = keys.next().await {
+        if let DecodedKey::Unicode(c) = key {
+            crate::print!("{}", c);
+        }
+    }
+}
+
+/// シリアルから受信した文字をそのままシリアルに送り返す
+#[cfg(target_arch = "x86_64")]
+async fn echo_serial() {
+    use amd64_lib::{serial, serial_print};
+
+    let Some(mut bytes) = serial::input_stream() else {
+        log::warn!("Serial input is not initialized");
+        return;
+    };
+
+    while let Some(byte) = bytes.next().await {
+        match byte {
+            b'\r' => {
+                serial_print!("\n");
+            }
+            // DEL
+            0x7f => {
+                serial_print!("\x08 \x08");
+            }
+            _ => {
+                serial_print!("{}", byte as char);
+            }
+        }
+    }
+}
@@ -0,0 +1,17 @@
+//! カーネルスレッドの設定
+
+/// スケジューラの初期化。以降、`kernel_main`は`main`スレッドとして他のスレッドと交互に実行される
+///
+/// ヒープとタイマを使うため、memory::init()とinterrupts::init_timer()の後に呼ぶこと
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn init() {
+    use amd64_lib::thread;
+
+    unsafe { thread::init() }
+        .unwrap_or_else(|e| panic!("Failed to initialize the scheduler: {:?}", e));
+
+    log::info!(
+        "Scheduler started (time slice: {} ticks)",
+        thread::TIME_SLICE_TICKS
+    );
+}
@@ -1,17 +1,18 @@
 // src/bin/qemu-bios.rs
 
-use std::{
-    env,
-    process::{self, Command},
-};
+use std::process::{self, Command};
+
+use emer_os::attach_test_disk;
 
 fn main() {
     let mut qemu = Command::new("qemu-system-x86_64");
     qemu.arg("--enable-kvm");
+    // カーネルから起動ディスクのパーティションを読めるよう、virtio-blkとして接続する
     qemu.arg("-drive");
-    qemu.arg(format!("format=raw,file={}", env!("BIOS_IMAGE")));
+    qemu.arg(format!("if=virtio,format=raw,file={}", env!("BIOS_IMAGE")));
     qemu.arg("-serial");
     qemu.arg("stdio");
+    attach_test_disk(&mut qemu);
     let exit_status = qemu.status().unwrap();
     process::exit(exit_status.code().unwrap_or(-1));
 }
@@ -0,0 +1,21 @@
+//! QEMUで起動するランナーに共通する処理
+
+use std::{env, process::Command};
+
+/// テスト用ディスクをvirtio-blkとして接続する
+///
+/// 環境変数`EMER_DISK`でイメージを差し替えられる。
+/// `EMER_VIRTIO_LEGACY`を設定すると、レガシーなインタフェースだけを持つデバイスとして接続する
+pub fn attach_test_disk(qemu: &mut Command) {
+    let disk = env::var("EMER_DISK").unwrap_or_else(|_| env!("TEST_DISK_IMAGE").to_string());
+    let transport = if env::var_os("EMER_VIRTIO_LEGACY").is_some() {
+        ",disable-legacy=off,disable-modern=on"
+    } else {
+        ""
+    };
+
+    qemu.arg("-drive");
+    qemu.arg(format!("if=none,id=testdisk,format=raw,file={}", disk));
+    qemu.arg("-device");
+    qemu.arg(format!("virtio-blk-pci,drive=testdisk{}", transport));
+}